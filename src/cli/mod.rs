@@ -3,7 +3,9 @@ mod parse;
 use crate::drop_policy::DropPolicy;
 use crate::node::Node;
 use crate::protocol::tcp::prelude::{Port, Remote, SocketDescriptor};
-use crate::protocol::tcp::{TcpAcceptError, TcpConnError, TcpListenError, TcpSendError};
+use crate::protocol::tcp::{
+    LingerConfig, TcpAcceptError, TcpConnError, TcpListenError, TcpReadError, TcpSendError,
+};
 use crate::protocol::Protocol;
 use crate::repl::{HandleUserInput, HandleUserInputError, Repl};
 use async_trait::async_trait;
@@ -11,12 +13,19 @@ use std::fs::File;
 use std::io::Write;
 use std::net::Ipv4Addr;
 use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a shutdown waits for remotes to ack the FINs of open connections.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Command {
     ListInterface(Option<String>),
     ListRoute(Option<String>),
-    ListSockets(Option<String>),
+    ListSockets {
+        file: Option<String>,
+        verbose: bool,
+    },
     InterfaceDown(u16),
     InterfaceUp(u16),
     SendIPv4Packet {
@@ -33,7 +42,21 @@ pub enum Command {
         would_block: bool,
     },
     Shutdown(SocketDescriptor, TcpShutdownKind),
-    Close(SocketDescriptor),
+    Close(SocketDescriptor, LingerConfig),
+    Bandwidth(SocketDescriptor),
+    OutOfOrder {
+        descriptor: SocketDescriptor,
+        clear: bool,
+    },
+    Drops {
+        reset: bool,
+    },
+    NoDelay {
+        descriptor: SocketDescriptor,
+        nodelay: bool,
+    },
+    #[cfg(any(test, feature = "debug-commands"))]
+    Retransmit(SocketDescriptor),
     SendFile {
         path: String,
         dest_ip: Ipv4Addr,
@@ -43,6 +66,12 @@ pub enum Command {
         out_path: String,
         port: Port,
     },
+    HttpGet {
+        dest_ip: Ipv4Addr,
+        port: Port,
+        path: String,
+    },
+    EchoServer(Port),
     Quit,
     None,
 }
@@ -59,7 +88,11 @@ pub enum SendFileError {
     OpenFile(std::io::Error),
     ReadFile(std::io::Error),
     Connect(TcpConnError),
-    Send(TcpSendError),
+    /// The connection failed after the remote acked the first `acked` bytes.
+    Send {
+        error: TcpSendError,
+        acked: usize,
+    },
 }
 
 #[derive(Debug)]
@@ -67,6 +100,20 @@ pub enum RecvFileError {
     FileIo(std::io::Error),
     Listen(TcpListenError),
     Accept(TcpAcceptError),
+    Read(TcpReadError),
+}
+
+#[derive(Debug)]
+pub enum EchoServerError {
+    Listen(TcpListenError),
+    Accept(TcpAcceptError),
+}
+
+#[derive(Debug)]
+pub enum HttpGetError {
+    Connect(TcpConnError),
+    Send(TcpSendError),
+    Read(TcpReadError),
 }
 
 impl From<std::io::Error> for RecvFileError {
@@ -86,7 +133,11 @@ impl<DP: DropPolicy> HandleUserInput for Cli<DP> {
         user_input: String,
     ) -> Result<(), crate::repl::HandleUserInputError> {
         match parse::parse_command(user_input) {
-            Ok(Command::Quit) => return Err(HandleUserInputError::Terminate),
+            Ok(Command::Quit) => {
+                eprintln!("Commencing graceful shutdown");
+                self.node.shutdown(SHUTDOWN_TIMEOUT).await;
+                return Err(HandleUserInputError::Terminate);
+            }
             Ok(Command::None) => (),
             Ok(cmd) => self.execute_command(cmd).await,
             Err(e) => {
@@ -119,8 +170,8 @@ impl<DP: DropPolicy> Cli<DP> {
             Command::ListRoute(op) => {
                 self.print_routes(op).await;
             }
-            Command::ListSockets(op) => {
-                self.print_sockets(op).await;
+            Command::ListSockets { file, verbose } => {
+                self.print_sockets(file, verbose).await;
             }
             Command::InterfaceDown(interface) => {
                 eprintln!("Turning down interface {interface}");
@@ -147,7 +198,7 @@ impl<DP: DropPolicy> Cli<DP> {
                     .send(payload.as_bytes(), protocol, virtual_ip)
                     .await
                 {
-                    eprintln!("Failed to send packet: {e:?}");
+                    eprintln!("Failed to send packet: {e}");
                 }
             }
             Command::SendTCPPacket(socket_descriptor, payload) => {
@@ -167,15 +218,38 @@ impl<DP: DropPolicy> Cli<DP> {
                 if would_block {
                     self.tcp_read(descriptor, num_bytes).await;
                 } else {
-                    self.tcp_bg_read(descriptor, num_bytes).await;
+                    self.tcp_try_read(descriptor, num_bytes).await;
                 }
             }
 
             Command::Shutdown(socket, opt) => {
                 self.shutdown(socket, opt).await;
             }
-            Command::Close(socket_descriptor) => {
-                self.close_socket(socket_descriptor).await;
+            Command::Close(socket_descriptor, linger) => {
+                self.close_socket(socket_descriptor, linger).await;
+            }
+            Command::Bandwidth(socket_descriptor) => {
+                self.print_bandwidth(socket_descriptor).await;
+            }
+            Command::OutOfOrder { descriptor, clear } => {
+                if clear {
+                    self.clear_out_of_order(descriptor).await;
+                } else {
+                    self.print_out_of_order(descriptor).await;
+                }
+            }
+            Command::Drops { reset } => {
+                self.print_drops(reset);
+            }
+            Command::NoDelay {
+                descriptor,
+                nodelay,
+            } => {
+                self.set_nodelay(descriptor, nodelay).await;
+            }
+            #[cfg(any(test, feature = "debug-commands"))]
+            Command::Retransmit(descriptor) => {
+                self.force_retransmit(descriptor).await;
             }
             Command::SendFile {
                 path,
@@ -185,6 +259,12 @@ impl<DP: DropPolicy> Cli<DP> {
                 self.send_file(&path, (dest_ip, port));
             }
             Command::RecvFile { out_path, port } => self.recv_file(&out_path, port),
+            Command::HttpGet {
+                dest_ip,
+                port,
+                path,
+            } => self.http_get((dest_ip, port), &path),
+            Command::EchoServer(port) => self.echo_server(port),
             Command::Quit => {
                 eprintln!("Quitting");
             }
@@ -216,8 +296,8 @@ impl<DP: DropPolicy> Cli<DP> {
         todo!()
     }
 
-    async fn print_sockets(&self, file: Option<String>) {
-        self.node.print_sockets(file).await;
+    async fn print_sockets(&self, file: Option<String>, verbose: bool) {
+        self.node.print_sockets(file, verbose).await;
     }
 
     async fn tcp_send(&self, socket_descriptor: SocketDescriptor, payload: Vec<u8>) {
@@ -229,9 +309,14 @@ impl<DP: DropPolicy> Cli<DP> {
         }
     }
 
-    async fn tcp_bg_read(&self, descriptor: SocketDescriptor, num_bytes: usize) {
-        let node = self.node.clone();
-        tokio::spawn(async move { tcp_read(&node, descriptor, num_bytes).await });
+    async fn tcp_try_read(&self, descriptor: SocketDescriptor, num_bytes: usize) {
+        match self.node.tcp_try_read(descriptor, num_bytes).await {
+            Ok(bytes) => println!("{}", String::from_utf8_lossy(&bytes)),
+            Err(TcpReadError::WouldBlock) => {
+                println!("No bytes to read on socket {}", descriptor.0)
+            }
+            Err(e) => eprintln!("Failed to read: {e:?}"),
+        }
     }
 
     async fn tcp_read(&self, descriptor: SocketDescriptor, num_bytes: usize) {
@@ -243,7 +328,23 @@ impl<DP: DropPolicy> Cli<DP> {
             Some(socket) => match option {
                 TcpShutdownKind::Read => socket.close_read().await,
                 TcpShutdownKind::Write => socket.close().await,
-                TcpShutdownKind::ReadWrite => socket.close_rw().await,
+                TcpShutdownKind::ReadWrite => {
+                    if let Err(e) = socket.close_rw().await {
+                        eprintln!("Failed to shut down socket {}: {e:?}", descriptor.0)
+                    }
+                }
+            },
+            None => {
+                eprintln!("Socket {} not found", descriptor.0)
+            }
+        }
+    }
+
+    async fn print_bandwidth(&self, descriptor: SocketDescriptor) {
+        match self.node.get_socket_by_descriptor(descriptor).await {
+            Some(socket) => match socket.throughput_bps().await {
+                Some(bps) => println!("{}\t{:.0} bps", descriptor.0, bps),
+                None => eprintln!("Socket {} has no connection", descriptor.0),
             },
             None => {
                 eprintln!("Socket {} not found", descriptor.0)
@@ -251,6 +352,82 @@ impl<DP: DropPolicy> Cli<DP> {
         }
     }
 
+    async fn print_out_of_order(&self, descriptor: SocketDescriptor) {
+        match self.node.get_socket_by_descriptor(descriptor).await {
+            Some(socket) => match socket.out_of_order_ranges().await {
+                Some(ranges) => {
+                    println!("start\tend");
+                    for (start, end) in ranges {
+                        println!("{start}\t{end}");
+                    }
+                }
+                None => eprintln!("Socket {} has no connection", descriptor.0),
+            },
+            None => {
+                eprintln!("Socket {} not found", descriptor.0)
+            }
+        }
+    }
+
+    async fn clear_out_of_order(&self, descriptor: SocketDescriptor) {
+        match self.node.get_socket_by_descriptor(descriptor).await {
+            Some(socket) => match socket.clear_out_of_order().await {
+                Some(n_segments) => eprintln!("Discarded {n_segments} out-of-order segments"),
+                None => eprintln!("Socket {} has no connection", descriptor.0),
+            },
+            None => {
+                eprintln!("Socket {} not found", descriptor.0)
+            }
+        }
+    }
+
+    /// Prints the drop policy's counts, then restarts them if `reset`.
+    fn print_drops(&self, reset: bool) {
+        match self.node.drop_stats() {
+            Some(stats) => {
+                println!("seen\tdropped\tratio");
+                println!(
+                    "{}\t{}\t{:.4}",
+                    stats.seen,
+                    stats.dropped,
+                    stats.drop_ratio()
+                );
+            }
+            None => eprintln!("The drop policy does not count packets"),
+        }
+        if reset {
+            self.node.reset_drop_stats();
+            eprintln!("Reset drop counters");
+        }
+    }
+
+    async fn set_nodelay(&self, descriptor: SocketDescriptor, nodelay: bool) {
+        match self.node.get_socket_by_descriptor(descriptor).await {
+            Some(socket) => {
+                if socket.set_nodelay(nodelay).await.is_none() {
+                    eprintln!("Socket {} has no connection", descriptor.0);
+                }
+            }
+            None => {
+                eprintln!("Socket {} not found", descriptor.0)
+            }
+        }
+    }
+
+    #[cfg(any(test, feature = "debug-commands"))]
+    async fn force_retransmit(&self, descriptor: SocketDescriptor) {
+        match self.node.get_socket_by_descriptor(descriptor).await {
+            Some(socket) => {
+                if socket.force_retransmit().await.is_none() {
+                    eprintln!("Socket {} has no connection", descriptor.0);
+                }
+            }
+            None => {
+                eprintln!("Socket {} not found", descriptor.0)
+            }
+        }
+    }
+
     async fn open_listen_socket_on(&self, port: Port) {
         match self.node.listen(port).await {
             Ok(_) => eprintln!("Listen socket opened on port {}", port.0),
@@ -307,7 +484,28 @@ impl<DP: DropPolicy> Cli<DP> {
         });
     }
 
-    async fn close_socket(&self, socket_descriptor: SocketDescriptor) {
+    fn http_get(&self, remote: impl Into<Remote>, path: &str) {
+        let remote = remote.into();
+        let node = self.node.clone();
+        let path: String = path.into();
+        tokio::spawn(async move {
+            http_get(&node, remote, &path, &mut std::io::stdout()).await;
+        });
+    }
+
+    fn echo_server(&self, port: Port) {
+        let node = self.node.clone();
+        tokio::spawn(async move {
+            if let Err(e) = echo_server(node, port).await {
+                eprintln!("Echo server on port {} stopped. Error: {e:?}", port.0);
+            }
+        });
+    }
+
+    async fn close_socket(&self, socket_descriptor: SocketDescriptor, linger: LingerConfig) {
+        if let Some(socket) = self.node.get_socket_by_descriptor(socket_descriptor).await {
+            socket.set_linger(linger);
+        }
         if self
             .node
             .close_socket_by_descriptor(socket_descriptor)
@@ -332,3 +530,226 @@ async fn tcp_read<DP: DropPolicy>(node: &Node<DP>, sid: SocketDescriptor, num_by
         }
     }
 }
+
+/// Accepts connections on `port` forever, echoing each one's bytes back
+/// until its client closes.
+async fn echo_server<DP: DropPolicy>(
+    node: Arc<Node<DP>>,
+    port: Port,
+) -> Result<(), EchoServerError> {
+    let mut listener = node.listen(port).await.map_err(EchoServerError::Listen)?;
+    eprintln!("Echo server listening on port {}", port.0);
+
+    loop {
+        let conn = listener.accept().await.map_err(EchoServerError::Accept)?;
+        let node = node.clone();
+        tokio::spawn(async move {
+            let remote = *conn.remote();
+            if let Err(e) = node.echo(conn).await {
+                eprintln!("Failed to echo to {remote:?}. Error: {e:?}");
+            }
+        });
+    }
+}
+
+async fn http_get<DP: DropPolicy, W: Write>(
+    node: &Node<DP>,
+    remote: Remote,
+    path: &str,
+    out: &mut W,
+) {
+    match node.http_get(remote, path).await {
+        Ok(response) => {
+            if let Err(e) = writeln!(out, "{}", String::from_utf8_lossy(&response)) {
+                eprintln!("Failed to print the response to GET {path}. Error: {e:?}");
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to GET {path}. Error: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::drop_policy::NeverDrop;
+    use crate::fixture::node::start_node_with;
+    use crate::protocol::tcp::{SocketStatus, TcpCloseError};
+    use crate::Args;
+
+    #[tokio::test]
+    async fn http_get_prints_response() {
+        let request = b"GET /index.html HTTP/1.0\r\n\r\n";
+        let response = "HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let client = start_node(&abc_net.a).await;
+        let server = start_node(&abc_net.b).await;
+
+        let port = Port(8080);
+        let mut listener = server.listen(port).await.unwrap();
+        let server_task = tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            let mut received = vec![0; request.len()];
+            conn.read_all(&mut received).await.unwrap();
+            assert_eq!(received, request);
+
+            conn.send_all(response.as_bytes()).await.unwrap();
+            server.close_socket(conn.socket_id()).await.unwrap();
+        });
+
+        let server_ip = abc_net.b.get_my_interface_ips()[0];
+        let mut out = Vec::new();
+        http_get(
+            &client,
+            Remote::new(server_ip, port),
+            "/index.html",
+            &mut out,
+        )
+        .await;
+        server_task.await.unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{response}\n"));
+    }
+
+    /// Output whose reader has gone away, like a closed stdout.
+    struct ClosedOutput;
+
+    impl Write for ClosedOutput {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn http_get_survives_closed_output() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let client = start_node(&abc_net.a).await;
+        let server = start_node(&abc_net.b).await;
+
+        let port = Port(8081);
+        let mut listener = server.listen(port).await.unwrap();
+        tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            conn.send_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            server.close_socket(conn.socket_id()).await.unwrap();
+        });
+
+        let server_ip = abc_net.b.get_my_interface_ips()[0];
+        http_get(
+            &client,
+            Remote::new(server_ip, port),
+            "/",
+            &mut ClosedOutput,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn echo_server_echoes_bytes() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let client = start_node(&abc_net.a).await;
+        let server = start_node(&abc_net.b).await;
+
+        let port = Port(7);
+        tokio::spawn(echo_server(server, port));
+        // Let the server open its listen socket
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let server_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = client.connect(server_ip, port).await.unwrap();
+        for payload in [&b"hello"[..], &[7; 5000]] {
+            conn.send_all(payload).await.unwrap();
+            let mut echoed = vec![0; payload.len()];
+            conn.read_all(&mut echoed).await.unwrap();
+            assert_eq!(echoed, payload);
+        }
+
+        client.close_socket(conn.socket_id()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_both_fails_reads_and_writes() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let client = start_node(&abc_net.a).await;
+        let server = start_node(&abc_net.b).await;
+
+        let port = Port(9090);
+        let mut listener = server.listen(port).await.unwrap();
+        let server_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = client.connect(server_ip, port).await.unwrap();
+        let _remote_conn = listener.accept().await.unwrap();
+
+        let pending_conn = conn.clone();
+        let pending_read = tokio::spawn(async move {
+            let mut buf = [0; 8];
+            pending_conn.read_all(&mut buf).await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let descriptor = client
+            .get_socket_descriptor(conn.socket_id())
+            .await
+            .unwrap();
+        let mut cli = Cli::new(client.clone());
+        cli.handle(format!("sd {} both", descriptor.0))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            pending_read.await.unwrap(),
+            Err(TcpReadError::Closed(_))
+        ));
+        assert!(matches!(
+            conn.read_some(&mut [0; 8]).await,
+            Err(TcpReadError::Closed(_))
+        ));
+        assert_eq!(
+            conn.send_all(b"hello").await.unwrap_err(),
+            TcpSendError::ConnClosed
+        );
+
+        let socket = client.get_socket_by_descriptor(descriptor).await.unwrap();
+        assert!(matches!(
+            socket.close_rw().await,
+            Err(TcpCloseError::AlreadyClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn quit_closes_connections_gracefully() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let client = start_node(&abc_net.a).await;
+        let server = start_node(&abc_net.b).await;
+
+        let port = Port(9091);
+        let mut listener = server.listen(port).await.unwrap();
+        let server_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = client.connect(server_ip, port).await.unwrap();
+        let remote_conn = listener.accept().await.unwrap();
+
+        let mut cli = Cli::new(client.clone());
+        assert_eq!(
+            cli.handle("q".into()).await,
+            Err(HandleUserInputError::Terminate)
+        );
+
+        // The server sees a FIN, not a reset.
+        assert_eq!(remote_conn.read_to_end().await.unwrap(), b"");
+        assert!(matches!(
+            conn.state(),
+            SocketStatus::FinWait2 | SocketStatus::TimeWait
+        ));
+    }
+
+    async fn start_node(args: &Args) -> Arc<Node<NeverDrop>> {
+        start_node_with(args, NeverDrop, |b| b).await
+    }
+}
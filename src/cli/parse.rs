@@ -1,7 +1,10 @@
 use std::{fmt::Display, str::SplitWhitespace};
 
 use crate::protocol::{
-    tcp::prelude::{Port, SocketDescriptor},
+    tcp::{
+        prelude::{Port, SocketDescriptor},
+        LingerConfig,
+    },
     Protocol,
 };
 
@@ -69,6 +72,40 @@ pub enum ParseTcpShutdownError {
 pub enum ParseCloseError {
     NoSocketDescriptor,
     InvalidSocketDescriptor,
+    InvalidCloseType(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseBandwidthError {
+    NoSocketDescriptor,
+    InvalidSocketDescriptor,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseOutOfOrderError {
+    NoSocketDescriptor,
+    InvalidSocketDescriptor,
+    InvalidOption(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseDropsError {
+    InvalidOption(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseNoDelayError {
+    NoSocketDescriptor,
+    InvalidSocketDescriptor,
+    NoOption,
+    InvalidOption(String),
+}
+
+#[cfg(any(test, feature = "debug-commands"))]
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseRetransmitError {
+    NoSocketDescriptor,
+    InvalidSocketDescriptor,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -87,6 +124,21 @@ pub enum ParseRecvFileError {
     InvalidPort,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseHttpGetError {
+    NoIp,
+    InvalidIp,
+    NoPort,
+    InvalidPort,
+    NoPath,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseEchoServerError {
+    NoPort,
+    InvalidPort,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ParseError {
     Unknown,
@@ -99,8 +151,16 @@ pub enum ParseError {
     TcpRead(ParseTcpReadError),
     TcpShutdown(ParseTcpShutdownError),
     TcpClose(ParseCloseError),
+    Bandwidth(ParseBandwidthError),
+    OutOfOrder(ParseOutOfOrderError),
+    Drops(ParseDropsError),
+    NoDelay(ParseNoDelayError),
+    #[cfg(any(test, feature = "debug-commands"))]
+    Retransmit(ParseRetransmitError),
     SendFile(ParseSendFileError),
     RecvFile(ParseRecvFileError),
+    HttpGet(ParseHttpGetError),
+    EchoServer(ParseEchoServerError),
 }
 
 impl Display for ParseError {
@@ -153,7 +213,38 @@ impl Display for ParseError {
             ParseError::TcpClose(e) => {
                 write!(
                     f,
-                    "Invalid close command. Usage: cl <socket ID>. Error: {e:?}"
+                    "Invalid close command. Usage: cl <socket ID> [graceful|abort]. Error: {e:?}"
+                )
+            }
+            ParseError::Bandwidth(e) => {
+                write!(
+                    f,
+                    "Invalid bandwidth command. Usage: bw <socket ID>. Error: {e:?}"
+                )
+            }
+            ParseError::OutOfOrder(e) => {
+                write!(
+                    f,
+                    "Invalid out-of-order command. Usage: ooo <socket ID> [clear]. Error: {e:?}"
+                )
+            }
+            ParseError::Drops(e) => {
+                write!(
+                    f,
+                    "Invalid drops command. Usage: drops [reset]. Error: {e:?}"
+                )
+            }
+            ParseError::NoDelay(e) => {
+                write!(
+                    f,
+                    "Invalid nodelay command. Usage: nodelay <socket ID> <on|off>. Error: {e:?}"
+                )
+            }
+            #[cfg(any(test, feature = "debug-commands"))]
+            ParseError::Retransmit(e) => {
+                write!(
+                    f,
+                    "Invalid retransmit command. Usage: retx <socket ID>. Error: {e:?}"
                 )
             }
             ParseError::SendFile(e) => {
@@ -168,6 +259,18 @@ impl Display for ParseError {
                     "Invalid receive file command. Usage: rf <filename> <port>. Error: {e:?}"
                 )
             }
+            ParseError::HttpGet(e) => {
+                write!(
+                    f,
+                    "Invalid get command. Usage: get <ip> <port> <path>. Error: {e:?}"
+                )
+            }
+            ParseError::EchoServer(e) => {
+                write!(
+                    f,
+                    "Invalid echo server command. Usage: echoserver <port>. Error: {e:?}"
+                )
+            }
         }
     }
 }
@@ -226,6 +329,37 @@ impl From<ParseCloseError> for ParseError {
     }
 }
 
+impl From<ParseBandwidthError> for ParseError {
+    fn from(v: ParseBandwidthError) -> Self {
+        ParseError::Bandwidth(v)
+    }
+}
+
+impl From<ParseOutOfOrderError> for ParseError {
+    fn from(v: ParseOutOfOrderError) -> Self {
+        ParseError::OutOfOrder(v)
+    }
+}
+
+impl From<ParseDropsError> for ParseError {
+    fn from(v: ParseDropsError) -> Self {
+        ParseError::Drops(v)
+    }
+}
+
+impl From<ParseNoDelayError> for ParseError {
+    fn from(v: ParseNoDelayError) -> Self {
+        ParseError::NoDelay(v)
+    }
+}
+
+#[cfg(any(test, feature = "debug-commands"))]
+impl From<ParseRetransmitError> for ParseError {
+    fn from(v: ParseRetransmitError) -> Self {
+        ParseError::Retransmit(v)
+    }
+}
+
 impl From<ParseSendFileError> for ParseError {
     fn from(v: ParseSendFileError) -> Self {
         ParseError::SendFile(v)
@@ -238,6 +372,18 @@ impl From<ParseRecvFileError> for ParseError {
     }
 }
 
+impl From<ParseHttpGetError> for ParseError {
+    fn from(v: ParseHttpGetError) -> Self {
+        ParseError::HttpGet(v)
+    }
+}
+
+impl From<ParseEchoServerError> for ParseError {
+    fn from(v: ParseEchoServerError) -> Self {
+        ParseError::EchoServer(v)
+    }
+}
+
 pub fn parse_command(line: String) -> Result<Command, ParseError> {
     let mut tokens = line.split_whitespace();
     let c = tokens.next();
@@ -325,11 +471,10 @@ fn parse_cmd(cmd: &str, mut tokens: SplitWhitespace) -> Result<Command, ParseErr
             })
         }
         "ls" => {
-            let arg = tokens.next();
-            match arg {
-                Some(arg) => Ok(Command::ListSockets(Some(arg.to_string()))),
-                None => Ok(Command::ListSockets(None)),
-            }
+            let mut tokens = tokens.peekable();
+            let verbose = tokens.next_if_eq(&"-v").is_some();
+            let file = tokens.next().map(|arg| arg.to_string());
+            Ok(Command::ListSockets { file, verbose })
         }
         "a" => {
             let arg = tokens.next().ok_or(ParseOpenListenSocketError::NoPort)?;
@@ -413,8 +558,81 @@ fn parse_cmd(cmd: &str, mut tokens: SplitWhitespace) -> Result<Command, ParseErr
                 sid.parse()
                     .map_err(|_| ParseCloseError::InvalidSocketDescriptor)?,
             );
+            let linger = match tokens.next() {
+                None | Some("graceful") => LingerConfig::Graceful,
+                Some("abort") => LingerConfig::Abort,
+                Some(token) => return Err(ParseCloseError::InvalidCloseType(token.into()).into()),
+            };
+
+            Ok(Command::Close(sid, linger))
+        }
+        "bw" => {
+            let sid = tokens
+                .next()
+                .ok_or(ParseBandwidthError::NoSocketDescriptor)?;
+            let sid = SocketDescriptor(
+                sid.parse()
+                    .map_err(|_| ParseBandwidthError::InvalidSocketDescriptor)?,
+            );
 
-            Ok(Command::Close(sid))
+            Ok(Command::Bandwidth(sid))
+        }
+        "ooo" => {
+            let sid = tokens
+                .next()
+                .ok_or(ParseOutOfOrderError::NoSocketDescriptor)?;
+            let sid = SocketDescriptor(
+                sid.parse()
+                    .map_err(|_| ParseOutOfOrderError::InvalidSocketDescriptor)?,
+            );
+            let clear = match tokens.next() {
+                Some("clear") => true,
+                Some(token) => return Err(ParseOutOfOrderError::InvalidOption(token.into()).into()),
+                None => false,
+            };
+
+            Ok(Command::OutOfOrder {
+                descriptor: sid,
+                clear,
+            })
+        }
+        "drops" => {
+            let reset = match tokens.next() {
+                Some("reset") => true,
+                Some(token) => return Err(ParseDropsError::InvalidOption(token.into()).into()),
+                None => false,
+            };
+
+            Ok(Command::Drops { reset })
+        }
+        "nodelay" => {
+            let sid = tokens.next().ok_or(ParseNoDelayError::NoSocketDescriptor)?;
+            let sid = SocketDescriptor(
+                sid.parse()
+                    .map_err(|_| ParseNoDelayError::InvalidSocketDescriptor)?,
+            );
+            let nodelay = match tokens.next().ok_or(ParseNoDelayError::NoOption)? {
+                "on" => true,
+                "off" => false,
+                token => return Err(ParseNoDelayError::InvalidOption(token.into()).into()),
+            };
+
+            Ok(Command::NoDelay {
+                descriptor: sid,
+                nodelay,
+            })
+        }
+        #[cfg(any(test, feature = "debug-commands"))]
+        "retx" => {
+            let sid = tokens
+                .next()
+                .ok_or(ParseRetransmitError::NoSocketDescriptor)?;
+            let sid = SocketDescriptor(
+                sid.parse()
+                    .map_err(|_| ParseRetransmitError::InvalidSocketDescriptor)?,
+            );
+
+            Ok(Command::Retransmit(sid))
         }
         "sf" => {
             let filename = tokens.next().ok_or(ParseSendFileError::NoFile)?;
@@ -449,6 +667,34 @@ fn parse_cmd(cmd: &str, mut tokens: SplitWhitespace) -> Result<Command, ParseErr
                 port,
             })
         }
+        "get" => {
+            let ip = tokens
+                .next()
+                .ok_or(ParseHttpGetError::NoIp)?
+                .parse()
+                .map_err(|_| ParseHttpGetError::InvalidIp)?;
+            let port = tokens
+                .next()
+                .ok_or(ParseHttpGetError::NoPort)?
+                .parse::<u16>()
+                .map_err(|_| ParseHttpGetError::InvalidPort)?
+                .into();
+            let path = tokens.next().ok_or(ParseHttpGetError::NoPath)?;
+
+            Ok(Command::HttpGet {
+                dest_ip: ip,
+                port,
+                path: path.into(),
+            })
+        }
+        "echoserver" => {
+            let port = tokens
+                .next()
+                .ok_or(ParseEchoServerError::NoPort)?
+                .parse::<u16>()
+                .map_err(|_| ParseEchoServerError::InvalidPort)?;
+            Ok(Command::EchoServer(Port(port)))
+        }
         "q" => Ok(Command::Quit),
         _ => Err(ParseError::Unknown),
     }
@@ -635,8 +881,167 @@ mod tests {
             ParseCloseError::InvalidSocketDescriptor.into(),
         );
 
+        assert_eq!(
+            parse_command("cl 33 now".into()).unwrap_err(),
+            ParseCloseError::InvalidCloseType("now".into()).into(),
+        );
+
         let c = parse_command("cl 33".into()).unwrap();
-        assert_eq!(c, Command::Close(SocketDescriptor(33)));
+        assert_eq!(
+            c,
+            Command::Close(SocketDescriptor(33), LingerConfig::Graceful)
+        );
+
+        let c = parse_command("cl 33 abort".into()).unwrap();
+        assert_eq!(c, Command::Close(SocketDescriptor(33), LingerConfig::Abort));
+    }
+
+    #[test]
+    fn parse_list_sockets() {
+        assert_eq!(
+            parse_command("ls".into()).unwrap(),
+            Command::ListSockets {
+                file: None,
+                verbose: false
+            }
+        );
+        assert_eq!(
+            parse_command("ls -v".into()).unwrap(),
+            Command::ListSockets {
+                file: None,
+                verbose: true
+            }
+        );
+        assert_eq!(
+            parse_command("ls -v sockets.txt".into()).unwrap(),
+            Command::ListSockets {
+                file: Some("sockets.txt".into()),
+                verbose: true
+            }
+        );
+    }
+
+    #[test]
+    fn parse_bandwidth() {
+        assert_eq!(
+            parse_command("bw".into()).unwrap_err(),
+            ParseBandwidthError::NoSocketDescriptor.into(),
+        );
+
+        assert_eq!(
+            parse_command("bw xx".into()).unwrap_err(),
+            ParseBandwidthError::InvalidSocketDescriptor.into(),
+        );
+
+        let c = parse_command("bw 2".into()).unwrap();
+        assert_eq!(c, Command::Bandwidth(SocketDescriptor(2)));
+    }
+
+    #[test]
+    fn parse_out_of_order() {
+        assert_eq!(
+            parse_command("ooo".into()).unwrap_err(),
+            ParseOutOfOrderError::NoSocketDescriptor.into(),
+        );
+
+        assert_eq!(
+            parse_command("ooo xx".into()).unwrap_err(),
+            ParseOutOfOrderError::InvalidSocketDescriptor.into(),
+        );
+
+        assert_eq!(
+            parse_command("ooo 1 drop".into()).unwrap_err(),
+            ParseOutOfOrderError::InvalidOption("drop".into()).into(),
+        );
+
+        let c = parse_command("ooo 1".into()).unwrap();
+        assert_eq!(
+            c,
+            Command::OutOfOrder {
+                descriptor: SocketDescriptor(1),
+                clear: false
+            }
+        );
+
+        let c = parse_command("ooo 1 clear".into()).unwrap();
+        assert_eq!(
+            c,
+            Command::OutOfOrder {
+                descriptor: SocketDescriptor(1),
+                clear: true
+            }
+        );
+    }
+
+    #[test]
+    fn parse_drops() {
+        assert_eq!(
+            parse_command("drops all".into()).unwrap_err(),
+            ParseDropsError::InvalidOption("all".into()).into(),
+        );
+
+        let c = parse_command("drops".into()).unwrap();
+        assert_eq!(c, Command::Drops { reset: false });
+
+        let c = parse_command("drops reset".into()).unwrap();
+        assert_eq!(c, Command::Drops { reset: true });
+    }
+
+    #[test]
+    fn parse_nodelay() {
+        assert_eq!(
+            parse_command("nodelay".into()).unwrap_err(),
+            ParseNoDelayError::NoSocketDescriptor.into(),
+        );
+
+        assert_eq!(
+            parse_command("nodelay xx".into()).unwrap_err(),
+            ParseNoDelayError::InvalidSocketDescriptor.into(),
+        );
+
+        assert_eq!(
+            parse_command("nodelay 1".into()).unwrap_err(),
+            ParseNoDelayError::NoOption.into(),
+        );
+
+        assert_eq!(
+            parse_command("nodelay 1 yes".into()).unwrap_err(),
+            ParseNoDelayError::InvalidOption("yes".into()).into(),
+        );
+
+        let c = parse_command("nodelay 1 on".into()).unwrap();
+        assert_eq!(
+            c,
+            Command::NoDelay {
+                descriptor: SocketDescriptor(1),
+                nodelay: true
+            }
+        );
+
+        let c = parse_command("nodelay 1 off".into()).unwrap();
+        assert_eq!(
+            c,
+            Command::NoDelay {
+                descriptor: SocketDescriptor(1),
+                nodelay: false
+            }
+        );
+    }
+
+    #[test]
+    fn parse_retransmit() {
+        assert_eq!(
+            parse_command("retx".into()).unwrap_err(),
+            ParseRetransmitError::NoSocketDescriptor.into(),
+        );
+
+        assert_eq!(
+            parse_command("retx xx".into()).unwrap_err(),
+            ParseRetransmitError::InvalidSocketDescriptor.into(),
+        );
+
+        let c = parse_command("retx 3".into()).unwrap();
+        assert_eq!(c, Command::Retransmit(SocketDescriptor(3)));
     }
 
     #[test]
@@ -703,4 +1108,58 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn parse_http_get() {
+        assert_eq!(
+            parse_command("get".into()).unwrap_err(),
+            ParseHttpGetError::NoIp.into()
+        );
+
+        assert_eq!(
+            parse_command("get localhost".into()).unwrap_err(),
+            ParseHttpGetError::InvalidIp.into()
+        );
+
+        assert_eq!(
+            parse_command("get 1.2.3.4".into()).unwrap_err(),
+            ParseHttpGetError::NoPort.into()
+        );
+
+        assert_eq!(
+            parse_command("get 1.2.3.4 http".into()).unwrap_err(),
+            ParseHttpGetError::InvalidPort.into()
+        );
+
+        assert_eq!(
+            parse_command("get 1.2.3.4 80".into()).unwrap_err(),
+            ParseHttpGetError::NoPath.into()
+        );
+
+        let c = parse_command("get 1.2.3.4 80 /index.html".into()).unwrap();
+        assert_eq!(
+            c,
+            Command::HttpGet {
+                dest_ip: Ipv4Addr::new(1, 2, 3, 4),
+                port: Port(80),
+                path: "/index.html".into()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_echo_server() {
+        assert_eq!(
+            parse_command("echoserver".into()).unwrap_err(),
+            ParseEchoServerError::NoPort.into()
+        );
+
+        assert_eq!(
+            parse_command("echoserver echo".into()).unwrap_err(),
+            ParseEchoServerError::InvalidPort.into()
+        );
+
+        let c = parse_command("echoserver 7".into()).unwrap();
+        assert_eq!(c, Command::EchoServer(Port(7)));
+    }
 }
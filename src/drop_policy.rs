@@ -1,9 +1,49 @@
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use etherparse::Ipv4HeaderSlice;
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use rand::{thread_rng, Rng};
+
+use crate::protocol::Protocol;
 
 pub trait DropPolicy: 'static + Sync + Send {
     fn should_drop(&self, ip_header: &Ipv4HeaderSlice<'_>) -> bool;
+
+    /// How long to hold a received packet back before handling it, if at
+    /// all. Packets received in the meantime are handled as usual.
+    fn delay(&self, _ip_header: &Ipv4HeaderSlice<'_>, _payload: &[u8]) -> Option<Duration> {
+        None
+    }
+
+    /// Alters a received packet, IP header included, before anything else
+    /// is done with it.
+    fn corrupt(&self, _packet: &mut [u8]) {}
+
+    /// How many packets the policy has decided on, if it counts them.
+    fn stats(&self) -> Option<DropStats> {
+        None
+    }
+
+    /// Restarts the counts reported by `stats` from zero.
+    fn reset_stats(&self) {}
+}
+
+/// The packets a drop policy has seen, and how many of them it dropped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DropStats {
+    pub seen: usize,
+    pub dropped: usize,
+}
+
+impl DropStats {
+    pub fn drop_ratio(&self) -> f64 {
+        if self.seen == 0 {
+            0.0
+        } else {
+            self.dropped as f64 / self.seen as f64
+        }
+    }
 }
 
 // TODO: use NeverDrop policy for better inline performance
@@ -22,11 +62,13 @@ pub struct DropFactor {
     never_drop: bool,
     factor: usize,
     count: AtomicUsize,
+    dropped: AtomicUsize,
 }
 
 #[allow(dead_code)]
 impl DropFactor {
-    /// Configure the router to drop 1 packet every `drop_factor` packets.
+    /// Configure the router to drop 1 packet every `1 / drop_factor` packets,
+    /// starting with packet number `1 / drop_factor`.
     pub fn new(drop_factor: f32) -> Self {
         if (0.0..1.0).contains(&drop_factor) {
             let factor: usize = (1.0 / drop_factor) as usize;
@@ -34,6 +76,7 @@ impl DropFactor {
                 never_drop: drop_factor == 0.0,
                 factor,
                 count: AtomicUsize::new(0),
+                dropped: AtomicUsize::new(0),
             }
         } else {
             panic!("drop factor must be between 0 and 1")
@@ -44,14 +87,307 @@ impl DropFactor {
 impl DropPolicy for DropFactor {
     #[inline]
     fn should_drop(&self, _ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
         if self.never_drop {
             return false;
         }
 
-        let count = self
-            .count
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        count % self.factor == 0
+        // The `factor`th packet is the first dropped, rather than the very
+        // first one, which is often a SYN.
+        let drop = (count + 1) % self.factor == 0;
+        if drop {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        drop
+    }
+
+    fn stats(&self) -> Option<DropStats> {
+        Some(DropStats {
+            seen: self.count.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        })
+    }
+
+    fn reset_stats(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.dropped.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Holds back the `nth` TCP segment carrying data by `delay`, then lets it
+/// through. Every other packet is handled as usual.
+pub struct DelayOnce {
+    nth: usize,
+    delay: Duration,
+    data_segments: AtomicUsize,
+    delayed_seq_no: Mutex<Option<u32>>,
+    resent: Arc<AtomicUsize>,
+}
+
+#[allow(dead_code)]
+impl DelayOnce {
+    pub fn new(nth: usize, delay: Duration) -> Self {
+        Self {
+            nth,
+            delay,
+            data_segments: AtomicUsize::new(0),
+            delayed_seq_no: Mutex::new(None),
+            resent: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Counts the segments received after the delayed one that carry the same
+    /// sequence number, i.e. its retransmissions.
+    pub fn resent_counter(&self) -> Arc<AtomicUsize> {
+        self.resent.clone()
+    }
+}
+
+impl DropPolicy for DelayOnce {
+    fn should_drop(&self, _ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+        false
+    }
+
+    fn delay(&self, ip_header: &Ipv4HeaderSlice<'_>, payload: &[u8]) -> Option<Duration> {
+        let tcp: u8 = Protocol::Tcp.into();
+        if ip_header.protocol() != tcp {
+            return None;
+        }
+        let tcp_header = TcpHeaderSlice::from_slice(payload).ok()?;
+        if payload.len() == tcp_header.slice().len() {
+            return None;
+        }
+
+        let seq_no = tcp_header.sequence_number();
+        let mut delayed_seq_no = self.delayed_seq_no.lock().unwrap();
+        match *delayed_seq_no {
+            Some(delayed) => {
+                if delayed == seq_no {
+                    self.resent.fetch_add(1, Ordering::Relaxed);
+                }
+                None
+            }
+            None => {
+                let count = self.data_segments.fetch_add(1, Ordering::Relaxed);
+                if count + 1 == self.nth {
+                    *delayed_seq_no = Some(seq_no);
+                    Some(self.delay)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Holds each packet back with probability `probability`, just long enough
+/// for the packet after it to arrive first. A packet nothing follows within
+/// the hold is let through late. Contents are never altered.
+pub struct Reorder {
+    probability: f64,
+    hold: Duration,
+    held: Arc<AtomicUsize>,
+}
+
+#[allow(dead_code)]
+impl Reorder {
+    /// How long a packet is held back by default. Packets sent back to back
+    /// arrive well within it.
+    pub const DEFAULT_HOLD: Duration = Duration::from_millis(2);
+
+    pub fn new(probability: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "probability must be between 0 and 1"
+        );
+        Self {
+            probability,
+            hold: Self::DEFAULT_HOLD,
+            held: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn with_hold(self, hold: Duration) -> Self {
+        Self { hold, ..self }
+    }
+
+    /// Counts the packets held back so far.
+    pub fn held_counter(&self) -> Arc<AtomicUsize> {
+        self.held.clone()
+    }
+}
+
+impl DropPolicy for Reorder {
+    fn should_drop(&self, _ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+        false
+    }
+
+    fn delay(&self, _ip_header: &Ipv4HeaderSlice<'_>, _payload: &[u8]) -> Option<Duration> {
+        if !thread_rng().gen_bool(self.probability) {
+            return None;
+        }
+        self.held.fetch_add(1, Ordering::Relaxed);
+        Some(self.hold)
+    }
+}
+
+/// Flips a random bit in the payload of a `fraction` of TCP packets, leaving
+/// their IP headers intact so that they still reach the TCP handler.
+pub struct Corrupt {
+    fraction: f64,
+    corrupted: Arc<AtomicUsize>,
+}
+
+#[allow(dead_code)]
+impl Corrupt {
+    pub fn new(fraction: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "fraction must be between 0 and 1"
+        );
+        Self {
+            fraction,
+            corrupted: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Counts the packets corrupted so far.
+    pub fn corrupted_counter(&self) -> Arc<AtomicUsize> {
+        self.corrupted.clone()
+    }
+}
+
+impl DropPolicy for Corrupt {
+    fn should_drop(&self, _ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+        false
+    }
+
+    fn corrupt(&self, packet: &mut [u8]) {
+        let Ok(header) = Ipv4HeaderSlice::from_slice(packet) else {
+            return;
+        };
+        let tcp: u8 = Protocol::Tcp.into();
+        let header_len = header.slice().len();
+        if header.protocol() != tcp || packet.len() == header_len {
+            return;
+        }
+
+        let mut rng = thread_rng();
+        if !rng.gen_bool(self.fraction) {
+            return;
+        }
+        let bit = rng.gen_range(0..(packet.len() - header_len) * 8);
+        packet[header_len + bit / 8] ^= 1 << (bit % 8);
+        self.corrupted.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Applies two policies at once: a packet is dropped if either drops it, and
+/// held back for as long as both delay it.
+impl<A: DropPolicy, B: DropPolicy> DropPolicy for (A, B) {
+    fn should_drop(&self, ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+        // Both see every packet, so that their counts stay in step.
+        let first = self.0.should_drop(ip_header);
+        let second = self.1.should_drop(ip_header);
+        first || second
+    }
+
+    fn delay(&self, ip_header: &Ipv4HeaderSlice<'_>, payload: &[u8]) -> Option<Duration> {
+        match (
+            self.0.delay(ip_header, payload),
+            self.1.delay(ip_header, payload),
+        ) {
+            (Some(first), Some(second)) => Some(first + second),
+            (first, second) => first.or(second),
+        }
+    }
+
+    fn corrupt(&self, packet: &mut [u8]) {
+        self.0.corrupt(packet);
+        self.1.corrupt(packet);
+    }
+}
+
+/// Models a bottleneck link for TCP: segments are let through one after
+/// another at no more than `bytes_per_sec`, queueing behind the ones before
+/// them, then take `latency` to arrive. Other packets, such as RIP updates,
+/// skip the link.
+pub struct RateLimit {
+    bytes_per_sec: f64,
+    latency: Duration,
+    /// When the packets queued so far will all have been let through.
+    queue_free_at: Mutex<Instant>,
+}
+
+#[allow(dead_code)]
+impl RateLimit {
+    pub fn new(bytes_per_sec: usize) -> Self {
+        assert!(bytes_per_sec > 0, "rate must be positive");
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            latency: Duration::ZERO,
+            queue_free_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Add a propagation delay to every segment.
+    pub fn with_latency(self, latency: Duration) -> Self {
+        Self { latency, ..self }
+    }
+}
+
+impl DropPolicy for RateLimit {
+    fn should_drop(&self, _ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+        false
+    }
+
+    fn delay(&self, ip_header: &Ipv4HeaderSlice<'_>, payload: &[u8]) -> Option<Duration> {
+        let tcp: u8 = Protocol::Tcp.into();
+        if ip_header.protocol() != tcp {
+            return None;
+        }
+
+        let n_bytes = ip_header.slice().len() + payload.len();
+        let tx_time = Duration::from_secs_f64(n_bytes as f64 / self.bytes_per_sec);
+
+        let now = Instant::now();
+        let mut queue_free_at = self.queue_free_at.lock().unwrap();
+        *queue_free_at = (*queue_free_at).max(now) + tx_time;
+        Some(*queue_free_at - now + self.latency)
+    }
+}
+
+/// Delays every TCP segment by a latency drawn uniformly from `min..=max`,
+/// so that with `min < max` segments may also arrive out of order. Other
+/// packets, such as RIP updates, are not delayed so that routes stay fresh.
+pub struct Latency {
+    min: Duration,
+    max: Duration,
+}
+
+#[allow(dead_code)]
+impl Latency {
+    pub fn fixed(latency: Duration) -> Self {
+        Self::uniform(latency, latency)
+    }
+
+    pub fn uniform(min: Duration, max: Duration) -> Self {
+        assert!(min <= max, "minimum latency must not exceed the maximum");
+        Self { min, max }
+    }
+}
+
+impl DropPolicy for Latency {
+    fn should_drop(&self, _ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+        false
+    }
+
+    fn delay(&self, ip_header: &Ipv4HeaderSlice<'_>, _payload: &[u8]) -> Option<Duration> {
+        let tcp: u8 = Protocol::Tcp.into();
+        if ip_header.protocol() != tcp {
+            return None;
+        }
+        Some(thread_rng().gen_range(self.min..=self.max))
     }
 }
 
@@ -91,4 +427,60 @@ mod tests {
 
         assert_eq!(dropped as f64, (iters as f64 * 0.2).floor());
     }
+
+    #[test]
+    fn first_packet_is_delivered() {
+        let dropper = DropFactor::new(0.05);
+
+        let mut bytes = Vec::new();
+        Ipv4Header::new(
+            10,
+            10,
+            10,
+            Ipv4Addr::new(0, 0, 0, 0).octets(),
+            Ipv4Addr::new(0, 0, 0, 0).octets(),
+        )
+        .write(&mut bytes)
+        .unwrap();
+        let ip_header_slice = Ipv4HeaderSlice::from_slice(&bytes).unwrap();
+
+        let dropped: Vec<_> = (0..40)
+            .map(|_| dropper.should_drop(&ip_header_slice))
+            .collect();
+        assert!(!dropped[0]);
+        assert!(dropped[19] && dropped[39]);
+        assert_eq!(dropped.iter().filter(|d| **d).count(), 2);
+    }
+
+    #[test]
+    fn latency_composes_with_drop_policies() {
+        let mut bytes = Vec::new();
+        Ipv4Header::new(
+            0,
+            10,
+            Protocol::Tcp.into(),
+            Ipv4Addr::new(0, 0, 0, 0).octets(),
+            Ipv4Addr::new(0, 0, 0, 0).octets(),
+        )
+        .write(&mut bytes)
+        .unwrap();
+        let ip_header_slice = Ipv4HeaderSlice::from_slice(&bytes).unwrap();
+
+        let latency = Duration::from_millis(30);
+        assert_eq!(NeverDrop.delay(&ip_header_slice, &[]), None);
+        assert_eq!(
+            (NeverDrop, Latency::fixed(latency)).delay(&ip_header_slice, &[]),
+            Some(latency)
+        );
+        assert_eq!(
+            (DropFactor::new(0.5), Latency::fixed(latency)).delay(&ip_header_slice, &[]),
+            Some(latency)
+        );
+
+        let jittery = Latency::uniform(Duration::from_millis(10), Duration::from_millis(20));
+        for _ in 0..100 {
+            let delay = jittery.delay(&ip_header_slice, &[]).unwrap();
+            assert!((Duration::from_millis(10)..=Duration::from_millis(20)).contains(&delay));
+        }
+    }
 }
@@ -1 +1,2 @@
 pub mod netlinks;
+pub mod node;
@@ -1,8 +1,32 @@
+use rand::{thread_rng, Rng};
+
 use crate::Args;
 
-pub mod abc {
-    use rand::{thread_rng, Rng};
+/// Assign each host a random port, rewriting links to match, so that tests
+/// running in parallel don't collide.
+fn replace_ports(args: &mut [Args]) {
+    let mut rng = thread_rng();
+    let mut replacements = Vec::new();
+    for arg in args.iter_mut() {
+        let old = arg.host_port;
+        let new = rng.gen_range(1024..65535);
+        arg.host_port = new;
+        replacements.push((old, new));
+    }
+
+    for arg in args {
+        for link in &mut arg.links {
+            for (old, new) in &replacements {
+                if link.dest_port == *old {
+                    link.dest_port = *new;
+                    break;
+                }
+            }
+        }
+    }
+}
 
+pub mod abc {
     use super::*;
 
     pub struct ABCNet {
@@ -23,7 +47,7 @@ pub mod abc {
         fn into_shuffled(self) -> ABCNet {
             let mut args = [self.a, self.b, self.c];
 
-            Self::replace_ports(&mut args);
+            replace_ports(&mut args);
 
             let mut args_iter = args.into_iter();
             let (a, b, c) = (
@@ -34,28 +58,6 @@ pub mod abc {
 
             ABCNet { a, b, c }
         }
-
-        fn replace_ports(args: &mut [Args]) {
-            let mut rng = thread_rng();
-            let mut replacements = Vec::new();
-            for arg in args.iter_mut() {
-                let old = arg.host_port;
-                let new = rng.gen_range(1024..65535);
-                arg.host_port = new;
-                replacements.push((old, new));
-            }
-
-            for arg in args {
-                for link in &mut arg.links {
-                    for (old, new) in &replacements {
-                        if link.dest_port == *old {
-                            link.dest_port = *new;
-                            break;
-                        }
-                    }
-                }
-            }
-        }
     }
 
     pub fn load() -> ABCNet {
@@ -66,3 +68,73 @@ pub mod abc {
         ABCNet::load_default().into_shuffled()
     }
 }
+
+/// A and B, connected by two disjoint paths: one through R1, one through R2.
+pub mod diamond {
+    use super::*;
+
+    pub struct DiamondNet {
+        pub a: Args,
+        pub r1: Args,
+        pub r2: Args,
+        pub b: Args,
+    }
+
+    impl DiamondNet {
+        fn load_default() -> DiamondNet {
+            Self {
+                a: Args::parse_from_path("./net_links/diamond/A.lnx").unwrap(),
+                r1: Args::parse_from_path("./net_links/diamond/R1.lnx").unwrap(),
+                r2: Args::parse_from_path("./net_links/diamond/R2.lnx").unwrap(),
+                b: Args::parse_from_path("./net_links/diamond/B.lnx").unwrap(),
+            }
+        }
+
+        fn into_shuffled(self) -> DiamondNet {
+            let mut args = [self.a, self.r1, self.r2, self.b];
+
+            replace_ports(&mut args);
+
+            let [a, r1, r2, b] = args;
+            DiamondNet { a, r1, r2, b }
+        }
+    }
+
+    pub fn gen_unique() -> DiamondNet {
+        DiamondNet::load_default().into_shuffled()
+    }
+}
+
+/// A, B and C, each linked to both others.
+pub mod triangle {
+    use super::*;
+
+    pub struct TriangleNet {
+        pub a: Args,
+        pub b: Args,
+        pub c: Args,
+    }
+
+    impl TriangleNet {
+        fn load_default() -> TriangleNet {
+            Self {
+                a: Args::parse_from_path("./net_links/triangle/A.lnx").unwrap(),
+                b: Args::parse_from_path("./net_links/triangle/B.lnx").unwrap(),
+                c: Args::parse_from_path("./net_links/triangle/C.lnx").unwrap(),
+            }
+        }
+
+        fn into_shuffled(self) -> TriangleNet {
+            let mut args = [self.a, self.b, self.c];
+
+            replace_ports(&mut args);
+
+            let [a, b, c] = args;
+            TriangleNet { a, b, c }
+        }
+    }
+
+    pub fn gen_unique() -> TriangleNet {
+        TriangleNet::load_default().into_shuffled()
+    }
+}
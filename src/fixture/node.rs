@@ -0,0 +1,38 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::drop_policy::{DropPolicy, NeverDrop};
+use crate::node::{Node, NodeBuilder, NodeBuilderStep};
+use crate::protocol::rip::RipHandler;
+use crate::protocol::Protocol;
+use crate::Args;
+
+/// Starts a node with fast RIP timers, dropping packets by `drop_policy`.
+/// `configure` sets up the builder further, and may override the timers.
+/// Returns once the node has had time to converge on routes.
+pub async fn start_node_with<'a, DP: DropPolicy>(
+    cfg: &'a Args,
+    drop_policy: DP,
+    configure: impl FnOnce(NodeBuilderStep<'a, NeverDrop>) -> NodeBuilderStep<'a, NeverDrop>,
+) -> Arc<Node<DP>> {
+    let mut builder = configure(
+        NodeBuilder::new(cfg)
+            .with_rip_interval(Duration::from_millis(1))
+            .with_entry_max_age(Duration::from_millis(12))
+            .with_prune_interval(Duration::from_millis(1)),
+    );
+    let node = Arc::new(
+        builder
+            .with_drop_policy(drop_policy)
+            .with_protocol_handler(Protocol::Rip, RipHandler::default())
+            .build()
+            .await,
+    );
+    let node_runner = node.clone();
+    tokio::spawn(async move {
+        node_runner.run().await;
+    });
+    // Give nodes time to converge on routes
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    node
+}
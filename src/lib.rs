@@ -10,7 +10,7 @@ pub mod protocol;
 mod repl;
 mod utils;
 
-pub use net::vtlink::Args;
+pub use net::vtlink::{Args, LnxConfig, LnxField, ParseLnxError};
 
 /// Trait to be implemented by payload to be sent over the network.
 trait Message {
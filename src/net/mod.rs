@@ -1,15 +1,39 @@
 pub mod vtlink;
 
-use std::net::Ipv4Addr;
+use std::{fmt, net::Ipv4Addr};
 
 use async_trait::async_trait;
 
+use crate::utils::logging::NodeLogger;
+
 #[derive(Debug)]
 pub enum SendError {
-    NoForwardingEntry,
-    Unreachable,
+    /// No reachable route leads to the destination.
+    NoRoute,
+    /// The route to the destination leaves through an interface that is
+    /// down.
+    InterfaceDown,
+    /// There is no link with the requested number.
     NoLink,
-    Transport(vtlink::Error),
+    /// The requested source address does not belong to the link the packet
+    /// would leave on.
+    SourceMismatch,
+    /// The link failed to put the packet on the wire.
+    LinkIoError(std::io::Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::NoRoute => write!(f, "no route to destination"),
+            SendError::InterfaceDown => write!(f, "interface to next hop is down"),
+            SendError::NoLink => write!(f, "no such link"),
+            SendError::SourceMismatch => {
+                write!(f, "source address does not belong to the outgoing link")
+            }
+            SendError::LinkIoError(e) => write!(f, "link failed to send: {}", e),
+        }
+    }
 }
 
 #[async_trait]
@@ -22,4 +46,38 @@ pub trait Net: 'static + Send + Sync {
         protocol: P,
         dest: Ipv4Addr,
     ) -> Result<(), SendError>;
+
+    /// Send bytes to a destination, stamping them with a fixed source address.
+    ///
+    /// Used by connections, whose local address must not change even if the
+    /// route to the destination does.
+    async fn send_from<P: Into<u8> + Send>(
+        &self,
+        src: Ipv4Addr,
+        payload: &[u8],
+        protocol: P,
+        dest: Ipv4Addr,
+    ) -> Result<(), SendError>;
+
+    /// Send bytes to a destination out of a specific link, bypassing routing.
+    ///
+    /// This is a diagnostic seam for forcing a packet's next hop.
+    async fn send_via<P: Into<u8> + Send>(
+        &self,
+        link_no: u16,
+        payload: &[u8],
+        protocol: P,
+        dest: Ipv4Addr,
+    ) -> Result<(), SendError>;
+
+    /// The MTU of the link that packets to `dest` leave on, as routed now.
+    /// `None` if there is no route to `dest`.
+    async fn path_mtu(&self, _dest: Ipv4Addr) -> Option<usize> {
+        None
+    }
+
+    /// The logger for lines emitted on behalf of this network's node.
+    fn logger(&self) -> NodeLogger {
+        NodeLogger::default()
+    }
 }
@@ -0,0 +1,273 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use etherparse::{Ipv4Header, Ipv4HeaderSlice};
+use log::Level;
+
+use crate::node_log;
+use crate::utils::logging::NodeLogger;
+
+/// How long the fragments of a packet are kept waiting for the rest.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Fragment offsets are counted in units of 8 bytes (RFC 791).
+const FRAGMENT_OFFSET_UNIT: usize = 8;
+
+/// Splits an IP packet into fragments no longer than `mtu` bytes each. A
+/// packet that already fits is returned as is.
+pub fn fragment(packet: &[u8], mtu: usize, logger: NodeLogger) -> Vec<Vec<u8>> {
+    if packet.len() <= mtu {
+        return vec![packet.to_vec()];
+    }
+    let Ok((header, rest)) = Ipv4Header::from_slice(packet) else {
+        return vec![packet.to_vec()];
+    };
+    let payload = &rest[..(header.payload_len as usize).min(rest.len())];
+
+    // Every fragment but the last carries a multiple of 8 bytes.
+    let max_chunk =
+        (mtu.saturating_sub(header.header_len()) / FRAGMENT_OFFSET_UNIT) * FRAGMENT_OFFSET_UNIT;
+    if header.dont_fragment || max_chunk == 0 {
+        node_log!(
+            logger,
+            Level::Warn,
+            "Cannot fragment a {} byte packet for MTU {}",
+            packet.len(),
+            mtu
+        );
+        return vec![packet.to_vec()];
+    }
+
+    let chunks = payload.chunks(max_chunk);
+    let n_chunks = chunks.len();
+    chunks
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut frag_header = header.clone();
+            frag_header.payload_len = chunk.len() as u16;
+            frag_header.fragments_offset =
+                header.fragments_offset + (i * max_chunk / FRAGMENT_OFFSET_UNIT) as u16;
+            frag_header.more_fragments = header.more_fragments || i + 1 < n_chunks;
+
+            let mut frag = Vec::with_capacity(frag_header.header_len() + chunk.len());
+            frag_header
+                .write(&mut frag)
+                .expect("IP header serialization error");
+            frag.extend_from_slice(chunk);
+            frag
+        })
+        .collect()
+}
+
+pub fn is_fragment(header: &Ipv4HeaderSlice<'_>) -> bool {
+    header.more_fragments() || header.fragments_offset() > 0
+}
+
+/// Fragments of one packet are those sharing a source, destination,
+/// identification and protocol.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+struct FragmentKey {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    id: u16,
+    protocol: u8,
+}
+
+struct PartialPacket {
+    /// The header of the first fragment, once it arrives.
+    header: Option<Ipv4Header>,
+    /// Payload bytes, keyed by their offset into the original payload.
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// The length of the original payload, once the last fragment arrives.
+    payload_len: Option<usize>,
+    first_received: Instant,
+}
+
+impl PartialPacket {
+    fn new(now: Instant) -> Self {
+        Self {
+            header: None,
+            fragments: BTreeMap::new(),
+            payload_len: None,
+            first_received: now,
+        }
+    }
+
+    /// The original payload, if every byte of it has arrived.
+    fn payload(&self) -> Option<Vec<u8>> {
+        let payload_len = self.payload_len?;
+        let mut payload = Vec::with_capacity(payload_len);
+        for (&offset, bytes) in &self.fragments {
+            if offset > payload.len() {
+                return None;
+            }
+            let end = (offset + bytes.len()).min(payload_len);
+            if end > payload.len() {
+                payload.extend_from_slice(&bytes[payload.len() - offset..end - offset]);
+            }
+        }
+        (payload.len() == payload_len).then_some(payload)
+    }
+}
+
+/// Collects fragments until the packet they belong to is whole.
+pub struct Reassembler {
+    timeout: Duration,
+    partial: HashMap<FragmentKey, PartialPacket>,
+    logger: NodeLogger,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(REASSEMBLY_TIMEOUT, NodeLogger::default())
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, logger: NodeLogger) -> Self {
+        Self {
+            timeout,
+            partial: HashMap::new(),
+            logger,
+        }
+    }
+
+    /// Adds a fragment, returning the reassembled packet if it was the last
+    /// one missing. Packets still incomplete after the timeout are discarded.
+    pub fn insert(&mut self, header: &Ipv4HeaderSlice<'_>, payload: &[u8]) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        self.discard_expired(now);
+
+        let key = FragmentKey {
+            src: header.source_addr(),
+            dst: header.destination_addr(),
+            id: header.identification(),
+            protocol: header.protocol(),
+        };
+        let offset = header.fragments_offset() as usize * FRAGMENT_OFFSET_UNIT;
+        let payload = &payload[..(header.payload_len() as usize).min(payload.len())];
+
+        let partial = self
+            .partial
+            .entry(key)
+            .or_insert_with(|| PartialPacket::new(now));
+        if offset == 0 {
+            partial.header = Some(header.to_header());
+        }
+        if !header.more_fragments() {
+            partial.payload_len = Some(offset + payload.len());
+        }
+        partial.fragments.insert(offset, payload.to_vec());
+
+        let payload = partial.payload()?;
+        let mut header = partial.header.clone()?;
+        self.partial.remove(&key);
+
+        header.more_fragments = false;
+        header.fragments_offset = 0;
+        if header.set_payload_len(payload.len()).is_err() {
+            node_log!(
+                self.logger,
+                Level::Warn,
+                "Reassembled packet from {} is too long",
+                key.src
+            );
+            return None;
+        }
+        let mut packet = Vec::with_capacity(header.header_len() + payload.len());
+        header
+            .write(&mut packet)
+            .expect("IP header serialization error");
+        packet.extend_from_slice(&payload);
+        Some(packet)
+    }
+
+    fn discard_expired(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let logger = self.logger;
+        self.partial.retain(|key, partial| {
+            let expired = now.duration_since(partial.first_received) >= timeout;
+            if expired {
+                node_log!(
+                    logger,
+                    Level::Debug,
+                    "Discarding incomplete packet {:?}",
+                    key
+                );
+            }
+            !expired
+        });
+    }
+
+    /// The number of packets with fragments still missing.
+    #[cfg(test)]
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::net::Ipv4PacketBuilder;
+
+    use super::*;
+
+    #[test]
+    fn reassembles_fragments_in_any_order() {
+        let payload: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let packet = Ipv4PacketBuilder::default()
+            .with_src(Ipv4Addr::new(10, 0, 0, 1))
+            .with_dst(Ipv4Addr::new(10, 0, 0, 2))
+            .with_protocol(0)
+            .with_payload(&payload)
+            .build()
+            .unwrap();
+
+        let fragments = fragment(&packet, 1000, NodeLogger::default());
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|frag| frag.len() <= 1000));
+
+        let mut reassembler = Reassembler::default();
+        let mut reassembled = None;
+        for frag in fragments.iter().rev() {
+            let header = Ipv4HeaderSlice::from_slice(frag).unwrap();
+            assert!(is_fragment(&header));
+            assert!(reassembled.is_none());
+            reassembled = reassembler.insert(&header, &frag[header.slice().len()..]);
+        }
+        assert_eq!(reassembled.unwrap(), packet);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn discards_incomplete_packets_after_timeout() {
+        let payload = [7; 3000];
+        let packet = Ipv4PacketBuilder::default()
+            .with_src(Ipv4Addr::new(10, 0, 0, 1))
+            .with_dst(Ipv4Addr::new(10, 0, 0, 2))
+            .with_protocol(0)
+            .with_payload(&payload)
+            .build()
+            .unwrap();
+        let fragments = fragment(&packet, 1000, NodeLogger::default());
+
+        let mut reassembler = Reassembler::new(Duration::from_millis(10), NodeLogger::default());
+        for frag in &fragments[..fragments.len() - 1] {
+            let header = Ipv4HeaderSlice::from_slice(frag).unwrap();
+            assert!(reassembler
+                .insert(&header, &frag[header.slice().len()..])
+                .is_none());
+        }
+        assert_eq!(reassembler.pending(), 1);
+
+        // The last fragment is too late to complete the packet.
+        std::thread::sleep(Duration::from_millis(20));
+        let last = fragments.last().unwrap();
+        let header = Ipv4HeaderSlice::from_slice(last).unwrap();
+        assert!(reassembler
+            .insert(&header, &last[header.slice().len()..])
+            .is_none());
+        assert_eq!(reassembler.pending(), 1);
+    }
+}
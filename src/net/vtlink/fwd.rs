@@ -4,14 +4,58 @@ use std::{
     time::{Duration, Instant},
 };
 
-#[derive(Default)]
+use log::Level;
+use tokio::sync::broadcast;
+
+use crate::node_log;
+use crate::utils::logging::NodeLogger;
+
+// The number of route changes buffered for each subscriber. Subscribers that
+// fall further behind observe a `RecvError::Lagged`.
+const ROUTE_CHANGE_CHANNEL_SZ: usize = 64;
+
+/// A modification made to the forwarding table.
+#[derive(Copy, Clone, Debug)]
+pub enum RouteChange {
+    Added(Entry),
+    Updated(Entry),
+    Removed(Entry),
+}
+
 pub struct ForwardingTable {
     entries: Vec<Entry>,
+    changes: broadcast::Sender<RouteChange>,
+    logger: NodeLogger,
+}
+
+impl Default for ForwardingTable {
+    fn default() -> Self {
+        Self::with_entries(Vec::new(), NodeLogger::default())
+    }
 }
 
 impl ForwardingTable {
-    pub fn with_entries(entries: Vec<Entry>) -> Self {
-        Self { entries }
+    pub fn with_entries(entries: Vec<Entry>, logger: NodeLogger) -> Self {
+        let (changes, _) = broadcast::channel(ROUTE_CHANGE_CHANNEL_SZ);
+        Self {
+            entries,
+            changes,
+            logger,
+        }
+    }
+
+    /// Subscribe to changes made to this table from now on.
+    ///
+    /// A subscriber that lags behind misses the oldest changes; it should
+    /// re-read the table upon `RecvError::Lagged`.
+    pub fn subscribe(&self) -> broadcast::Receiver<RouteChange> {
+        self.changes.subscribe()
+    }
+
+    /// Notify subscribers that an entry was modified in place, e.g. through
+    /// `find_mut_entry_for`.
+    pub fn notify_updated(&self, entry: Entry) {
+        self.publish(RouteChange::Updated(entry));
     }
 
     pub fn has_entry_for(&self, addr: Ipv4Addr) -> bool {
@@ -27,11 +71,42 @@ impl ForwardingTable {
     }
 
     pub fn delete_mut_entry_for(&mut self, addr: Ipv4Addr) {
-        self.entries.retain(|e| e.destination != addr)
+        let (removed, kept) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|e| e.destination == addr);
+        self.entries = kept;
+        self.publish_removed(removed);
+    }
+
+    /// Marks every learned route through `next_hop` unreachable, returning
+    /// the updated entries.
+    pub fn mark_unreachable_via(&mut self, next_hop: Ipv4Addr) -> Vec<Entry> {
+        let mut updated = Vec::new();
+        for entry in self.entries.iter_mut() {
+            if entry.next_hop == next_hop
+                && !entry.is_local
+                && !entry.is_static
+                && !entry.is_unreachable()
+            {
+                entry.mark_unreachable();
+                updated.push(*entry);
+            }
+        }
+        for entry in &updated {
+            node_log!(
+                self.logger,
+                Level::Info,
+                "Marked entry unreachable: {}",
+                entry
+            );
+            self.notify_updated(*entry);
+        }
+        updated
     }
 
     pub fn add_entry(&mut self, entry: Entry) {
         self.entries.push(entry);
+        self.publish(RouteChange::Added(entry));
     }
 
     pub fn entries(&self) -> &[Entry] {
@@ -39,26 +114,61 @@ impl ForwardingTable {
     }
 
     pub fn prune(&mut self, max_age: Duration) {
-        for (i, entry) in self.entries().iter().enumerate() {
-            if !entry.is_local() && entry.last_updated.elapsed() > max_age {
-                log::warn!(
-                    "Deleting entry {i}, {:?}, age: {:?}",
-                    entry,
-                    entry.last_updated.elapsed()
-                );
-            }
+        let (deleted, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|e| e.is_expired(max_age));
+        self.entries = kept;
+
+        for entry in &deleted {
+            node_log!(
+                self.logger,
+                Level::Warn,
+                "Deleting entry {:?}, age: {:?}",
+                entry,
+                entry.last_updated.elapsed()
+            );
         }
 
-        let num_deleted = {
-            let len_before = self.entries.len();
-            self.entries
-                .retain(|e| e.is_local() || e.last_updated.elapsed() < max_age);
-            let len_after = self.entries().len();
-            len_before - len_after
-        };
+        let num_deleted = deleted.len();
         if num_deleted > 0 {
-            log::info!("Table pruned, {num_deleted} entries deleted");
+            node_log!(
+                self.logger,
+                Level::Info,
+                "Table pruned, {num_deleted} entries deleted"
+            );
         }
+        self.publish_removed(deleted);
+    }
+
+    /// Deletes the entry for `addr` if it is older than `max_age`.
+    ///
+    /// Returns the entry if it is still live.
+    pub fn prune_entry_for(&mut self, addr: Ipv4Addr, max_age: Duration) -> Option<Entry> {
+        let entry = *self.find_entry_for(addr)?;
+        if !entry.is_expired(max_age) {
+            return Some(entry);
+        }
+
+        node_log!(
+            self.logger,
+            Level::Warn,
+            "Deleting entry {:?} on lookup, age: {:?}",
+            entry,
+            entry.last_updated.elapsed()
+        );
+        self.delete_mut_entry_for(addr);
+        None
+    }
+
+    fn publish_removed(&self, removed: Vec<Entry>) {
+        for entry in removed {
+            self.publish(RouteChange::Removed(entry));
+        }
+    }
+
+    fn publish(&self, change: RouteChange) {
+        // Errs only when there are no subscribers, which is fine.
+        self.changes.send(change).ok();
     }
 }
 
@@ -68,6 +178,7 @@ pub struct Entry {
     next_hop: Ipv4Addr,
     cost: u32,
     is_local: bool,
+    is_static: bool,
     pub last_updated: Instant,
 }
 
@@ -78,6 +189,19 @@ impl Entry {
             next_hop,
             cost,
             is_local: false,
+            is_static: false,
+            last_updated: Instant::now(),
+        }
+    }
+
+    /// Constructs a manually configured entry, which is never pruned.
+    pub fn new_static(destination: Ipv4Addr, next_hop: Ipv4Addr, cost: u32) -> Self {
+        Self {
+            destination,
+            next_hop,
+            cost,
+            is_local: false,
+            is_static: true,
             last_updated: Instant::now(),
         }
     }
@@ -88,6 +212,7 @@ impl Entry {
             next_hop,
             cost,
             is_local: true,
+            is_static: false,
             last_updated: Instant::now(),
         }
     }
@@ -113,13 +238,18 @@ impl Entry {
         self.is_local
     }
 
+    /// Whether this entry was configured manually rather than learned
+    pub fn is_static(&self) -> bool {
+        self.is_static
+    }
+
+    /// Whether this entry has gone without an update for longer than
+    /// `max_age`. Local and static entries never expire.
+    pub fn is_expired(&self, max_age: Duration) -> bool {
+        !self.is_local && !self.is_static && self.last_updated.elapsed() >= max_age
+    }
+
     pub fn update(&mut self, next_hop: Ipv4Addr, cost: u32) {
-        log::info!(
-            "Update routing entry: old: {}, new next hop: {}, new cost: {}",
-            self,
-            next_hop,
-            cost
-        );
         self.next_hop = next_hop;
         self.cost = cost;
         self.restart_delete_timer();
@@ -134,7 +264,6 @@ impl Entry {
     }
 
     pub fn restart_delete_timer(&mut self) {
-        log::debug!("resetting timer for entry: {}", self);
         self.last_updated = Instant::now();
     }
 
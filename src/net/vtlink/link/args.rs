@@ -5,7 +5,7 @@ use std::{
     net::Ipv4Addr,
 };
 
-use super::{LinkDefinition, ParseLinkError};
+use super::{LinkDefinition, LnxConfig, ParseLnxError};
 
 /// Input to a router; used to establish a router's interfaces.
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -19,13 +19,7 @@ pub struct Args {
 
 #[derive(Debug)]
 pub enum ParseArgsError {
-    MissingFirstLine,
-    NoHost,
-    NoPort,
-    NoLinks,
-    MalformedPort,
-    MalformedLink(ParseLinkError),
-    ReadLineError(std::io::Error),
+    Lnx(ParseLnxError),
     OpenLinkFileError(std::io::Error),
     MissingLinkFileArg,
 }
@@ -54,43 +48,13 @@ impl Args {
         Ok(Args::try_parse(br)?)
     }
 
+    /// Parses an lnx file, see `LnxConfig::try_parse`.
     pub fn try_parse<B>(reader: B) -> Result<Args, ParseArgsError>
     where
         B: BufRead,
     {
-        let mut lines = reader.lines();
-        let host_ip_port = lines
-            .next()
-            .ok_or(ParseArgsError::MissingFirstLine)?
-            .map_err(ParseArgsError::ReadLineError)?;
-
-        let mut ip_port = host_ip_port.split_whitespace();
-        // ignored: assume localhost
-        let _ip = ip_port.next().ok_or(ParseArgsError::NoHost)?;
-        let port = ip_port
-            .next()
-            .ok_or(ParseArgsError::NoPort)?
-            .parse::<u16>()
-            .map_err(|_| ParseArgsError::MalformedPort)?;
-
-        let mut links = Vec::new();
-        for line in lines {
-            let raw_link = line.map_err(ParseArgsError::ReadLineError)?;
-            links.push(
-                LinkDefinition::try_parse(raw_link.as_str())
-                    .map_err(ParseArgsError::MalformedLink)?,
-            );
-        }
-
-        if links.is_empty() {
-            return Err(ParseArgsError::NoLinks);
-        }
-
-        Ok(Args {
-            host_port: port,
-            links,
-            lossy: false,
-        })
+        let cfg = LnxConfig::try_parse(reader).map_err(ParseArgsError::Lnx)?;
+        Ok(cfg.into())
     }
 
     pub fn get_my_interface_ips(&self) -> Vec<Ipv4Addr> {
@@ -132,6 +96,7 @@ impl Display for Args {
 
 #[cfg(test)]
 mod tests {
+    use super::super::DEFAULT_LINK_MTU;
     use super::*;
     use std::net::Ipv4Addr;
 
@@ -149,12 +114,14 @@ mod tests {
                     LinkDefinition {
                         dest_port: 5000,
                         interface_ip: Ipv4Addr::new(192, 168, 0, 2),
-                        dest_ip: Ipv4Addr::new(192, 168, 0, 1)
+                        dest_ip: Ipv4Addr::new(192, 168, 0, 1),
+                        mtu: DEFAULT_LINK_MTU,
                     },
                     LinkDefinition {
                         dest_port: 5002,
                         interface_ip: Ipv4Addr::new(192, 168, 0, 3),
-                        dest_ip: Ipv4Addr::new(192, 168, 0, 4)
+                        dest_ip: Ipv4Addr::new(192, 168, 0, 4),
+                        mtu: DEFAULT_LINK_MTU,
                     }
                 ]
             }
@@ -0,0 +1,66 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Hands packets on a fixed delay after they are pushed, in the order they
+/// were pushed.
+pub struct DelayLine {
+    delay: Duration,
+    queue: mpsc::UnboundedSender<(Instant, Vec<u8>)>,
+}
+
+impl DelayLine {
+    /// Starts a line passing each packet to `deliver` once `delay` has
+    /// elapsed. The line stops once it is dropped and has emptied.
+    pub fn spawn<F, Fut>(delay: Duration, mut deliver: F) -> Self
+    where
+        F: FnMut(Vec<u8>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let (queue, mut pending) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+        tokio::spawn(async move {
+            // Every packet waits equally long, so they fall due in order.
+            while let Some((due, packet)) = pending.recv().await {
+                tokio::time::sleep_until(due).await;
+                deliver(packet).await;
+            }
+        });
+        Self { delay, queue }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn push(&self, packet: Vec<u8>) {
+        // The receiving task only stops once this line is dropped.
+        let _ = self.queue.send((Instant::now() + self.delay, packet));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn delivers_in_order_after_delay() {
+        let delay = Duration::from_millis(50);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let line = DelayLine::spawn(delay, move |packet| {
+            tx.send((Instant::now(), packet)).unwrap();
+            std::future::ready(())
+        });
+
+        let pushed_at = Instant::now();
+        for i in 0..10 {
+            line.push(vec![i]);
+        }
+        for i in 0..10 {
+            let (delivered_at, packet) = rx.recv().await.unwrap();
+            assert_eq!(packet, vec![i]);
+            assert!(delivered_at - pushed_at >= delay);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader},
+    net::Ipv4Addr,
+    str::FromStr,
+};
+
+use super::{Args, LinkDefinition, DEFAULT_LINK_MTU};
+
+/// A parsed lnx file: the port this host binds to, and its links.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LnxConfig {
+    /// The port where this host runs.
+    pub host_port: u16,
+    /// The host's links, ordered by their interface ID number.
+    pub links: Vec<LinkDefinition>,
+}
+
+/// A field of an lnx file line.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LnxField {
+    Host,
+    HostPort,
+    LinkHost,
+    LinkPort,
+    InterfaceIp,
+    DestIp,
+    Mtu,
+}
+
+#[derive(Debug)]
+pub enum ParseLnxError {
+    Io(std::io::Error),
+    /// The file has no host line.
+    Empty,
+    /// The file has a host line but no links.
+    NoLinks,
+    /// A line (1-indexed) is missing a field.
+    MissingField {
+        line: usize,
+        field: LnxField,
+    },
+    /// A line (1-indexed) has a field that could not be parsed.
+    MalformedField {
+        line: usize,
+        field: LnxField,
+        value: String,
+    },
+}
+
+impl From<std::io::Error> for ParseLnxError {
+    fn from(e: std::io::Error) -> Self {
+        ParseLnxError::Io(e)
+    }
+}
+
+impl LnxConfig {
+    pub fn parse(path: &str) -> Result<LnxConfig, ParseLnxError> {
+        LnxConfig::try_parse(BufReader::new(File::open(path)?))
+    }
+
+    pub fn try_parse<B: BufRead>(reader: B) -> Result<LnxConfig, ParseLnxError> {
+        let mut lines = reader
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| line.map(|l| (idx + 1, l)))
+            .filter(|line| !matches!(line, Ok((_, l)) if l.trim().is_empty()));
+
+        let (line_no, host_line) = lines.next().ok_or(ParseLnxError::Empty)??;
+        let mut fields = LineFields::new(line_no, &host_line);
+        // ignored: assume localhost
+        fields.next_raw(LnxField::Host)?;
+        let host_port = fields.next_parsed(LnxField::HostPort)?;
+
+        let mut links = Vec::new();
+        for line in lines {
+            let (line_no, raw_link) = line?;
+            let mut fields = LineFields::new(line_no, &raw_link);
+            fields.next_raw(LnxField::LinkHost)?;
+            links.push(LinkDefinition {
+                dest_port: fields.next_parsed(LnxField::LinkPort)?,
+                interface_ip: fields.next_parsed::<Ipv4Addr>(LnxField::InterfaceIp)?,
+                dest_ip: fields.next_parsed::<Ipv4Addr>(LnxField::DestIp)?,
+                mtu: fields
+                    .next_parsed_opt(LnxField::Mtu)?
+                    .unwrap_or(DEFAULT_LINK_MTU),
+            });
+        }
+
+        if links.is_empty() {
+            return Err(ParseLnxError::NoLinks);
+        }
+
+        Ok(LnxConfig { host_port, links })
+    }
+}
+
+impl From<LnxConfig> for Args {
+    fn from(cfg: LnxConfig) -> Self {
+        Args {
+            host_port: cfg.host_port,
+            links: cfg.links,
+            lossy: false,
+        }
+    }
+}
+
+/// The whitespace-separated fields of a single lnx line.
+struct LineFields<'a> {
+    line: usize,
+    split: std::str::SplitWhitespace<'a>,
+}
+
+impl<'a> LineFields<'a> {
+    fn new(line: usize, raw: &'a str) -> Self {
+        Self {
+            line,
+            split: raw.split_whitespace(),
+        }
+    }
+
+    fn next_raw(&mut self, field: LnxField) -> Result<&'a str, ParseLnxError> {
+        self.split.next().ok_or(ParseLnxError::MissingField {
+            line: self.line,
+            field,
+        })
+    }
+
+    fn next_parsed<T: FromStr>(&mut self, field: LnxField) -> Result<T, ParseLnxError> {
+        let raw = self.next_raw(field)?;
+        self.parse(raw, field)
+    }
+
+    /// Like `next_parsed`, for a trailing field that may be left out.
+    fn next_parsed_opt<T: FromStr>(&mut self, field: LnxField) -> Result<Option<T>, ParseLnxError> {
+        self.split
+            .next()
+            .map(|raw| self.parse(raw, field))
+            .transpose()
+    }
+
+    fn parse<T: FromStr>(&self, raw: &str, field: LnxField) -> Result<T, ParseLnxError> {
+        raw.parse().map_err(|_| ParseLnxError::MalformedField {
+            line: self.line,
+            field,
+            value: raw.into(),
+        })
+    }
+}
+
+impl Display for LnxField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LnxField::Host => write!(f, "host"),
+            LnxField::HostPort => write!(f, "host port"),
+            LnxField::LinkHost => write!(f, "link host"),
+            LnxField::LinkPort => write!(f, "link port"),
+            LnxField::InterfaceIp => write!(f, "interface IP"),
+            LnxField::DestIp => write!(f, "destination IP"),
+            LnxField::Mtu => write!(f, "MTU"),
+        }
+    }
+}
+
+impl Display for ParseLnxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseLnxError::Io(e) => write!(f, "failed to read lnx file: {}", e),
+            ParseLnxError::Empty => write!(f, "lnx file is empty"),
+            ParseLnxError::NoLinks => write!(f, "lnx file defines no links"),
+            ParseLnxError::MissingField { line, field } => {
+                write!(f, "line {}: missing {}", line, field)
+            }
+            ParseLnxError::MalformedField { line, field, value } => {
+                write!(f, "line {}: malformed {} \"{}\"", line, field, value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lnx_file() {
+        let cfg = LnxConfig::parse("./net_links/abc/B.lnx").unwrap();
+        assert_eq!(Args::from(cfg), crate::fixture::netlinks::abc::load().b);
+    }
+
+    #[test]
+    fn parse_link_mtu() {
+        let parse_link = |raw_link: &str| {
+            LnxConfig::try_parse(format!("localhost 5001\n{raw_link}\n").as_bytes())
+                .map(|cfg| cfg.links[0])
+        };
+
+        let link = parse_link("localhost 5000 192.168.0.2 192.168.0.1").unwrap();
+        assert_eq!(link.mtu, DEFAULT_LINK_MTU);
+        assert_eq!(DEFAULT_LINK_MTU, 1500);
+
+        let link = parse_link("localhost 5000 192.168.0.2 192.168.0.1 576").unwrap();
+        assert_eq!(link.mtu, 576);
+        assert_eq!(link.dest_ip, Ipv4Addr::new(192, 168, 0, 1));
+
+        assert!(matches!(
+            parse_link("localhost 5000 192.168.0.2 192.168.0.1 big"),
+            Err(ParseLnxError::MalformedField {
+                line: 2,
+                field: LnxField::Mtu,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn malformed_lnx_names_line() {
+        let raw = "localhost 5001\n\
+                   localhost 5000 192.168.0.2 192.168.0.1\n\
+                   localhost 5002 192.168.0.300 192.168.0.4\n";
+        let err = LnxConfig::try_parse(raw.as_bytes()).unwrap_err();
+
+        assert!(matches!(
+            &err,
+            ParseLnxError::MalformedField {
+                line: 3,
+                field: LnxField::InterfaceIp,
+                value,
+            } if value == "192.168.0.300"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "line 3: malformed interface IP \"192.168.0.300\""
+        );
+    }
+}
@@ -1,14 +1,19 @@
 mod args;
+mod delay;
+mod lnx;
 
 pub use args::Args;
 use core::fmt;
+pub use lnx::{LnxConfig, LnxField, ParseLnxError};
 use std::{
+    collections::HashMap,
     ops::{Deref, DerefMut},
     usize,
 };
 
 use std::net::Ipv4Addr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
     net::UdpSocket,
     sync::{
@@ -17,20 +22,28 @@ use tokio::{
     },
 };
 
+use self::delay::DelayLine;
+use super::frag;
+use crate::utils::logging::NodeLogger;
 use crate::utils::net::localhost_with_port;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// The MTU of a link whose definition does not set one, in bytes.
+pub const DEFAULT_LINK_MTU: usize = 1500;
+
 #[derive(Debug)]
 pub enum Error {
     LinkNotFound,
     LinkInactive,
+    Io(std::io::Error),
 }
 
 impl From<SendError> for Error {
     fn from(e: SendError) -> Self {
         match e {
             SendError::LinkInactive => Error::LinkInactive,
+            SendError::Io(e) => Error::Io(e),
         }
     }
 }
@@ -79,6 +92,9 @@ impl<'a> DerefMut for LinkMutRef<'a> {
 pub struct VtLinkLayer {
     links: Links,
     listener_sub: Mutex<Option<Sender<Vec<u8>>>>,
+    /// How long packets arriving over a link are held back, keyed by the
+    /// port of the host at its other end.
+    inbound_delays: Arc<std::sync::Mutex<HashMap<u16, Duration>>>,
 }
 
 impl VtLinkLayer {
@@ -101,22 +117,21 @@ impl VtLinkLayer {
         Self {
             links,
             listener_sub: Mutex::new(None),
+            inbound_delays: Arc::default(),
         }
     }
 
     /// Send bytes to a destination.
     ///
     /// The destination is typically the next-hop address for a packet.
-    pub async fn send(&self, payload: &[u8], next_hop: Ipv4Addr) -> Result<()> {
+    pub async fn send(&self, payload: &[u8], next_hop: Ipv4Addr, logger: NodeLogger) -> Result<()> {
         self.links
             .find(|link| link.dest() == next_hop)
             .await
             .ok_or(Error::LinkNotFound)?
-            .send(payload)
+            .send(payload, logger)
             .await
-            .map_err(|e| match e {
-                SendError::LinkInactive => Error::LinkInactive,
-            })
+            .map_err(Error::from)
     }
 
     pub async fn activate_link(&self, link_no: u16) -> Result<()> {
@@ -139,10 +154,38 @@ impl VtLinkLayer {
         Ok(())
     }
 
+    /// Delays packets sent out of a link by `forward`, and packets arriving
+    /// over it by `reverse`. A zero duration leaves that direction undelayed.
+    pub async fn set_link_latency(
+        &self,
+        link_no: u16,
+        forward: Duration,
+        reverse: Duration,
+    ) -> Result<()> {
+        let mut link = self
+            .links
+            .get_mut(link_no)
+            .await
+            .ok_or(Error::LinkNotFound)?;
+        link.set_latency(forward);
+
+        let mut inbound_delays = self.inbound_delays.lock().unwrap();
+        if reverse.is_zero() {
+            inbound_delays.remove(&link.dest_port);
+        } else {
+            inbound_delays.insert(link.dest_port, reverse);
+        }
+        Ok(())
+    }
+
     pub async fn iter_links(&self) -> LinkIter<'_> {
         self.links.iter().await
     }
 
+    pub async fn get_link(&self, link_no: u16) -> Option<LinkRef<'_>> {
+        self.links.get(link_no).await
+    }
+
     pub async fn find_link_to(&self, dest: Ipv4Addr) -> Option<LinkRef<'_>> {
         self.links.find(|link| link.dest() == dest).await
     }
@@ -169,14 +212,28 @@ impl VtLinkLayer {
 
         let (tx, rx) = broadcast::channel(1024);
         let sender = tx.clone();
+        let inbound_delays = self.inbound_delays.clone();
 
         tokio::spawn(async move {
             let mut buf = [0; 65536];
-            while let Ok(sz) = sock.recv(&mut buf).await {
-                if sender.send(buf[..sz].into()).is_err() {
-                    log::debug!("All subscribers terminated");
-                    break;
+            let mut delay_lines: HashMap<u16, DelayLine> = HashMap::new();
+            while let Ok((sz, from)) = sock.recv_from(&mut buf).await {
+                let delay = inbound_delays.lock().unwrap().get(&from.port()).copied();
+                let Some(delay) = delay else {
+                    if sender.send(buf[..sz].into()).is_err() {
+                        log::debug!("All subscribers terminated");
+                        break;
+                    }
+                    continue;
+                };
+
+                let line = delay_lines
+                    .entry(from.port())
+                    .or_insert_with(|| DelayLine::spawn(delay, delayed_delivery(sender.clone())));
+                if line.delay() != delay {
+                    *line = DelayLine::spawn(delay, delayed_delivery(sender.clone()));
                 }
+                line.push(buf[..sz].into());
             }
         });
 
@@ -185,6 +242,15 @@ impl VtLinkLayer {
     }
 }
 
+/// Passes packets that were held back on to the host's subscribers.
+fn delayed_delivery(sender: Sender<Vec<u8>>) -> impl FnMut(Vec<u8>) -> std::future::Ready<()> {
+    move |packet| {
+        // With no subscribers left, the packet has nowhere to go.
+        let _ = sender.send(packet);
+        std::future::ready(())
+    }
+}
+
 #[derive(Default)]
 struct Links(RwLock<Vec<Link>>);
 
@@ -271,64 +337,31 @@ pub struct LinkDefinition {
     pub interface_ip: Ipv4Addr,
     /// The virtual IP of the connected host's interface.
     pub dest_ip: Ipv4Addr,
+    /// The largest IP packet the link carries, in bytes.
+    pub mtu: usize,
 }
 
 pub struct Link {
     dest_port: u16,
     dest_virtual_ip: Ipv4Addr,
     src_virtual_ip: Ipv4Addr,
+    mtu: usize,
     activated: bool,
     sock: Arc<UdpSocket>,
-}
-
-#[derive(Debug)]
-pub enum ParseLinkError {
-    NoIp,
-    NoPort,
-    NoSrcVirtualIp,
-    NoDstVirtualIp,
-    MalformedPort,
-    MalformedIp,
+    /// Holds back packets sent out of the link, if it is given latency.
+    outbound: Option<DelayLine>,
 }
 
 impl LinkDefinition {
-    pub fn try_parse(raw_link: &str) -> std::result::Result<Self, ParseLinkError> {
-        let mut split = raw_link.split_whitespace();
-
-        split.next().ok_or(ParseLinkError::NoIp)?;
-
-        let dest_port = split
-            .next()
-            .ok_or(ParseLinkError::NoPort)?
-            .parse::<u16>()
-            .map_err(|_| ParseLinkError::MalformedPort)?;
-
-        let interface_ip = split
-            .next()
-            .ok_or(ParseLinkError::NoSrcVirtualIp)?
-            .parse()
-            .map_err(|_| ParseLinkError::MalformedIp)?;
-
-        let dest_ip = split
-            .next()
-            .ok_or(ParseLinkError::NoDstVirtualIp)?
-            .parse()
-            .map_err(|_| ParseLinkError::MalformedIp)?;
-
-        Ok(LinkDefinition {
-            dest_port,
-            interface_ip,
-            dest_ip,
-        })
-    }
-
     pub fn into_link(self, udp_socket: Arc<UdpSocket>) -> Link {
         Link {
             dest_port: self.dest_port,
             dest_virtual_ip: self.dest_ip,
             src_virtual_ip: self.interface_ip,
+            mtu: self.mtu,
             activated: true,
             sock: udp_socket,
+            outbound: None,
         }
     }
 }
@@ -336,19 +369,32 @@ impl LinkDefinition {
 #[derive(Debug)]
 pub enum SendError {
     LinkInactive,
+    /// The UDP socket underneath the link failed to send.
+    Io(std::io::Error),
 }
 
 impl Link {
     /// On this link, send a message conforming to one of the supported protocols.
-    pub async fn send(&self, payload: &[u8]) -> std::result::Result<(), SendError> {
+    /// Packets longer than the link's MTU are sent in fragments.
+    pub async fn send(
+        &self,
+        payload: &[u8],
+        logger: NodeLogger,
+    ) -> std::result::Result<(), SendError> {
         if !self.activated {
             return Err(SendError::LinkInactive);
         }
 
-        self.sock
-            .send_to(payload, localhost_with_port(self.dest_port))
-            .await
-            .unwrap();
+        for fragment in frag::fragment(payload, self.mtu, logger) {
+            if let Some(outbound) = &self.outbound {
+                outbound.push(fragment);
+                continue;
+            }
+            self.sock
+                .send_to(&fragment, localhost_with_port(self.dest_port))
+                .await
+                .map_err(SendError::Io)?;
+        }
 
         Ok(())
     }
@@ -365,6 +411,23 @@ impl Link {
         }
     }
 
+    fn set_latency(&mut self, latency: Duration) {
+        if latency.is_zero() {
+            self.outbound = None;
+            return;
+        }
+
+        let sock = self.sock.clone();
+        let dest = localhost_with_port(self.dest_port);
+        self.outbound = Some(DelayLine::spawn(latency, move |packet| {
+            let sock = sock.clone();
+            async move {
+                // A packet that fails to go out late is lost, as on a real link.
+                let _ = sock.send_to(&packet, dest).await;
+            }
+        }));
+    }
+
     pub fn is_disabled(&self) -> bool {
         !self.activated
     }
@@ -377,6 +440,10 @@ impl Link {
         self.src_virtual_ip
     }
 
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
     pub fn clone_socket(&self) -> Arc<UdpSocket> {
         self.sock.clone()
     }
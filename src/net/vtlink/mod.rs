@@ -1,52 +1,101 @@
+mod frag;
 mod fwd;
 mod link;
 
-pub use link::Args;
+pub use link::{Args, LnxConfig, LnxField, ParseLnxError};
 use tokio::sync::broadcast::error::RecvError;
 
 use crate::drop_policy::{self, DropPolicy};
+use crate::protocol::icmp::IcmpMessage;
 use crate::protocol::rip::RipMessage;
 use crate::protocol::{Protocol, ProtocolHandler};
+use crate::utils::logging::NodeLogger;
 use crate::utils::loop_with_interval;
 use crate::utils::net::Ipv4PacketBuilder;
-use crate::Message;
+use crate::{node_log, Message};
 use async_trait::async_trait;
 use etherparse::{InternetSlice, Ipv4HeaderSlice, SlicedPacket};
+use log::{Level, LevelFilter};
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::task::JoinHandle;
 
-use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::{broadcast, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-pub use self::fwd::{Entry, ForwardingTable};
+use self::frag::Reassembler;
+pub use self::fwd::{Entry, ForwardingTable, RouteChange};
 pub(crate) use link::{Error, Link, VtLinkLayer};
 pub use link::{LinkIter, LinkRef};
 
 use super::{Net, SendError};
 
+/// At most `RIP_TRIGGERED_UPDATE_LIMIT` triggered updates are sent per
+/// `RIP_TRIGGERED_UPDATE_INTERVAL`. Changes whose update is suppressed are
+/// deferred, and go out together once the interval ends (RFC 2453, section
+/// 3.10.1).
+const RIP_TRIGGERED_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+const RIP_TRIGGERED_UPDATE_LIMIT: usize = 10;
+
+impl From<link::SendError> for SendError {
+    fn from(e: link::SendError) -> Self {
+        match e {
+            link::SendError::LinkInactive => SendError::InterfaceDown,
+            link::SendError::Io(e) => SendError::LinkIoError(e),
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum PacketDecision {
     Drop,
     Forward,
     Consume,
+    /// The packet is not for us, and its TTL runs out before it can be
+    /// forwarded.
+    Expire,
+}
+
+/// When expired routes are deleted from the forwarding table.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PruneStrategy {
+    /// Scan the whole table every `prune_interval`.
+    #[default]
+    Eager,
+    /// Delete a route only when it is looked up and found expired. Expired
+    /// routes that are never looked up linger in the table.
+    Lazy,
 }
 
 pub struct VtLinkNetConfig<DP: DropPolicy> {
     pub prune_interval: Duration,
+    pub prune_strategy: PruneStrategy,
     pub rip_update_interval: Duration,
     pub entry_max_age: Duration,
     pub drop_policy: DP,
+    /// Whether to run RIP. Without RIP, routing relies solely on
+    /// directly-connected and static routes.
+    pub rip_enabled: bool,
+    /// Experimental. Whether a connection may keep its source address when
+    /// its packets are rerouted out of a different interface.
+    pub connection_migration: bool,
+    /// Overrides the global log level for this node's TCP and RIP lines.
+    pub log_level: Option<LevelFilter>,
 }
 
 impl Default for VtLinkNetConfig<drop_policy::NeverDrop> {
     fn default() -> Self {
         Self {
             prune_interval: Duration::from_secs(1),
+            prune_strategy: PruneStrategy::default(),
             rip_update_interval: Duration::from_secs(5),
             entry_max_age: Duration::from_secs(12),
             drop_policy: drop_policy::NeverDrop::default(),
+            rip_enabled: true,
+            connection_migration: false,
+            log_level: None,
         }
     }
 }
@@ -54,16 +103,25 @@ pub struct VtLinkNet<DP: DropPolicy> {
     links: Arc<VtLinkLayer>,
     my_addrs: Vec<Ipv4Addr>,
     routes: Arc<RwLock<ForwardingTable>>,
-    pruner: JoinHandle<()>,
-    rip_updater: JoinHandle<()>,
+    prune_strategy: PruneStrategy,
+    entry_max_age: Duration,
+    connection_migration: bool,
+    logger: NodeLogger,
+    pruner: Option<JoinHandle<()>>,
+    rip_updater: Option<JoinHandle<()>>,
+    triggered_updates: Arc<Mutex<TriggeredUpdateLimiter>>,
+    /// Packets dropped because their TTL ran out on the way through.
+    ttl_expired: AtomicUsize,
+    /// The IP identification of the next packet sent.
+    next_identification: AtomicU16,
+    reassembler: Mutex<Reassembler>,
     drop_policy: DP,
 }
 
 #[async_trait]
 impl<DP: DropPolicy> Net for VtLinkNet<DP> {
     async fn get_outbound_ip(&self, dest: Ipv4Addr) -> Option<[u8; 4]> {
-        let rt = self.routes.read().await;
-        if let Some(forward_rule) = rt.find_entry_for(dest) {
+        if let Some(forward_rule) = self.find_route(dest).await {
             self.links
                 .find_link_to(forward_rule.next_hop())
                 .await
@@ -73,42 +131,90 @@ impl<DP: DropPolicy> Net for VtLinkNet<DP> {
         }
     }
 
+    async fn path_mtu(&self, dest: Ipv4Addr) -> Option<usize> {
+        self.find_egress_link(dest)
+            .await
+            .ok()
+            .map(|link| link.mtu())
+    }
+
     async fn send<P: Into<u8> + Send>(
         &self,
         payload: &[u8],
         protocol: P,
         dest_vip: Ipv4Addr,
     ) -> Result<(), SendError> {
-        let table = self.routes.read().await;
+        let link = self.find_egress_link(dest_vip).await?;
 
-        let entry = table
-            .find_entry_for(dest_vip)
-            .ok_or(SendError::NoForwardingEntry)?;
+        let packet = Ipv4PacketBuilder::default()
+            .with_src(link.source())
+            .with_dst(dest_vip)
+            .with_payload(payload)
+            .with_protocol(protocol)
+            .with_identification(self.next_identification())
+            .build()
+            .unwrap();
 
-        if entry.is_unreachable() {
-            return Err(SendError::Unreachable);
+        link.send(&packet, self.logger)
+            .await
+            .map_err(SendError::from)
+    }
+
+    async fn send_from<P: Into<u8> + Send>(
+        &self,
+        src: Ipv4Addr,
+        payload: &[u8],
+        protocol: P,
+        dest_vip: Ipv4Addr,
+    ) -> Result<(), SendError> {
+        let link = self.find_egress_link(dest_vip).await?;
+        if link.source() != src && !self.connection_migration {
+            return Err(SendError::SourceMismatch);
         }
 
+        let packet = Ipv4PacketBuilder::default()
+            .with_src(src)
+            .with_dst(dest_vip)
+            .with_payload(payload)
+            .with_protocol(protocol)
+            .with_identification(self.next_identification())
+            .build()
+            .unwrap();
+
+        link.send(&packet, self.logger)
+            .await
+            .map_err(SendError::from)
+    }
+
+    async fn send_via<P: Into<u8> + Send>(
+        &self,
+        link_no: u16,
+        payload: &[u8],
+        protocol: P,
+        dest_vip: Ipv4Addr,
+    ) -> Result<(), SendError> {
         let link = self
             .links
-            .find_link_to(entry.next_hop())
+            .get_link(link_no)
             .await
-            .ok_or_else(|| {
-                log::warn!("No link found for next hop {}", entry.next_hop());
-                SendError::NoLink
-            })?;
+            .ok_or(SendError::NoLink)?;
 
         let packet = Ipv4PacketBuilder::default()
             .with_src(link.source())
             .with_dst(dest_vip)
             .with_payload(payload)
             .with_protocol(protocol)
+            .with_identification(self.next_identification())
             .build()
             .unwrap();
 
-        link.send(&packet)
+        link.send(&packet, self.logger)
             .await
-            .map_err(|e| SendError::Transport(e.into()))
+            .map_err(SendError::from)
+    }
+
+    fn logger(&self) -> NodeLogger {
+        self.logger
     }
 }
 
@@ -116,38 +222,145 @@ impl<DP: DropPolicy> VtLinkNet<DP> {
     pub fn new(links: Arc<VtLinkLayer>, program_args: &Args, config: VtLinkNetConfig<DP>) -> Self {
         let my_addrs = program_args.get_my_interface_ips();
 
-        let entries = program_args
+        let mut entries: Vec<_> = program_args
             .links
             .iter()
             .map(|l| Entry::new_local(l.interface_ip, l.interface_ip, 0 /* cost */))
             .collect();
-        let routes = Arc::new(RwLock::new(ForwardingTable::with_entries(entries)));
-
-        let prune_interval = config.prune_interval;
-        let entry_max_age = config.entry_max_age;
-        let rip_update_interval = config.rip_update_interval;
-
-        let pruner_routes = routes.clone();
-        let pruner = tokio::spawn(async move {
-            prune_routing_table(pruner_routes, prune_interval, entry_max_age).await;
-        });
-
-        let rip_updater_routes = routes.clone();
-        let rip_updater_links = links.clone();
-        let rip_updater = tokio::spawn(async move {
-            periodic_rip_update(rip_updater_routes, rip_updater_links, rip_update_interval).await;
-        });
+        if !config.rip_enabled {
+            // Without RIP, neighbors never advertise themselves.
+            entries.extend(
+                program_args
+                    .links
+                    .iter()
+                    .map(|l| Entry::new_static(l.dest_ip, l.dest_ip, 1 /* cost */)),
+            );
+        }
+        let logger = NodeLogger::new(config.log_level);
+        let routes = Arc::new(RwLock::new(ForwardingTable::with_entries(entries, logger)));
+
+        let (pruner, rip_updater) = if config.rip_enabled {
+            let prune_interval = config.prune_interval;
+            let entry_max_age = config.entry_max_age;
+            let rip_update_interval = config.rip_update_interval;
+
+            let pruner = (config.prune_strategy == PruneStrategy::Eager).then(|| {
+                let pruner_routes = routes.clone();
+                tokio::spawn(async move {
+                    prune_routing_table(pruner_routes, prune_interval, entry_max_age, logger).await;
+                })
+            });
+
+            let rip_updater_routes = routes.clone();
+            let rip_updater_links = links.clone();
+            let rip_updater = tokio::spawn(async move {
+                periodic_rip_update(
+                    rip_updater_routes,
+                    rip_updater_links,
+                    rip_update_interval,
+                    entry_max_age,
+                    logger,
+                )
+                .await;
+            });
+            (pruner, Some(rip_updater))
+        } else {
+            (None, None)
+        };
 
         Self {
             links,
             my_addrs,
             routes,
+            prune_strategy: config.prune_strategy,
+            entry_max_age: config.entry_max_age,
+            connection_migration: config.connection_migration,
+            logger,
             pruner,
             rip_updater,
+            triggered_updates: Arc::default(),
+            ttl_expired: AtomicUsize::new(0),
+            next_identification: AtomicU16::new(0),
+            reassembler: Mutex::new(Reassembler::new(frag::REASSEMBLY_TIMEOUT, logger)),
             drop_policy: config.drop_policy,
         }
     }
 
+    /// Turns off a link. Routes through it become unreachable at once; the
+    /// other neighbors are told so, and asked for their routes so that an
+    /// alternate one is found without waiting for their periodic updates.
+    pub async fn deactivate_link(&self, link_no: u16) -> Result<(), Error> {
+        let neighbor = self
+            .links
+            .get_link(link_no)
+            .await
+            .ok_or(Error::LinkNotFound)?
+            .dest();
+        self.links.deactivate_link(link_no).await?;
+        if self.rip_updater.is_none() {
+            return Ok(());
+        }
+
+        let unreachable = self.routes.write().await.mark_unreachable_via(neighbor);
+        self.send_triggered_update(&unreachable).await;
+        for link in &*self.links.iter_links().await {
+            send_rip_message(link, RipMessage::request(), self.logger).await;
+        }
+        Ok(())
+    }
+
+    /// Sends changed routes to every neighbor right away, rather than with the
+    /// next periodic update.
+    pub async fn send_triggered_update(&self, updates: &[Entry]) {
+        if updates.is_empty() {
+            return;
+        }
+        let flush_after = {
+            let mut limiter = self.triggered_updates.lock().unwrap();
+            if limiter.try_acquire() {
+                None
+            } else {
+                Some(limiter.defer(updates))
+            }
+        };
+        match flush_after {
+            None => send_triggered_update(&self.links, updates, self.logger).await,
+            Some(None) => {
+                node_log!(
+                    self.logger,
+                    Level::Debug,
+                    "Triggered update rate limit reached, deferring triggered update"
+                );
+            }
+            Some(Some(delay)) => {
+                node_log!(
+                    self.logger,
+                    Level::Debug,
+                    "Triggered update rate limit reached, deferring triggered update by {:?}",
+                    delay
+                );
+                let limiter = self.triggered_updates.clone();
+                let routes = self.routes.clone();
+                let links = self.links.clone();
+                let logger = self.logger;
+                tokio::spawn(async move {
+                    flush_deferred_updates(limiter, routes, links, delay, logger).await;
+                });
+            }
+        }
+    }
+
+    /// Sends the whole table to a neighbor that requested it.
+    pub async fn send_rip_response(&self, neighbor: Ipv4Addr) {
+        if let Some(link) = self.links.find_link_to(neighbor).await {
+            let rip_msg = RipMessage::from_entries_with_poisoned_reverse(
+                self.routes.read().await.entries(),
+                neighbor,
+            );
+            send_rip_message(&link, rip_msg, self.logger).await;
+        }
+    }
+
     pub fn links(&self) -> &VtLinkLayer {
         self.links.as_ref()
     }
@@ -160,27 +373,73 @@ impl<DP: DropPolicy> VtLinkNet<DP> {
         self.routes.read().await
     }
 
+    /// Subscribe to changes made to the forwarding table.
+    pub async fn subscribe_routes(&self) -> broadcast::Receiver<RouteChange> {
+        self.routes.read().await.subscribe()
+    }
+
+    fn next_identification(&self) -> u16 {
+        self.next_identification.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn drop_policy(&self) -> &DP {
+        &self.drop_policy
+    }
+
+    /// The number of packets dropped because their TTL ran out.
+    pub fn ttl_expired(&self) -> usize {
+        self.ttl_expired.load(Ordering::Relaxed)
+    }
+
     pub fn is_my_addr(&self, addr: Ipv4Addr) -> bool {
         self.my_addrs.iter().any(|a| *a == addr)
     }
 
     pub async fn run(&self, handlers: &HashMap<Protocol, Box<dyn ProtocolHandler<DP>>>) {
         let mut listener = self.links.listen().await;
+        // Packets the drop policy holds back, with when to handle them.
+        let mut delayed: Vec<(tokio::time::Instant, Vec<u8>)> = Vec::new();
         loop {
-            match listener.recv().await {
-                Ok(bytes) => {
-                    self.handle_packet_bytes(&bytes, handlers).await;
-                }
-                Err(e) => match e {
-                    RecvError::Lagged(n) => {
-                        log::warn!("Missed handling {n} packets b/c internal buffer full")
+            let next_due = delayed.iter().map(|(due, _)| *due).min();
+            tokio::select! {
+                recvd = listener.recv() => match recvd {
+                    Ok(mut bytes) => {
+                        self.drop_policy.corrupt(&mut bytes);
+                        match self.delay_of(&bytes) {
+                            Some(delay) => {
+                                delayed.push((tokio::time::Instant::now() + delay, bytes));
+                            }
+                            None => self.handle_packet_bytes(&bytes, handlers).await,
+                        }
                     }
-                    RecvError::Closed => break,
+                    Err(e) => match e {
+                        RecvError::Lagged(n) => {
+                            node_log!(self.logger, Level::Warn, "Missed handling {n} packets b/c internal buffer full")
+                        }
+                        RecvError::Closed => break,
+                    },
                 },
+                _ = tokio::time::sleep_until(next_due.unwrap_or_else(tokio::time::Instant::now)),
+                    if next_due.is_some() =>
+                {
+                    let now = tokio::time::Instant::now();
+                    let (due, pending) = delayed.into_iter().partition(|(at, _)| *at <= now);
+                    delayed = pending;
+                    for (_, bytes) in due {
+                        self.handle_packet_bytes(&bytes, handlers).await;
+                    }
+                }
             }
         }
     }
 
+    /// How long the drop policy holds back a received packet, if at all.
+    fn delay_of(&self, bytes: &[u8]) -> Option<Duration> {
+        let header = Ipv4HeaderSlice::from_slice(bytes).ok()?;
+        let payload = &bytes[header.slice().len()..];
+        self.drop_policy.delay(&header, payload)
+    }
+
     async fn handle_packet_bytes(
         &self,
         bytes: &[u8],
@@ -217,7 +476,59 @@ impl<DP: DropPolicy> VtLinkNet<DP> {
         match self.decide_packet(header).await {
             PacketDecision::Drop => {}
             PacketDecision::Forward => self.forward_packet(header, payload).await,
+            PacketDecision::Consume if frag::is_fragment(header) => {
+                self.reassemble_and_consume(header, payload, handlers).await
+            }
             PacketDecision::Consume => self.consume_packet(header, payload, handlers).await,
+            PacketDecision::Expire => self.expire_packet(header, payload).await,
+        }
+    }
+
+    /// Consumes the packet a fragment belongs to, once it is whole.
+    async fn reassemble_and_consume<'a>(
+        &self,
+        header: &Ipv4HeaderSlice<'a>,
+        payload: &[u8],
+        handlers: &HashMap<Protocol, Box<dyn ProtocolHandler<DP>>>,
+    ) {
+        let packet = self.reassembler.lock().unwrap().insert(header, payload);
+        if let Some(packet) = packet {
+            let header = Ipv4HeaderSlice::from_slice(&packet).unwrap();
+            let payload = &packet[header.slice().len()..];
+            self.consume_packet(&header, payload, handlers).await;
+        }
+    }
+
+    async fn expire_packet<'a>(&self, header: &Ipv4HeaderSlice<'a>, payload: &[u8]) {
+        node_log!(
+            self.logger,
+            Level::Debug,
+            "packet TTL expired; dropping packet"
+        );
+        self.ttl_expired.fetch_add(1, Ordering::Relaxed);
+        self.send_icmp_error(IcmpMessage::time_exceeded(header, payload), header)
+            .await;
+    }
+
+    /// Tells the source of `offending` why it was dropped. Errors about ICMP
+    /// messages are never sent, so that two routers cannot trade them
+    /// forever (RFC 1122, section 3.2.2).
+    async fn send_icmp_error<'a>(&self, message: IcmpMessage, offending: &Ipv4HeaderSlice<'a>) {
+        if offending.protocol() == Protocol::Icmp.into() {
+            return;
+        }
+        let source = offending.source_addr();
+        if let Err(e) = self
+            .send(&message.into_bytes(), Protocol::Icmp, source)
+            .await
+        {
+            node_log!(
+                self.logger,
+                Level::Debug,
+                "Could not send ICMP error to {}, {:?}",
+                source,
+                e
+            );
         }
     }
 
@@ -240,7 +551,11 @@ impl<DP: DropPolicy> VtLinkNet<DP> {
 
     pub async fn decide_packet<'a>(&self, header: &Ipv4HeaderSlice<'a>) -> PacketDecision {
         if !verify_header_checksum(header) {
-            log::debug!("packet header checksum invalid; dropping packet");
+            node_log!(
+                self.logger,
+                Level::Debug,
+                "packet header checksum invalid; dropping packet"
+            );
             return PacketDecision::Drop;
         }
 
@@ -248,12 +563,21 @@ impl<DP: DropPolicy> VtLinkNet<DP> {
         match self.links.find_link_to(sender).await {
             Some(link) => {
                 if link.is_disabled() {
-                    log::info!("Ignoring RIP packet from {}, link disabled", sender);
+                    node_log!(
+                        self.logger,
+                        Level::Info,
+                        "Ignoring RIP packet from {}, link disabled",
+                        sender
+                    );
                     return PacketDecision::Drop;
                 }
             }
             None => {
-                log::debug!("Could not obtain the link where a packet is sent; is this in a test?");
+                node_log!(
+                    self.logger,
+                    Level::Debug,
+                    "Could not obtain the link where a packet is sent; is this in a test?"
+                );
             }
         };
 
@@ -265,9 +589,9 @@ impl<DP: DropPolicy> VtLinkNet<DP> {
             return PacketDecision::Consume;
         }
 
-        if header.ttl() == 0 {
-            log::debug!("packet TTL = 0; dropping packet");
-            return PacketDecision::Drop;
+        // The TTL is decremented on forwarding, and must not reach 0.
+        if header.ttl() <= 1 {
+            return PacketDecision::Expire;
         }
 
         PacketDecision::Forward
@@ -275,38 +599,96 @@ impl<DP: DropPolicy> VtLinkNet<DP> {
 
     pub async fn forward_packet<'a>(&self, header: &Ipv4HeaderSlice<'a>, payload: &[u8]) {
         let dest = header.destination_addr();
-        let rt = self.routes.read().await;
 
-        if let Some(entry) = rt.find_entry_for(dest) {
+        if let Some(entry) = self.find_route(dest).await {
             match self.links.find_link_to(entry.next_hop()).await {
                 Some(link) => {
-                    let packet = Ipv4PacketBuilder::default()
-                        .with_src(header.source_addr())
-                        .with_dst(header.destination_addr())
-                        .with_payload(payload)
-                        .with_protocol(header.protocol())
-                        .with_ttl(header.ttl() - 1)
-                        .build()
-                        .unwrap();
-
-                    if let Err(e) = link.send(&packet).await {
-                        log::warn!("Error forwarding packet, {:?}", e);
+                    // Fragments are forwarded as they are, bar the TTL.
+                    let mut forwarded = header.to_header();
+                    forwarded.time_to_live -= 1;
+                    let mut packet = Vec::with_capacity(forwarded.header_len() + payload.len());
+                    forwarded
+                        .write(&mut packet)
+                        .expect("IP header serialization error");
+                    packet.extend_from_slice(payload);
+
+                    if let Err(e) = link.send(&packet, self.logger).await {
+                        node_log!(self.logger, Level::Warn, "Error forwarding packet, {:?}", e);
                     }
                 }
                 None => {
-                    log::warn!("No link to next hop {}, dropping packet", entry.next_hop());
+                    node_log!(
+                        self.logger,
+                        Level::Warn,
+                        "No link to next hop {}, dropping packet",
+                        entry.next_hop()
+                    );
                 }
             }
         } else {
-            log::warn!("No route to {}, dropping packet", dest);
+            node_log!(
+                self.logger,
+                Level::Warn,
+                "No route to {}, dropping packet",
+                dest
+            );
+            self.send_icmp_error(
+                IcmpMessage::destination_unreachable(header, payload),
+                header,
+            )
+            .await;
         }
     }
 }
 
+impl<DP: DropPolicy> VtLinkNet<DP> {
+    /// Finds the link a packet to `dest` leaves on. A route through a link
+    /// that is down is reported as such, even once it is marked unreachable
+    /// because of it.
+    async fn find_egress_link(&self, dest: Ipv4Addr) -> Result<LinkRef<'_>, SendError> {
+        let entry = self.find_route(dest).await.ok_or(SendError::NoRoute)?;
+
+        let Some(link) = self.links.find_link_to(entry.next_hop()).await else {
+            node_log!(
+                self.logger,
+                Level::Warn,
+                "No link found for next hop {}",
+                entry.next_hop()
+            );
+            return Err(SendError::NoRoute);
+        };
+        if link.is_disabled() {
+            return Err(SendError::InterfaceDown);
+        }
+        if entry.is_unreachable() {
+            return Err(SendError::NoRoute);
+        }
+        Ok(link)
+    }
+
+    /// Finds the route to `dest`, deleting it if it has expired under lazy
+    /// pruning.
+    async fn find_route(&self, dest: Ipv4Addr) -> Option<Entry> {
+        let entry = *self.routes.read().await.find_entry_for(dest)?;
+        if self.prune_strategy == PruneStrategy::Eager || !entry.is_expired(self.entry_max_age) {
+            return Some(entry);
+        }
+
+        self.routes
+            .write()
+            .await
+            .prune_entry_for(dest, self.entry_max_age)
+    }
+}
+
 impl<DP: DropPolicy> Drop for VtLinkNet<DP> {
     fn drop(&mut self) {
-        self.pruner.abort();
-        self.rip_updater.abort();
+        if let Some(pruner) = &self.pruner {
+            pruner.abort();
+        }
+        if let Some(rip_updater) = &self.rip_updater {
+            rip_updater.abort();
+        }
     }
 }
 
@@ -314,41 +696,166 @@ async fn prune_routing_table(
     table: Arc<RwLock<ForwardingTable>>,
     prune_interval: Duration,
     max_age: Duration,
+    logger: NodeLogger,
 ) {
     loop_with_interval(prune_interval, || async {
-        log::debug!("Pruning table");
+        node_log!(logger, Level::Debug, "Pruning table");
         let mut table = table.write().await;
         table.prune(max_age);
     })
     .await;
 }
 
+/// Sends the whole table to every neighbor each `interval`. Under lazy pruning
+/// the table may still hold expired routes, which are left out so that
+/// neighbors do not keep them alive.
 async fn periodic_rip_update(
     table: Arc<RwLock<ForwardingTable>>,
     links: Arc<VtLinkLayer>,
     interval: Duration,
+    max_age: Duration,
+    logger: NodeLogger,
 ) {
     loop_with_interval(interval, || async {
         for link in &*links.iter_links().await {
-            log::debug!("Sending periodic update to {}", link.dest());
-            let rip_msg = RipMessage::from_entries_with_poisoned_reverse(
-                table.read().await.entries(),
-                link.dest(),
+            node_log!(
+                logger,
+                Level::Debug,
+                "Sending periodic update to {}",
+                link.dest()
             );
-            let rip_msg_bytes = rip_msg.into_bytes();
-            let packet = Ipv4PacketBuilder::default()
-                .with_payload(&rip_msg_bytes)
-                .with_protocol(Protocol::Rip)
-                .with_src(link.source())
-                .with_dst(link.dest())
-                .build()
-                .unwrap();
-            link.send(&packet).await.ok();
+            let live: Vec<_> = table
+                .read()
+                .await
+                .entries()
+                .iter()
+                .filter(|e| !e.is_expired(max_age))
+                .copied()
+                .collect();
+            let rip_msg = RipMessage::from_entries_with_poisoned_reverse(&live, link.dest());
+            send_rip_message(link, rip_msg, logger).await;
         }
     })
     .await;
 }
 
+async fn send_rip_message(link: &Link, rip_msg: RipMessage, logger: NodeLogger) {
+    let rip_msg_bytes = rip_msg.into_bytes();
+    let packet = Ipv4PacketBuilder::default()
+        .with_payload(&rip_msg_bytes)
+        .with_protocol(Protocol::Rip)
+        .with_src(link.source())
+        .with_dst(link.dest())
+        // RIP messages are for neighbors only, and are never forwarded.
+        .with_ttl(1)
+        .build()
+        .unwrap();
+    // Ignore the error case, which occurs when sending data out on a disabled link.
+    link.send(&packet, logger).await.ok();
+}
+
+/// Sends changed routes to every neighbor.
+async fn send_triggered_update(links: &VtLinkLayer, updates: &[Entry], logger: NodeLogger) {
+    for link in &*links.iter_links().await {
+        node_log!(
+            logger,
+            Level::Debug,
+            "Sending triggered update to {}",
+            link.dest()
+        );
+        let rip_msg = RipMessage::from_entries_with_poisoned_reverse(updates, link.dest());
+        send_rip_message(link, rip_msg, logger).await;
+    }
+}
+
+/// Sends the routes whose triggered updates were deferred, as they stand once
+/// the rate limit lets another update through.
+async fn flush_deferred_updates(
+    limiter: Arc<Mutex<TriggeredUpdateLimiter>>,
+    table: Arc<RwLock<ForwardingTable>>,
+    links: Arc<VtLinkLayer>,
+    mut delay: Duration,
+    logger: NodeLogger,
+) {
+    let destinations = loop {
+        tokio::time::sleep(delay).await;
+        let mut guard = limiter.lock().unwrap();
+        if guard.try_acquire() {
+            break guard.take_deferred();
+        }
+        delay = guard.until_interval_ends();
+    };
+
+    let updates: Vec<_> = {
+        let table = table.read().await;
+        destinations
+            .iter()
+            .filter_map(|&dest| table.find_entry_for(dest).copied())
+            .collect()
+    };
+    if !updates.is_empty() {
+        send_triggered_update(&links, &updates, logger).await;
+    }
+}
+
+/// Limits the rate of triggered RIP updates, so that a burst of route changes
+/// does not flood the network.
+struct TriggeredUpdateLimiter {
+    interval_start: Instant,
+    sent_in_interval: usize,
+    /// The destinations whose changes are waiting for the rate limit, in the
+    /// order they changed.
+    deferred: Vec<Ipv4Addr>,
+}
+
+impl Default for TriggeredUpdateLimiter {
+    fn default() -> Self {
+        Self {
+            interval_start: Instant::now(),
+            sent_in_interval: 0,
+            deferred: Vec::new(),
+        }
+    }
+}
+
+impl TriggeredUpdateLimiter {
+    /// Returns whether another triggered update may be sent, counting it if so.
+    fn try_acquire(&mut self) -> bool {
+        if self.interval_start.elapsed() >= RIP_TRIGGERED_UPDATE_INTERVAL {
+            self.interval_start = Instant::now();
+            self.sent_in_interval = 0;
+        }
+
+        if self.sent_in_interval < RIP_TRIGGERED_UPDATE_LIMIT {
+            self.sent_in_interval += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Holds back the changes of a suppressed update. Returns how long until
+    /// the deferred changes may go out, if no earlier deferral already waits
+    /// for them.
+    fn defer(&mut self, updates: &[Entry]) -> Option<Duration> {
+        let flush_pending = !self.deferred.is_empty();
+        for entry in updates {
+            if !self.deferred.contains(&entry.destination()) {
+                self.deferred.push(entry.destination());
+            }
+        }
+        (!flush_pending).then(|| self.until_interval_ends())
+    }
+
+    fn take_deferred(&mut self) -> Vec<Ipv4Addr> {
+        std::mem::take(&mut self.deferred)
+    }
+
+    fn until_interval_ends(&self) -> Duration {
+        RIP_TRIGGERED_UPDATE_INTERVAL.saturating_sub(self.interval_start.elapsed())
+    }
+}
+
 fn verify_header_checksum(header: &Ipv4HeaderSlice<'_>) -> bool {
     let owned_header = header.to_header();
     match owned_header.calc_header_checksum() {
@@ -365,6 +872,25 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn suppressed_triggered_updates_are_deferred() {
+        let mut limiter = TriggeredUpdateLimiter::default();
+        for _ in 0..RIP_TRIGGERED_UPDATE_LIMIT {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+
+        let (d1, d2) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let delay = limiter.defer(&[Entry::new(d1, d1, 1)]).unwrap();
+        assert!(delay <= RIP_TRIGGERED_UPDATE_INTERVAL);
+        // Later changes join the update already waiting.
+        assert_eq!(
+            limiter.defer(&[Entry::new(d2, d2, 1), Entry::new(d1, d1, 2)]),
+            None
+        );
+        assert_eq!(limiter.take_deferred(), vec![d1, d2]);
+    }
+
     #[tokio::test]
     async fn drop_packet_with_invalid_checksum() {
         let r = make_mock_router().await;
@@ -393,7 +919,7 @@ mod tests {
             .decide_packet(&Ipv4HeaderSlice::from_slice(&packet).unwrap())
             .await;
 
-        assert_eq!(decision, PacketDecision::Drop);
+        assert_eq!(decision, PacketDecision::Expire);
     }
 
     #[tokio::test]
@@ -433,6 +959,247 @@ mod tests {
         assert_eq!(decision, PacketDecision::Consume);
     }
 
+    #[tokio::test]
+    async fn send_via_bypasses_routing() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let c_ip = abc_net.c.get_my_interface_ips()[0];
+        let b = make_mock_router_with_args(abc_net.b).await;
+        let c_links = VtLinkLayer::new(&abc_net.c).await;
+        let mut c_listener = c_links.listen().await;
+
+        // B has not learned any route to C yet.
+        assert!(matches!(
+            b.send(&[1, 2, 3, 4], Protocol::Test, c_ip).await,
+            Err(SendError::NoRoute)
+        ));
+
+        // B's second link connects to C.
+        b.send_via(1, &[1, 2, 3, 4], Protocol::Test, c_ip)
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(1), c_listener.recv())
+            .await
+            .expect("C should receive the packet")
+            .unwrap();
+        let header = Ipv4HeaderSlice::from_slice(&received).unwrap();
+        assert_eq!(header.destination_addr(), c_ip);
+        assert_eq!(&received[header.slice().len()..], &[1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn lazy_prune_on_lookup() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let c_ip = abc_net.c.get_my_interface_ips()[0];
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let links = Arc::new(VtLinkLayer::new(&abc_net.a).await);
+        let max_age = Duration::from_millis(20);
+        let router = VtLinkNet::new(
+            links,
+            &abc_net.a,
+            VtLinkNetConfig {
+                prune_interval: Duration::from_millis(1),
+                prune_strategy: PruneStrategy::Lazy,
+                entry_max_age: max_age,
+                ..VtLinkNetConfig::default()
+            },
+        );
+        router
+            .get_forwarding_table_mut()
+            .await
+            .add_entry(Entry::new(c_ip, b_ip, 2));
+
+        // Many prune intervals pass, but nothing scans the table.
+        tokio::time::sleep(max_age * 3).await;
+        assert!(router.get_forwarding_table().await.has_entry_for(c_ip));
+
+        assert!(router.get_outbound_ip(c_ip).await.is_none());
+        assert!(!router.get_forwarding_table().await.has_entry_for(c_ip));
+    }
+
+    #[tokio::test]
+    async fn lazy_prune_leaves_expired_routes_out_of_periodic_updates() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let c_ip = abc_net.c.get_my_interface_ips()[0];
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let links = Arc::new(VtLinkLayer::new(&abc_net.a).await);
+        let b_links = VtLinkLayer::new(&abc_net.b).await;
+        let max_age = Duration::from_millis(20);
+        let router = VtLinkNet::new(
+            links,
+            &abc_net.a,
+            VtLinkNetConfig {
+                prune_strategy: PruneStrategy::Lazy,
+                rip_update_interval: Duration::from_millis(10),
+                entry_max_age: max_age,
+                ..VtLinkNetConfig::default()
+            },
+        );
+        router
+            .get_forwarding_table_mut()
+            .await
+            .add_entry(Entry::new(c_ip, b_ip, 2));
+
+        tokio::time::sleep(max_age * 3).await;
+        assert!(router.get_forwarding_table().await.has_entry_for(c_ip));
+
+        // Updates sent before the route expired may still be on their way, so
+        // a few are skipped.
+        let mut b_listener = b_links.listen().await;
+        let mut updates = Vec::new();
+        while updates.len() < 3 {
+            let bytes = tokio::time::timeout(Duration::from_secs(1), b_listener.recv())
+                .await
+                .expect("B should receive periodic updates")
+                .unwrap();
+            let header = Ipv4HeaderSlice::from_slice(&bytes).unwrap();
+            if header.protocol() == Protocol::Rip.into() {
+                updates.push(RipMessage::from_bytes(&bytes[header.slice().len()..]));
+            }
+        }
+        let live: Vec<_> = router
+            .get_forwarding_table()
+            .await
+            .entries()
+            .iter()
+            .filter(|e| e.destination() != c_ip)
+            .copied()
+            .collect();
+        assert_eq!(
+            updates.pop().unwrap(),
+            RipMessage::from_entries_with_poisoned_reverse(&live, b_ip)
+        );
+    }
+
+    #[tokio::test]
+    async fn path_mtu_of_egress_link() {
+        let mut abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let c_ip = abc_net.c.get_my_interface_ips()[0];
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        abc_net.a.links[0].mtu = 576;
+        let router = make_mock_router_with_args(abc_net.a).await;
+
+        assert_eq!(router.path_mtu(c_ip).await, None);
+
+        router
+            .get_forwarding_table_mut()
+            .await
+            .add_entry(Entry::new(c_ip, b_ip, 2));
+        assert_eq!(router.path_mtu(c_ip).await, Some(576));
+    }
+
+    #[tokio::test]
+    async fn forwarding_drops_packets_whose_ttl_expires() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let a_ip = abc_net.a.get_my_interface_ips()[0];
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let c_ip = abc_net.c.get_my_interface_ips()[0];
+        let a_links = VtLinkLayer::new(&abc_net.a).await;
+        let b = Arc::new(make_mock_router_with_args(abc_net.b).await);
+        let c_links = VtLinkLayer::new(&abc_net.c).await;
+        let mut c_listener = c_links.listen().await;
+
+        b.get_forwarding_table_mut()
+            .await
+            .add_entry(Entry::new(c_ip, c_ip, 1));
+        let b_runner = b.clone();
+        tokio::spawn(async move { b_runner.run(&HashMap::new()).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // A packet that B would forward with TTL 0 never reaches C; one with
+        // a hop to spare does, with its TTL decremented.
+        for (ttl, payload) in [(1, [1; 4]), (2, [2; 4])] {
+            let packet = Ipv4PacketBuilder::default()
+                .with_src(a_ip)
+                .with_dst(c_ip)
+                .with_ttl(ttl)
+                .with_protocol(Protocol::Test)
+                .with_payload(&payload)
+                .build()
+                .unwrap();
+            a_links
+                .send(&packet, b_ip, NodeLogger::default())
+                .await
+                .unwrap();
+        }
+
+        let received =
+            tokio::time::timeout(Duration::from_secs(1), recv_test_packet(&mut c_listener))
+                .await
+                .expect("C should receive the packet");
+        let header = Ipv4HeaderSlice::from_slice(&received).unwrap();
+        assert_eq!(header.ttl(), 1);
+        assert!(verify_header_checksum(&header));
+        assert_eq!(&received[header.slice().len()..], &[2; 4]);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), recv_test_packet(&mut c_listener))
+                .await
+                .is_err()
+        );
+        assert_eq!(b.ttl_expired(), 1);
+    }
+
+    #[tokio::test]
+    async fn large_packets_are_fragmented_and_reassembled() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let a = make_mock_router_with_args(abc_net.a).await;
+        let b_links = Arc::new(VtLinkLayer::new(&abc_net.b).await);
+        let mut b_datagrams = b_links.listen().await;
+        let b = VtLinkNet::new(b_links, &abc_net.b, VtLinkNetConfig::default());
+
+        let (delivered_tx, mut delivered) = tokio::sync::mpsc::unbounded_channel();
+        let mut handlers: HashMap<Protocol, Box<dyn ProtocolHandler<NeverDrop>>> = HashMap::new();
+        handlers.insert(Protocol::Test, Box::new(CapturingHandler(delivered_tx)));
+        tokio::spawn(async move { b.run(&handlers).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let payload: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        a.send_via(0, &payload, Protocol::Test, b_ip).await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(1), delivered.recv())
+            .await
+            .expect("B should reassemble the packet")
+            .unwrap();
+        assert_eq!(received, payload);
+
+        let mut fragments = 0;
+        while let Ok(datagram) = b_datagrams.try_recv() {
+            let header = Ipv4HeaderSlice::from_slice(&datagram).unwrap();
+            if header.protocol() == Protocol::Test.into() {
+                assert!(datagram.len() <= link::DEFAULT_LINK_MTU);
+                fragments += 1;
+            }
+        }
+        assert_eq!(fragments, 3);
+    }
+
+    /// Passes on the payloads of the packets it handles.
+    struct CapturingHandler(tokio::sync::mpsc::UnboundedSender<Vec<u8>>);
+
+    #[async_trait]
+    impl<DP: DropPolicy> ProtocolHandler<DP> for CapturingHandler {
+        async fn handle_packet<'a>(
+            &self,
+            _header: &Ipv4HeaderSlice<'a>,
+            payload: &[u8],
+            _net: &VtLinkNet<DP>,
+        ) {
+            self.0.send(payload.to_vec()).ok();
+        }
+    }
+
+    /// Receives the next packet of the test protocol, skipping RIP updates.
+    async fn recv_test_packet(listener: &mut broadcast::Receiver<Vec<u8>>) -> Vec<u8> {
+        loop {
+            let bytes = listener.recv().await.unwrap();
+            let protocol = Ipv4HeaderSlice::from_slice(&bytes).unwrap().protocol();
+            if protocol == Protocol::Test.into() {
+                return bytes;
+            }
+        }
+    }
+
     fn make_random_packet() -> Vec<u8> {
         let (header, mut payload) = make_random_packet_internal();
         let mut v = Vec::new();
@@ -1,24 +1,51 @@
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::cli::{RecvFileError, SendFileError};
-use crate::drop_policy::{self, DropPolicy};
-use crate::net::vtlink::{self, LinkIter, LinkRef, VtLinkLayer, VtLinkNet, VtLinkNetConfig};
+use crate::cli::{HttpGetError, RecvFileError, SendFileError};
+use crate::drop_policy::{self, DropPolicy, DropStats};
+use crate::net::vtlink::{
+    self, Entry, LinkIter, LinkRef, PruneStrategy, RouteChange, VtLinkLayer, VtLinkNet,
+    VtLinkNetConfig,
+};
 use crate::net::Net;
+use crate::protocol::tcp::congestion::CongestionAlgorithm;
 use crate::protocol::tcp::prelude::{Port, Remote, SocketDescriptor, SocketId};
 use crate::protocol::tcp::{
-    SocketRef, Tcp, TcpCloseError, TcpConn, TcpConnError, TcpHandler, TcpListenError, TcpListener,
-    TcpReadError, TcpSendError,
+    KeepaliveConfig, SocketRef, SocketStatus, Tcp, TcpCloseError, TcpConfig, TcpConn, TcpConnError,
+    TcpHandler, TcpListenError, TcpListener, TcpReadError, TcpSendError,
+    TCP_MAX_DELAYED_ACK_INTERVAL,
 };
 use crate::protocol::{Protocol, ProtocolHandler};
-use crate::{net, Args};
+use crate::{net, node_log, Args};
+use log::{Level, LevelFilter};
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::{broadcast, watch};
 
 pub struct NodeBuilder;
 
+/// Aggregate state of a node, for spotting resource pressure.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NodeMetrics {
+    /// Bytes written to connections that their remotes have not acked yet.
+    pub send_buffered_bytes: usize,
+    /// Bytes received on connections that have not been read yet.
+    pub recv_buffered_bytes: usize,
+    /// Packets dropped because their TTL ran out while being forwarded.
+    pub ttl_expired_packets: usize,
+    /// TCP segments dropped because they failed their checksum.
+    pub checksum_failures: usize,
+}
+
+impl NodeMetrics {
+    pub fn buffered_bytes(&self) -> usize {
+        self.send_buffered_bytes + self.recv_buffered_bytes
+    }
+}
+
 impl NodeBuilder {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(args: &Args) -> NodeBuilderStep<'_, drop_policy::NeverDrop> {
@@ -30,9 +57,15 @@ pub struct NodeBuilderStep<'a, DP: DropPolicy> {
     args: &'a Args,
     built: bool,
     prune_interval: Duration,
+    prune_strategy: PruneStrategy,
     rip_update_interval: Duration,
     drop_policy: DP,
     entry_max_age: Duration,
+    rip_enabled: bool,
+    connection_migration: bool,
+    log_level: Option<LevelFilter>,
+    delayed_bind: bool,
+    tcp_config: TcpConfig,
 }
 
 impl<'a, DP: DropPolicy> NodeBuilderStep<'a, DP> {
@@ -41,12 +74,48 @@ impl<'a, DP: DropPolicy> NodeBuilderStep<'a, DP> {
             args,
             built: false,
             prune_interval: Duration::from_secs(1),
+            prune_strategy: PruneStrategy::default(),
             rip_update_interval: Duration::from_secs(5),
             entry_max_age: Duration::from_secs(12),
             drop_policy,
+            rip_enabled: true,
+            connection_migration: false,
+            log_level: None,
+            delayed_bind: false,
+            tcp_config: TcpConfig::default(),
+        }
+    }
+
+    /// Disable RIP entirely, routing solely on directly-connected and static
+    /// routes.
+    ///
+    /// No RIP updates are sent, routes are never pruned, and any RIP protocol
+    /// handler is dropped.
+    pub fn without_rip(self) -> Self {
+        Self {
+            rip_enabled: false,
+            ..self
         }
     }
 
+    /// Experimental. Keep connections alive across interface changes by
+    /// letting them keep their source address when rerouted out of another
+    /// interface.
+    pub fn with_connection_migration(self) -> Self {
+        Self {
+            connection_migration: true,
+            ..self
+        }
+    }
+
+    /// Experimental. Enable TCP Fast Open, so that connections to a host
+    /// this node holds a cookie for carry their first data in the SYN, and
+    /// listeners accept such data before the handshake completes.
+    pub fn with_tfo(mut self, tfo: bool) -> Self {
+        self.tcp_config.fast_open = tfo;
+        self
+    }
+
     /// Set the interval of sending up periodic RIP updates.
     pub fn with_rip_interval(self, rip_interval: Duration) -> Self {
         Self {
@@ -71,6 +140,98 @@ impl<'a, DP: DropPolicy> NodeBuilderStep<'a, DP> {
         }
     }
 
+    /// Set when expired routing entries are deleted: periodically, or only
+    /// when they are looked up.
+    pub fn with_prune_strategy(self, prune_strategy: PruneStrategy) -> Self {
+        Self {
+            prune_strategy,
+            ..self
+        }
+    }
+
+    /// Set the TCP maximum segment lifetime. Closed connections linger in
+    /// TIME_WAIT for twice this long.
+    pub fn with_msl(mut self, msl: Duration) -> Self {
+        self.tcp_config.msl = msl;
+        self
+    }
+
+    /// Set the range of local ports handed out to outbound connections.
+    /// Connecting fails once every port in the range is in use.
+    pub fn with_ephemeral_ports(mut self, ephemeral_ports: RangeInclusive<u16>) -> Self {
+        self.tcp_config.ephemeral_ports = ephemeral_ports;
+        self
+    }
+
+    /// Override the log level of this node's TCP and RIP lines, independently
+    /// of the global `log` filter.
+    pub fn with_log_level(self, log_level: LevelFilter) -> Self {
+        Self {
+            log_level: Some(log_level),
+            ..self
+        }
+    }
+
+    /// Reset a TCP connection when a send makes no progress, i.e. the remote
+    /// acknowledges nothing, for longer than `timeout`.
+    pub fn with_send_stall_timeout(mut self, timeout: Duration) -> Self {
+        self.tcp_config.send_stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Let listeners bind to an interface IP whose link is down. Such a
+    /// listener accepts connections once the link is activated.
+    pub fn with_delayed_bind(self) -> Self {
+        Self {
+            delayed_bind: true,
+            ..self
+        }
+    }
+
+    /// Let TCP connections delay acking received data by up to `interval`,
+    /// capped at [`TCP_MAX_DELAYED_ACK_INTERVAL`]. A zero interval acks every
+    /// segment as it arrives.
+    pub fn with_delayed_ack_interval(mut self, interval: Duration) -> Self {
+        self.tcp_config.delayed_ack_interval =
+            (!interval.is_zero()).then(|| interval.min(TCP_MAX_DELAYED_ACK_INTERVAL));
+        self
+    }
+
+    /// Choose how TCP connections control congestion. By default they send
+    /// as much as the remote's window allows.
+    pub fn with_congestion_control(mut self, congestion_control: CongestionAlgorithm) -> Self {
+        self.tcp_config.congestion_control = congestion_control;
+        self
+    }
+
+    /// Let TCP connections space their segments out evenly over a round trip,
+    /// instead of sending as many as the window allows back to back.
+    pub fn with_pacing(mut self, pacing: bool) -> Self {
+        self.tcp_config.pacing = pacing;
+        self
+    }
+
+    /// Keep at most `max` distinct ranges of out-of-order data per TCP
+    /// connection. A segment that would start another is dropped, to be
+    /// retransmitted once the gaps before it are filled.
+    pub fn with_max_out_of_order_ranges(mut self, max: usize) -> Self {
+        self.tcp_config.max_out_of_order_ranges = Some(max);
+        self
+    }
+
+    /// Probe TCP connections that sit idle, aborting those whose peer stops
+    /// answering.
+    pub fn with_keepalive(mut self, keepalive: KeepaliveConfig) -> Self {
+        self.tcp_config.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Replace every TCP setting at once. The methods above each adjust one
+    /// of them.
+    pub fn with_tcp_config(self, tcp_config: TcpConfig) -> Self {
+        Self { tcp_config, ..self }
+    }
+
     /// Configure a policy specifying how packets are dropped.
     pub fn with_drop_policy<NewDP: DropPolicy>(
         &mut self,
@@ -80,9 +241,15 @@ impl<'a, DP: DropPolicy> NodeBuilderStep<'a, DP> {
             args: self.args,
             built: self.built,
             prune_interval: self.prune_interval,
+            prune_strategy: self.prune_strategy,
             rip_update_interval: self.rip_update_interval,
             entry_max_age: self.entry_max_age,
             drop_policy,
+            rip_enabled: self.rip_enabled,
+            connection_migration: self.connection_migration,
+            log_level: self.log_level,
+            delayed_bind: self.delayed_bind,
+            tcp_config: self.tcp_config.clone(),
         }
     }
 
@@ -108,9 +275,15 @@ impl<'a, DP: DropPolicy> NodeBuilderStep<'a, DP> {
             args: self.args,
             built: self.built,
             prune_interval: self.prune_interval,
+            prune_strategy: self.prune_strategy,
             rip_update_interval: self.rip_update_interval,
             drop_policy: Some(self.drop_policy),
             entry_max_age: self.entry_max_age,
+            rip_enabled: self.rip_enabled,
+            connection_migration: self.connection_migration,
+            log_level: self.log_level,
+            delayed_bind: self.delayed_bind,
+            tcp_config: self.tcp_config,
             protocol_handlers: HashMap::default(),
         }
     }
@@ -120,9 +293,15 @@ pub struct NodeBuilderHandlerStep<'a, DP: DropPolicy> {
     args: &'a Args,
     built: bool,
     prune_interval: Duration,
+    prune_strategy: PruneStrategy,
     rip_update_interval: Duration,
     drop_policy: Option<DP>,
     entry_max_age: Duration,
+    rip_enabled: bool,
+    connection_migration: bool,
+    log_level: Option<LevelFilter>,
+    delayed_bind: bool,
+    tcp_config: TcpConfig,
     protocol_handlers: HashMap<Protocol, Box<dyn ProtocolHandler<DP>>>,
 }
 
@@ -151,13 +330,25 @@ impl<'a, DP: DropPolicy> NodeBuilderHandlerStep<'a, DP> {
             self.args,
             VtLinkNetConfig {
                 prune_interval: self.prune_interval,
+                prune_strategy: self.prune_strategy,
                 rip_update_interval: self.rip_update_interval,
                 entry_max_age: self.entry_max_age,
                 drop_policy: self.drop_policy.take().unwrap(),
+                rip_enabled: self.rip_enabled,
+                connection_migration: self.connection_migration,
+                log_level: self.log_level,
             },
         ));
 
-        let tcp = Arc::new(Tcp::new(net.clone()));
+        if !self.rip_enabled && self.protocol_handlers.remove(&Protocol::Rip).is_some() {
+            node_log!(
+                net.logger(),
+                Level::Info,
+                "RIP is disabled, ignoring the RIP protocol handler"
+            );
+        }
+
+        let tcp = Tcp::new(net.clone(), self.tcp_config);
         self.protocol_handlers
             .insert(Protocol::Tcp, Box::new(TcpHandler::new(tcp.clone())));
 
@@ -170,6 +361,8 @@ impl<'a, DP: DropPolicy> NodeBuilderHandlerStep<'a, DP> {
             tcp,
             net,
             protocol_handlers,
+            delayed_bind: self.delayed_bind,
+            stopped: watch::channel(false).0,
         }
     }
 }
@@ -178,6 +371,9 @@ pub struct Node<DP: DropPolicy> {
     tcp: Arc<Tcp<VtLinkNet<DP>>>,
     net: Arc<VtLinkNet<DP>>,
     protocol_handlers: HashMap<Protocol, Box<dyn ProtocolHandler<DP>>>,
+    delayed_bind: bool,
+    /// Set once the node is shut down, which stops `run`.
+    stopped: watch::Sender<bool>,
 }
 
 impl<DP: DropPolicy> Node<DP> {
@@ -196,13 +392,40 @@ impl<DP: DropPolicy> Node<DP> {
 
     /// Turns off a link interface.
     pub async fn deactivate(&self, link_no: u16) -> Result<(), vtlink::Error> {
-        self.net.links().deactivate_link(link_no).await
+        self.net.deactivate_link(link_no).await
+    }
+
+    /// Delays packets sent out of a link by `forward`, and packets arriving
+    /// over it by `reverse`, simulating a path slower one way than the other.
+    pub async fn set_link_latency(
+        &self,
+        link_no: u16,
+        forward: Duration,
+        reverse: Duration,
+    ) -> Result<(), vtlink::Error> {
+        self.net
+            .links()
+            .set_link_latency(link_no, forward, reverse)
+            .await
     }
 
     pub async fn is_my_addr(&self, addr: Ipv4Addr) -> bool {
         self.net.is_my_addr(addr)
     }
 
+    /// Add a manually configured route, which is never pruned.
+    pub async fn add_static_route(&self, destination: Ipv4Addr, next_hop: Ipv4Addr, cost: u32) {
+        self.net
+            .get_forwarding_table_mut()
+            .await
+            .add_entry(Entry::new_static(destination, next_hop, cost));
+    }
+
+    /// Subscribe to additions, updates and removals of routing entries.
+    pub async fn subscribe_routes(&self) -> broadcast::Receiver<RouteChange> {
+        self.net.subscribe_routes().await
+    }
+
     /// Iterate all links (both active and inactive) for this host.
     ///
     /// This is useful for sending out periodic RIP messages to all links.
@@ -210,10 +433,30 @@ impl<DP: DropPolicy> Node<DP> {
         self.net.links().iter_links().await
     }
 
+    /// Lists this node's interfaces as (link id, interface IP, is active).
+    pub async fn interface_ips(&self) -> Vec<(u16, Ipv4Addr, bool)> {
+        self.iter_links()
+            .await
+            .iter()
+            .enumerate()
+            .map(|(id, link)| (id.try_into().unwrap(), link.source(), !link.is_disabled()))
+            .collect()
+    }
+
     pub async fn close_socket(&self, socket_id: SocketId) -> Result<(), TcpCloseError> {
         self.tcp.close(socket_id).await
     }
 
+    /// Closes a socket and waits for it to be removed, after its TIME_WAIT
+    /// period if any.
+    pub async fn close_socket_and_wait(
+        &self,
+        socket_id: SocketId,
+        timeout: Duration,
+    ) -> Result<(), TcpCloseError> {
+        self.tcp.close_and_wait(socket_id, timeout).await
+    }
+
     pub async fn close_socket_by_descriptor(
         &self,
         socket_descriptor: SocketDescriptor,
@@ -255,6 +498,18 @@ impl<DP: DropPolicy> Node<DP> {
             .await
     }
 
+    /// Read the bytes already received over a TCP connection, up to
+    /// `n_bytes`, without waiting for more.
+    pub async fn tcp_try_read(
+        &self,
+        socket_descriptor: SocketDescriptor,
+        n_bytes: usize,
+    ) -> Result<Vec<u8>, TcpReadError> {
+        self.tcp
+            .try_read_on_socket_descriptor(socket_descriptor, n_bytes)
+            .await
+    }
+
     pub async fn get_socket(&self, socket_id: SocketId) -> Option<SocketRef<'_, VtLinkNet<DP>>> {
         self.tcp.get_socket(socket_id).await
     }
@@ -270,8 +525,55 @@ impl<DP: DropPolicy> Node<DP> {
         self.tcp.get_socket_descriptor(socket_id).await
     }
 
+    pub async fn socket_id_for_descriptor(
+        &self,
+        socket_descriptor: SocketDescriptor,
+    ) -> Option<SocketId> {
+        self.tcp.socket_id_for_descriptor(socket_descriptor).await
+    }
+
+    pub async fn list_sockets(&self) -> Vec<(SocketDescriptor, SocketId, SocketStatus)> {
+        self.tcp.list_sockets().await
+    }
+
+    pub async fn connections_to(&self, ip: Ipv4Addr) -> Vec<SocketId> {
+        self.tcp.connections_to(ip).await
+    }
+
+    /// Handles incoming packets until the node is shut down.
     pub async fn run(&self) {
-        self.net.run(&self.protocol_handlers).await;
+        let mut stopped = self.stopped.subscribe();
+        tokio::select! {
+            _ = self.net.run(&self.protocol_handlers) => {}
+            _ = stopped.wait_for(|stopped| *stopped) => {}
+        }
+    }
+
+    /// Closes every connection, waiting up to `timeout` for the remotes to
+    /// ack the FINs, then stops `run`. Listening sockets are closed first,
+    /// so no new connections are accepted meanwhile.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.tcp.shutdown(timeout).await;
+        self.stopped.send_replace(true);
+    }
+
+    pub async fn metrics(&self) -> NodeMetrics {
+        let (send_buffered_bytes, recv_buffered_bytes) = self.tcp.buffered_bytes().await;
+        NodeMetrics {
+            send_buffered_bytes,
+            recv_buffered_bytes,
+            ttl_expired_packets: self.net.ttl_expired(),
+            checksum_failures: self.tcp.checksum_failures(),
+        }
+    }
+
+    /// Packets seen and dropped by the drop policy, if it counts them.
+    pub fn drop_stats(&self) -> Option<DropStats> {
+        self.net.drop_policy().stats()
+    }
+
+    pub fn reset_drop_stats(&self) {
+        self.net.drop_policy().reset_stats();
     }
 
     pub async fn connect(
@@ -282,10 +584,80 @@ impl<DP: DropPolicy> Node<DP> {
         self.tcp.connect(Remote::new(dest_ip, dest_port)).await
     }
 
+    /// Connects to a remote from `local_port`, rather than an ephemeral port.
+    pub async fn connect_from(
+        &self,
+        local_port: Port,
+        dest_ip: Ipv4Addr,
+        dest_port: Port,
+    ) -> Result<TcpConn, TcpConnError> {
+        self.tcp
+            .connect_from(local_port, Remote::new(dest_ip, dest_port))
+            .await
+    }
+
+    /// Connects to a remote, failing with `TcpConnError::Timeout` if the
+    /// connection is not established within `timeout`.
+    pub async fn connect_with_timeout(
+        &self,
+        dest_ip: Ipv4Addr,
+        dest_port: Port,
+        timeout: Duration,
+    ) -> Result<TcpConn, TcpConnError> {
+        self.tcp
+            .connect_with_timeout(Remote::new(dest_ip, dest_port), timeout)
+            .await
+    }
+
+    /// Connects to a remote, signing and verifying the connection's segments
+    /// with `key`, per RFC 2385.
+    pub async fn connect_with_md5_key(
+        &self,
+        dest_ip: Ipv4Addr,
+        dest_port: Port,
+        key: Vec<u8>,
+    ) -> Result<TcpConn, TcpConnError> {
+        self.tcp
+            .connect_with_md5_key(Remote::new(dest_ip, dest_port), key)
+            .await
+    }
+
+    /// Connects to a remote, sending `data` as the first bytes of the
+    /// connection, in the SYN if TCP Fast Open allows.
+    pub async fn connect_with_data(
+        &self,
+        dest_ip: Ipv4Addr,
+        dest_port: Port,
+        data: &[u8],
+    ) -> Result<TcpConn, TcpConnError> {
+        self.tcp
+            .connect_with_data(Remote::new(dest_ip, dest_port), data)
+            .await
+    }
+
     pub async fn listen(&self, port: Port) -> Result<TcpListener, TcpListenError> {
         self.tcp.listen(port).await
     }
 
+    /// Listens on `port`, accepting only connections addressed to interface
+    /// IP `ip`.
+    ///
+    /// Errs if `ip`'s link is down, unless the node was built with delayed
+    /// bind.
+    pub async fn listen_on(&self, ip: Ipv4Addr, port: Port) -> Result<TcpListener, TcpListenError> {
+        if !ip.is_unspecified() {
+            match self.find_link_with_interface_ip(ip).await {
+                Some(link) => {
+                    if link.is_disabled() && !self.delayed_bind {
+                        return Err(TcpListenError::InterfaceDown(ip));
+                    }
+                }
+                None => return Err(TcpListenError::AddrNotAvailable(ip)),
+            }
+        }
+        self.tcp.listen_on(ip, port).await
+    }
+
     pub async fn send_file(&self, path: &str, remote: Remote) -> Result<(), SendFileError> {
         let mut f = File::open(path).await.map_err(SendFileError::OpenFile)?;
 
@@ -294,7 +666,12 @@ impl<DP: DropPolicy> Node<DP> {
             .await
             .map_err(SendFileError::ReadFile)?;
 
-        self.connect_and_send_bytes(remote, &input).await
+        let (_, conn) = self.connect_and_send_bytes(remote, &input).await?;
+        self.close_socket(conn.socket_id())
+            .await
+            .expect("Socket should be open");
+
+        Ok(())
     }
 
     pub async fn recv_file(&self, out_path: &str, port: Port) -> Result<(), RecvFileError> {
@@ -306,32 +683,584 @@ impl<DP: DropPolicy> Node<DP> {
         Ok(())
     }
 
+    /// Connects to `remote` and sends `bytes`, returning how many bytes the
+    /// remote acked along with the connection, which is left open so more can
+    /// be sent on it.
     pub async fn connect_and_send_bytes(
         &self,
         remote: Remote,
         bytes: &[u8],
-    ) -> Result<(), SendFileError> {
+    ) -> Result<(usize, TcpConn), SendFileError> {
         let conn = self
             .connect(remote.ip(), remote.port())
             .await
             .map_err(SendFileError::Connect)?;
 
-        conn.send_all(bytes).await.map_err(SendFileError::Send)?;
+        let acked_before = conn.stats().bytes_acked;
+        match conn.send_all(bytes).await {
+            Ok(()) => Ok((bytes.len(), conn)),
+            Err(error) => {
+                let acked = conn.stats().bytes_acked - acked_before;
+                Err(SendFileError::Send {
+                    error,
+                    acked: acked.min(bytes.len()),
+                })
+            }
+        }
+    }
 
-        self.close_socket(conn.socket_id())
+    pub async fn listen_and_recv_bytes(&self, port: Port) -> Result<Vec<u8>, RecvFileError> {
+        let mut listener = self.tcp.listen(port).await.map_err(RecvFileError::Listen)?;
+        let socket = listener.accept().await.map_err(RecvFileError::Accept)?;
+        socket.read_to_end().await.map_err(RecvFileError::Read)
+    }
+
+    /// Fetches `path` from an HTTP/1.0 server, returning the raw response,
+    /// headers included.
+    pub async fn http_get(&self, remote: Remote, path: &str) -> Result<Vec<u8>, HttpGetError> {
+        let conn = self
+            .connect(remote.ip(), remote.port())
             .await
-            .expect("Socket should be open");
+            .map_err(HttpGetError::Connect)?;
+
+        let request = format!("GET {path} HTTP/1.0\r\n\r\n");
+        conn.send_all(request.as_bytes())
+            .await
+            .map_err(HttpGetError::Send)?;
+        let response = conn.read_to_end().await.map_err(HttpGetError::Read)?;
+
+        // If the server reset the connection, its socket is already gone.
+        self.close_socket(conn.socket_id()).await.ok();
+
+        Ok(response)
+    }
+
+    /// Echoes every byte received on `conn` back to the remote, until the
+    /// remote closes. Then closes `conn`.
+    pub async fn echo(&self, conn: TcpConn) -> Result<(), TcpSendError> {
+        let mut buf = [0; 1024];
+        while let Ok(n) = conn.read_some(&mut buf).await {
+            conn.send_all(&buf[..n]).await?;
+        }
+
+        // If the remote reset the connection, its socket is already gone.
+        self.close_socket(conn.socket_id()).await.ok();
 
         Ok(())
     }
 
-    pub async fn listen_and_recv_bytes(&self, port: Port) -> Result<Vec<u8>, RecvFileError> {
-        let mut listener = self.tcp.listen(port).await.map_err(RecvFileError::Listen)?;
-        let socket = listener.accept().await.map_err(RecvFileError::Accept)?;
-        Ok(socket.read_till_closed().await)
+    pub async fn print_sockets(&self, file: Option<String>, verbose: bool) {
+        self.tcp.print_sockets(file, verbose).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex as StdMutex, Once};
+
+    use async_trait::async_trait;
+    use etherparse::Ipv4HeaderSlice;
+    use log::{Level, Log, Metadata, Record};
+
+    use crate::drop_policy::NeverDrop;
+    use crate::fixture::node::start_node_with;
+    use crate::protocol::icmp::{IcmpHandler, IcmpKind};
+    use crate::protocol::rip::RipHandler;
+    use crate::protocol::tcp::{LingerConfig, SocketStatus, TcpAcceptError};
+
+    /// Counts the RIP packets a node receives, without dropping any packet.
+    struct RipCounter(Arc<AtomicUsize>);
+
+    impl DropPolicy for RipCounter {
+        fn should_drop(&self, ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+            let rip: u8 = Protocol::Rip.into();
+            if ip_header.protocol() == rip {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+            false
+        }
+    }
+
+    struct CountingHandler(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl<DP: DropPolicy> ProtocolHandler<DP> for CountingHandler {
+        async fn handle_packet<'a>(
+            &self,
+            _header: &Ipv4HeaderSlice<'a>,
+            _payload: &[u8],
+            _net: &VtLinkNet<DP>,
+        ) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records every line that reaches the global logger.
+    struct CaptureLogger(StdMutex<Vec<(Level, String)>>);
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURED_LOGS: CaptureLogger = CaptureLogger(StdMutex::new(Vec::new()));
+
+    fn capture_logs() -> &'static CaptureLogger {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| log::set_logger(&CAPTURED_LOGS).unwrap());
+        &CAPTURED_LOGS
+    }
+
+    #[tokio::test]
+    async fn forward_on_static_routes_without_rip() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let rip_received = Arc::new(AtomicUsize::new(0));
+        let delivered = Arc::new(AtomicUsize::new(0));
+
+        let mut nodes = Vec::new();
+        for args in [&abc_net.a, &abc_net.b, &abc_net.c] {
+            let node = Arc::new(
+                NodeBuilder::new(args)
+                    .without_rip()
+                    .with_drop_policy(RipCounter(rip_received.clone()))
+                    .with_protocol_handler(Protocol::Test, CountingHandler(delivered.clone()))
+                    .build()
+                    .await,
+            );
+            let node_runner = node.clone();
+            tokio::spawn(async move {
+                node_runner.run().await;
+            });
+            nodes.push(node);
+        }
+
+        // A reaches C through B.
+        let (a_to_b, c_ip) = (abc_net.a.links[0].dest_ip, abc_net.c.links[0].interface_ip);
+        nodes[0].add_static_route(c_ip, a_to_b, 2).await;
+        nodes[0].send(b"hello", Protocol::Test, c_ip).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(delivered.load(Ordering::Relaxed), 1);
+        assert_eq!(rip_received.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn link_latency_delays_each_direction() {
+        let (forward, reverse) = (Duration::from_millis(60), Duration::from_millis(20));
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let c_ip = abc_net.c.get_my_interface_ips()[0];
+        let a = start_node(&abc_net.a).await;
+        let b = start_node(&abc_net.b).await;
+        let c = start_node(&abc_net.c).await;
+
+        // B's second link leads to C, so the SYN is slowed on its way there
+        // and the SYN-ACK on its way back.
+        b.set_link_latency(1, forward, reverse).await.unwrap();
+
+        let port = Port(6100);
+        let mut listener = c.listen(port).await.unwrap();
+        tokio::spawn(async move { listener.accept().await });
+        let started = tokio::time::Instant::now();
+        a.connect(c_ip, port).await.unwrap();
+        let handshake = started.elapsed();
+
+        let path_rtt = forward + reverse;
+        assert!(
+            handshake >= path_rtt && handshake < path_rtt + path_rtt / 4,
+            "Handshake took {handshake:?} over a {forward:?} + {reverse:?} path"
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_destination_reported_over_icmp() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let icmp = IcmpHandler::default();
+        let mut icmp_errors = icmp.subscribe();
+
+        let a = Arc::new(
+            NodeBuilder::new(&abc_net.a)
+                .without_rip()
+                .with_protocol_handler(Protocol::Icmp, icmp)
+                .build()
+                .await,
+        );
+        let b = Arc::new(NodeBuilder::new(&abc_net.b).without_rip().build().await);
+        for node in [a.clone(), b.clone()] {
+            tokio::spawn(async move { node.run().await });
+        }
+
+        // A routes an unknown address through B, which has no route to it.
+        let unknown_ip = Ipv4Addr::new(10, 10, 10, 10);
+        let (a_ip, a_to_b) = (abc_net.a.links[0].interface_ip, abc_net.a.links[0].dest_ip);
+        a.add_static_route(unknown_ip, a_to_b, 2).await;
+        a.send(b"hello", Protocol::Test, unknown_ip).await.unwrap();
+
+        let (reporter, error) = tokio::time::timeout(Duration::from_secs(1), icmp_errors.recv())
+            .await
+            .expect("A should be told the destination is unreachable")
+            .unwrap();
+        assert_eq!(reporter, a_to_b);
+        assert_eq!(error.kind(), IcmpKind::DestinationUnreachable);
+        let quoted = error.quoted_header().unwrap();
+        assert_eq!(quoted.source_addr(), a_ip);
+        assert_eq!(quoted.destination_addr(), unknown_ip);
+    }
+
+    #[tokio::test]
+    async fn drop_stats_match_drop_factor() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let a = Arc::new(NodeBuilder::new(&abc_net.a).without_rip().build().await);
+        let b = Arc::new(
+            NodeBuilder::new(&abc_net.b)
+                .without_rip()
+                .with_drop_policy(drop_policy::DropFactor::new(0.05))
+                .build()
+                .await,
+        );
+        let (a_runner, b_runner) = (a.clone(), b.clone());
+        tokio::spawn(async move { a_runner.run().await });
+        tokio::spawn(async move { b_runner.run().await });
+
+        let b_ip = abc_net.a.links[0].dest_ip;
+        for _ in 0..1000 {
+            a.send(b"hello", Protocol::Test, b_ip).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let stats = b.drop_stats().unwrap();
+        assert!(stats.seen > 900, "{stats:?}");
+        assert!((stats.drop_ratio() - 0.05).abs() < 0.005, "{stats:?}");
+
+        b.reset_drop_stats();
+        assert_eq!(b.drop_stats(), Some(DropStats::default()));
+        assert_eq!(a.drop_stats(), None);
+    }
+
+    #[tokio::test]
+    async fn route_removed_on_link_down() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let b_ips = abc_net.b.get_my_interface_ips();
+
+        let a = start_node(&abc_net.a).await;
+        let _b = start_node(&abc_net.b).await;
+
+        let mut routes = a.subscribe_routes().await;
+        a.deactivate(0).await.unwrap();
+
+        let removed = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match routes.recv().await {
+                    Ok(RouteChange::Removed(entry)) => break entry,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => panic!("Route table dropped"),
+                }
+            }
+        })
+        .await
+        .expect("Route removal should be published");
+
+        assert!(b_ips.contains(&removed.destination()));
+    }
+
+    #[tokio::test]
+    async fn send_reports_why_it_failed() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+
+        let a = start_node(&abc_net.a).await;
+        let _b = start_node(&abc_net.b).await;
+
+        assert!(matches!(
+            a.send(b"hi", Protocol::Test, Ipv4Addr::new(203, 0, 113, 1))
+                .await,
+            Err(net::SendError::NoRoute)
+        ));
+
+        // B is only reachable over A's first link.
+        a.deactivate(0).await.unwrap();
+        assert!(matches!(
+            a.send(b"hi", Protocol::Test, b_ip).await,
+            Err(net::SendError::InterfaceDown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_connections_cleanly() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let a = Arc::new(
+            NodeBuilder::new(&abc_net.a)
+                .with_rip_interval(Duration::from_millis(10))
+                .with_protocol_handler(Protocol::Rip, RipHandler::default())
+                .build()
+                .await,
+        );
+        let a_runner = a.clone();
+        let a_run = tokio::spawn(async move { a_runner.run().await });
+        let b = start_node(&abc_net.b).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let mut a_listener = a.listen(Port(5656)).await.unwrap();
+        let mut b_listener = b.listen(Port(5657)).await.unwrap();
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = a.connect(b_ip, Port(5657)).await.unwrap();
+        let remote_conn = b_listener.accept().await.unwrap();
+
+        a.shutdown(Duration::from_secs(1)).await;
+        tokio::time::timeout(Duration::from_secs(1), a_run)
+            .await
+            .expect("run should stop once the node is shut down")
+            .unwrap();
+
+        // A's FIN went out and was acked, and B sees the end of the stream.
+        assert!(matches!(
+            conn.state(),
+            SocketStatus::FinWait2 | SocketStatus::TimeWait
+        ));
+        let mut buf = [0; 1];
+        assert!(matches!(
+            remote_conn.read_all(&mut buf).await,
+            Err(TcpReadError::Closed(0))
+        ));
+        // No more connections are accepted.
+        assert!(matches!(
+            a_listener.accept().await,
+            Err(TcpAcceptError::ListenSocketClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn isolated_node_routes_expire_without_counting_to_infinity() {
+        let net = crate::fixture::netlinks::triangle::gen_unique();
+        let a_ips = net.a.get_my_interface_ips();
+
+        let a = start_node(&net.a).await;
+        let b = start_node(&net.b).await;
+        let c = start_node(&net.c).await;
+
+        let mut b_routes = b.subscribe_routes().await;
+        let mut c_routes = c.subscribe_routes().await;
+        a.deactivate(0).await.unwrap();
+        a.deactivate(1).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // B and C may briefly route to A through each other, but poisoned
+        // reverse stops them from bouncing ever costlier routes back and forth.
+        for routes in [&mut b_routes, &mut c_routes] {
+            loop {
+                let entry = match routes.try_recv() {
+                    Ok(RouteChange::Added(entry) | RouteChange::Updated(entry)) => entry,
+                    Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                };
+                if a_ips.contains(&entry.destination()) && !entry.is_unreachable() {
+                    assert!(entry.cost() <= 2, "Counted to {}", entry.cost());
+                }
+            }
+        }
+        for node in [&b, &c] {
+            let table = node.net.get_forwarding_table().await;
+            for ip in &a_ips {
+                assert!(table.find_entry_for(*ip).is_none_or(|e| e.is_unreachable()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fail_over_before_periodic_update() {
+        let net = crate::fixture::netlinks::triangle::gen_unique();
+        let (a_to_b, a_to_c) = (net.a.links[0], net.a.links[1]);
+        // C's interface facing B, which A reaches directly until its link to C
+        // goes down.
+        let c_ip = net.c.links[0].interface_ip;
+
+        let rip_interval = Duration::from_secs(5);
+        let mut nodes = Vec::new();
+        for args in [&net.a, &net.b, &net.c] {
+            nodes.push(
+                start_node_with(args, NeverDrop, |b| {
+                    b.with_rip_interval(rip_interval)
+                        .with_entry_max_age(Duration::from_secs(12))
+                        .with_prune_interval(Duration::from_secs(1))
+                })
+                .await,
+            );
+        }
+        let a = &nodes[0];
+
+        // Converging relies on triggered updates, too.
+        let route_to_c = |next_hop: Ipv4Addr, cost: u32| async move {
+            loop {
+                let table = a.net.get_forwarding_table().await;
+                if let Some(entry) = table.find_entry_for(c_ip) {
+                    if entry.next_hop() == next_hop && entry.cost() == cost {
+                        break;
+                    }
+                }
+                drop(table);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), route_to_c(a_to_c.dest_ip, 1))
+            .await
+            .expect("A should route to C directly");
+
+        a.deactivate(1).await.unwrap();
+        tokio::time::timeout(rip_interval / 10, route_to_c(a_to_b.dest_ip, 2))
+            .await
+            .expect("A should fail over to the route through B");
+    }
+
+    #[tokio::test]
+    async fn interface_ips_reflect_link_state() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let b_ips = abc_net.b.get_my_interface_ips();
+        let b = start_node(&abc_net.b).await;
+
+        let expected: Vec<_> = (0..)
+            .zip(b_ips.iter())
+            .map(|(id, ip)| (id, *ip, true))
+            .collect();
+        assert_eq!(b.interface_ips().await, expected);
+
+        b.deactivate(1).await.unwrap();
+        assert_eq!(b.interface_ips().await[1], (1, b_ips[1], false));
+    }
+
+    #[tokio::test]
+    async fn delayed_bind_accepts_once_interface_is_up() {
+        let port = Port(7003);
+
+        // Without delayed bind, a listener needs its interface up.
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let b = start_node(&abc_net.b).await;
+        b.deactivate(0).await.unwrap();
+        assert!(matches!(
+            b.listen_on(b_ip, port).await,
+            Err(TcpListenError::InterfaceDown(_))
+        ));
+
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let a = start_node(&abc_net.a).await;
+        let b = start_node_with(&abc_net.b, NeverDrop, |b| slow_rip(b).with_delayed_bind()).await;
+        b.deactivate(0).await.unwrap();
+        let mut listener = b.listen_on(b_ip, port).await.unwrap();
+
+        b.activate(0).await.unwrap();
+        // Give nodes time to converge on routes
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let conn = a.connect(b_ip, port).await.unwrap();
+        let accepted = listener.accept().await.unwrap();
+        assert_eq!(accepted.remote().port(), conn.local_port());
+    }
+
+    #[tokio::test]
+    async fn metrics_count_buffered_bytes() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let a = start_node(&abc_net.a).await;
+        let b = start_node(&abc_net.b).await;
+
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let ports = [Port(7004), Port(7005)];
+        let payload_sz = 10_000;
+        let mut remote_conns = Vec::new();
+        for port in ports {
+            let mut listener = b.listen(port).await.unwrap();
+            let conn = a.connect(b_ip, port).await.unwrap();
+            remote_conns.push(listener.accept().await.unwrap());
+            tokio::spawn(async move { conn.send_all(&vec![1; payload_sz]).await });
+        }
+
+        // Nothing is read yet, so the data sits in B's receive buffers.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(a.metrics().await.buffered_bytes() + b.metrics().await.buffered_bytes() > 0);
+        assert!(b.metrics().await.recv_buffered_bytes > 0);
+
+        for conn in &remote_conns {
+            let mut out = vec![0; payload_sz];
+            conn.read_all(&mut out).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(a.metrics().await, NodeMetrics::default());
+        assert_eq!(b.metrics().await, NodeMetrics::default());
+    }
+
+    #[tokio::test]
+    async fn echo_ends_when_client_resets() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let a = start_node(&abc_net.a).await;
+        let b = start_node(&abc_net.b).await;
+
+        let port = Port(7006);
+        let mut listener = b.listen(port).await.unwrap();
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = a.connect(b_ip, port).await.unwrap();
+        let remote_conn = listener.accept().await.unwrap();
+        let echo = tokio::spawn(async move { b.echo(remote_conn).await });
+
+        conn.set_linger(LingerConfig::Abort);
+        a.close_socket(conn.socket_id()).await.unwrap();
+        let echoed = tokio::time::timeout(Duration::from_secs(1), echo)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(echoed, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn log_level_override() {
+        let logs = capture_logs();
+
+        for (level, port, debug_expected) in [
+            (LevelFilter::Debug, Port(7001), true),
+            (LevelFilter::Info, Port(7002), false),
+        ] {
+            let abc_net = crate::fixture::netlinks::abc::gen_unique();
+            let a =
+                start_node_with(&abc_net.a, NeverDrop, |b| slow_rip(b).with_log_level(level)).await;
+            let b =
+                start_node_with(&abc_net.b, NeverDrop, |b| slow_rip(b).with_log_level(level)).await;
+
+            let b_ip = abc_net.b.get_my_interface_ips()[0];
+            let _listener = b.listen(port).await.unwrap();
+            a.connect(b_ip, port).await.unwrap();
+
+            // B logs every TCP segment it receives on the listening port.
+            let segment_line = format!("{}:{}", b_ip, port.0);
+            let debug_logged = logs
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|(lvl, line)| *lvl == Level::Debug && line.ends_with(&segment_line));
+            assert_eq!(debug_logged, debug_expected);
+        }
+    }
+    /// RIP timers slow enough to watch routes change.
+    fn slow_rip(builder: NodeBuilderStep<'_, NeverDrop>) -> NodeBuilderStep<'_, NeverDrop> {
+        builder
+            .with_rip_interval(Duration::from_millis(10))
+            .with_entry_max_age(Duration::from_millis(100))
+            .with_prune_interval(Duration::from_millis(10))
     }
 
-    pub async fn print_sockets(&self, file: Option<String>) {
-        self.tcp.print_sockets(file).await
+    async fn start_node(args: &Args) -> Arc<Node<NeverDrop>> {
+        start_node_with(args, NeverDrop, slow_rip).await
     }
 }
@@ -3,12 +3,14 @@
 use std::sync::Arc;
 use std::time::Duration;
 
+use tokio::signal::unix::{signal, SignalKind};
+
 use ip_tcp::cli;
 use ip_tcp::node::NodeBuilder;
 use ip_tcp::Args;
 
-use cli::Cli;
-use ip_tcp::protocol::{rip::RipHandler, test::TestHandler, Protocol};
+use cli::{Cli, SHUTDOWN_TIMEOUT};
+use ip_tcp::protocol::{icmp::IcmpHandler, rip::RipHandler, test::TestHandler, Protocol};
 
 const RIP_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
 const ROUTING_ENTRY_MAX_AGE: Duration = Duration::from_secs(12);
@@ -32,6 +34,7 @@ async fn main() {
             .with_entry_max_age(ROUTING_ENTRY_MAX_AGE)
             .with_protocol_handler(Protocol::Rip, RipHandler::default())
             .with_protocol_handler(Protocol::Test, TestHandler::default())
+            .with_protocol_handler(Protocol::Icmp, IcmpHandler::default())
             .build()
             .await,
     );
@@ -41,5 +44,20 @@ async fn main() {
         Cli::new(cli_node).run().await;
     });
 
+    let shutdown_node = node.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown_node.shutdown(SHUTDOWN_TIMEOUT).await;
+    });
+
     node.run().await;
 }
+
+/// Resolves on the first SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
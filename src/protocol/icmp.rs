@@ -0,0 +1,240 @@
+use std::cmp::min;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use async_trait::async_trait;
+use etherparse::Ipv4HeaderSlice;
+use log::Level;
+use tokio::sync::broadcast;
+
+use crate::drop_policy::DropPolicy;
+use crate::net::vtlink::VtLinkNet;
+use crate::net::Net;
+use crate::protocol::ProtocolHandler;
+use crate::{node_log, Message};
+
+/// Bytes of the offending packet's payload quoted in an ICMP error, after its
+/// IP header (RFC 792).
+const ICMP_QUOTED_PAYLOAD_SZ: usize = 8;
+
+/// Type, code, checksum, and the 4 unused bytes of an error message.
+const ICMP_HEADER_SZ: usize = 8;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum IcmpKind {
+    /// No route to the packet's destination (type 3, code 0).
+    DestinationUnreachable,
+    /// The packet's TTL ran out on the way (type 11, code 0).
+    TimeExceeded,
+}
+
+impl IcmpKind {
+    fn type_and_code(self) -> (u8, u8) {
+        match self {
+            IcmpKind::DestinationUnreachable => (3, 0),
+            IcmpKind::TimeExceeded => (11, 0),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseIcmpError {
+    Truncated,
+    BadChecksum,
+    UnsupportedType(u8),
+}
+
+impl TryFrom<u8> for IcmpKind {
+    type Error = ParseIcmpError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            3 => Ok(IcmpKind::DestinationUnreachable),
+            11 => Ok(IcmpKind::TimeExceeded),
+            _ => Err(ParseIcmpError::UnsupportedType(value)),
+        }
+    }
+}
+
+/// An ICMP error, quoting the start of the packet that caused it.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct IcmpMessage {
+    kind: IcmpKind,
+    /// The offending packet's IP header and the first bytes of its payload.
+    quoted: Vec<u8>,
+}
+
+impl IcmpMessage {
+    pub fn destination_unreachable(header: &Ipv4HeaderSlice, payload: &[u8]) -> Self {
+        Self::quoting(IcmpKind::DestinationUnreachable, header, payload)
+    }
+
+    pub fn time_exceeded(header: &Ipv4HeaderSlice, payload: &[u8]) -> Self {
+        Self::quoting(IcmpKind::TimeExceeded, header, payload)
+    }
+
+    fn quoting(kind: IcmpKind, header: &Ipv4HeaderSlice, payload: &[u8]) -> Self {
+        let mut quoted = header.slice().to_vec();
+        quoted.extend_from_slice(&payload[..min(payload.len(), ICMP_QUOTED_PAYLOAD_SZ)]);
+        Self { kind, quoted }
+    }
+
+    pub fn kind(&self) -> IcmpKind {
+        self.kind
+    }
+
+    /// The IP header of the packet that caused this error.
+    pub fn quoted_header(&self) -> Option<Ipv4HeaderSlice<'_>> {
+        Ipv4HeaderSlice::from_slice(&self.quoted).ok()
+    }
+
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, ParseIcmpError> {
+        if bytes.len() < ICMP_HEADER_SZ {
+            return Err(ParseIcmpError::Truncated);
+        }
+        if checksum(bytes) != 0 {
+            return Err(ParseIcmpError::BadChecksum);
+        }
+        Ok(Self {
+            kind: IcmpKind::try_from(bytes[0])?,
+            quoted: bytes[ICMP_HEADER_SZ..].to_vec(),
+        })
+    }
+}
+
+impl Message for IcmpMessage {
+    fn into_bytes(self) -> Vec<u8> {
+        let (icmp_type, code) = self.kind.type_and_code();
+        let mut v = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+        v.extend_from_slice(&self.quoted);
+
+        let checksum = checksum(&v);
+        v[2..4].copy_from_slice(&checksum.to_be_bytes());
+        v
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self::try_from_bytes(bytes).expect("Malformed ICMP message")
+    }
+}
+
+impl fmt::Display for IcmpMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            IcmpKind::DestinationUnreachable => "Destination unreachable",
+            IcmpKind::TimeExceeded => "Time exceeded",
+        };
+        match self.quoted_header() {
+            Some(header) => write!(
+                f,
+                "{} for packet from {} to {}",
+                kind,
+                header.source_addr(),
+                header.destination_addr()
+            ),
+            None => write!(f, "{kind}"),
+        }
+    }
+}
+
+/// The Internet checksum (RFC 1071): the one's complement of the one's
+/// complement sum of the 16-bit words in `bytes`.
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum = bytes
+        .chunks(2)
+        .map(|word| match *word {
+            [hi, lo] => u16::from_be_bytes([hi, lo]) as u32,
+            [hi] => u16::from_be_bytes([hi, 0]) as u32,
+            _ => unreachable!(),
+        })
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Reports the ICMP errors a node receives, along with the router that sent
+/// each.
+pub struct IcmpHandler {
+    errors: broadcast::Sender<(Ipv4Addr, IcmpMessage)>,
+}
+
+impl Default for IcmpHandler {
+    fn default() -> Self {
+        Self {
+            errors: broadcast::channel(64).0,
+        }
+    }
+}
+
+impl IcmpHandler {
+    pub fn subscribe(&self) -> broadcast::Receiver<(Ipv4Addr, IcmpMessage)> {
+        self.errors.subscribe()
+    }
+}
+
+#[async_trait]
+impl<DP: DropPolicy> ProtocolHandler<DP> for IcmpHandler {
+    async fn handle_packet<'a>(
+        &self,
+        header: &Ipv4HeaderSlice<'a>,
+        payload: &[u8],
+        net: &VtLinkNet<DP>,
+    ) {
+        let message = match IcmpMessage::try_from_bytes(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                node_log!(net.logger(), Level::Warn, "Dropping ICMP message, {:?}", e);
+                return;
+            }
+        };
+
+        node_log!(
+            net.logger(),
+            Level::Info,
+            "{} (reported by {})",
+            message,
+            header.source_addr()
+        );
+        self.errors.send((header.source_addr(), message)).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::Ipv4Header;
+
+    use super::*;
+    use crate::protocol::Protocol;
+
+    #[test]
+    fn icmp_message_serde() {
+        let header = Ipv4Header::new(20, 1, Protocol::Tcp.into(), [10, 0, 0, 1], [10, 0, 0, 9]);
+        let mut header_bytes = Vec::new();
+        header.write(&mut header_bytes).unwrap();
+        let header = Ipv4HeaderSlice::from_slice(&header_bytes).unwrap();
+
+        let msg = IcmpMessage::time_exceeded(&header, &[7; 20]);
+        assert_eq!(
+            msg.quoted.len(),
+            header_bytes.len() + ICMP_QUOTED_PAYLOAD_SZ
+        );
+
+        let bytes = msg.clone().into_bytes();
+        assert_eq!(bytes[0], 11);
+        let parsed = IcmpMessage::from_bytes(&bytes);
+        assert_eq!(parsed, msg);
+        assert_eq!(
+            parsed.quoted_header().unwrap().destination_addr(),
+            Ipv4Addr::new(10, 0, 0, 9)
+        );
+
+        let mut corrupted = bytes;
+        corrupted[ICMP_HEADER_SZ] ^= 1;
+        assert!(matches!(
+            IcmpMessage::try_from_bytes(&corrupted),
+            Err(ParseIcmpError::BadChecksum)
+        ));
+    }
+}
@@ -3,6 +3,7 @@ use etherparse::Ipv4HeaderSlice;
 
 use crate::{drop_policy::DropPolicy, net::vtlink::VtLinkNet};
 
+pub mod icmp;
 pub mod rip;
 pub mod tcp;
 pub mod test;
@@ -19,6 +20,7 @@ pub trait ProtocolHandler<DP: DropPolicy>: Send + Sync {
 
 #[derive(PartialEq, Eq, Hash, Debug)]
 pub enum Protocol {
+    Icmp,
     Rip,
     Test,
     Tcp,
@@ -34,6 +36,7 @@ impl TryFrom<u8> for Protocol {
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             0 => Ok(Protocol::Test),
+            1 => Ok(Protocol::Icmp),
             200 => Ok(Protocol::Rip),
             6 => Ok(Protocol::Tcp),
             _ => Err(ParseProtocolError::Unsupported),
@@ -55,6 +58,7 @@ impl TryFrom<&str> for Protocol {
 impl Into<u8> for Protocol {
     fn into(self) -> u8 {
         match self {
+            Protocol::Icmp => 1,
             Protocol::Rip => 200,
             Protocol::Test => 0,
             Protocol::Tcp => 6,
@@ -1,11 +1,13 @@
 use async_trait::async_trait;
 use etherparse::Ipv4HeaderSlice;
+use log::Level;
 
 use crate::{
     drop_policy::DropPolicy,
-    net::vtlink::{Entry as RoutingEntry, ForwardingTable, Link, VtLinkNet},
-    protocol::Protocol,
-    utils::net::Ipv4PacketBuilder,
+    net::vtlink::{Entry as RoutingEntry, ForwardingTable, VtLinkNet},
+    net::Net,
+    node_log,
+    utils::logging::NodeLogger,
 };
 
 use std::{cmp, cmp::Ordering, net::Ipv4Addr};
@@ -72,6 +74,18 @@ impl TryFrom<u16> for Command {
 }
 
 impl RipMessage {
+    /// A request for the receiver's whole table.
+    pub fn request() -> Self {
+        Self {
+            command: Command::Request,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.command == Command::Request
+    }
+
     pub fn from_entries_with_poisoned_reverse(
         entries: &[RoutingEntry],
         receiver: Ipv4Addr,
@@ -203,15 +217,23 @@ impl<DP: DropPolicy> ProtocolHandler<DP> for RipHandler {
     {
         let message = RipMessage::from_bytes(payload);
 
-        log::debug!("Received RIP packet from {}", header.source_addr());
+        node_log!(
+            net.logger(),
+            Level::Debug,
+            "Received RIP packet from {}",
+            header.source_addr()
+        );
 
-        let mut ft = net.get_forwarding_table_mut().await;
-        let updates = self.update_forwarding_table(&mut ft, message, header.source_addr());
-        if !updates.is_empty() {
-            for link in &*net.links().iter_links().await {
-                self.send_triggered_update(&updates, link).await;
-            }
+        if message.is_request() {
+            net.send_rip_response(header.source_addr()).await;
+            return;
         }
+
+        let updates = {
+            let mut ft = net.get_forwarding_table_mut().await;
+            self.update_forwarding_table(&mut ft, message, header.source_addr(), net.logger())
+        };
+        net.send_triggered_update(&updates).await;
     }
 }
 
@@ -223,6 +245,7 @@ impl RipHandler {
         table: &mut ForwardingTable,
         message: RipMessage,
         sender: Ipv4Addr,
+        logger: NodeLogger,
     ) -> Vec<RoutingEntry> {
         let mut updates = Vec::new();
 
@@ -232,23 +255,31 @@ impl RipHandler {
                 Some(local_entry) => {
                     match entry_cost.cmp(&local_entry.cost()) {
                         Ordering::Less => {
-                            log::info!(
+                            node_log!(
+                                logger,
+                                Level::Info,
                                 "Found a cheaper entry; old: {:?}, new: {:?}",
                                 local_entry,
                                 entry
                             );
                             local_entry.update(sender, entry_cost);
-                            updates.push(*local_entry);
+                            let updated = *local_entry;
+                            updates.push(updated);
+                            table.notify_updated(updated);
                         }
                         Ordering::Greater => {
                             if local_entry.next_hop() == sender {
-                                log::info!(
+                                node_log!(
+                                    logger,
+                                    Level::Info,
                                     "Updating entry cost; old: {:?}, new: {:?}",
                                     local_entry,
                                     entry
                                 );
                                 local_entry.update_cost(entry_cost);
-                                updates.push(*local_entry);
+                                let updated = *local_entry;
+                                updates.push(updated);
+                                table.notify_updated(updated);
                             }
                         }
                         Ordering::Equal => {
@@ -256,13 +287,19 @@ impl RipHandler {
                             // Accepting the new report could destabilize the network (see Ex. 8
                             // in the Chapter 13 of Dordal).
                             if local_entry.next_hop() == sender {
+                                node_log!(
+                                    logger,
+                                    Level::Debug,
+                                    "Resetting timer for entry: {}",
+                                    local_entry
+                                );
                                 local_entry.restart_delete_timer();
                             }
                         }
                     }
                 }
                 None => {
-                    log::info!("Adding new entry: {:?}", entry);
+                    node_log!(logger, Level::Info, "Adding new entry: {:?}", entry);
                     let dest = entry.address;
                     let entry = RoutingEntry::new(dest, sender, entry_cost);
                     table.add_entry(entry);
@@ -273,21 +310,6 @@ impl RipHandler {
 
         updates
     }
-
-    async fn send_triggered_update(&self, updates: &[RoutingEntry], link: &Link) {
-        log::debug!("Sending triggered update to {}", link.dest());
-        let rip_msg_bytes =
-            RipMessage::from_entries_with_poisoned_reverse(updates, link.dest()).into_bytes();
-        let packet = Ipv4PacketBuilder::default()
-            .with_src(link.source())
-            .with_dst(link.dest())
-            .with_payload(&rip_msg_bytes)
-            .with_protocol(Protocol::Rip)
-            .build()
-            .unwrap();
-        // Ignore the error case, which occurs when sending data out on a disabled link.
-        link.send(&packet).await.ok();
-    }
 }
 
 #[cfg(test)]
@@ -310,4 +332,30 @@ mod tests {
 
         assert_eq!(m, parsed);
     }
+
+    #[test]
+    fn rip_message_wire_format() {
+        let msg = RipMessage {
+            command: Command::Response,
+            entries: vec![Entry::with_default_mask(3, Ipv4Addr::new(10, 0, 0, 1))],
+        };
+
+        // Command, number of entries, then each entry's cost, address and mask.
+        let expected: Vec<u8> = [
+            &[0, 2][..],
+            &[0, 1],
+            &[0, 0, 0, 3],
+            &[10, 0, 0, 1],
+            &[255, 255, 255, 255],
+        ]
+        .concat();
+        assert_eq!(msg.clone().into_bytes(), expected);
+        assert_eq!(RipMessage::from_bytes(&expected), msg);
+
+        let request = RipMessage {
+            command: Command::Request,
+            entries: vec![],
+        };
+        assert_eq!(request.into_bytes(), vec![0, 1, 0, 0]);
+    }
 }
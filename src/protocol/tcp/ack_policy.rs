@@ -1,8 +1,10 @@
+use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
 
 use etherparse::TcpHeaderSlice;
 
-pub trait AckPolicy {
+pub trait AckPolicy: fmt::Debug {
     fn should_ack(&self, tcp_header: &TcpHeaderSlice<'_>) -> bool;
 }
 
@@ -29,3 +31,65 @@ impl<const LAG: usize> AckPolicy for BatchedAck<LAG> {
         LAG == 0 || self.count.fetch_add(1, Ordering::Relaxed) % LAG == 0
     }
 }
+
+/// Utility that ACKs every n-th segment received.
+///
+/// Segments in between are acknowledged by the cumulative ACK of the n-th
+/// segment, or by the transport's ACK timer, whichever comes first.
+#[derive(Debug)]
+pub struct EveryNAck {
+    n: usize,
+    count: AtomicUsize,
+}
+
+impl EveryNAck {
+    pub fn new(n: usize) -> Self {
+        Self {
+            n,
+            count: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl AckPolicy for EveryNAck {
+    #[inline]
+    fn should_ack(&self, _tcp_header: &TcpHeaderSlice<'_>) -> bool {
+        if self.n <= 1 {
+            return true;
+        }
+        let n = self.n;
+        let prev = self
+            .count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| Some((c + 1) % n))
+            .unwrap();
+        prev + 1 == n
+    }
+}
+
+/// Utility that delegates to a policy which can be replaced while a
+/// connection is running. Defaults to `AlwaysAck`.
+#[derive(Debug)]
+pub struct ConfigurableAck {
+    inner: RwLock<Box<dyn AckPolicy + Send + Sync>>,
+}
+
+impl Default for ConfigurableAck {
+    fn default() -> Self {
+        Self {
+            inner: RwLock::new(Box::new(AlwaysAck)),
+        }
+    }
+}
+
+impl ConfigurableAck {
+    pub fn set<P: AckPolicy + Send + Sync + 'static>(&self, policy: P) {
+        *self.inner.write().unwrap() = Box::new(policy);
+    }
+}
+
+impl AckPolicy for ConfigurableAck {
+    #[inline]
+    fn should_ack(&self, tcp_header: &TcpHeaderSlice<'_>) -> bool {
+        self.inner.read().unwrap().should_ack(tcp_header)
+    }
+}
@@ -2,12 +2,12 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::{
     cmp::{max, min, Reverse},
     collections::BinaryHeap,
-    sync::{atomic::AtomicU16, atomic::Ordering::SeqCst, Arc},
+    sync::{atomic::AtomicUsize, atomic::Ordering::SeqCst, Arc},
     time::{Duration, Instant},
     usize,
 };
 
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, Mutex, OwnedMutexGuard};
 
 use crate::utils::sync::Notifier;
 
@@ -20,9 +20,9 @@ pub struct SendBufClosed;
 #[derive(Debug, Clone)]
 pub struct SendBuf<const N: usize> {
     inner: Arc<Mutex<InnerSendBuf<N>>>,
-    window_size: Arc<AtomicU16>,
-    window_size_tx: broadcast::Sender<u16>,
-    window_size_rx: Arc<broadcast::Receiver<u16>>,
+    window_size: Arc<AtomicUsize>,
+    window_size_tx: broadcast::Sender<usize>,
+    window_size_rx: Arc<broadcast::Receiver<usize>>,
     tail_update_tx: broadcast::Sender<usize>,
     tail_update_rx: Arc<broadcast::Receiver<usize>>,
     not_full: Notifier,
@@ -41,7 +41,7 @@ impl<const N: usize> SendBuf<N> {
         let tail_update_rx = Arc::new(tail_update_rx);
         Self {
             inner: Arc::new(Mutex::new(InnerSendBuf::new(initial_seq_no))),
-            window_size: Arc::new(AtomicU16::new(N.try_into().unwrap())),
+            window_size: Arc::new(AtomicUsize::new(N)),
             window_size_tx,
             window_size_rx,
             tail_update_tx,
@@ -77,7 +77,7 @@ impl<const N: usize> SendBuf<N> {
     }
 
     /// Set the window size that was sent to us by our remote.
-    pub fn set_window_size(&self, window_size: u16) {
+    pub fn set_window_size(&self, window_size: usize) {
         self.window_size.store(window_size, SeqCst);
         self.window_size_tx
             .send(window_size)
@@ -85,12 +85,12 @@ impl<const N: usize> SendBuf<N> {
     }
 
     /// Get the window size that was sent to us by our remote.
-    pub fn window_size(&self) -> u16 {
+    pub fn window_size(&self) -> usize {
         self.window_size.load(SeqCst)
     }
 
     /// Get notified when window size is updated.
-    pub fn window_size_update(&self) -> broadcast::Receiver<u16> {
+    pub fn window_size_update(&self) -> broadcast::Receiver<usize> {
         self.window_size_tx.subscribe()
     }
 
@@ -111,6 +111,52 @@ impl<const N: usize> SendBuf<N> {
         Ok(written)
     }
 
+    /// Like `SendBuf::write()`, but blocks until at least one byte is
+    /// written.
+    pub async fn write_some(&self, bytes: &[u8]) -> Result<usize, SendBufClosed> {
+        if bytes.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if !self.open.load(Ordering::Acquire) {
+                return Err(SendBufClosed);
+            }
+            let mut send_buf = self.inner.lock().await;
+            let written = send_buf.write(bytes);
+            if written > 0 {
+                self.written.notify_all();
+                return Ok(written);
+            }
+            let not_full = self.not_full.notified();
+            drop(send_buf);
+            not_full.wait().await;
+        }
+    }
+
+    /// Waits for room for at least one byte, which is held for the returned
+    /// reservation until it is written to or dropped.
+    ///
+    /// Unlike `write_some`, the wait does not borrow the bytes to write, so
+    /// poll-based writers may keep it across polls with different buffers.
+    pub async fn reserve(&self) -> Result<SendReservation<N>, SendBufClosed> {
+        loop {
+            if !self.open.load(Ordering::Acquire) {
+                return Err(SendBufClosed);
+            }
+            let send_buf = self.inner.clone().lock_owned().await;
+            if !send_buf.is_full() {
+                return Ok(SendReservation {
+                    inner: send_buf,
+                    written: self.written.clone(),
+                });
+            }
+            let not_full = self.not_full.notified();
+            drop(send_buf);
+            not_full.wait().await;
+        }
+    }
+
     /// Writes all bytes into the buffer.
     ///
     /// This function could block for a _long_ time, if it tries to write a
@@ -246,6 +292,24 @@ impl<const N: usize> SendBuf<N> {
         }
     }
 
+    /// Blocks until the remote has acked every byte before `seq_no`.
+    ///
+    /// Errs if the buffer is closed first.
+    pub async fn wait_for_acked(&self, seq_no: usize) -> Result<(), SendBufClosed> {
+        loop {
+            let send_buf = self.inner.lock().await;
+            if send_buf.tail >= seq_no {
+                return Ok(());
+            }
+            if self.closed() {
+                return Err(SendBufClosed);
+            }
+            let notifier = self.not_full.notified();
+            drop(send_buf);
+            notifier.wait().await;
+        }
+    }
+
     /// When a buffer is closed, wait for all of its content to be consumed.
     ///
     /// Returns the final sequence number of the buffer, which is both the head
@@ -269,15 +333,31 @@ impl<const N: usize> SendBuf<N> {
     }
 
     pub async fn close(&self) -> Result<(), SendBufClosed> {
-        if self
-            .open
+        self.open
             .compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed)
-            .unwrap()
-        {
-            Ok(())
-        } else {
-            Err(SendBufClosed)
+            .map(|_| {
+                // wake pending writers, if any
+                self.not_full.notify_all();
+            })
+            .map_err(|_| SendBufClosed)
+    }
+}
+
+/// Room in a `SendBuf`, reserved by `SendBuf::reserve`.
+pub struct SendReservation<const N: usize> {
+    inner: OwnedMutexGuard<InnerSendBuf<N>>,
+    written: Notifier,
+}
+
+impl<const N: usize> SendReservation<N> {
+    /// Writes as many bytes as there is room for, at least one if `bytes` is
+    /// not empty, returning the number of bytes written.
+    pub fn write(mut self, bytes: &[u8]) -> usize {
+        let written = self.inner.write(bytes);
+        if written > 0 {
+            self.written.notify_all();
         }
+        written
     }
 }
 
@@ -595,8 +675,16 @@ pub struct RecvBuf<const N: usize> {
 impl<const N: usize> RecvBuf<N> {
     /// Constructs a new RecvBuf.
     pub fn new(starting_seq_no: usize) -> Self {
+        Self::with_max_early_ranges(starting_seq_no, None)
+    }
+
+    /// Constructs a new RecvBuf keeping at most `max_early_ranges` distinct
+    /// ranges of early arrivals, if set.
+    pub fn with_max_early_ranges(starting_seq_no: usize, max_early_ranges: Option<usize>) -> Self {
+        let mut inner = InnerRecvBuf::new(starting_seq_no);
+        inner.max_early_ranges = max_early_ranges;
         Self {
-            inner: Arc::new(Mutex::new(InnerRecvBuf::new(starting_seq_no))),
+            inner: Arc::new(Mutex::new(inner)),
             written: Notifier::new(),
             read: Notifier::new(),
             open: Arc::new(AtomicBool::new(true)),
@@ -639,6 +727,15 @@ impl<const N: usize> RecvBuf<N> {
         !self.open.load(Ordering::Acquire)
     }
 
+    /// Drop every consumable byte, returning how many were dropped.
+    pub async fn discard(&self) -> usize {
+        let discarded = self.inner.lock().await.discard();
+        if discarded > 0 {
+            self.read.notify_all();
+        }
+        discarded
+    }
+
     /// Try to fill the provided buffer.
     ///
     /// The method returns a slice of the written bytes, which will be a subslice
@@ -660,21 +757,65 @@ impl<const N: usize> RecvBuf<N> {
     /// Try to write some bytes into the `dest` buffer, returning the number of
     /// bytes written.
     ///
-    /// Blocks until at least one byte is written into `dest`.
+    /// Blocks until at least one byte is written into `dest`. Bytes received
+    /// before the buffer was closed are still returned; only then does this
+    /// err.
     pub async fn try_fill_some<'a>(&'a self, dest: &'a mut [u8]) -> Result<usize, RecvBufClosed> {
         assert!(!dest.is_empty());
 
         loop {
-            if self.closed() {
-                return Err(RecvBufClosed);
-            }
+            let written = self.written.notified();
+            let closed = self.closed();
             let mut recv_buf = self.inner.lock().await;
             let consumed = recv_buf.try_fill(dest);
             if !consumed.is_empty() {
                 self.read.notify_all();
                 return Ok(consumed.len());
             }
+            if closed {
+                return Err(RecvBufClosed);
+            }
+            drop(recv_buf);
+            written.wait().await;
+        }
+    }
+
+    /// Like `try_fill_some()`, but returns at once, with zero bytes written
+    /// if none are buffered.
+    pub async fn fill_available(&self, dest: &mut [u8]) -> Result<usize, RecvBufClosed> {
+        let closed = self.closed();
+        let consumed = self.inner.lock().await.try_fill(dest).len();
+        if consumed > 0 {
+            self.read.notify_all();
+            return Ok(consumed);
+        }
+        if closed {
+            return Err(RecvBufClosed);
+        }
+        Ok(0)
+    }
+
+    /// Like `try_fill_some()`, but stops right after the first `delim` byte,
+    /// leaving any bytes past it in the buffer.
+    pub async fn fill_until(&self, delim: u8, dest: &mut [u8]) -> Result<usize, RecvBufClosed> {
+        assert!(!dest.is_empty());
+
+        loop {
             let written = self.written.notified();
+            let closed = self.closed();
+            let mut recv_buf = self.inner.lock().await;
+            let n_bytes = match recv_buf.position(delim) {
+                Some(pos) => min(pos + 1, dest.len()),
+                None => dest.len(),
+            };
+            let consumed = recv_buf.try_fill(&mut dest[..n_bytes]);
+            if !consumed.is_empty() {
+                self.read.notify_all();
+                return Ok(consumed.len());
+            }
+            if closed {
+                return Err(RecvBufClosed);
+            }
             drop(recv_buf);
             written.wait().await;
         }
@@ -733,6 +874,18 @@ impl<const N: usize> RecvBuf<N> {
             .map(|_| self.written.notify_all())
     }
 
+    /// Get the [start, end) sequence number ranges of bytes that arrived
+    /// early, i.e. beyond a hole in the byte stream, in ascending order.
+    pub async fn early_arrival_ranges(&self) -> Vec<(usize, usize)> {
+        self.inner.lock().await.early_arrival_ranges()
+    }
+
+    /// Forget about all early arrivals, returning how many segments were
+    /// discarded. The remote has to retransmit them.
+    pub async fn clear_early_arrivals(&self) -> usize {
+        self.inner.lock().await.clear_early_arrivals()
+    }
+
     /// Like `RecvBuf::try_write()`, but blocks until all of `bytes` are written.
     ///
     /// Calling this method could block for a _long_ time. This is because the
@@ -750,7 +903,8 @@ impl<const N: usize> RecvBuf<N> {
                 }
                 Err(e) => {
                     match e {
-                        WriteRangeError::SeqNoTooSmall(_) => return Err(e),
+                        WriteRangeError::SeqNoTooSmall(_)
+                        | WriteRangeError::TooManyEarlyArrivals => return Err(e),
                         WriteRangeError::ExceedBuffer(_) => {
                             // wait for room to free up
                             let has_room = self.read.notified();
@@ -775,6 +929,8 @@ struct InnerRecvBuf<const N: usize> {
     tail: usize,
     head: usize,
     early_arrivals: BinaryHeap<Reverse<SegmentMeta>>,
+    /// The most distinct ranges `early_arrivals` may cover, if bounded.
+    max_early_ranges: Option<usize>,
     // Debugging use only
     initial_seq_no: usize,
 }
@@ -787,6 +943,9 @@ pub enum WriteRangeError {
     /// Writing too much into the buffer. Contains the sequence number
     /// corresponding to the tail of the buffer.
     ExceedBuffer(usize),
+    /// The bytes arrived early, apart from all other early arrivals, and
+    /// there are already as many ranges of those as allowed.
+    TooManyEarlyArrivals,
 }
 
 impl<const N: usize> InnerRecvBuf<N> {
@@ -796,6 +955,7 @@ impl<const N: usize> InnerRecvBuf<N> {
             tail: initial_seq_no,
             head: initial_seq_no,
             early_arrivals: BinaryHeap::new(),
+            max_early_ranges: None,
             initial_seq_no,
         }
     }
@@ -821,6 +981,18 @@ impl<const N: usize> InnerRecvBuf<N> {
         }
     }
 
+    /// Consume every consumable byte without copying it out.
+    pub fn discard(&mut self) -> usize {
+        let discarded = self.read_remaining_size();
+        self.tail = self.head;
+        discarded
+    }
+
+    /// Get the offset of the first consumable byte equal to `byte`, if any.
+    pub fn position(&self, byte: u8) -> Option<usize> {
+        (self.tail..self.head).position(|seq_no| self.buf[seq_no % self.size()] == byte)
+    }
+
     /// Attempts to write bytes starting at a sequence number.
     ///
     /// This method errs when the write remaining size of this buffer is less
@@ -828,9 +1000,22 @@ impl<const N: usize> InnerRecvBuf<N> {
     ///
     /// Either all bytes or no byte will be written: in the error case, no byte
     /// shall be written in the buffer.
+    ///
+    /// A segment that starts below the head but extends past it (e.g. a
+    /// retransmission that was repacketized) has its already-received prefix
+    /// trimmed off.
     pub fn write(&mut self, seq_no: usize, bytes: &[u8]) -> Result<(), WriteRangeError> {
-        self.validate_write_range(seq_no, seq_no + bytes.len())
-            .map(|_| self.write_unchecked(seq_no, bytes))
+        let (seq_no, bytes) = if seq_no < self.head && seq_no + bytes.len() > self.head {
+            (self.head, &bytes[self.head - seq_no..])
+        } else {
+            (seq_no, bytes)
+        };
+        self.validate_write_range(seq_no, seq_no + bytes.len())?;
+        if self.starts_too_many_early_ranges(seq_no, seq_no + bytes.len()) {
+            return Err(WriteRangeError::TooManyEarlyArrivals);
+        }
+        self.write_unchecked(seq_no, bytes);
+        Ok(())
     }
 
     /// Whether the internal byte buffer is non-contiguous, i.e. some bytes
@@ -839,6 +1024,29 @@ impl<const N: usize> InnerRecvBuf<N> {
         !self.early_arrivals.is_empty()
     }
 
+    /// Get the [start, end) ranges covered by early arrivals, merging
+    /// overlapping and adjacent segments.
+    pub fn early_arrival_ranges(&self) -> Vec<(usize, usize)> {
+        let mut segments: Vec<_> = self.early_arrivals.iter().map(|s| s.0).collect();
+        segments.sort();
+
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for segment in segments {
+            let end = segment.seq_no + segment.size;
+            match ranges.last_mut() {
+                Some(last) if segment.seq_no <= last.1 => last.1 = max(last.1, end),
+                _ => ranges.push((segment.seq_no, end)),
+            }
+        }
+        ranges
+    }
+
+    pub fn clear_early_arrivals(&mut self) -> usize {
+        let n_segments = self.early_arrivals.len();
+        self.early_arrivals.clear();
+        n_segments
+    }
+
     /// Get the next sequence number expected to be sent by the sender.
     pub fn expected_next(&self) -> usize {
         self.head
@@ -897,7 +1105,7 @@ impl<const N: usize> InnerRecvBuf<N> {
             if self.has_early_arrival() {
                 self.drain_early_arrivals();
             }
-        } else {
+        } else if !self.is_early_arrival(seq_no, seq_no + bytes.len()) {
             self.early_arrivals.push(Reverse(SegmentMeta {
                 seq_no,
                 size: bytes.len(),
@@ -905,6 +1113,28 @@ impl<const N: usize> InnerRecvBuf<N> {
         }
     }
 
+    /// Whether writing [start, end) would add a range of early arrivals that
+    /// touches none of the others, beyond `max_early_ranges`.
+    fn starts_too_many_early_ranges(&self, start: usize, end: usize) -> bool {
+        let Some(max) = self.max_early_ranges else {
+            return false;
+        };
+        if start == self.head {
+            return false;
+        }
+        let ranges = self.early_arrival_ranges();
+        let touches_range = ranges.iter().any(|&(s, e)| start <= e && s <= end);
+        !touches_range && ranges.len() >= max
+    }
+
+    /// Whether [start, end) is already covered by a single early arrival, in
+    /// which case a retransmission of it need not be tracked again.
+    fn is_early_arrival(&self, start: usize, end: usize) -> bool {
+        self.early_arrivals
+            .iter()
+            .any(|s| s.0.seq_no <= start && end <= s.0.seq_no + s.0.size)
+    }
+
     /// Consume exactly n bytes.
     fn consume_unchecked(&mut self, n_bytes: usize, dest: &mut [u8]) {
         let start = self.tail % self.size();
@@ -1563,6 +1793,50 @@ mod tests {
             consumer.join().unwrap();
         }
 
+        #[test]
+        fn retransmitted_overlaps() {
+            let mut buf = make_default_inner_recvbuf(0);
+
+            buf.write(0, &[1, 2, 3, 4]).unwrap();
+            buf.write(8, &[9, 10]).unwrap();
+            buf.write(8, &[9, 10]).unwrap();
+            buf.write(8, &[9]).unwrap();
+            assert_eq!(buf.early_arrivals.len(), 1);
+
+            // Fully received before: rejected. Straddling the head: trimmed.
+            assert!(matches!(
+                buf.write(0, &[1, 2]),
+                Err(WriteRangeError::SeqNoTooSmall(4))
+            ));
+            buf.write(2, &[3, 4, 5, 6, 7, 8]).unwrap();
+            assert_eq!(buf.expected_next(), 10);
+            assert!(!buf.has_early_arrival());
+
+            let mut dest = [0; 10];
+            assert_eq!(buf.try_fill(&mut dest), [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        }
+
+        #[test]
+        fn early_arrival_ranges_are_capped() {
+            let mut buf = make_default_inner_recvbuf(0);
+            buf.max_early_ranges = Some(2);
+
+            buf.write(10, &[0; 5]).unwrap();
+            buf.write(20, &[0; 5]).unwrap();
+            assert!(matches!(
+                buf.write(30, &[0; 5]),
+                Err(WriteRangeError::TooManyEarlyArrivals)
+            ));
+
+            // Growing a range, or filling the gap at the head, is still fine.
+            buf.write(25, &[0; 5]).unwrap();
+            assert_eq!(buf.early_arrival_ranges(), vec![(10, 15), (20, 30)]);
+            buf.write(0, &[0; 10]).unwrap();
+            assert_eq!(buf.expected_next(), 15);
+            buf.write(35, &[0; 5]).unwrap();
+            assert_eq!(buf.early_arrival_ranges().len(), 2);
+        }
+
         fn fill_buf<const N: usize>(buf: &mut InnerRecvBuf<N>, start_seq_no: usize, data: &[u8]) {
             let mut curr = start_seq_no;
             loop {
@@ -0,0 +1,488 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Segments a connection may send before hearing back from the remote
+/// (RFC 5681, section 3.1).
+const INITIAL_WINDOW_SEGMENTS: usize = 4;
+
+/// The smallest window BBR keeps, so that delayed ACKs still flow.
+const BBR_MIN_WINDOW_SEGMENTS: usize = 4;
+
+/// The gain that doubles the sending rate every round trip: 2 / ln(2).
+const BBR_HIGH_GAIN: f64 = 2.885;
+
+/// Window gain outside of startup, leaving room for delayed and
+/// aggregated ACKs.
+const BBR_CWND_GAIN: f64 = 2.0;
+
+/// Pacing gains cycled through while probing for bandwidth, one round trip
+/// each: probe above the estimate, drain the queue that built up, cruise.
+const BBR_PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+/// Round trips the bottleneck bandwidth estimate remembers samples for.
+const BBR_BW_FILTER_ROUNDS: u64 = 10;
+
+/// Growth in bandwidth, per round trip, below which startup has filled the
+/// pipe.
+const BBR_FULL_BW_GROWTH: f64 = 1.25;
+
+/// Round trips without `BBR_FULL_BW_GROWTH` after which startup ends.
+const BBR_FULL_BW_ROUNDS: usize = 3;
+
+/// How long a minimum RTT sample stays valid before it is probed again.
+const BBR_MIN_RTT_EXPIRY: Duration = Duration::from_secs(10);
+
+/// How long the window is held at its minimum to measure the minimum RTT.
+const BBR_PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+
+/// Which congestion controller new connections use.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionAlgorithm {
+    /// Send as much as the remote's window allows.
+    #[default]
+    Unlimited,
+    /// Loss-based AIMD with slow start and fast recovery (RFC 5681).
+    Reno,
+    /// Rate-based control paced at the estimated bottleneck bandwidth.
+    Bbr,
+}
+
+impl CongestionAlgorithm {
+    pub fn controller(self, mss: usize) -> Box<dyn CongestionControl> {
+        match self {
+            CongestionAlgorithm::Unlimited => Box::new(Unlimited),
+            CongestionAlgorithm::Reno => Box::new(Reno::new(mss)),
+            CongestionAlgorithm::Bbr => Box::new(Bbr::new(mss)),
+        }
+    }
+}
+
+/// What an ACK of new data tells about the path.
+#[derive(Debug, Clone, Copy)]
+pub struct AckSample {
+    /// Bytes newly acked.
+    pub acked: usize,
+    /// Bytes still in flight after the ACK.
+    pub in_flight: usize,
+    /// The time since the newest segment acked was first sent.
+    pub rtt: Option<Duration>,
+    /// Whether that segment was retransmitted, in which case its ACK may
+    /// answer a later copy and `rtt` only bounds the round trip from above.
+    pub retransmitted: bool,
+    /// Bytes per second delivered between sending the newest segment acked
+    /// and its ACK.
+    pub delivery_rate: Option<f64>,
+    /// Bytes delivered when the newest segment acked was sent.
+    pub prior_delivered: usize,
+    /// Bytes delivered so far, including those just acked.
+    pub delivered: usize,
+}
+
+/// Decides how fast a connection sends, from what its ACKs and losses tell
+/// about the path.
+pub trait CongestionControl: fmt::Debug + Send + Sync {
+    /// The most bytes that may be in flight.
+    fn window(&self) -> usize;
+
+    /// Bytes per second to space segments out at. `None` sends them as soon
+    /// as the window allows.
+    fn pacing_rate(&self) -> Option<f64> {
+        None
+    }
+
+    fn on_ack(&mut self, sample: &AckSample, now: Instant);
+
+    /// The oldest segment was resent after duplicate ACKs.
+    fn on_fast_retransmit(&mut self, in_flight: usize);
+
+    /// The oldest segment was resent on the retransmission timeout.
+    fn on_timeout(&mut self, in_flight: usize);
+
+    /// The last timeout turned out spurious (RFC 5682): the segments were
+    /// delayed, not lost. Undoes what `on_timeout` did to the window.
+    fn on_spurious_timeout(&mut self) {}
+}
+
+/// Leaves flow control to the remote's window alone.
+#[derive(Debug, Default)]
+pub struct Unlimited;
+
+impl CongestionControl for Unlimited {
+    fn window(&self) -> usize {
+        usize::MAX
+    }
+
+    fn on_ack(&mut self, _sample: &AckSample, _now: Instant) {}
+
+    fn on_fast_retransmit(&mut self, _in_flight: usize) {}
+
+    fn on_timeout(&mut self, _in_flight: usize) {}
+}
+
+/// Slow start, congestion avoidance and the window reductions of fast
+/// retransmit and timeouts, per RFC 5681.
+#[derive(Debug)]
+pub struct Reno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    /// The window and threshold from before the last timeout, until a loss
+    /// confirms it.
+    before_timeout: Option<(usize, usize)>,
+}
+
+impl Reno {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: INITIAL_WINDOW_SEGMENTS * mss,
+            ssthresh: usize::MAX,
+            before_timeout: None,
+        }
+    }
+
+    /// Half the data in flight, but no less than two segments.
+    fn reduced_ssthresh(&self, in_flight: usize) -> usize {
+        (in_flight / 2).max(2 * self.mss)
+    }
+}
+
+impl CongestionControl for Reno {
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, sample: &AckSample, _now: Instant) {
+        self.cwnd += if self.cwnd < self.ssthresh {
+            sample.acked.min(self.mss)
+        } else {
+            (self.mss * self.mss / self.cwnd).max(1)
+        };
+    }
+
+    fn on_fast_retransmit(&mut self, in_flight: usize) {
+        self.before_timeout = None;
+        self.ssthresh = self.reduced_ssthresh(in_flight);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_timeout(&mut self, in_flight: usize) {
+        self.before_timeout = Some((self.cwnd, self.ssthresh));
+        self.ssthresh = self.reduced_ssthresh(in_flight);
+        self.cwnd = self.mss;
+    }
+
+    fn on_spurious_timeout(&mut self) {
+        if let Some((cwnd, ssthresh)) = self.before_timeout.take() {
+            self.cwnd = cwnd;
+            self.ssthresh = ssthresh;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BbrMode {
+    /// Doubling the sending rate every round trip until the bandwidth stops
+    /// growing.
+    Startup,
+    /// Draining the queue startup built up.
+    Drain,
+    /// Cycling through `BBR_PROBE_BW_GAINS`.
+    ProbeBw { phase: usize, phase_start: Instant },
+    /// Holding the window at its minimum to drain the queue and measure the
+    /// minimum RTT. Starts counting down once in flight data is that low.
+    ProbeRtt { until: Option<Instant> },
+}
+
+/// A simplified BBR: the window and pacing rate follow the bottleneck
+/// bandwidth and minimum RTT measured from ACKs, rather than reacting to
+/// losses.
+#[derive(Debug)]
+pub struct Bbr {
+    mss: usize,
+    mode: BbrMode,
+    /// The highest delivery rate sampled in each recent round trip.
+    bw_samples: VecDeque<(u64, f64)>,
+    /// Round trips counted so far.
+    round: u64,
+    /// Delivered bytes at which the current round trip ends.
+    next_round_delivered: usize,
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Instant,
+    /// The bandwidth startup last saw grow, and rounds since.
+    full_bw: f64,
+    full_bw_rounds: usize,
+    filled_pipe: bool,
+}
+
+impl Bbr {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            mode: BbrMode::Startup,
+            bw_samples: VecDeque::new(),
+            round: 0,
+            next_round_delivered: 0,
+            min_rtt: None,
+            min_rtt_stamp: Instant::now(),
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            filled_pipe: false,
+        }
+    }
+
+    /// The bottleneck bandwidth in bytes per second.
+    fn btl_bw(&self) -> Option<f64> {
+        self.bw_samples.iter().map(|(_, bw)| *bw).reduce(f64::max)
+    }
+
+    /// The bandwidth-delay product, in bytes.
+    fn bdp(&self) -> Option<f64> {
+        Some(self.btl_bw()? * self.min_rtt?.as_secs_f64())
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode {
+            BbrMode::Startup => BBR_HIGH_GAIN,
+            BbrMode::Drain => 1.0 / BBR_HIGH_GAIN,
+            BbrMode::ProbeBw { phase, .. } => BBR_PROBE_BW_GAINS[phase],
+            BbrMode::ProbeRtt { .. } => 1.0,
+        }
+    }
+
+    fn cwnd_gain(&self) -> f64 {
+        match self.mode {
+            BbrMode::Startup | BbrMode::Drain => BBR_HIGH_GAIN,
+            BbrMode::ProbeBw { .. } | BbrMode::ProbeRtt { .. } => BBR_CWND_GAIN,
+        }
+    }
+
+    fn min_window(&self) -> usize {
+        BBR_MIN_WINDOW_SEGMENTS * self.mss
+    }
+
+    fn update_bw(&mut self, sample: &AckSample) {
+        if let Some(rate) = sample.delivery_rate {
+            match self.bw_samples.back_mut() {
+                Some((round, bw)) if *round == self.round => *bw = bw.max(rate),
+                _ => self.bw_samples.push_back((self.round, rate)),
+            }
+        }
+        while let Some((round, _)) = self.bw_samples.front() {
+            if round + BBR_BW_FILTER_ROUNDS > self.round {
+                break;
+            }
+            self.bw_samples.pop_front();
+        }
+    }
+
+    /// Startup is over once the bandwidth stops growing for a few rounds.
+    fn check_full_pipe(&mut self) {
+        let Some(bw) = self.btl_bw() else {
+            return;
+        };
+        if bw >= self.full_bw * BBR_FULL_BW_GROWTH {
+            self.full_bw = bw;
+            self.full_bw_rounds = 0;
+        } else {
+            self.full_bw_rounds += 1;
+            self.filled_pipe = self.full_bw_rounds >= BBR_FULL_BW_ROUNDS;
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.mode = BbrMode::ProbeBw {
+            phase: 0,
+            phase_start: now,
+        };
+    }
+
+    fn update_mode(&mut self, sample: &AckSample, round_start: bool, now: Instant) {
+        let min_rtt = self.min_rtt.unwrap_or_default();
+        match self.mode {
+            BbrMode::Startup => {
+                if round_start {
+                    self.check_full_pipe();
+                }
+                if self.filled_pipe {
+                    self.mode = BbrMode::Drain;
+                }
+            }
+            BbrMode::Drain => {
+                if self.bdp().is_none_or(|bdp| sample.in_flight as f64 <= bdp) {
+                    self.enter_probe_bw(now);
+                }
+            }
+            BbrMode::ProbeBw { phase, phase_start } => {
+                if now - phase_start > min_rtt {
+                    self.mode = BbrMode::ProbeBw {
+                        phase: (phase + 1) % BBR_PROBE_BW_GAINS.len(),
+                        phase_start: now,
+                    };
+                }
+            }
+            BbrMode::ProbeRtt { until: None } => {
+                if sample.in_flight <= self.min_window() {
+                    self.mode = BbrMode::ProbeRtt {
+                        until: Some(now + BBR_PROBE_RTT_DURATION.max(min_rtt)),
+                    };
+                }
+            }
+            BbrMode::ProbeRtt { until: Some(until) } => {
+                if now >= until {
+                    self.min_rtt_stamp = now;
+                    if self.filled_pipe {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.mode = BbrMode::Startup;
+                    }
+                }
+            }
+        }
+
+        let probing_rtt = matches!(self.mode, BbrMode::ProbeRtt { .. });
+        if !probing_rtt && now - self.min_rtt_stamp > BBR_MIN_RTT_EXPIRY {
+            self.mode = BbrMode::ProbeRtt { until: None };
+        }
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn window(&self) -> usize {
+        if let BbrMode::ProbeRtt { .. } = self.mode {
+            return self.min_window();
+        }
+        match self.bdp() {
+            Some(bdp) => ((self.cwnd_gain() * bdp) as usize).max(self.min_window()),
+            None => INITIAL_WINDOW_SEGMENTS * self.mss,
+        }
+    }
+
+    fn pacing_rate(&self) -> Option<f64> {
+        self.btl_bw().map(|bw| self.pacing_gain() * bw)
+    }
+
+    fn on_ack(&mut self, sample: &AckSample, now: Instant) {
+        let round_start = sample.prior_delivered >= self.next_round_delivered;
+        if round_start {
+            self.next_round_delivered = sample.delivered;
+            self.round += 1;
+        }
+        self.update_bw(sample);
+
+        // Until a clean sample arrives, an upper bound beats no estimate.
+        let rtt = sample
+            .rtt
+            .filter(|_| !sample.retransmitted || self.min_rtt.is_none());
+        if let Some(rtt) = rtt {
+            let expired = now - self.min_rtt_stamp > BBR_MIN_RTT_EXPIRY;
+            if expired || self.min_rtt.is_none_or(|min_rtt| rtt <= min_rtt) {
+                self.min_rtt = Some(rtt);
+                self.min_rtt_stamp = now;
+            }
+        }
+
+        self.update_mode(sample, round_start, now);
+    }
+
+    // Losses are not taken as a sign of congestion; the model of the path
+    // alone sets the rate.
+    fn on_fast_retransmit(&mut self, _in_flight: usize) {}
+
+    fn on_timeout(&mut self, _in_flight: usize) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    fn ack(acked: usize, in_flight: usize, delivered: usize) -> AckSample {
+        AckSample {
+            acked,
+            in_flight,
+            rtt: Some(Duration::from_millis(10)),
+            retransmitted: false,
+            delivery_rate: None,
+            prior_delivered: delivered - acked,
+            delivered,
+        }
+    }
+
+    #[test]
+    fn reno_halves_window_on_loss() {
+        let mut reno = Reno::new(MSS);
+        let now = Instant::now();
+        assert_eq!(reno.window(), INITIAL_WINDOW_SEGMENTS * MSS);
+
+        // Slow start grows the window by a segment per segment acked.
+        for i in 1..=4 {
+            reno.on_ack(&ack(MSS, 0, i * MSS), now);
+        }
+        assert_eq!(reno.window(), 8 * MSS);
+
+        reno.on_fast_retransmit(8 * MSS);
+        assert_eq!(reno.window(), 4 * MSS);
+
+        // Congestion avoidance grows it by about a segment per window.
+        for i in 5..=8 {
+            reno.on_ack(&ack(MSS, 0, i * MSS), now);
+        }
+        assert!((4 * MSS..5 * MSS).contains(&reno.window()));
+
+        reno.on_timeout(4 * MSS);
+        assert_eq!(reno.window(), MSS);
+    }
+
+    #[test]
+    fn reno_undoes_spurious_timeout() {
+        let mut reno = Reno::new(MSS);
+        let now = Instant::now();
+        reno.on_fast_retransmit(8 * MSS);
+        let (cwnd, ssthresh) = (reno.cwnd, reno.ssthresh);
+
+        reno.on_timeout(4 * MSS);
+        reno.on_ack(&ack(MSS, 3 * MSS, MSS), now);
+        reno.on_spurious_timeout();
+        assert_eq!((reno.cwnd, reno.ssthresh), (cwnd, ssthresh));
+
+        // Only the last timeout is undone, and only once.
+        reno.on_timeout(4 * MSS);
+        reno.on_spurious_timeout();
+        reno.on_spurious_timeout();
+        assert_eq!(reno.window(), cwnd);
+    }
+
+    #[test]
+    fn bbr_paces_at_bottleneck_bandwidth() {
+        let mut bbr = Bbr::new(MSS);
+        let start = Instant::now();
+        assert_eq!(bbr.pacing_rate(), None);
+
+        // Every round trip delivers 1 MB/s, however fast data is sent.
+        let rtt = Duration::from_millis(10);
+        let bw = 1_000_000.0;
+        let mut delivered = 0;
+        for round in 1..=20u32 {
+            let acked = (bw * rtt.as_secs_f64()) as usize;
+            let sample = AckSample {
+                acked,
+                in_flight: 0,
+                rtt: Some(rtt),
+                retransmitted: false,
+                delivery_rate: Some(bw),
+                prior_delivered: delivered,
+                delivered: delivered + acked,
+            };
+            delivered += acked;
+            bbr.on_ack(&sample, start + rtt * round);
+        }
+
+        assert!(matches!(bbr.mode, BbrMode::ProbeBw { .. }));
+        let pacing_rate = bbr.pacing_rate().unwrap();
+        assert!((0.75 * bw..=1.25 * bw).contains(&pacing_rate));
+        assert_eq!(bbr.window(), (BBR_CWND_GAIN * bw * 0.01) as usize);
+    }
+}
@@ -0,0 +1,102 @@
+//! TCP Fast Open (RFC 7413).
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::RwLock;
+
+use md5::{Digest, Md5};
+use rand::{thread_rng, Rng};
+
+use super::options::{self, TCP_OPTION_NOOP};
+use crate::utils::constant_time_eq;
+
+/// The option kind of the Fast Open cookie option.
+pub const TCP_OPTION_FAST_OPEN: u8 = 34;
+
+const COOKIE_LEN: usize = 8;
+const SECRET_LEN: usize = 16;
+
+/// Fast Open state of a TCP stack: the secret this node's cookies are
+/// derived from as a server, and the cookies it holds as a client.
+#[derive(Debug)]
+pub struct FastOpen {
+    secret: [u8; SECRET_LEN],
+    cookies: RwLock<HashMap<Ipv4Addr, Vec<u8>>>,
+}
+
+impl Default for FastOpen {
+    fn default() -> Self {
+        Self {
+            secret: thread_rng().gen(),
+            cookies: RwLock::default(),
+        }
+    }
+}
+
+impl FastOpen {
+    /// The cookie this server hands out to a client.
+    pub fn cookie_for(&self, client: Ipv4Addr) -> Vec<u8> {
+        let mut hasher = Md5::new();
+        hasher.update(self.secret);
+        hasher.update(client.octets());
+        hasher.finalize()[..COOKIE_LEN].to_vec()
+    }
+
+    /// Whether a client presented the cookie this server handed out to it.
+    pub fn is_valid_cookie(&self, client: Ipv4Addr, cookie: &[u8]) -> bool {
+        constant_time_eq(cookie, &self.cookie_for(client))
+    }
+
+    /// The cookie a server handed out to this client, if any.
+    pub fn cached_cookie(&self, server: Ipv4Addr) -> Option<Vec<u8>> {
+        self.cookies.read().unwrap().get(&server).cloned()
+    }
+
+    pub fn cache_cookie(&self, server: Ipv4Addr, cookie: &[u8]) {
+        self.cookies
+            .write()
+            .unwrap()
+            .insert(server, cookie.to_vec());
+    }
+}
+
+/// Lays out a Fast Open option carrying `cookie`, padded to a multiple of 4
+/// bytes. An empty cookie requests one from the server.
+pub fn cookie_option(cookie: &[u8]) -> Vec<u8> {
+    let mut options = vec![TCP_OPTION_FAST_OPEN, (cookie.len() + 2) as u8];
+    options.extend_from_slice(cookie);
+    while options.len() % 4 != 0 {
+        options.insert(0, TCP_OPTION_NOOP);
+    }
+    options
+}
+
+/// Finds the cookie of a Fast Open option. An empty cookie is a cookie
+/// request.
+pub fn find_cookie(options: &[u8]) -> Option<&[u8]> {
+    options::find_option(options, TCP_OPTION_FAST_OPEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::protocol::tcp::options::TCP_OPTION_END;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+
+    #[test]
+    fn cookie_round_trip() {
+        let fast_open = FastOpen::default();
+        let cookie = fast_open.cookie_for(CLIENT);
+
+        let options = cookie_option(&cookie);
+        assert_eq!(options.len() % 4, 0);
+        assert_eq!(find_cookie(&options), Some(&cookie[..]));
+        assert!(fast_open.is_valid_cookie(CLIENT, &cookie));
+        assert!(!fast_open.is_valid_cookie(Ipv4Addr::new(192, 168, 0, 2), &cookie));
+
+        assert_eq!(find_cookie(&cookie_option(&[])), Some(&[][..]));
+        assert_eq!(find_cookie(&[TCP_OPTION_NOOP, TCP_OPTION_END]), None);
+    }
+}
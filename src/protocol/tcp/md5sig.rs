@@ -0,0 +1,186 @@
+//! The TCP MD5 signature option (RFC 2385).
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::RwLock;
+
+use etherparse::{TcpHeader, TcpHeaderSlice};
+use md5::{Digest, Md5};
+
+use super::options::{self, TCP_OPTION_NOOP};
+use crate::utils::constant_time_eq;
+
+/// The option kind of the MD5 signature option.
+pub const TCP_OPTION_MD5_SIG: u8 = 19;
+
+const MD5_OPTION_LEN: usize = 18;
+const MD5_DIGEST_LEN: usize = 16;
+const TCP_FIXED_HEADER_LEN: usize = 20;
+const TCP_PROTOCOL_NO: u8 = 6;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Md5Error {
+    /// A key is configured for the peer, but the segment is not signed.
+    MissingSignature,
+    /// The segment is signed, but no key is configured for the peer.
+    UnexpectedSignature,
+    /// The signature does not match the segment.
+    BadSignature,
+    /// The segment is not a valid TCP segment, or has no room for the option.
+    Malformed,
+}
+
+/// MD5 signature keys, by peer address.
+#[derive(Debug, Default)]
+pub struct Md5Keys(RwLock<HashMap<Ipv4Addr, Vec<u8>>>);
+
+impl Md5Keys {
+    /// Sets or (with `None`) removes the key shared with a peer.
+    pub fn set(&self, peer: Ipv4Addr, key: Option<Vec<u8>>) {
+        let mut keys = self.0.write().unwrap();
+        match key {
+            Some(key) => keys.insert(peer, key),
+            None => keys.remove(&peer),
+        };
+    }
+
+    pub fn get(&self, peer: Ipv4Addr) -> Option<Vec<u8>> {
+        self.0.read().unwrap().get(&peer).cloned()
+    }
+}
+
+/// Adds an MD5 signature option to a TCP segment, updating its checksum.
+pub fn sign_segment(
+    segment: &[u8],
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    key: &[u8],
+) -> Result<Vec<u8>, Md5Error> {
+    let tcp_header = TcpHeaderSlice::from_slice(segment).map_err(|_| Md5Error::Malformed)?;
+    let payload = &segment[tcp_header.slice().len()..];
+
+    // The signature is computed over the final header length, so lay out the
+    // option before filling in the digest.
+    let mut options = vec![
+        TCP_OPTION_NOOP,
+        TCP_OPTION_NOOP,
+        TCP_OPTION_MD5_SIG,
+        MD5_OPTION_LEN as u8,
+    ];
+    let digest_start = options.len();
+    options.extend_from_slice(&[0; MD5_DIGEST_LEN]);
+    options.extend_from_slice(tcp_header.options());
+
+    let mut header = tcp_header.to_header();
+    header
+        .set_options_raw(&options)
+        .map_err(|_| Md5Error::Malformed)?;
+    let digest = compute_digest(&header_bytes(&header), payload, src, dst, key);
+
+    options[digest_start..digest_start + MD5_DIGEST_LEN].copy_from_slice(&digest);
+    header
+        .set_options_raw(&options)
+        .map_err(|_| Md5Error::Malformed)?;
+    header.checksum = header
+        .calc_checksum_ipv4_raw(src.octets(), dst.octets(), payload)
+        .map_err(|_| Md5Error::Malformed)?;
+
+    let mut bytes = header_bytes(&header);
+    bytes.extend_from_slice(payload);
+    Ok(bytes)
+}
+
+/// Checks a received segment's MD5 signature against the key shared with its
+/// sender, if any.
+pub fn verify_segment(
+    tcp_header: &TcpHeaderSlice<'_>,
+    payload: &[u8],
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    key: Option<&[u8]>,
+) -> Result<(), Md5Error> {
+    let signature = options::find_option(tcp_header.options(), TCP_OPTION_MD5_SIG);
+    match (signature, key) {
+        (None, None) => Ok(()),
+        (None, Some(_)) => Err(Md5Error::MissingSignature),
+        (Some(_), None) => Err(Md5Error::UnexpectedSignature),
+        (Some(signature), Some(key)) => {
+            let digest = compute_digest(tcp_header.slice(), payload, src, dst, key);
+            if constant_time_eq(&digest, signature) {
+                Ok(())
+            } else {
+                Err(Md5Error::BadSignature)
+            }
+        }
+    }
+}
+
+/// Digests, in order: the IPv4 pseudo-header, the fixed TCP header with a
+/// zero checksum, the payload, and the key.
+fn compute_digest(
+    header: &[u8],
+    payload: &[u8],
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    key: &[u8],
+) -> [u8; MD5_DIGEST_LEN] {
+    let segment_len: u16 = (header.len() + payload.len()).try_into().unwrap();
+    let mut fixed_header = [0; TCP_FIXED_HEADER_LEN];
+    fixed_header.copy_from_slice(&header[..TCP_FIXED_HEADER_LEN]);
+    fixed_header[16..18].fill(0);
+
+    let mut hasher = Md5::new();
+    hasher.update(src.octets());
+    hasher.update(dst.octets());
+    hasher.update([0, TCP_PROTOCOL_NO]);
+    hasher.update(segment_len.to_be_bytes());
+    hasher.update(fixed_header);
+    hasher.update(payload);
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+fn header_bytes(header: &TcpHeader) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    header.write(&mut bytes).unwrap();
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const DST: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+    #[test]
+    fn sign_and_verify() {
+        let mut segment = Vec::new();
+        TcpHeader::new(1000, 2000, 42, 1024)
+            .write(&mut segment)
+            .unwrap();
+        segment.extend_from_slice(b"hello");
+
+        let signed = sign_segment(&segment, SRC, DST, b"secret").unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&signed).unwrap();
+        let payload = &signed[tcp_header.slice().len()..];
+        assert_eq!(payload, b"hello");
+        assert_eq!(
+            tcp_header.checksum(),
+            tcp_header
+                .calc_checksum_ipv4_raw(SRC.octets(), DST.octets(), payload)
+                .unwrap()
+        );
+
+        let verify = |key: Option<&[u8]>, payload: &[u8]| {
+            verify_segment(&tcp_header, payload, SRC, DST, key)
+        };
+        assert_eq!(verify(Some(b"secret"), payload), Ok(()));
+        assert_eq!(
+            verify(Some(b"secret"), b"jello"),
+            Err(Md5Error::BadSignature)
+        );
+        assert_eq!(verify(Some(b"other"), payload), Err(Md5Error::BadSignature));
+        assert_eq!(verify(None, payload), Err(Md5Error::UnexpectedSignature));
+    }
+}
@@ -1,30 +1,45 @@
-mod ack_policy;
+pub mod ack_policy;
 #[allow(dead_code)]
 mod buf;
+pub mod congestion;
+pub mod fastopen;
+pub mod md5sig;
+pub mod options;
 pub mod prelude;
 mod socket;
 mod transport;
 
-use std::collections::HashMap;
-use std::ops::Deref;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::ops::{Deref, RangeInclusive};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 use std::usize;
 
 use crate::drop_policy::DropPolicy;
 use crate::net::Net;
+use crate::node_log;
 use crate::protocol::tcp::socket::UpdateAction;
-use crate::{net::vtlink::VtLinkNet, protocol::ProtocolHandler};
+use crate::utils::logging::NodeLogger;
+use crate::{
+    net::vtlink::VtLinkNet,
+    protocol::{Protocol, ProtocolHandler},
+};
 use async_trait::async_trait;
-use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use etherparse::{Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use log::Level;
 use socket::Socket;
-pub use socket::{TcpConn, TcpListener};
+pub use socket::{AcceptPermit, Incoming, LingerConfig, SocketStatus, TcpConn, TcpListener};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::{RwLock, RwLockReadGuard};
+use tokio::sync::{mpsc, Notify, RwLock, RwLockReadGuard};
+pub use transport::{KeepaliveConfig, SocketStats};
 
+use self::congestion::CongestionAlgorithm;
+use self::fastopen::FastOpen;
 use self::prelude::{Port, Remote, SocketDescriptor, SocketId};
-use self::socket::{SocketStatus, SynReceived, TransportError};
+use self::socket::{SynReceived, TransportError};
 
 pub const TCP_DEFAULT_WINDOW_SZ: usize = (1 << 16) - 1;
 
@@ -37,16 +52,143 @@ pub const MAX_PENDING_TCP_CONNECTIONS: usize = 1024;
 
 pub const TCP_DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
 
+// The maximum number of challenge ACKs (RFC 5961) a connection sends within
+// each TCP_CHALLENGE_ACK_INTERVAL.
+pub const TCP_CHALLENGE_ACK_LIMIT: usize = 10;
+
+pub const TCP_CHALLENGE_ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+// The default maximum number of bytes `TcpConn::read_to_end` accumulates.
+pub const TCP_DEFAULT_READ_TO_END_LIMIT: usize = 1 << 28;
+
+// The sliding window over which a connection's throughput is measured.
+pub const TCP_THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+
+// The shortest span of acks a delivery rate sample is taken over.
+pub const TCP_DELIVERY_RATE_INTERVAL: Duration = Duration::from_millis(20);
+
+// How long a delivery rate sample counts towards a connection's bandwidth
+// estimate.
+pub const TCP_BANDWIDTH_WINDOW: Duration = Duration::from_secs(2);
+
+// The maximum segment lifetime. A socket stays in TIME_WAIT for twice this
+// long before it is removed.
+pub const TCP_DEFAULT_MSL: Duration = Duration::from_secs(30);
+
+// The window shift advertised in the window scale option. The receive buffer
+// fits in an unscaled window.
+pub const TCP_WINDOW_SHIFT: u8 = 0;
+
+// How long a received segment may go unacked, waiting for another segment to
+// share its ACK.
+pub const TCP_DEFAULT_DELAYED_ACK_INTERVAL: Duration = Duration::from_millis(40);
+
+// The longest an ACK may be delayed.
+pub const TCP_MAX_DELAYED_ACK_INTERVAL: Duration = Duration::from_millis(200);
+
+// How often a shutdown checks whether every FIN has been acked.
+pub const TCP_SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// The local ports handed out to outbound connections.
+pub const TCP_DEFAULT_EPHEMERAL_PORTS: RangeInclusive<u16> = 1024..=u16::MAX;
+
+// Segments failing their checksum are logged as a count, at most once per
+// interval, so that a corrupting link cannot flood the log.
+pub const TCP_CHECKSUM_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How a TCP stack's connections behave.
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    /// The maximum segment lifetime. Closed connections linger in TIME_WAIT
+    /// for twice this long.
+    pub msl: Duration,
+    /// The local ports handed out to outbound connections.
+    pub ephemeral_ports: RangeInclusive<u16>,
+    /// How long a send may go without the remote acknowledging anything
+    /// before the connection is reset. `None` lets sends block indefinitely.
+    pub send_stall_timeout: Option<Duration>,
+    /// The retransmission timeout connections start with, before any
+    /// round-trip time is measured. `None` uses the default.
+    pub initial_rto: Option<Duration>,
+    /// How long connections may delay acking received data. `None` acks each
+    /// segment as it arrives.
+    pub delayed_ack_interval: Option<Duration>,
+    /// Whether SYNs may carry data, using TCP Fast Open cookies.
+    pub fast_open: bool,
+    /// The congestion controller connections are created with.
+    pub congestion_control: CongestionAlgorithm,
+    /// Whether connections space their segments out over a round trip rather
+    /// than sending a window's worth at once.
+    pub pacing: bool,
+    /// The most distinct ranges of out-of-order data a connection keeps.
+    /// `None` keeps as many as its receive window holds.
+    pub max_out_of_order_ranges: Option<usize>,
+    /// The keepalive settings connections start with. `None` leaves idle
+    /// connections unprobed.
+    pub keepalive: Option<KeepaliveConfig>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            msl: TCP_DEFAULT_MSL,
+            ephemeral_ports: TCP_DEFAULT_EPHEMERAL_PORTS,
+            send_stall_timeout: None,
+            initial_rto: None,
+            delayed_ack_interval: Some(TCP_DEFAULT_DELAYED_ACK_INTERVAL),
+            fast_open: false,
+            congestion_control: CongestionAlgorithm::default(),
+            pacing: false,
+            max_out_of_order_ranges: None,
+            keepalive: None,
+        }
+    }
+}
+
+/// A stack's configuration as its sockets see it, along with the state they
+/// share.
+pub(crate) struct SocketConfig {
+    tcp: TcpConfig,
+    /// Fast Open state, present when Fast Open is enabled.
+    fast_open: Option<FastOpen>,
+}
+
+impl SocketConfig {
+    fn new(tcp: TcpConfig) -> Self {
+        Self {
+            fast_open: tcp.fast_open.then(FastOpen::default),
+            tcp,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum TcpConnError {
     ConnectionExists(Remote),
     Transport(TransportError),
     Timeout,
+    /// The connection attempt was abandoned before it completed.
+    Aborted,
+    /// Every ephemeral port is taken by an open socket.
+    NoEphemeralPorts,
+    /// The remote reset the connection attempt, as nothing listens on its
+    /// port.
+    Refused,
+    /// The requested local port already carries a connection to the same
+    /// remote.
+    LocalPortInUse(Port),
+    /// The data to send on connecting, of the given length, does not fit in
+    /// the send buffer.
+    DataTooLarge(usize),
 }
 
 #[derive(Debug)]
 pub enum TcpListenError {
     PortOccupied(Port),
+    /// The IP to listen on is not one of the node's interfaces.
+    AddrNotAvailable(Ipv4Addr),
+    /// The interface to listen on is down.
+    InterfaceDown(Ipv4Addr),
 }
 
 #[derive(Debug)]
@@ -57,8 +199,13 @@ pub enum TcpAcceptError {
 #[derive(Debug, PartialEq, Eq)]
 pub enum TcpSendError {
     NoSocket(SocketDescriptor),
+    /// The socket is a listener, which cannot carry data.
+    NotAConnection,
     ConnNotEstablished,
     ConnClosed,
+    /// The remote acknowledged nothing for the send stall timeout, and the
+    /// connection was reset.
+    StallTimeout,
 }
 
 #[derive(Debug)]
@@ -68,6 +215,10 @@ pub enum TcpReadError {
     /// Returns the number of bytes written into the buffer.
     Closed(usize),
     ConnNotEstablished,
+    /// The remote sent more than the given limit before closing.
+    ExceedsLimit(usize),
+    /// No bytes are buffered yet, and a non-blocking read would have waited.
+    WouldBlock,
 }
 
 #[derive(Debug)]
@@ -75,40 +226,167 @@ pub enum TcpCloseError {
     NoSocketOnDescriptor(SocketDescriptor),
     NoSocketOnId(SocketId),
     AlreadyClosed,
+    /// The socket was not removed within the time limit.
+    Timeout,
 }
 
 /// A TCP stack.
 pub struct Tcp<N: Net + 'static> {
     sockets: RwLock<SocketTable<N>>,
+    msl: Duration,
+    socket_removed: Notify,
+    checksum_failures: ChecksumFailures,
+    logger: NodeLogger,
 }
 
 impl<N: Net> Tcp<N> {
-    pub fn new(net: Arc<N>) -> Self {
-        let sockets = RwLock::new(SocketTable::new(net));
-        Tcp { sockets }
+    pub fn new(net: Arc<N>, config: TcpConfig) -> Arc<Self> {
+        let msl = config.msl;
+        let logger = net.logger();
+        let (updates_tx, updates_rx) = mpsc::unbounded_channel();
+        let sockets = RwLock::new(SocketTable::new(net, config, updates_tx));
+        Arc::new_cyclic(|tcp| {
+            tokio::spawn(Self::apply_socket_updates(tcp.clone(), updates_rx));
+            Tcp {
+                sockets,
+                msl,
+                socket_removed: Notify::new(),
+                checksum_failures: ChecksumFailures::default(),
+                logger,
+            }
+        })
+    }
+
+    /// Carries out the updates sockets ask for on their own, such as closing
+    /// once their connection is given up on. Stops with the stack.
+    async fn apply_socket_updates(
+        tcp: Weak<Self>,
+        mut updates: mpsc::UnboundedReceiver<UpdateAction<N>>,
+    ) {
+        while let Some(action) = updates.recv().await {
+            let Some(tcp) = tcp.upgrade() else {
+                return;
+            };
+            tcp.apply_update(action).await;
+        }
+    }
+
+    async fn apply_update(self: &Arc<Self>, action: UpdateAction<N>) {
+        match action {
+            UpdateAction::NewSynReceivedSocket(syn_recvd) => {
+                self.sockets
+                    .write()
+                    .await
+                    .add_new_syn_recvd_socket(syn_recvd)
+                    .unwrap();
+            }
+            UpdateAction::CloseSocket(id) => self.remove_socket(id).await,
+            UpdateAction::CloseSocketAfterTimeWait(id) => self.remove_socket_after_time_wait(id),
+        }
+    }
+
+    /// The segments dropped so far because they failed their checksum.
+    pub fn checksum_failures(&self) -> usize {
+        self.checksum_failures.count()
     }
 
     /// Attempts to connect to a host, establishing the client side of a TCP connection.
     pub async fn connect(&self, remote: Remote) -> Result<TcpConn, TcpConnError> {
+        self.connect_with_data(remote, &[]).await
+    }
+
+    /// Like `connect`, but gives up with `TcpConnError::Timeout` if the
+    /// connection is not established within `timeout`, rather than
+    /// `TCP_DEFAULT_CONNECTION_TIMEOUT`.
+    pub async fn connect_with_timeout(
+        &self,
+        remote: Remote,
+        timeout: Duration,
+    ) -> Result<TcpConn, TcpConnError> {
+        self.open_connection(None, remote, &[], timeout, None).await
+    }
+
+    /// Like `connect`, but from `local_port` rather than the next free
+    /// ephemeral port. The port may carry connections to other remotes.
+    pub async fn connect_from(
+        &self,
+        local_port: Port,
+        remote: Remote,
+    ) -> Result<TcpConn, TcpConnError> {
+        self.open_connection(
+            Some(local_port),
+            remote,
+            &[],
+            TCP_DEFAULT_CONNECTION_TIMEOUT,
+            None,
+        )
+        .await
+    }
+
+    /// Connects to a host, sending `data` as the first bytes of the connection.
+    ///
+    /// With TCP Fast Open, and a cookie from a prior connection to the host,
+    /// the first segment of `data` rides in the SYN. Whatever the SYN does not
+    /// carry is sent once the connection is established. Fails with
+    /// `TcpConnError::DataTooLarge` if `data` does not fit in the send buffer.
+    pub async fn connect_with_data(
+        &self,
+        remote: Remote,
+        data: &[u8],
+    ) -> Result<TcpConn, TcpConnError> {
+        self.open_connection(None, remote, data, TCP_DEFAULT_CONNECTION_TIMEOUT, None)
+            .await
+    }
+
+    /// Like `connect`, but signs the connection's segments with `key`, which
+    /// the remote shares (RFC 2385). Segments from the remote that are not
+    /// signed with it are dropped.
+    pub async fn connect_with_md5_key(
+        &self,
+        remote: Remote,
+        key: Vec<u8>,
+    ) -> Result<TcpConn, TcpConnError> {
+        self.open_connection(None, remote, &[], TCP_DEFAULT_CONNECTION_TIMEOUT, Some(key))
+            .await
+    }
+
+    async fn open_connection(
+        &self,
+        local_port: Option<Port>,
+        remote: Remote,
+        data: &[u8],
+        timeout: Duration,
+        md5_key: Option<Vec<u8>>,
+    ) -> Result<TcpConn, TcpConnError> {
+        if data.len() > TCP_DEFAULT_WINDOW_SZ {
+            return Err(TcpConnError::DataTooLarge(data.len()));
+        }
         let mut sockets = self.sockets.write().await;
-        let socket = sockets.add_new_socket(remote).map_err(|e| match e {
-            AddSocketError::ConnectionExists(sid) => TcpConnError::ConnectionExists(sid.remote()),
+        let socket = match local_port {
+            Some(port) => sockets.add_new_socket_from(port, remote),
+            None => sockets.add_new_socket(remote),
+        }
+        .map_err(|e| match (e, local_port) {
+            (AddSocketError::ConnectionExists(_), Some(port)) => TcpConnError::LocalPortInUse(port),
+            (AddSocketError::ConnectionExists(sid), None) => {
+                TcpConnError::ConnectionExists(sid.remote())
+            }
+            (AddSocketError::NoEphemeralPorts, _) => TcpConnError::NoEphemeralPorts,
         })?;
 
         let socket_id = socket.id();
-        let on_connected = socket
-            .initiate_connection()
-            .await
-            .expect("Failed to send SYN packet");
+        socket.set_md5_key(remote.ip(), md5_key);
+        let on_connected = socket.initiate_connection(data, timeout).await;
         drop(sockets);
 
-        match on_connected
-            .await
-            .expect("Failed to receive connection status")
-        {
+        let connected = match on_connected {
+            Ok(on_connected) => on_connected.await.unwrap_or(Err(TcpConnError::Aborted)),
+            Err(e) => Err(e),
+        };
+        match connected {
             Ok(r) => Ok(r),
             Err(e) => {
-                self.sockets.write().await.remove_by_id(socket_id);
+                self.remove_socket(socket_id).await;
                 Err(e)
             }
         }
@@ -116,10 +394,25 @@ impl<N: Net> Tcp<N> {
 
     /// Starts listening for incoming connections at a port. Opens a listener socket.
     pub async fn listen(&self, port: Port) -> Result<TcpListener, TcpListenError> {
+        self.listen_on(Ipv4Addr::UNSPECIFIED, port).await
+    }
+
+    /// Starts listening for incoming connections at a port, accepting only
+    /// those addressed to interface IP `ip`.
+    ///
+    /// Listeners bound to different IPs may share a port. A connection
+    /// prefers the listener bound to its destination IP over one listening
+    /// on every interface.
+    pub async fn listen_on(&self, ip: Ipv4Addr, port: Port) -> Result<TcpListener, TcpListenError> {
         let mut sockets = self.sockets.write().await;
-        let socket = sockets.add_new_listen_socket(port).map_err(|e| match e {
-            AddSocketError::ConnectionExists(sid) => TcpListenError::PortOccupied(sid.local_port()),
-        })?;
+        let socket = sockets
+            .add_new_listen_socket(ip, port)
+            .map_err(|e| match e {
+                AddSocketError::ConnectionExists(sid) => {
+                    TcpListenError::PortOccupied(sid.local_port())
+                }
+                AddSocketError::NoEphemeralPorts => unreachable!(),
+            })?;
         Ok(socket.listen(port).await.unwrap())
     }
 
@@ -146,6 +439,10 @@ impl<N: Net> Tcp<N> {
             .get_socket_by_descriptor(socket_descriptor)
             .ok_or(TcpReadError::NoSocket(socket_descriptor))?;
 
+        if n_bytes == 0 {
+            return Ok(Vec::new());
+        }
+
         if socket
             .is_read_closed()
             .await
@@ -160,6 +457,24 @@ impl<N: Net> Tcp<N> {
         Ok(out_buf)
     }
 
+    /// Reads up to `n_bytes` bytes that are already buffered on a socket,
+    /// without waiting for more.
+    pub async fn try_read_on_socket_descriptor(
+        &self,
+        socket_descriptor: SocketDescriptor,
+        n_bytes: usize,
+    ) -> Result<Vec<u8>, TcpReadError> {
+        let sockets = self.sockets.read().await;
+        let socket = sockets
+            .get_socket_by_descriptor(socket_descriptor)
+            .ok_or(TcpReadError::NoSocket(socket_descriptor))?;
+
+        let mut out_buf = vec![0; n_bytes];
+        let n = socket.try_read(&mut out_buf).await?;
+        out_buf.truncate(n);
+        Ok(out_buf)
+    }
+
     pub async fn get_socket(&self, socket_id: SocketId) -> Option<SocketRef<'_, N>> {
         let table = self.sockets.read().await;
         let socket: *const Socket<N> = table.socket_map.get(&socket_id)?;
@@ -187,16 +502,170 @@ impl<N: Net> Tcp<N> {
         table.socket_map.get(&socket_id).map(|s| s.descriptor())
     }
 
+    pub async fn socket_id_for_descriptor(
+        &self,
+        socket_descriptor: SocketDescriptor,
+    ) -> Option<SocketId> {
+        let table = self.sockets.read().await;
+        table
+            .get_socket_by_descriptor(socket_descriptor)
+            .map(|s| s.id())
+    }
+
+    /// A snapshot of every socket, ordered by descriptor. It is taken under a
+    /// single read of the socket table, so no socket is seen half-opened or
+    /// half-removed.
+    pub async fn list_sockets(&self) -> Vec<(SocketDescriptor, SocketId, SocketStatus)> {
+        let table = self.sockets.read().await;
+        let mut sockets = Vec::with_capacity(table.socket_map.len());
+        for socket in table.socket_map.values() {
+            sockets.push((socket.descriptor(), socket.id(), socket.status().await));
+        }
+        sockets.sort_by_key(|(descriptor, _, _)| descriptor.0);
+        sockets
+    }
+
+    /// The sockets connected to a remote IP, on any port, in the order they
+    /// were opened.
+    pub async fn connections_to(&self, ip: Ipv4Addr) -> Vec<SocketId> {
+        let table = self.sockets.read().await;
+        let mut sockets: Vec<_> = table
+            .socket_map
+            .values()
+            .filter(|s| s.remote_ip() == ip)
+            .collect();
+        sockets.sort_by_key(|s| s.descriptor().0);
+        sockets.into_iter().map(|s| s.id()).collect()
+    }
+
+    /// The (send, receive) bytes buffered across all connections.
+    pub async fn buffered_bytes(&self) -> (usize, usize) {
+        let table = self.sockets.read().await;
+        let mut buffered = (0, 0);
+        for socket in table.socket_map.values() {
+            if let Some((send, recv)) = socket.buffered_bytes().await {
+                buffered.0 += send;
+                buffered.1 += recv;
+            }
+        }
+        buffered
+    }
+
     pub async fn close(&self, socket_id: SocketId) -> Result<(), TcpCloseError> {
         let table = self.sockets.read().await;
         let sock = table
             .get_socket_by_id(socket_id)
             .ok_or(TcpCloseError::NoSocketOnId(socket_id))?;
 
-        sock.close().await;
+        if sock.linger() == LingerConfig::Abort && sock.abort().await {
+            drop(table);
+            self.remove_socket(socket_id).await;
+        } else {
+            sock.close().await;
+        }
         Ok(())
     }
 
+    /// Closes a socket, and waits until it is fully closed and removed,
+    /// including any time spent in TIME_WAIT.
+    pub async fn close_and_wait(
+        &self,
+        socket_id: SocketId,
+        timeout: Duration,
+    ) -> Result<(), TcpCloseError> {
+        // Register interest before closing so that a removal is not missed.
+        let removed = self.socket_removed.notified();
+        tokio::pin!(removed);
+        removed.as_mut().enable();
+
+        let table = self.sockets.read().await;
+        let sock = table
+            .get_socket_by_id(socket_id)
+            .ok_or(TcpCloseError::NoSocketOnId(socket_id))?;
+        if matches!(sock.status().await, SocketStatus::Listen) {
+            drop(table);
+            self.remove_socket(socket_id).await;
+            return Ok(());
+        }
+        sock.close().await;
+        drop(table);
+
+        tokio::time::timeout(timeout, async {
+            loop {
+                if self
+                    .sockets
+                    .read()
+                    .await
+                    .get_socket_by_id(socket_id)
+                    .is_none()
+                {
+                    return;
+                }
+                removed.as_mut().await;
+                removed.set(self.socket_removed.notified());
+                removed.as_mut().enable();
+            }
+        })
+        .await
+        .map_err(|_| TcpCloseError::Timeout)
+    }
+
+    /// Removes every listening socket and closes every connection, then
+    /// waits up to `timeout` for the remotes to ack the FINs.
+    pub async fn shutdown(&self, timeout: Duration) {
+        let ids: Vec<_> = self
+            .sockets
+            .read()
+            .await
+            .socket_map
+            .keys()
+            .copied()
+            .collect();
+        for id in ids {
+            let table = self.sockets.read().await;
+            let Some(sock) = table.get_socket_by_id(id) else {
+                continue;
+            };
+            match sock.status().await {
+                SocketStatus::Listen => {
+                    drop(table);
+                    self.remove_socket(id).await;
+                }
+                SocketStatus::Established | SocketStatus::CloseWait => sock.close().await,
+                _ => {}
+            }
+        }
+
+        let fins_acked = async {
+            while self.fins_in_flight().await > 0 {
+                tokio::time::sleep(TCP_SHUTDOWN_POLL_INTERVAL).await;
+            }
+        };
+        if tokio::time::timeout(timeout, fins_acked).await.is_err() {
+            node_log!(
+                self.logger,
+                Level::Warn,
+                "Shut down with FINs still unacked after {:?}",
+                timeout
+            );
+        }
+    }
+
+    /// The sockets whose FIN the remote has yet to ack.
+    async fn fins_in_flight(&self) -> usize {
+        let table = self.sockets.read().await;
+        let mut n = 0;
+        for socket in table.socket_map.values() {
+            if matches!(
+                socket.status().await,
+                SocketStatus::FinWait1 | SocketStatus::Closing | SocketStatus::LastAck
+            ) {
+                n += 1;
+            }
+        }
+        n
+    }
+
     pub async fn close_by_descriptor(
         &self,
         socket_descriptor: SocketDescriptor,
@@ -210,7 +679,11 @@ impl<N: Net> Tcp<N> {
             // For listen sockets, delete directly
             let sock_id = sock.id();
             drop(table);
-            self.sockets.write().await.remove_by_id(sock_id);
+            self.remove_socket(sock_id).await;
+        } else if sock.linger() == LingerConfig::Abort && sock.abort().await {
+            let sock_id = sock.id();
+            drop(table);
+            self.remove_socket(sock_id).await;
         } else {
             sock.close().await;
         }
@@ -218,25 +691,45 @@ impl<N: Net> Tcp<N> {
         Ok(())
     }
 
-    pub async fn print_sockets(&self, file: Option<String>) {
+    async fn remove_socket(&self, socket_id: SocketId) {
+        self.sockets.write().await.remove_by_id(socket_id);
+        self.socket_removed.notify_waiters();
+    }
+
+    /// Removes a socket that entered TIME_WAIT once 2 * MSL has elapsed.
+    fn remove_socket_after_time_wait(self: &Arc<Self>, socket_id: SocketId) {
+        let tcp = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(2 * tcp.msl).await;
+            tcp.remove_socket(socket_id).await;
+        });
+    }
+
+    /// Prints the socket table, to `file` if given. `verbose` adds a line of
+    /// statistics under each connected socket.
+    pub async fn print_sockets(&self, file: Option<String>, verbose: bool) {
+        let header = "id\tstate\t\tlocal window size\tremote window size";
+        let mut lines = vec![header.to_string()];
+        let table = self.sockets.read().await;
+        for (_, socket) in table.socket_map.iter() {
+            lines.push(socket.as_table_entry_string().await);
+            if verbose {
+                if let Some(summary) = socket.stats_summary().await {
+                    lines.push(format!("\t{summary}"));
+                }
+            }
+        }
+
         match file {
             Some(file) => {
                 let mut f = File::create(file).await.unwrap();
-                f.write_all(b"id\tstate\tlocal window size\tremote window size\n")
-                    .await
-                    .unwrap();
-                let table = self.sockets.read().await;
-                for (_, socket) in table.socket_map.iter() {
-                    f.write_all(socket.as_table_entry_string().await.as_bytes())
-                        .await
-                        .unwrap();
+                for line in lines {
+                    f.write_all(format!("{line}\n").as_bytes()).await.unwrap();
                 }
             }
             None => {
-                println!("id\tstate\t\tlocal window size\tremote window size");
-                let table = self.sockets.read().await;
-                for (_, socket) in table.socket_map.iter() {
-                    println!("{}", socket.as_table_entry_string().await);
+                for line in lines {
+                    println!("{line}");
                 }
             }
         }
@@ -264,6 +757,7 @@ unsafe impl<'a, N: Net> Send for SocketRef<'a, N> {}
 #[derive(Debug)]
 pub enum AddSocketError {
     ConnectionExists(SocketId),
+    NoEphemeralPorts,
 }
 
 pub(crate) struct SocketTable<N: Net + 'static> {
@@ -273,16 +767,42 @@ pub(crate) struct SocketTable<N: Net + 'static> {
 }
 
 impl<N: Net> SocketTable<N> {
-    pub fn new(net: Arc<N>) -> Self {
+    pub fn new(
+        net: Arc<N>,
+        config: TcpConfig,
+        updates: mpsc::UnboundedSender<UpdateAction<N>>,
+    ) -> Self {
         Self {
-            socket_builder: SocketBuilder::new(net),
+            socket_builder: SocketBuilder::new(net, config, updates),
             socket_id_map: HashMap::new(),
             socket_map: HashMap::new(),
         }
     }
 
     pub fn add_new_socket(&mut self, remote: Remote) -> Result<&mut Socket<N>, AddSocketError> {
-        let sock_id = self.socket_builder.make_socket_id(remote);
+        let sock_id = self
+            .socket_builder
+            .make_socket_id(remote)
+            .ok_or(AddSocketError::NoEphemeralPorts)?;
+        self.add_outbound_socket(sock_id)
+    }
+
+    /// Adds an outbound socket to `remote` from `local_port`, which
+    /// ephemeral port allocation then skips until every socket on it is gone.
+    pub fn add_new_socket_from(
+        &mut self,
+        local_port: Port,
+        remote: Remote,
+    ) -> Result<&mut Socket<N>, AddSocketError> {
+        let sock_id = self.socket_builder.make_socket_id_from(local_port, remote);
+        self.add_outbound_socket(sock_id)
+    }
+
+    fn add_outbound_socket(&mut self, sock_id: SocketId) -> Result<&mut Socket<N>, AddSocketError> {
+        if self.socket_map.contains_key(&sock_id) {
+            self.release_port_if_unused(sock_id.local_port());
+            return Err(AddSocketError::ConnectionExists(sock_id));
+        }
         let (descriptor, socket) = self.socket_builder.build_with_id(sock_id);
 
         self.insert(descriptor, socket)
@@ -290,37 +810,39 @@ impl<N: Net> SocketTable<N> {
 
     pub fn add_new_listen_socket(
         &mut self,
+        local_ip: Ipv4Addr,
         local_port: Port,
     ) -> Result<&mut Socket<N>, AddSocketError> {
         let (descriptor, socket) = self
             .socket_builder
-            .build_with_id(SocketId::for_listen_socket(local_port));
+            .build_with_id(SocketId::for_listen_socket(local_ip, local_port));
 
         self.insert(descriptor, socket)
     }
 
     pub fn add_new_syn_recvd_socket(
         &mut self,
-        remote: Remote,
-        local_port: Port,
         syn_recvd_state: SynReceived<N>,
     ) -> Result<&mut Socket<N>, AddSocketError> {
-        let sock_id = SocketId::build()
-            .with_remote_ip(remote.ip())
-            .with_remote_port(remote.port())
-            .with_local_port(local_port)
-            .build()
-            .unwrap();
-
         let descriptor = self.socket_builder.allocate_socket_descriptor();
-        let s = syn_recvd_state.into_socket(sock_id, descriptor);
+        let s = syn_recvd_state.into_socket(descriptor, self.socket_builder.updates.clone());
 
         self.insert(descriptor, s)
     }
 
     pub fn remove_by_id(&mut self, id: SocketId) {
-        // TODO: lazily delete socket entries in socket_id_map
-        self.socket_map.remove(&id);
+        if let Some(socket) = self.socket_map.remove(&id) {
+            self.socket_id_map.remove(&socket.descriptor());
+            self.release_port_if_unused(id.local_port());
+        }
+    }
+
+    /// Hands `port` back to ephemeral port allocation, unless another socket
+    /// still uses it.
+    fn release_port_if_unused(&mut self, port: Port) {
+        if !self.socket_map.keys().any(|id| id.local_port() == port) {
+            self.socket_builder.release_port(port);
+        }
     }
 
     pub fn get_socket_by_id(&self, id: SocketId) -> Option<&Socket<N>> {
@@ -333,9 +855,13 @@ impl<N: Net> SocketTable<N> {
             .and_then(|sock_id| self.socket_map.get(sock_id))
     }
 
-    pub fn get_listener_socket(&self, port: Port) -> Option<&Socket<N>> {
-        let id = SocketId::for_listen_socket(port);
-        self.get_socket_by_id(id)
+    /// The listener for connections to `ip`:`port`, preferring one bound to
+    /// `ip` over one bound to every interface.
+    pub fn get_listener_socket(&self, ip: Ipv4Addr, port: Port) -> Option<&Socket<N>> {
+        self.get_socket_by_id(SocketId::for_listen_socket(ip, port))
+            .or_else(|| {
+                self.get_socket_by_id(SocketId::for_listen_socket(Ipv4Addr::UNSPECIFIED, port))
+            })
     }
 
     fn insert(
@@ -358,30 +884,97 @@ impl<N: Net> SocketTable<N> {
     }
 }
 
-struct SocketBuilder<N> {
+/// Counts segments that failed their checksum, logging the count at most once
+/// per `TCP_CHECKSUM_LOG_INTERVAL`.
+#[derive(Default)]
+struct ChecksumFailures {
+    count: AtomicUsize,
+    // When the count was last logged, and what it was then.
+    last_logged: Mutex<Option<(Instant, usize)>>,
+}
+
+impl ChecksumFailures {
+    fn record(&self, logger: NodeLogger) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut last_logged = self.last_logged.lock().unwrap();
+        let logged_count = match *last_logged {
+            Some((at, _)) if at.elapsed() < TCP_CHECKSUM_LOG_INTERVAL => return,
+            Some((_, logged_count)) => logged_count,
+            None => 0,
+        };
+        node_log!(
+            logger,
+            Level::Info,
+            "Dropped {} TCP segments that failed their checksum, {} in total",
+            count - logged_count,
+            count
+        );
+        *last_logged = Some((Instant::now(), count));
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+struct SocketBuilder<N: Net> {
     next_socket_descriptor: usize,
     next_port: u16,
+    ephemeral_ports: RangeInclusive<u16>,
+    // Ephemeral ports held by open sockets.
+    ports_in_use: HashSet<Port>,
     net: Arc<N>,
+    config: Arc<SocketConfig>,
+    updates: mpsc::UnboundedSender<UpdateAction<N>>,
 }
 
 impl<N: Net> SocketBuilder<N> {
-    fn new(net: Arc<N>) -> Self {
+    fn new(
+        net: Arc<N>,
+        config: TcpConfig,
+        updates: mpsc::UnboundedSender<UpdateAction<N>>,
+    ) -> Self {
+        let ephemeral_ports = config.ephemeral_ports.clone();
         Self {
             net,
-            next_port: 1024,
+            config: Arc::new(SocketConfig::new(config)),
+            updates,
+            next_port: *ephemeral_ports.start(),
+            ephemeral_ports,
+            ports_in_use: HashSet::new(),
             next_socket_descriptor: 0,
         }
     }
 
     fn build_with_id(&mut self, socket_id: SocketId) -> (SocketDescriptor, Socket<N>) {
         let descriptor = self.allocate_socket_descriptor();
-        let sock = Socket::new(socket_id, descriptor, self.net.clone());
+        let sock = Socket::new(
+            socket_id,
+            descriptor,
+            self.net.clone(),
+            self.config.clone(),
+            self.updates.clone(),
+        );
         (descriptor, sock)
     }
 
-    fn make_socket_id(&mut self, remote: Remote) -> SocketId {
-        let local_port = Port(self.next_port);
-        self.next_port += 1;
+    /// Makes the ID of an outbound socket to `remote`, on a free ephemeral
+    /// port. Returns `None` if every ephemeral port is in use.
+    fn make_socket_id(&mut self, remote: Remote) -> Option<SocketId> {
+        let local_port = self.allocate_port()?;
+        let sock_id = SocketId::build()
+            .with_remote_ip(remote.ip())
+            .with_remote_port(remote.port())
+            .with_local_port(local_port)
+            .build()
+            .unwrap();
+        Some(sock_id)
+    }
+
+    /// Makes the ID of an outbound socket to `remote` on `local_port`, marking
+    /// the port as in use.
+    fn make_socket_id_from(&mut self, local_port: Port, remote: Remote) -> SocketId {
+        self.ports_in_use.insert(local_port);
         SocketId::build()
             .with_remote_ip(remote.ip())
             .with_remote_port(remote.port())
@@ -390,6 +983,27 @@ impl<N: Net> SocketBuilder<N> {
             .unwrap()
     }
 
+    /// Takes the next free ephemeral port, wrapping around the range.
+    fn allocate_port(&mut self) -> Option<Port> {
+        let (start, end) = (*self.ephemeral_ports.start(), *self.ephemeral_ports.end());
+        for _ in self.ephemeral_ports.clone() {
+            let port = Port(self.next_port);
+            self.next_port = if self.next_port == end {
+                start
+            } else {
+                self.next_port + 1
+            };
+            if self.ports_in_use.insert(port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    fn release_port(&mut self, port: Port) {
+        self.ports_in_use.remove(&port);
+    }
+
     fn allocate_socket_descriptor(&mut self) -> SocketDescriptor {
         let descriptor = SocketDescriptor(
             self.next_socket_descriptor
@@ -417,13 +1031,23 @@ impl<N: Net, DP: DropPolicy> ProtocolHandler<DP> for TcpHandler<N> {
         &self,
         ip_header: &Ipv4HeaderSlice<'a>,
         payload: &[u8],
-        _net: &VtLinkNet<DP>,
+        net: &VtLinkNet<DP>,
     ) where
         DP: DropPolicy,
     {
         // Step 1: validate checksum
-        let tcp_header = TcpHeaderSlice::from_slice(payload).expect("Failed to parse TCP Header");
-        log::debug!(
+        let Ok(tcp_header) = TcpHeaderSlice::from_slice(payload) else {
+            node_log!(
+                net.logger(),
+                Level::Warn,
+                "Dropping malformed TCP segment from {}",
+                ip_header.source_addr()
+            );
+            return;
+        };
+        node_log!(
+            net.logger(),
+            Level::Debug,
             "Received packet tcp header len: {}, source: {}:{}, dest: {}:{}",
             payload.len(),
             ip_header.source_addr(),
@@ -446,68 +1070,125 @@ impl<N: Net, DP: DropPolicy> ProtocolHandler<DP> for TcpHandler<N> {
                 .calc_checksum_ipv4(ip_header, tcp_payload)
                 .unwrap()
         {
-            log::error!("TCP checksum failed");
-        } else {
-            let sockets = self.tcp.sockets.read().await;
-            let action = match sockets.get_socket_by_id(sock_id) {
-                Some(socket) => {
-                    socket
-                        .handle_packet(ip_header, &tcp_header, tcp_payload)
-                        .await
-                }
-                None => match sockets.get_listener_socket(tcp_header.destination_port().into()) {
-                    Some(listener_sock) => {
-                        listener_sock
-                            .handle_packet(ip_header, &tcp_header, payload)
-                            .await
-                    }
-                    None => {
-                        log::info!("Received TCP packet that doesn't match with any connection");
-                        return;
-                    }
-                },
-            };
+            self.tcp.checksum_failures.record(net.logger());
+            return;
+        }
 
-            if let Some(action) = action {
-                match action {
-                    UpdateAction::NewSynReceivedSocket(syn_recvd) => {
-                        drop(sockets);
-                        self.tcp
-                            .sockets
-                            .write()
-                            .await
-                            .add_new_syn_recvd_socket(
-                                Remote::new(
-                                    ip_header.source_addr(),
-                                    tcp_header.source_port().into(),
-                                ),
-                                tcp_header.destination_port().into(),
-                                syn_recvd,
-                            )
-                            .unwrap();
-                    }
-                    UpdateAction::CloseSocket(id) => {
-                        drop(sockets);
-                        self.tcp.sockets.write().await.remove_by_id(id);
-                    }
-                }
+        let sockets = self.tcp.sockets.read().await;
+        let socket = sockets.get_socket_by_id(sock_id).or_else(|| {
+            sockets.get_listener_socket(
+                ip_header.destination_addr(),
+                tcp_header.destination_port().into(),
+            )
+        });
+
+        // A segment is checked against the key its socket shares with the
+        // sender. One for no socket must not be signed.
+        let md5_key = socket.and_then(|socket| socket.md5_key(ip_header.source_addr()));
+        if let Err(e) = md5sig::verify_segment(
+            &tcp_header,
+            tcp_payload,
+            ip_header.source_addr(),
+            ip_header.destination_addr(),
+            md5_key.as_deref(),
+        ) {
+            node_log!(
+                net.logger(),
+                Level::Info,
+                "Dropping TCP segment from {}, MD5 signature check failed: {:?}",
+                ip_header.source_addr(),
+                e
+            );
+            return;
+        }
+
+        let Some(socket) = socket else {
+            node_log!(
+                net.logger(),
+                Level::Info,
+                "Received TCP packet that doesn't match with any connection"
+            );
+            if !tcp_header.rst() {
+                let rst = make_reset_reply(ip_header, &tcp_header, tcp_payload.len());
+                net.send_from(
+                    ip_header.destination_addr(),
+                    &rst,
+                    Protocol::Tcp,
+                    ip_header.source_addr(),
+                )
+                .await
+                .ok();
             }
+            return;
+        };
+
+        if let Some(action) = socket
+            .handle_packet(ip_header, &tcp_header, tcp_payload)
+            .await
+        {
+            drop(sockets);
+            self.tcp.apply_update(action).await;
         }
     }
 }
 
+/// Builds the RST answering a segment that matches no socket, as in RFC 793,
+/// section 3.4. The RST takes its sequence number from the segment's ACK, if
+/// any; otherwise, it acks everything the segment occupies.
+fn make_reset_reply(
+    ip_header: &Ipv4HeaderSlice<'_>,
+    tcp_header: &TcpHeaderSlice<'_>,
+    payload_len: usize,
+) -> Vec<u8> {
+    let mut header = TcpHeader::new(
+        tcp_header.destination_port(),
+        tcp_header.source_port(),
+        0,
+        0,
+    );
+    header.rst = true;
+    if tcp_header.ack() {
+        header.sequence_number = tcp_header.acknowledgment_number();
+    } else {
+        let seg_len = payload_len + tcp_header.syn() as usize + tcp_header.fin() as usize;
+        header.ack = true;
+        header.acknowledgment_number = tcp_header.sequence_number().wrapping_add(seg_len as u32);
+    }
+    header.checksum = header
+        .calc_checksum_ipv4_raw(
+            ip_header.destination_addr().octets(),
+            ip_header.source_addr().octets(),
+            &[],
+        )
+        .unwrap();
+
+    let mut bytes = Vec::new();
+    header.write(&mut bytes).unwrap();
+    bytes
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use std::{future::Future, sync::Arc, time::Duration};
+    use std::{
+        cmp::{max, min},
+        future::Future,
+        net::{Ipv4Addr, SocketAddrV4},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
 
     use tokio::sync::Barrier;
 
     use crate::{
-        drop_policy::{DropFactor, NeverDrop},
-        node::{Node, NodeBuilder},
-        protocol::{rip::RipHandler, tcp::socket::SocketStatus, Protocol},
+        drop_policy::{Corrupt, DelayOnce, DropFactor, Latency, NeverDrop, RateLimit, Reorder},
+        fixture::node::start_node_with,
+        node::{Node, NodeBuilder, NodeBuilderStep},
+        protocol::{tcp::congestion::CongestionAlgorithm, Protocol},
         Args,
     };
 
@@ -549,6 +1230,71 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn delayed_segment_is_resent_once() {
+        let test_file_size = 100_000;
+
+        // Long enough for the segment's retransmission timer to fire, and
+        // for it to arrive well after its retransmission.
+        let policy = DelayOnce::new(5, Duration::from_millis(300));
+        let resent = policy.resent_counter();
+
+        let f = test_send_file(make_in_mem_test_file(test_file_size), policy);
+        test_timeout(Duration::from_secs(5), f).await;
+        assert_eq!(resent.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn reordered_send_file() {
+        let test_file_size = 1_000_000;
+
+        let policy = Reorder::new(0.1);
+        let held = policy.held_counter();
+
+        let f = test_send_file(make_in_mem_test_file(test_file_size), policy);
+        test_timeout(Duration::from_secs(10), f).await;
+        assert!(held.load(Ordering::Relaxed) > 0);
+    }
+
+    #[tokio::test]
+    async fn corrupted_send_file() {
+        let test_file_size = 1_000_000;
+
+        // Corrupted segments fail their checksum, go unacked and are resent.
+        let policy = Corrupt::new(0.01);
+        let corrupted = policy.corrupted_counter();
+
+        let f = test_send_file(make_in_mem_test_file(test_file_size), policy);
+        test_timeout(Duration::from_secs(10), f).await;
+        assert!(corrupted.load(Ordering::Relaxed) > 0);
+    }
+
+    #[tokio::test]
+    async fn checksum_failures_are_counted() {
+        let payload = make_in_mem_test_file(200_000);
+        let policy = Corrupt::new(0.05);
+        let corrupted = policy.corrupted_counter();
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), policy).await;
+
+        let (conn, remote_conn) = connect_nodes(&n1, &n2, Port(5683)).await;
+
+        let mut received = vec![0; payload.len()];
+        let f =
+            async { tokio::join!(conn.send_all(&payload), remote_conn.read_all(&mut received)) };
+        let (sent, read) = test_timeout(Duration::from_secs(10), f).await;
+        sent.unwrap();
+        read.unwrap();
+        assert_eq!(received, payload);
+
+        let failures = n2.metrics().await.checksum_failures;
+        assert!(failures > 0);
+        // A flipped bit may instead leave the header malformed.
+        assert!(failures <= corrupted.load(Ordering::Relaxed));
+        assert_eq!(n1.metrics().await.checksum_failures, 0);
+    }
+
     #[tokio::test]
     async fn bidirectional_send_file() {
         let test_file_size = 10_000_000;
@@ -656,14 +1402,1845 @@ mod tests {
         n2.await.unwrap();
     }
 
-    async fn test_send_file(in_mem_file: Vec<u8>, drop_policy: impl DropPolicy) {
+    #[tokio::test]
+    async fn send_on_listener_errs() {
         let abc_net = crate::fixture::netlinks::abc::gen_unique();
-        let send_cfg = abc_net.a.clone();
-        let recv_cfg = abc_net.b.clone();
+        let node = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+
+        let listen_port = Port(5656);
+        let _listener = node.listen(listen_port).await.unwrap();
+        let descriptor = node
+            .get_socket_descriptor(SocketId::for_listen_socket(
+                Ipv4Addr::UNSPECIFIED,
+                listen_port,
+            ))
+            .await
+            .unwrap();
 
-        let n1_cfg = send_cfg.clone();
-        let n2_cfg = recv_cfg.clone();
-        let expected = in_mem_file.clone();
+        let r = node.tcp_send(descriptor, b"hello").await;
+        assert_eq!(r.unwrap_err(), TcpSendError::NotAConnection);
+    }
+
+    #[tokio::test]
+    async fn listeners_per_interface_ip() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let a = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let b = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+        let c = create_and_start_node(abc_net.c.clone(), NeverDrop).await;
+
+        let port = Port(80);
+        let b_ips = abc_net.b.get_my_interface_ips();
+        let mut listeners = Vec::new();
+        for ip in &b_ips {
+            listeners.push(b.listen_on(*ip, port).await.unwrap());
+        }
+        assert!(matches!(
+            b.listen_on(b_ips[0], port).await,
+            Err(TcpListenError::PortOccupied(Port(80)))
+        ));
+
+        for (i, ip) in b_ips.iter().enumerate() {
+            // Dial each interface from the neighbour on its link, since B
+            // replies from the dialled address.
+            let dialer = if abc_net.a.links.iter().any(|l| l.dest_ip == *ip) {
+                &a
+            } else {
+                &c
+            };
+            let conn = dialer.connect(*ip, port).await.unwrap();
+            let accepted = test_timeout(Duration::from_secs(1), listeners[i].accept())
+                .await
+                .unwrap();
+            assert_eq!(accepted.remote().port(), conn.local_port());
+
+            for (j, other) in listeners.iter_mut().enumerate() {
+                if j != i {
+                    let r = tokio::time::timeout(Duration::from_millis(50), other.accept()).await;
+                    assert!(r.is_err(), "listener {j} accepted a SYN to {ip}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_concurrent_connections() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = Arc::new(create_and_start_node(abc_net.a.clone(), NeverDrop).await);
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let listen_port = Port(5656);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let connects: Vec<_> = (0..3)
+            .map(|_| {
+                let n1 = n1.clone();
+                tokio::spawn(async move { n1.connect(dest_ip, listen_port).await.unwrap() })
+            })
+            .collect();
+
+        let mut accepted = Vec::new();
+        for _ in 0..3 {
+            let conn = test_timeout(Duration::from_secs(1), listener.accept())
+                .await
+                .unwrap();
+            accepted.push(conn.remote().port().0);
+        }
+        let mut connected = Vec::new();
+        for connect in connects {
+            connected.push(connect.await.unwrap().local_port().0);
+        }
+        // Each accepted connection is bound to its own socket, not the
+        // listener's.
+        accepted.sort();
+        connected.sort();
+        assert_eq!(accepted, connected);
+
+        let listener_id = SocketId::for_listen_socket(Ipv4Addr::UNSPECIFIED, listen_port);
+        n2.close_socket_and_wait(listener_id, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            listener.accept().await,
+            Err(TcpAcceptError::ListenSocketClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn incoming_ends_when_listener_closes() {
+        use tokio_stream::StreamExt;
+
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let listen_port = Port(5672);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let mut connected = Vec::new();
+        for _ in 0..3 {
+            let conn = n1.connect(dest_ip, listen_port).await.unwrap();
+            connected.push(conn.local_port().0);
+        }
+
+        let mut incoming = listener.incoming();
+        let mut accepted = Vec::new();
+        for _ in 0..3 {
+            let conn = test_timeout(Duration::from_secs(1), incoming.next())
+                .await
+                .unwrap()
+                .unwrap();
+            accepted.push(conn.remote().port().0);
+        }
+        assert_eq!(accepted, connected);
+
+        let listener_id = SocketId::for_listen_socket(Ipv4Addr::UNSPECIFIED, listen_port);
+        n2.close_socket_and_wait(listener_id, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(test_timeout(Duration::from_secs(1), incoming.next())
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn socket_id_for_descriptor() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let node = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+
+        let listen_port = Port(5656);
+        let socket_id = SocketId::for_listen_socket(Ipv4Addr::UNSPECIFIED, listen_port);
+        let _listener = node.listen(listen_port).await.unwrap();
+        let descriptor = node.get_socket_descriptor(socket_id).await.unwrap();
+        assert_eq!(
+            node.socket_id_for_descriptor(descriptor).await,
+            Some(socket_id)
+        );
+
+        node.close_socket_by_descriptor(descriptor).await.unwrap();
+        assert_eq!(node.socket_id_for_descriptor(descriptor).await, None);
+    }
+
+    #[tokio::test]
+    async fn list_sockets_reflects_listeners_and_connections() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let (port1, port2) = (Port(5675), Port(5676));
+        let _listener1 = n1.listen(port1).await.unwrap();
+        let mut listener2 = n2.listen(port2).await.unwrap();
+        let _other_listener = n2.listen(port1).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = n1.connect(dest_ip, port2).await.unwrap();
+        let remote_conn = listener2.accept().await.unwrap();
+
+        let listener_id = |port| SocketId::for_listen_socket(Ipv4Addr::UNSPECIFIED, port);
+        let sockets = n2.list_sockets().await;
+        let ids: Vec<_> = sockets.iter().map(|(_, id, _)| *id).collect();
+        assert_eq!(
+            ids,
+            vec![
+                listener_id(port2),
+                listener_id(port1),
+                remote_conn.socket_id()
+            ]
+        );
+        for (descriptor, id, status) in sockets {
+            assert_eq!(n2.socket_id_for_descriptor(descriptor).await, Some(id));
+            let expected = if id == remote_conn.socket_id() {
+                SocketStatus::Established
+            } else {
+                SocketStatus::Listen
+            };
+            assert_eq!(status, expected);
+        }
+
+        let sockets = n1.list_sockets().await;
+        assert_eq!(sockets.len(), 2);
+        assert_eq!(sockets[1].1, conn.socket_id());
+    }
+
+    #[tokio::test]
+    async fn close_and_wait_frees_socket() {
+        let msl = Duration::from_millis(50);
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = start_node_with(&abc_net.a, NeverDrop, |b| b.with_msl(msl)).await;
+        let n2 = start_node_with(&abc_net.b, NeverDrop, |b| b.with_msl(msl)).await;
+
+        let (conn, remote_conn) = connect_nodes(&n1, &n2, Port(5656)).await;
+
+        let socket_id = conn.socket_id();
+        let remote_socket_id = remote_conn.socket_id();
+        let (local, remote) = tokio::join!(
+            n1.close_socket_and_wait(socket_id, Duration::from_secs(2)),
+            n2.close_socket_and_wait(remote_socket_id, Duration::from_secs(2)),
+        );
+        local.unwrap();
+        remote.unwrap();
+
+        assert!(n1.get_socket(socket_id).await.is_none());
+        assert!(n2.get_socket(remote_socket_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn accept_from_yields_peer() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let listen_port = Port(5656);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = n1.connect(dest_ip, listen_port).await.unwrap();
+        let (_remote_conn, peer) = listener.accept_from().await.unwrap();
+
+        assert_eq!(peer.ip(), conn.local_ip());
+        assert_eq!(peer.port(), conn.local_port());
+    }
+
+    #[tokio::test]
+    async fn read_until_returns_one_line_at_a_time() {
+        let (n1, _n2, conn, remote_conn) = connected_pair(Port(5656)).await;
+
+        conn.send_all(b"hello\nworld\npartial").await.unwrap();
+        assert_eq!(remote_conn.read_until(b'\n', 64).await.unwrap(), b"hello\n");
+        assert_eq!(remote_conn.read_until(b'\n', 64).await.unwrap(), b"world\n");
+        assert_eq!(remote_conn.read_until(b'\n', 4).await.unwrap(), b"part");
+
+        n1.close_socket(conn.socket_id()).await.unwrap();
+        assert_eq!(remote_conn.read_until(b'\n', 64).await.unwrap(), b"ial");
+        assert!(matches!(
+            remote_conn.read_until(b'\n', 64).await,
+            Err(TcpReadError::Closed(0))
+        ));
+    }
+
+    #[tokio::test]
+    async fn shutdown_write_keeps_read_side_open() {
+        let (n1, _n2, conn, remote_conn) = connected_pair(Port(5669)).await;
+
+        conn.send_all(b"request").await.unwrap();
+        n1.get_socket(conn.socket_id()).await.unwrap().close().await;
+        assert_eq!(remote_conn.read_to_end().await.unwrap(), b"request");
+
+        // The active closer still reads what the remote sends after the FIN.
+        remote_conn.send_all(b"post-close payload").await.unwrap();
+        let mut buf = [0; 18];
+        conn.read_all(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"post-close payload");
+        assert_eq!(
+            n1.get_socket(conn.socket_id())
+                .await
+                .unwrap()
+                .status()
+                .await,
+            SocketStatus::FinWait2
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_read_drops_data_and_drains_writes() {
+        let (_n1, n2, conn, remote_conn) = connected_pair(Port(5670)).await;
+
+        n2.get_socket(remote_conn.socket_id())
+            .await
+            .unwrap()
+            .close_read()
+            .await;
+        assert!(matches!(
+            remote_conn.read_some(&mut [0; 8]).await,
+            Err(TcpReadError::Closed(_))
+        ));
+
+        // Nothing reads the remote's buffer, yet it never fills up.
+        let unread = make_in_mem_test_file(3 * TCP_DEFAULT_WINDOW_SZ);
+        tokio::time::timeout(Duration::from_secs(5), conn.send_all(&unread))
+            .await
+            .expect("Remote should keep its window open")
+            .unwrap();
+
+        // The remote can still send.
+        remote_conn.send_all(b"still writable").await.unwrap();
+        let mut buf = [0; 14];
+        conn.read_all(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"still writable");
+    }
+
+    #[tokio::test]
+    async fn connect_and_send_bytes_leaves_conn_open() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let listen_port = Port(5668);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let remote = Remote::new(dest_ip, listen_port);
+
+        let first = make_in_mem_test_file(3000);
+        let (acked, conn) = n1.connect_and_send_bytes(remote, &first).await.unwrap();
+        assert_eq!(acked, first.len());
+
+        let second = b"second phase";
+        conn.send_all(second).await.unwrap();
+        n1.close_socket(conn.socket_id()).await.unwrap();
+
+        let remote_conn = listener.accept().await.unwrap();
+        let got = remote_conn.read_to_end().await.unwrap();
+        assert_eq!(got[..first.len()], first[..]);
+        assert_eq!(got[first.len()..], second[..]);
+    }
+
+    #[tokio::test]
+    async fn socket_builder_assigns_ids_and_descriptors() {
+        use crate::net::vtlink::{VtLinkLayer, VtLinkNetConfig};
+
+        let args = crate::fixture::netlinks::abc::gen_unique().a;
+        let links = Arc::new(VtLinkLayer::new(&args).await);
+        let net = Arc::new(VtLinkNet::new(links, &args, VtLinkNetConfig::default()));
+        let config = TcpConfig {
+            ephemeral_ports: 40000..=40001,
+            ..Default::default()
+        };
+        let mut builder = SocketBuilder::new(net, config, mpsc::unbounded_channel().0);
+
+        let remote = Remote::new(Ipv4Addr::new(10, 0, 0, 2), Port(80));
+        let first_id = builder.make_socket_id(remote).unwrap();
+        let (first_descriptor, first) = builder.build_with_id(first_id);
+        let second_id = builder.make_socket_id(remote).unwrap();
+        let (second_descriptor, second) = builder.build_with_id(second_id);
+
+        assert_eq!(first.id(), first_id);
+        assert_eq!(first.descriptor(), first_descriptor);
+        assert_eq!(first_id.local_port(), Port(40000));
+        assert_eq!(first_id.remote_ip(), remote.ip());
+        assert_eq!(first_id.remote_port(), remote.port());
+        assert_eq!(second.id(), second_id);
+        assert_eq!(second.descriptor(), second_descriptor);
+        assert_eq!(second_id.local_port(), Port(40001));
+        assert_ne!(first_descriptor, second_descriptor);
+        assert_eq!(first.status().await, SocketStatus::Closed);
+    }
+
+    #[tokio::test]
+    async fn removed_socket_descriptor_no_longer_resolves() {
+        use crate::net::vtlink::{VtLinkLayer, VtLinkNetConfig};
+
+        let args = crate::fixture::netlinks::abc::gen_unique().a;
+        let links = Arc::new(VtLinkLayer::new(&args).await);
+        let net = Arc::new(VtLinkNet::new(links, &args, VtLinkNetConfig::default()));
+        let mut table = SocketTable::new(net, TcpConfig::default(), mpsc::unbounded_channel().0);
+
+        let port = Port(5677);
+        let socket = table
+            .add_new_listen_socket(Ipv4Addr::UNSPECIFIED, port)
+            .unwrap();
+        let (id, descriptor) = (socket.id(), socket.descriptor());
+        table.remove_by_id(id);
+        assert!(table.get_socket_by_descriptor(descriptor).is_none());
+
+        // Reopening the same socket must not revive its old descriptor.
+        let reopened = table
+            .add_new_listen_socket(Ipv4Addr::UNSPECIFIED, port)
+            .unwrap()
+            .descriptor();
+        assert_ne!(reopened, descriptor);
+        assert!(table.get_socket_by_descriptor(descriptor).is_none());
+        assert_eq!(table.get_socket_by_descriptor(reopened).unwrap().id(), id);
+    }
+
+    #[tokio::test]
+    async fn listener_survives_garbage_segments() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let listen_port = Port(5667);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let src_ip = abc_net.a.get_my_interface_ips()[0];
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+
+        // A SYN whose ack number would overflow, and SYNs carrying flags a
+        // listener never expects.
+        let syn = |src_port| {
+            let mut header = TcpHeader::new(src_port, listen_port.0, 0, 1024);
+            header.syn = true;
+            header
+        };
+        let mut overflowing_syn = syn(41000);
+        overflowing_syn.sequence_number = u32::MAX;
+        let mut syn_rst = syn(41001);
+        syn_rst.rst = true;
+        let mut syn_ack = syn(41002);
+        syn_ack.ack = true;
+        syn_ack.acknowledgment_number = 1234;
+        for mut header in [overflowing_syn, syn_rst, syn_ack] {
+            header.checksum = header
+                .calc_checksum_ipv4_raw(src_ip.octets(), dest_ip.octets(), &[])
+                .unwrap();
+            let mut segment = Vec::new();
+            header.write(&mut segment).unwrap();
+            n1.send(&segment, Protocol::Tcp, dest_ip).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let conn = n1.connect(dest_ip, listen_port).await.unwrap();
+        let remote_conn = listener.accept().await.unwrap();
+        conn.send_all(b"still alive").await.unwrap();
+        let mut buf = [0; 11];
+        remote_conn.read_all(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"still alive");
+    }
+
+    #[tokio::test]
+    async fn accept_with_limit_bounds_concurrency() {
+        const MAX_CONCURRENT: usize = 2;
+        const NUM_CONNS: usize = 5;
+
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let listen_port = Port(5656);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let mut conns = Vec::new();
+        for _ in 0..NUM_CONNS {
+            conns.push(n1.connect(dest_ip, listen_port).await.unwrap());
+        }
+
+        let handling = Arc::new(AtomicUsize::new(0));
+        let max_handling = Arc::new(AtomicUsize::new(0));
+        let mut handlers = Vec::new();
+        for _ in 0..NUM_CONNS {
+            let (_conn, permit) = listener.accept_with_limit(MAX_CONCURRENT).await.unwrap();
+            let handling = handling.clone();
+            let max_handling = max_handling.clone();
+            handlers.push(tokio::spawn(async move {
+                let now = handling.fetch_add(1, Ordering::SeqCst) + 1;
+                max_handling.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                handling.fetch_sub(1, Ordering::SeqCst);
+                drop(permit);
+            }));
+        }
+        for handler in handlers {
+            handler.await.unwrap();
+        }
+
+        assert_eq!(max_handling.load(Ordering::SeqCst), MAX_CONCURRENT);
+    }
+
+    #[tokio::test]
+    async fn close_without_data() {
+        let msl = Duration::from_millis(50);
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = start_node_with(&abc_net.a, NeverDrop, |b| b.with_msl(msl)).await;
+        let n2 = start_node_with(&abc_net.b, NeverDrop, |b| b.with_msl(msl)).await;
+
+        let (conn, remote_conn) = connect_nodes(&n1, &n2, Port(5656)).await;
+
+        n1.close_socket(conn.socket_id()).await.unwrap();
+        n2.close_socket(remote_conn.socket_id()).await.unwrap();
+
+        // Long enough for the FINs to be acked and TIME_WAIT to expire.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(conn.state(), SocketStatus::Closed);
+        assert_eq!(remote_conn.state(), SocketStatus::Closed);
+        assert!(n1.get_socket(conn.socket_id()).await.is_none());
+        assert!(n2.get_socket(remote_conn.socket_id()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn conn_state_follows_socket() {
+        let (n1, n2, conn, remote_conn) = connected_pair(Port(5656)).await;
+        assert_eq!(conn.state(), SocketStatus::Established);
+        assert_eq!(remote_conn.state(), SocketStatus::Established);
+
+        n1.close_socket(conn.socket_id()).await.unwrap();
+        // Give socket state some time to settle.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(conn.state(), SocketStatus::FinWait2);
+        assert_eq!(remote_conn.state(), SocketStatus::CloseWait);
+
+        n2.close_socket(remote_conn.socket_id()).await.unwrap();
+        assert_eq!(remote_conn.state(), SocketStatus::LastAck);
+    }
+
+    #[tokio::test]
+    async fn send_stall_resets_connection() {
+        let stall_timeout = Duration::from_millis(300);
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = start_node_with(&abc_net.a, NeverDrop, |b| {
+            b.with_send_stall_timeout(stall_timeout)
+        })
+        .await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        // B never reads, so that its window closes and stays closed.
+        let (conn, _remote_conn) = connect_nodes(&n1, &n2, Port(5656)).await;
+
+        let socket_id = conn.socket_id();
+        let descriptor = n1.get_socket_descriptor(socket_id).await.unwrap();
+        let payload = vec![1; 4 * TCP_DEFAULT_WINDOW_SZ];
+        let start = Instant::now();
+        let r = n1.tcp_send(descriptor, &payload).await;
+        assert_eq!(r.unwrap_err(), TcpSendError::StallTimeout);
+        assert!(start.elapsed() >= stall_timeout);
+
+        assert_eq!(conn.state(), SocketStatus::Closed);
+        test_timeout(Duration::from_secs(1), async {
+            while n1.get_socket(socket_id).await.is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn abortive_close_resets_peer() {
+        // B never reads, so that the send below stays pending.
+        let (n1, n2, conn, remote_conn) = connected_pair(Port(5673)).await;
+
+        let sender = conn.clone();
+        let pending_send =
+            tokio::spawn(async move { sender.send_all(&vec![1; 4 * TCP_DEFAULT_WINDOW_SZ]).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!pending_send.is_finished());
+
+        conn.set_linger(LingerConfig::Abort);
+        n1.close_socket(conn.socket_id()).await.unwrap();
+        let sent = test_timeout(Duration::from_millis(100), pending_send)
+            .await
+            .unwrap();
+        assert_eq!(sent.unwrap_err(), TcpSendError::ConnClosed);
+        // No TIME_WAIT for the closer.
+        assert!(n1.get_socket(conn.socket_id()).await.is_none());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(remote_conn.state(), SocketStatus::Closed);
+        assert!(n2.get_socket(remote_conn.socket_id()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn keepalive_detects_vanished_peer() {
+        let keepalive = KeepaliveConfig {
+            idle: Duration::from_millis(200),
+            interval: Duration::from_millis(100),
+            probes: 3,
+        };
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = start_node_with(&abc_net.a, NeverDrop, |b| b.with_keepalive(keepalive)).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let listen_port = Port(5674);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = n1.connect(dest_ip, listen_port).await.unwrap();
+        let remote_conn = listener.accept().await.unwrap();
+
+        // A live peer answers the probes, so an idle connection stays open.
+        tokio::time::sleep(keepalive.idle * 3).await;
+        assert_eq!(conn.state(), SocketStatus::Established);
+
+        // Take the peer off the network without letting it say goodbye.
+        for link_no in 0..abc_net.b.links.len() {
+            n2.deactivate(link_no as u16).await.unwrap();
+        }
+        let window = keepalive.idle + keepalive.interval * keepalive.probes as u32;
+        let mut buf = [0; 1];
+        let r = test_timeout(window + Duration::from_secs(1), conn.read_all(&mut buf)).await;
+        assert!(r.is_err());
+
+        // The socket is closed and removed, like one reset by its peer.
+        test_timeout(Duration::from_secs(1), async {
+            while n1
+                .list_sockets()
+                .await
+                .iter()
+                .any(|(_, id, _)| *id == conn.socket_id())
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+
+        // The peer comes back, unaware, and sends on the old connection.
+        for link_no in 0..abc_net.b.links.len() {
+            n2.activate(link_no as u16).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        tokio::spawn(async move { remote_conn.send_all(b"late").await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The node still handles segments.
+        let conn = test_timeout(Duration::from_secs(1), n1.connect(dest_ip, listen_port))
+            .await
+            .unwrap();
+        assert_eq!(conn.state(), SocketStatus::Established);
+    }
+
+    #[tokio::test]
+    async fn connect_from_pins_local_port() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let (port1, port2) = (Port(5678), Port(5679));
+        let mut listener1 = n2.listen(port1).await.unwrap();
+        let mut listener2 = n2.listen(port2).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let local_port = Port(5680);
+
+        let conn1 = n1.connect_from(local_port, dest_ip, port1).await.unwrap();
+        let conn2 = n1.connect_from(local_port, dest_ip, port2).await.unwrap();
+        assert_eq!(conn1.socket_id().local_port(), local_port);
+        assert_eq!(conn2.socket_id().local_port(), local_port);
+        let remote_conn1 = listener1.accept().await.unwrap();
+        let remote_conn2 = listener2.accept().await.unwrap();
+        assert_eq!(remote_conn1.socket_id().remote_port(), local_port);
+        assert_eq!(remote_conn2.socket_id().remote_port(), local_port);
+
+        let r = n1.connect_from(local_port, dest_ip, port1).await;
+        assert!(matches!(r, Err(TcpConnError::LocalPortInUse(p)) if p == local_port));
+        // The rejected attempt leaves the existing connection alone.
+        conn1.send_all(b"hello").await.unwrap();
+        let mut received = [0; 5];
+        remote_conn1.read_all(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn simultaneous_open_establishes_both_sides() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        // Neither side listens: each SYN reaches the other's SYN_SENT socket.
+        let (port1, port2) = (Port(5681), Port(5682));
+        let ip1 = abc_net.a.get_my_interface_ips()[0];
+        let ip2 = abc_net.b.get_my_interface_ips()[0];
+        let f = async {
+            tokio::join!(
+                n1.connect_from(port1, ip2, port2),
+                n2.connect_from(port2, ip1, port1)
+            )
+        };
+        let (conn1, conn2) = test_timeout(Duration::from_secs(5), f).await;
+        let (conn1, conn2) = (conn1.unwrap(), conn2.unwrap());
+        assert_eq!(conn1.state(), SocketStatus::Established);
+        assert_eq!(conn2.state(), SocketStatus::Established);
+
+        conn1.send_all(b"ping").await.unwrap();
+        conn2.send_all(b"pong").await.unwrap();
+        let (mut received1, mut received2) = ([0; 4], [0; 4]);
+        let f = async {
+            conn1.read_all(&mut received1).await.unwrap();
+            conn2.read_all(&mut received2).await.unwrap();
+        };
+        test_timeout(Duration::from_secs(5), f).await;
+        assert_eq!(&received1, b"pong");
+        assert_eq!(&received2, b"ping");
+    }
+
+    #[tokio::test]
+    async fn connect_to_closed_port_is_refused() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+        let _listener = n2.listen(Port(5656)).await.unwrap();
+
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let start = Instant::now();
+        let r = n1.connect(dest_ip, Port(5657)).await;
+        assert!(matches!(r, Err(TcpConnError::Refused)));
+        // Refused by the remote's RST rather than given up on after retries.
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(n1.connections_to(dest_ip).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connections_to_remote_ip() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let ports = [Port(5656), Port(5657)];
+        let mut listeners = Vec::new();
+        for port in ports {
+            listeners.push(n2.listen(port).await.unwrap());
+        }
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let mut conns = Vec::new();
+        for port in ports {
+            conns.push(n1.connect(dest_ip, port).await.unwrap());
+        }
+
+        assert_eq!(
+            n1.connections_to(dest_ip).await,
+            conns.iter().map(|c| c.socket_id()).collect::<Vec<_>>()
+        );
+        let a_ip = abc_net.a.get_my_interface_ips()[0];
+        assert!(n1.connections_to(a_ip).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connect_errs_once_ephemeral_ports_run_out() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = start_node_with(&abc_net.a, NeverDrop, |b| {
+            b.with_ephemeral_ports(40000..=40001)
+        })
+        .await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let listen_port = Port(5656);
+        let _listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let mut local_ports = Vec::new();
+        for _ in 0..2 {
+            let conn = n1.connect(dest_ip, listen_port).await.unwrap();
+            local_ports.push(conn.local_port());
+        }
+        assert_eq!(local_ports, vec![Port(40000), Port(40001)]);
+
+        let r = n1.connect(dest_ip, listen_port).await;
+        assert!(matches!(r, Err(TcpConnError::NoEphemeralPorts)));
+    }
+
+    #[tokio::test]
+    async fn fast_open_delivers_syn_data() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = start_node_with(&abc_net.a, NeverDrop, |b| b.with_tfo(true)).await;
+        let n2 = start_node_with(&abc_net.b, NeverDrop, |b| b.with_tfo(true)).await;
+
+        let listen_port = Port(5656);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        // The first connection only picks up a cookie.
+        let _first = n1.connect(dest_ip, listen_port).await.unwrap();
+        let _first_remote = listener.accept().await.unwrap();
+
+        let payload = b"hello";
+        let _second = n1
+            .connect_with_data(dest_ip, listen_port, payload)
+            .await
+            .unwrap();
+        let remote_conn = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        test_timeout(Duration::from_secs(1), remote_conn.read_all(&mut buf))
+            .await
+            .unwrap();
+        assert_eq!(&buf, payload);
+    }
+
+    #[tokio::test]
+    async fn connect_with_oversized_data_errs() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let node = NodeBuilder::new(&abc_net.a).build().await;
+
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let data = vec![0; TCP_DEFAULT_WINDOW_SZ + 1];
+        let r = node.connect_with_data(dest_ip, Port(5656), &data).await;
+        assert!(matches!(r, Err(TcpConnError::DataTooLarge(n)) if n == data.len()));
+        assert_eq!(
+            node.socket_id_for_descriptor(SocketDescriptor(0)).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_without_route_errs() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        // Without a RIP handler, A never learns a route to C.
+        let node = NodeBuilder::new(&abc_net.a).build().await;
+
+        let c_ip = abc_net.c.get_my_interface_ips()[0];
+        let r = node.connect(c_ip, Port(5656)).await;
+        assert!(matches!(
+            r,
+            Err(TcpConnError::Transport(TransportError::DestUnreachable(ip))) if ip == c_ip
+        ));
+        // The half-created socket is cleaned up.
+        assert_eq!(
+            node.socket_id_for_descriptor(SocketDescriptor(0)).await,
+            None
+        );
+    }
+
+    /// Drops every TCP segment, letting RIP through.
+    struct DropTcp;
+
+    impl DropPolicy for DropTcp {
+        fn should_drop(&self, ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+            let tcp: u8 = Protocol::Tcp.into();
+            ip_header.protocol() == tcp
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_to_black_hole_times_out() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        // B is reachable, but never hears a SYN.
+        let _n2 = create_and_start_node(abc_net.b.clone(), DropTcp).await;
+
+        let timeout = Duration::from_millis(500);
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let start = Instant::now();
+        let r = n1.connect_with_timeout(b_ip, Port(5656), timeout).await;
+        let elapsed = start.elapsed();
+
+        assert!(matches!(r, Err(TcpConnError::Timeout)));
+        assert!(
+            elapsed >= timeout && elapsed < timeout * 2,
+            "gave up after {elapsed:?}"
+        );
+        // The half-open socket is cleaned up.
+        assert_eq!(n1.socket_id_for_descriptor(SocketDescriptor(0)).await, None);
+    }
+
+    #[tokio::test]
+    async fn connect_gives_up_after_syn_retries() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let _n2 = create_and_start_node(abc_net.b.clone(), DropTcp).await;
+
+        // The retries run out well before the timeout.
+        let timeout = Duration::from_secs(30);
+        let b_ip = abc_net.b.get_my_interface_ips()[0];
+        let r = test_timeout(
+            Duration::from_secs(10),
+            n1.connect_with_timeout(b_ip, Port(5656), timeout),
+        )
+        .await;
+        assert!(matches!(r, Err(TcpConnError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn md5_signed_transfer() {
+        let payload = b"hello world!";
+        let (n1, n2, a_ip, b_ip) = start_md5_pair().await;
+
+        let listen_port = Port(5656);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        listener.set_md5_key(a_ip, Some(b"secret".to_vec()));
+        let conn = n1
+            .connect_with_md5_key(b_ip, listen_port, b"secret".to_vec())
+            .await
+            .unwrap();
+        let remote_conn = listener.accept().await.unwrap();
+        assert_eq!(remote_conn.md5_key(), Some(&b"secret"[..]));
+
+        conn.send_all(payload).await.unwrap();
+        let mut buf = vec![0; payload.len()];
+        remote_conn.read_all(&mut buf).await.unwrap();
+        assert_eq!(buf, payload);
+    }
+
+    #[tokio::test]
+    async fn md5_key_mismatch_rejects_segments() {
+        let (n1, n2, a_ip, b_ip) = start_md5_pair().await;
+
+        let listen_port = Port(5656);
+        let listener = n2.listen(listen_port).await.unwrap();
+        listener.set_md5_key(a_ip, Some(b"secret".to_vec()));
+        let r = tokio::time::timeout(
+            Duration::from_millis(500),
+            n1.connect_with_md5_key(b_ip, listen_port, b"not so secret".to_vec()),
+        )
+        .await;
+        assert!(r.is_err(), "SYN with a bad signature should be dropped");
+    }
+
+    #[tokio::test]
+    async fn md5_keys_are_per_socket() {
+        let (n1, n2, a_ip, b_ip) = start_md5_pair().await;
+
+        // A key on one listener leaves the peer's other connections unsigned.
+        let signed = n2.listen(Port(5684)).await.unwrap();
+        signed.set_md5_key(a_ip, Some(b"secret".to_vec()));
+        let mut unsigned = n2.listen(Port(5685)).await.unwrap();
+
+        let conn = n1.connect(b_ip, Port(5685)).await.unwrap();
+        let remote_conn = unsigned.accept().await.unwrap();
+        assert_eq!(remote_conn.md5_key(), None);
+        conn.send_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        remote_conn.read_all(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let r =
+            tokio::time::timeout(Duration::from_millis(500), n1.connect(b_ip, Port(5684))).await;
+        assert!(
+            r.is_err(),
+            "unsigned SYN to a keyed listener should be dropped"
+        );
+    }
+
+    #[tokio::test]
+    async fn fin_mid_transfer_keeps_sending() {
+        let (n1, n2, conn, remote_conn) = connected_pair(Port(5656)).await;
+
+        // Much more than fits in the windows, so that most of it is still
+        // buffered when the remote's FIN arrives.
+        let payload = make_in_mem_test_file(200_000);
+        let sender = {
+            let n1 = n1.clone();
+            let conn = conn.clone();
+            let payload = payload.clone();
+            tokio::spawn(async move {
+                conn.send_all(&payload).await.unwrap();
+                n1.close_socket(conn.socket_id()).await.unwrap();
+            })
+        };
+
+        // The remote reads nothing yet, so our data stays buffered until its
+        // FIN moves us to CloseWait.
+        n2.close_socket(remote_conn.socket_id()).await.unwrap();
+        test_timeout(Duration::from_secs(1), async {
+            while conn.state() != SocketStatus::CloseWait {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await;
+
+        // Our FIN follows every byte of our data.
+        let received = test_timeout(Duration::from_secs(5), remote_conn.read_till_closed()).await;
+        assert!(received == payload);
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn zero_byte_read_returns_immediately() {
+        let (n1, _n2, conn, _remote_conn) = connected_pair(Port(5656)).await;
+
+        // Nothing was sent, so any non-empty read would block.
+        let descriptor = n1.get_socket_descriptor(conn.socket_id()).await.unwrap();
+        let read = test_timeout(Duration::from_millis(100), n1.tcp_read(descriptor, 0)).await;
+        assert!(read.unwrap().is_empty());
+        test_timeout(Duration::from_millis(100), conn.read_all(&mut []))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_to_end_until_close() {
+        let payload = make_in_mem_test_file(50_000);
+        let (_nodes, remote_conn) = send_then_close(&payload).await;
+
+        let received = tokio::time::timeout(Duration::from_secs(2), remote_conn.read_to_end())
+            .await
+            .expect("Test should finish within time limit")
+            .unwrap();
+        assert!(received == payload);
+    }
+
+    #[tokio::test]
+    async fn read_to_end_over_limit_errs() {
+        let payload = make_in_mem_test_file(50_000);
+        let (_nodes, remote_conn) = send_then_close(&payload).await;
+
+        let r = tokio::time::timeout(
+            Duration::from_secs(2),
+            remote_conn.read_to_end_with_limit(payload.len() - 1),
+        )
+        .await
+        .expect("Test should finish within time limit");
+        assert!(matches!(r, Err(TcpReadError::ExceedsLimit(_))));
+    }
+
+    #[tokio::test]
+    async fn throughput_matches_send_rate() {
+        let chunk = make_in_mem_test_file(5_000);
+        let chunk_interval = Duration::from_millis(50);
+        let num_chunks = 30;
+        let expected_bps = (chunk.len() * 8) as f64 / chunk_interval.as_secs_f64();
+
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let send_cfg = abc_net.a.clone();
+        let recv_cfg = abc_net.b.clone();
+
+        let recv_listen_port = Port(5656);
+        let listen_barr = Arc::new(Barrier::new(2));
+        let listen_barr_clone = listen_barr.clone();
+        let n2_cfg = recv_cfg.clone();
+
+        let n1 = tokio::spawn(async move {
+            let node = create_and_start_node(send_cfg, NeverDrop).await;
+            listen_barr_clone.wait().await;
+
+            let dest_ip = n2_cfg.get_my_interface_ips()[0];
+            let conn = node.connect(dest_ip, recv_listen_port).await.unwrap();
+
+            let mut ticker = tokio::time::interval(chunk_interval);
+            for _ in 0..num_chunks {
+                ticker.tick().await;
+                conn.send_all(&chunk).await.unwrap();
+            }
+            ticker.tick().await;
+
+            let bps = conn.throughput_bps();
+            let error = (bps - expected_bps).abs() / expected_bps;
+            assert!(
+                error < 0.25,
+                "measured {bps} bps, expected about {expected_bps} bps"
+            );
+        });
+
+        let n2 = tokio::spawn(async move {
+            let node = create_and_start_node(recv_cfg, NeverDrop).await;
+            let mut listener = node.listen(recv_listen_port).await.unwrap();
+            listen_barr.wait().await;
+
+            let conn = listener.accept().await.unwrap();
+            conn.read_till_closed().await;
+        });
+
+        test_timeout(Duration::from_secs(5), n1).await.unwrap();
+        n2.abort();
+    }
+
+    #[tokio::test]
+    async fn bandwidth_estimate_converges_to_link_rate() {
+        let link_rate = 500_000;
+        let link_bps = (link_rate * 8) as f64;
+        let chunk = make_in_mem_test_file(32_000);
+        let num_chunks = 20;
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), RateLimit::new(link_rate)).await;
+
+        let recv_listen_port = Port(5657);
+        let mut listener = n2.listen(recv_listen_port).await.unwrap();
+        let receiver = tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            conn.read_till_closed().await;
+        });
+
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let started = Instant::now();
+        let conn = n1.connect(dest_ip, recv_listen_port).await.unwrap();
+        let handshake_rtt = started.elapsed();
+        assert_eq!(conn.estimated_bandwidth(), None);
+
+        // Sending a chunk at a time keeps the bottleneck's queue short.
+        let send = async {
+            for _ in 0..num_chunks {
+                conn.send_all(&chunk).await.unwrap();
+            }
+        };
+        test_timeout(Duration::from_secs(10), send).await;
+
+        // A sample spans at least TCP_DELIVERY_RATE_INTERVAL of acks. Each of
+        // its ends can be off by a segment's time on the link, and by as long
+        // as a round trip takes to get through the nodes.
+        let segment_time = Duration::from_secs_f64(MAX_SEGMENT_SZ as f64 / link_rate as f64);
+        let slack = 2 * (segment_time + handshake_rtt);
+        let tolerance = slack.as_secs_f64() / TCP_DELIVERY_RATE_INTERVAL.as_secs_f64();
+
+        let bps = conn.estimated_bandwidth().unwrap();
+        let error = (bps - link_bps).abs() / link_bps;
+        assert!(
+            error < tolerance,
+            "estimated {bps} bps over a {link_bps} bps link, within {tolerance} of it"
+        );
+        receiver.abort();
+    }
+
+    #[tokio::test]
+    async fn bbr_outperforms_reno_on_lossy_bottleneck() {
+        let link_rate = 250_000;
+        let lossy_bottleneck = || {
+            (
+                RateLimit::new(link_rate).with_latency(Duration::from_millis(50)),
+                DropFactor::new(0.02),
+            )
+        };
+        let reno = measure_goodput(CongestionAlgorithm::Reno, lossy_bottleneck()).await;
+        let bbr = measure_goodput(CongestionAlgorithm::Bbr, lossy_bottleneck()).await;
+        let shortfall = |goodput: f64| (link_rate as f64 - goodput).abs();
+        assert!(
+            shortfall(bbr) < shortfall(reno),
+            "BBR delivered {bbr:.0} B/s, Reno {reno:.0} B/s"
+        );
+    }
+
+    /// Bytes per second a connection from A delivers to B under `cc`, with
+    /// B receiving under `b_policy`.
+    async fn measure_goodput<DP: DropPolicy>(cc: CongestionAlgorithm, b_policy: DP) -> f64 {
+        let payload = make_in_mem_test_file(200_000);
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = start_node_with(&abc_net.a, NeverDrop, |b| with_cc(b, cc)).await;
+        let n2 = start_node_with(&abc_net.b, b_policy, |b| with_cc(b, cc)).await;
+
+        let recv_listen_port = Port(5658);
+        let mut listener = n2.listen(recv_listen_port).await.unwrap();
+        let receiver = tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            conn.read_till_closed().await
+        });
+
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = n1.connect(dest_ip, recv_listen_port).await.unwrap();
+        let start = tokio::time::Instant::now();
+        test_timeout(Duration::from_secs(20), conn.send_all(&payload))
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+        receiver.abort();
+        payload.len() as f64 / elapsed.as_secs_f64()
+    }
+
+    #[tokio::test]
+    async fn latency_increases_measured_rtt() {
+        let latency = Duration::from_millis(50);
+        let base_rtt = measure_smoothed_rtt(NeverDrop).await;
+        let delayed_rtt = measure_smoothed_rtt(Latency::fixed(latency)).await;
+        assert!(
+            delayed_rtt >= latency && delayed_rtt > base_rtt,
+            "RTT was {base_rtt:?} without latency, {delayed_rtt:?} with it"
+        );
+    }
+
+    #[tokio::test]
+    async fn smoothed_rtt_sums_asymmetric_link_latency() {
+        let (forward, reverse) = (Duration::from_millis(100), Duration::from_millis(20));
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let c_ip = abc_net.c.get_my_interface_ips()[0];
+        // Routes outlive the pause in RIP updates the latency introduces, and
+        // C acks at once so that RTT samples hold no ACK delay.
+        fn configure(b: NodeBuilderStep<'_, NeverDrop>) -> NodeBuilderStep<'_, NeverDrop> {
+            b.with_entry_max_age(Duration::from_secs(1))
+                .with_delayed_ack_interval(Duration::ZERO)
+        }
+        let a = start_node_with(&abc_net.a, NeverDrop, configure).await;
+        let b = start_node_with(&abc_net.b, NeverDrop, configure).await;
+        let c = start_node_with(&abc_net.c, NeverDrop, configure).await;
+        // B's second link leads to C: segments are slowed on their way there
+        // and their ACKs on the way back.
+        b.set_link_latency(1, forward, reverse).await.unwrap();
+
+        let listen_port = Port(5686);
+        let mut listener = c.listen(listen_port).await.unwrap();
+        let receiver = tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            conn.read_till_closed().await
+        });
+        let conn = a.connect(c_ip, listen_port).await.unwrap();
+        test_timeout(
+            Duration::from_secs(5),
+            conn.send_all(&make_in_mem_test_file(20_000)),
+        )
+        .await
+        .unwrap();
+        let rtt = conn.smoothed_rtt().expect("no RTT sampled");
+        receiver.abort();
+
+        // Beyond the path's delays, a round trip only takes the nodes' own
+        // time, a fraction of the delays.
+        let path_rtt = forward + reverse;
+        assert!(
+            rtt >= path_rtt && rtt < path_rtt + path_rtt / 4,
+            "SRTT was {rtt:?} over a {forward:?} + {reverse:?} path"
+        );
+    }
+
+    #[tokio::test]
+    async fn low_latency_round_trip_takes_one_rtt() {
+        let latency = Duration::from_millis(100);
+        let fast = measure_round_trip(latency, true).await;
+        let slow = measure_round_trip(latency, false).await;
+        // Otherwise Nagle holds the request's second write until the first is
+        // (late) acked, for a second trip to B.
+        assert!(
+            fast < 2 * latency && slow >= 2 * latency,
+            "Round trip took {fast:?} with the profile, {slow:?} without it"
+        );
+    }
+
+    /// How long a request written in two parts takes to be answered, with B
+    /// receiving `latency` late.
+    async fn measure_round_trip(latency: Duration, low_latency: bool) -> Duration {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), Latency::fixed(latency)).await;
+
+        let listen_port = Port(5665);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let server = tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            if low_latency {
+                conn.set_low_latency();
+            }
+            let request = conn.read_until(b'\n', 64).await.unwrap();
+            assert_eq!(request, b"ping\n");
+            conn.send_all(b"pong\n").await.unwrap();
+        });
+
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = n1.connect(dest_ip, listen_port).await.unwrap();
+        if low_latency {
+            conn.set_low_latency();
+        }
+        // Let the handshake's last ACK reach B first.
+        tokio::time::sleep(2 * latency).await;
+
+        let start = Instant::now();
+        for part in [&b"ping"[..], b"\n"] {
+            let conn = conn.clone();
+            tokio::spawn(async move { conn.send_all(part).await });
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let response = test_timeout(Duration::from_secs(5), conn.read_until(b'\n', 64))
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(response, b"pong\n");
+        server.await.unwrap();
+        elapsed
+    }
+
+    /// The smoothed RTT of a connection from A to B after sending B some
+    /// data, with B receiving under `b_policy`.
+    async fn measure_smoothed_rtt<DP: DropPolicy>(b_policy: DP) -> Duration {
+        let payload = make_in_mem_test_file(20_000);
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), b_policy).await;
+
+        let recv_listen_port = Port(5659);
+        let mut listener = n2.listen(recv_listen_port).await.unwrap();
+        let receiver = tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            conn.read_till_closed().await
+        });
+
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = n1.connect(dest_ip, recv_listen_port).await.unwrap();
+        test_timeout(Duration::from_secs(5), conn.send_all(&payload))
+            .await
+            .unwrap();
+        let rtt = conn.smoothed_rtt().expect("no RTT sampled");
+        receiver.abort();
+        rtt
+    }
+
+    /// Records when each TCP segment carrying data arrives.
+    #[derive(Clone, Default)]
+    struct DataArrivals(Arc<std::sync::Mutex<Vec<Instant>>>);
+
+    impl DataArrivals {
+        /// The median time between arrivals recorded so far, clearing them.
+        /// Unlike the mean, it is not thrown off by a late retransmission.
+        fn take_median_gap(&self) -> Duration {
+            let arrivals = std::mem::take(&mut *self.0.lock().unwrap());
+            let mut gaps: Vec<_> = arrivals.windows(2).map(|w| w[1] - w[0]).collect();
+            gaps.sort();
+            gaps[gaps.len() / 2]
+        }
+    }
+
+    impl DropPolicy for DataArrivals {
+        fn should_drop(&self, _ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+            false
+        }
+
+        fn delay(&self, ip_header: &Ipv4HeaderSlice<'_>, payload: &[u8]) -> Option<Duration> {
+            let tcp: u8 = Protocol::Tcp.into();
+            let carries_data = TcpHeaderSlice::from_slice(payload)
+                .is_ok_and(|header| header.slice().len() < payload.len());
+            if ip_header.protocol() == tcp && carries_data {
+                self.0.lock().unwrap().push(Instant::now());
+            }
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn pacing_spreads_out_segments() {
+        let unpaced_gap = measure_send_gap(false).await;
+        let paced_gap = measure_send_gap(true).await;
+        assert!(
+            paced_gap > unpaced_gap * 4 && paced_gap >= Duration::from_millis(1),
+            "segments {paced_gap:?} apart with pacing, {unpaced_gap:?} without"
+        );
+    }
+
+    /// The median gap between the segments of a window-sized send from A to B,
+    /// over a path with a 200ms RTT.
+    async fn measure_send_gap(pacing: bool) -> Duration {
+        let arrivals = DataArrivals::default();
+        let b_policy = (Latency::fixed(Duration::from_millis(200)), arrivals.clone());
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = start_node_with(&abc_net.a, NeverDrop, |b| b.with_pacing(pacing)).await;
+        let n2 = start_node_with(&abc_net.b, b_policy, |b| b.with_pacing(pacing)).await;
+
+        let recv_listen_port = Port(5660);
+        let mut listener = n2.listen(recv_listen_port).await.unwrap();
+        let receiver = tokio::spawn(async move {
+            let conn = listener.accept().await.unwrap();
+            conn.read_till_closed().await
+        });
+
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let conn = n1.connect(dest_ip, recv_listen_port).await.unwrap();
+        // Pacing needs an RTT sample to go by.
+        let warmup = make_in_mem_test_file(10 * MAX_SEGMENT_SZ);
+        test_timeout(Duration::from_secs(5), conn.send_all(&warmup))
+            .await
+            .unwrap();
+        arrivals.take_median_gap();
+
+        let payload = make_in_mem_test_file(TCP_DEFAULT_WINDOW_SZ - MAX_SEGMENT_SZ);
+        test_timeout(Duration::from_secs(5), conn.send_all(&payload))
+            .await
+            .unwrap();
+        receiver.abort();
+        arrivals.take_median_gap()
+    }
+
+    /// Holds the first SYN (or with `ack`, SYN-ACK) back for longer than any
+    /// test runs, as good as losing it, and records the sequence number of
+    /// each received.
+    struct LoseFirstSyn {
+        ack: bool,
+        seq_nos: Arc<std::sync::Mutex<Vec<u32>>>,
+    }
+
+    impl LoseFirstSyn {
+        fn new(ack: bool) -> Self {
+            Self {
+                ack,
+                seq_nos: Arc::default(),
+            }
+        }
+    }
+
+    impl DropPolicy for LoseFirstSyn {
+        fn should_drop(&self, _ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+            false
+        }
+
+        fn delay(&self, ip_header: &Ipv4HeaderSlice<'_>, payload: &[u8]) -> Option<Duration> {
+            let tcp: u8 = Protocol::Tcp.into();
+            if ip_header.protocol() != tcp {
+                return None;
+            }
+            let header = TcpHeaderSlice::from_slice(payload).ok()?;
+            if !header.syn() || header.ack() != self.ack {
+                return None;
+            }
+            let mut seq_nos = self.seq_nos.lock().unwrap();
+            seq_nos.push(header.sequence_number());
+            (seq_nos.len() == 1).then_some(Duration::from_secs(60))
+        }
+    }
+
+    /// A segment as seen on the wire: its flags (e.g. "SA" for a SYN-ACK),
+    /// its sequence and acknowledgment numbers relative to the sender's and
+    /// the receiver's initial sequence numbers, and its payload length.
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    struct TracedSegment {
+        flags: String,
+        seq: u32,
+        ack: u32,
+        len: usize,
+    }
+
+    impl TracedSegment {
+        fn new(flags: &str, seq: u32, ack: u32, len: usize) -> Self {
+            Self {
+                flags: flags.to_string(),
+                seq,
+                ack,
+                len,
+            }
+        }
+    }
+
+    struct RecordedSegment {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        header: TcpHeader,
+        len: usize,
+    }
+
+    /// Records every TCP segment received, without dropping any. Installed
+    /// on both ends of a connection, it sees every segment either emits.
+    #[derive(Clone, Default)]
+    struct SegmentTrace {
+        segments: Arc<std::sync::Mutex<Vec<RecordedSegment>>>,
+    }
+
+    impl SegmentTrace {
+        /// The segments `conn` emitted, in order.
+        fn emitted_by(&self, conn: &TcpConn) -> Vec<TracedSegment> {
+            let local = SocketAddrV4::new(conn.local_ip(), conn.local_port().0);
+            let remote = SocketAddrV4::new(conn.remote().ip(), conn.remote().port().0);
+            let segments = self.segments.lock().unwrap();
+            let isn = |from| {
+                segments
+                    .iter()
+                    .find(|s| s.src == from && s.header.syn)
+                    .map_or(0, |s| s.header.sequence_number)
+            };
+            let (local_isn, remote_isn) = (isn(local), isn(remote));
+
+            segments
+                .iter()
+                .filter(|s| s.src == local && s.dst == remote)
+                .map(|RecordedSegment { header, len, .. }| {
+                    let flags = [
+                        (header.syn, 'S'),
+                        (header.fin, 'F'),
+                        (header.rst, 'R'),
+                        (header.psh, 'P'),
+                        (header.ack, 'A'),
+                    ];
+                    TracedSegment {
+                        flags: flags.iter().filter(|f| f.0).map(|f| f.1).collect(),
+                        seq: header.sequence_number.wrapping_sub(local_isn),
+                        ack: match header.ack {
+                            true => header.acknowledgment_number.wrapping_sub(remote_isn),
+                            false => 0,
+                        },
+                        len: *len,
+                    }
+                })
+                .collect()
+        }
+    }
+
+    impl DropPolicy for SegmentTrace {
+        fn should_drop(&self, _ip_header: &Ipv4HeaderSlice<'_>) -> bool {
+            false
+        }
+
+        fn delay(&self, ip_header: &Ipv4HeaderSlice<'_>, payload: &[u8]) -> Option<Duration> {
+            let tcp: u8 = Protocol::Tcp.into();
+            if ip_header.protocol() != tcp {
+                return None;
+            }
+            let header = TcpHeaderSlice::from_slice(payload).ok()?;
+            let src = SocketAddrV4::new(ip_header.source_addr(), header.source_port());
+            let dst = SocketAddrV4::new(ip_header.destination_addr(), header.destination_port());
+            let len = payload.len() - header.slice().len();
+            self.segments.lock().unwrap().push(RecordedSegment {
+                src,
+                dst,
+                header: header.to_header(),
+                len,
+            });
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn open_send_close_segment_trace() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let trace = SegmentTrace::default();
+        let n1 = create_and_start_node(abc_net.a.clone(), trace.clone()).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), trace.clone()).await;
+
+        let (conn, remote_conn) = connect_nodes(&n1, &n2, Port(5666)).await;
+
+        conn.send_all(b"hello").await.unwrap();
+        n1.close_socket(conn.socket_id()).await.unwrap();
+        assert_eq!(remote_conn.read_to_end().await.unwrap(), b"hello");
+        // The passive closer is gone once its FIN is acked.
+        n2.close_socket_and_wait(remote_conn.socket_id(), Duration::from_secs(2))
+            .await
+            .unwrap();
+
+        // Open, send and active close, as in RFC 793, section 3.
+        assert_eq!(
+            trace.emitted_by(&conn),
+            vec![
+                TracedSegment::new("S", 0, 0, 0),
+                TracedSegment::new("A", 1, 1, 0),
+                TracedSegment::new("A", 1, 1, 5),
+                TracedSegment::new("FA", 6, 1, 0),
+                TracedSegment::new("A", 7, 2, 0),
+            ]
+        );
+        // Accept, ack and passive close.
+        assert_eq!(
+            trace.emitted_by(&remote_conn),
+            vec![
+                TracedSegment::new("SA", 0, 1, 0),
+                TracedSegment::new("A", 1, 6, 0),
+                TracedSegment::new("A", 1, 7, 0),
+                TracedSegment::new("FA", 1, 7, 0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_survives_lost_syn() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let policy = LoseFirstSyn::new(false);
+        let syns = policy.seq_nos.clone();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), policy).await;
+
+        let listen_port = Port(5664);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let (conn, remote_conn) = test_timeout(Duration::from_secs(2), async {
+            tokio::join!(n1.connect(dest_ip, listen_port), listener.accept())
+        })
+        .await;
+        conn.unwrap();
+        remote_conn.unwrap();
+
+        // The SYN was resent, with the same sequence number.
+        let syns = syns.lock().unwrap();
+        assert!(syns.len() >= 2);
+        assert!(syns.iter().all(|seq_no| *seq_no == syns[0]));
+    }
+
+    #[tokio::test]
+    async fn handshake_survives_lost_syn_ack() {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let policy = LoseFirstSyn::new(true);
+        let syn_acks = policy.seq_nos.clone();
+        let n1 = create_and_start_node(abc_net.a.clone(), policy).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+
+        let listen_port = Port(5662);
+        let mut listener = n2.listen(listen_port).await.unwrap();
+        let dest_ip = abc_net.b.get_my_interface_ips()[0];
+        let (conn, remote_conn) = test_timeout(Duration::from_secs(2), async {
+            tokio::join!(n1.connect(dest_ip, listen_port), listener.accept())
+        })
+        .await;
+        let conn = conn.unwrap();
+        let remote_conn = remote_conn.unwrap();
+        assert!(syn_acks.lock().unwrap().len() >= 2);
+
+        conn.send_all(b"hello").await.unwrap();
+        let mut received = [0; 5];
+        remote_conn.read_all(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn out_of_order_ranges_are_capped() {
+        let max_ranges = 2;
+        let payload = make_in_mem_test_file(200_000);
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        // Every dropped segment leaves a gap for later ones to pile up behind.
+        let n2 = start_node_with(&abc_net.b, DropFactor::new(0.05), |b| {
+            b.with_max_out_of_order_ranges(max_ranges)
+        })
+        .await;
+
+        let (conn, remote_conn) = connect_nodes(&n1, &n2, Port(5661)).await;
+
+        let done = AtomicBool::new(false);
+        let mut received = vec![0; payload.len()];
+        let recv = async {
+            remote_conn.read_all(&mut received).await.unwrap();
+            done.store(true, Ordering::SeqCst);
+        };
+        let sample = async {
+            let mut most_ranges = 0;
+            while !done.load(Ordering::SeqCst) {
+                most_ranges = max(most_ranges, remote_conn.out_of_order_ranges().await.len());
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            most_ranges
+        };
+        let f = async { tokio::join!(conn.send_all(&payload), recv, sample) };
+        let (sent, (), most_ranges) = test_timeout(Duration::from_secs(20), f).await;
+        sent.unwrap();
+
+        assert_eq!(received, payload);
+        assert!(
+            most_ranges <= max_ranges,
+            "{most_ranges} out-of-order ranges buffered"
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_count_retransmissions() {
+        let lossless = transfer_stats(NeverDrop).await;
+        assert_eq!(lossless.retransmissions, 0);
+        assert_eq!(lossless.bytes_acked, 200_000);
+        assert_eq!(lossless.bytes_sent, 200_000);
+
+        let lossy = transfer_stats(DropFactor::new(0.05)).await;
+        assert!(lossy.retransmissions > 0);
+        assert_eq!(lossy.bytes_acked, 200_000);
+        assert!(lossy.bytes_sent > 200_000);
+        assert!(lossy.segments_received > 0);
+    }
+
+    /// The sender's statistics after sending 200KB to a receiver dropping
+    /// packets by `drop_policy`.
+    async fn transfer_stats(drop_policy: impl DropPolicy) -> SocketStats {
+        let payload = make_in_mem_test_file(200_000);
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), drop_policy).await;
+
+        let (conn, remote_conn) = connect_nodes(&n1, &n2, Port(5663)).await;
+
+        let mut received = vec![0; payload.len()];
+        let f =
+            async { tokio::join!(conn.send_all(&payload), remote_conn.read_all(&mut received)) };
+        let (sent, read) = test_timeout(Duration::from_secs(10), f).await;
+        sent.unwrap();
+        read.unwrap();
+        assert_eq!(received, payload);
+        conn.stats()
+    }
+
+    #[tokio::test]
+    async fn slow_reader_throttles_sender() {
+        let chunk_sz = TCP_DEFAULT_WINDOW_SZ / 4;
+        let payload = make_in_mem_test_file(3 * TCP_DEFAULT_WINDOW_SZ);
+        let (_n1, _n2, conn, remote_conn) = connected_pair(Port(5656)).await;
+
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let reader_consumed = consumed.clone();
+        let expected = payload.clone();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut chunk = vec![0; chunk_sz];
+            while received.len() < expected.len() {
+                tokio::time::sleep(Duration::from_millis(25)).await;
+                let n = min(chunk_sz, expected.len() - received.len());
+                remote_conn.read_all(&mut chunk[..n]).await.unwrap();
+                received.extend_from_slice(&chunk[..n]);
+                reader_consumed.store(received.len(), Ordering::SeqCst);
+            }
+            assert_eq!(received, expected);
+        });
+
+        test_timeout(Duration::from_secs(5), conn.send_all(&payload))
+            .await
+            .unwrap();
+        // Everything the reader has not consumed must fit in its window.
+        let unread = payload.len() - consumed.load(Ordering::SeqCst);
+        assert!(
+            unread <= TCP_DEFAULT_WINDOW_SZ,
+            "{unread} bytes acked but unread"
+        );
+        test_timeout(Duration::from_secs(5), reader).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tee_conn_byte_streams() {
+        let (_n1, _n2, conn, remote_conn) = connected_pair(Port(5656)).await;
+
+        let dir = std::env::temp_dir();
+        let recv_path = dir.join(format!("titan-tee-recv-{}", std::process::id()));
+        let recv_path = recv_path.to_str().unwrap();
+        let sent_path = dir.join(format!("titan-tee-sent-{}", std::process::id()));
+        let sent_path = sent_path.to_str().unwrap();
+        remote_conn.tee(recv_path).await.unwrap();
+        conn.tee_sent(sent_path).await.unwrap();
+
+        let payload = make_in_mem_test_file(100_000);
+        let mut received = vec![0; payload.len()];
+        let (sent, read) = tokio::join!(
+            conn.send_all(&payload),
+            remote_conn.read_all(&mut received[..60_000])
+        );
+        sent.unwrap();
+        read.unwrap();
+        let mut read = 60_000;
+        while read < payload.len() {
+            read += remote_conn.read_some(&mut received[read..]).await.unwrap();
+        }
+
+        remote_conn.stop_tee().await;
+        conn.stop_tee().await;
+        let teed_recv = std::fs::read(recv_path).unwrap();
+        let teed_sent = std::fs::read(sent_path).unwrap();
+        std::fs::remove_file(recv_path).unwrap();
+        std::fs::remove_file(sent_path).unwrap();
+        assert!(teed_recv == payload);
+        assert!(teed_sent == payload);
+    }
+
+    #[tokio::test]
+    async fn copy_file_through_async_io() {
+        let (n1, _n2, mut conn, mut remote_conn) = connected_pair(Port(5671)).await;
+
+        let dir = std::env::temp_dir();
+        let in_path = dir.join(format!("titan-copy-in-{}", std::process::id()));
+        let out_path = dir.join(format!("titan-copy-out-{}", std::process::id()));
+        let payload = make_in_mem_test_file(200_000);
+        std::fs::write(&in_path, &payload).unwrap();
+
+        let mut in_file = File::open(&in_path).await.unwrap();
+        let mut out_file = File::create(&out_path).await.unwrap();
+        let send = async {
+            let copied = tokio::io::copy(&mut in_file, &mut conn).await.unwrap();
+            // Sends the FIN that ends the receiver's copy.
+            conn.shutdown().await.unwrap();
+            copied
+        };
+        let recv = async {
+            let copied = tokio::io::copy(&mut remote_conn, &mut out_file)
+                .await
+                .unwrap();
+            out_file.flush().await.unwrap();
+            copied
+        };
+        let (sent, received) =
+            tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(send, recv) })
+                .await
+                .expect("Copy should finish within time limit");
+
+        let copied = std::fs::read(&out_path).unwrap();
+        std::fs::remove_file(&in_path).unwrap();
+        std::fs::remove_file(&out_path).unwrap();
+        assert_eq!(sent, payload.len() as u64);
+        assert_eq!(received, payload.len() as u64);
+        assert!(copied == payload);
+        let status = n1
+            .get_socket(conn.socket_id())
+            .await
+            .unwrap()
+            .status()
+            .await;
+        assert!(matches!(
+            status,
+            SocketStatus::FinWait1 | SocketStatus::FinWait2
+        ));
+    }
+
+    #[tokio::test]
+    async fn sample_conn_stats() {
+        let interval = Duration::from_millis(10);
+        let (_n1, _n2, conn, remote_conn) = connected_pair(Port(5656)).await;
+
+        let path = std::env::temp_dir().join(format!("titan-sample-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        conn.start_sampling(interval, path).await.unwrap();
+        let start = Instant::now();
+
+        let payload = make_in_mem_test_file(100_000);
+        let mut received = vec![0; payload.len()];
+        let (sent, read) =
+            tokio::join!(conn.send_all(&payload), remote_conn.read_all(&mut received));
+        sent.unwrap();
+        read.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        conn.stop_sampling().await;
+        let expected_rows = (start.elapsed().as_millis() / interval.as_millis()) as usize + 1;
+
+        let csv = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("elapsed_ms,throughput_bps,unacked_bytes,send_window,recv_window")
+        );
+        let rows: Vec<_> = lines.collect();
+        assert!(
+            rows.len() >= expected_rows / 2 && rows.len() <= expected_rows + 1,
+            "sampled {} rows, expected about {expected_rows}",
+            rows.len()
+        );
+        assert!(rows.iter().all(|row| row.split(',').count() == 5));
+    }
+
+    #[tokio::test]
+    async fn connection_survives_interface_change() {
+        let payload = make_in_mem_test_file(1_000_000);
+        let expected = payload.clone();
+        let midpoint = payload.len() / 4;
+
+        let diamond = crate::fixture::netlinks::diamond::gen_unique();
+        // B's address on the path through R1.
+        let b_ip = diamond.b.get_my_interface_ips()[0];
+        let listen_port = Port(5656);
+
+        let a = start_node_with(&diamond.a, NeverDrop, migrating).await;
+        let _r1 = start_node_with(&diamond.r1, NeverDrop, migrating).await;
+        let _r2 = start_node_with(&diamond.r2, NeverDrop, migrating).await;
+        let b = start_node_with(&diamond.b, NeverDrop, migrating).await;
+
+        // Start with A only reachable through R1.
+        a.deactivate(1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let mut listener = b.listen(listen_port).await.unwrap();
+        let conn = a.connect(b_ip, listen_port).await.unwrap();
+        let sender = tokio::spawn(async move {
+            conn.send_all(&payload).await.unwrap();
+        });
+
+        let recv_conn = listener.accept().await.unwrap();
+        let mut buf = vec![0; expected.len()];
+        recv_conn.read_all(&mut buf[..midpoint]).await.unwrap();
+
+        // Move A from its interface towards R1 to its interface towards R2.
+        a.deactivate(0).await.unwrap();
+        a.activate(1).await.unwrap();
+
+        let f = async {
+            recv_conn.read_all(&mut buf[midpoint..]).await.unwrap();
+            sender.await.unwrap();
+        };
+        test_timeout(Duration::from_secs(10), f).await;
+        assert!(buf == expected);
+    }
+
+    async fn test_send_file(in_mem_file: Vec<u8>, drop_policy: impl DropPolicy) {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let send_cfg = abc_net.a.clone();
+        let recv_cfg = abc_net.b.clone();
+
+        let n1_cfg = send_cfg.clone();
+        let n2_cfg = recv_cfg.clone();
+        let expected = in_mem_file.clone();
         let listen_port = Port(8981);
 
         let n1 = tokio::spawn(async move {
@@ -677,9 +3254,12 @@ mod tests {
 
             // Give listener time to set up
             tokio::time::sleep(Duration::from_secs(1)).await;
-            node.connect_and_send_bytes(remote, &in_mem_file)
+            let (acked, conn) = node
+                .connect_and_send_bytes(remote, &in_mem_file)
                 .await
                 .unwrap();
+            assert_eq!(acked, in_mem_file.len());
+            node.close_socket(conn.socket_id()).await.unwrap();
         });
 
         let n2 = tokio::spawn(async move {
@@ -769,28 +3349,84 @@ mod tests {
     }
 
     async fn create_and_start_node<DP: DropPolicy>(cfg: Args, drop_policy: DP) -> Arc<Node<DP>> {
-        let node = Arc::new(
-            NodeBuilder::new(&cfg)
-                .with_rip_interval(Duration::from_millis(1))
-                .with_entry_max_age(Duration::from_millis(12))
-                .with_prune_interval(Duration::from_millis(1))
-                .with_drop_policy(drop_policy)
-                .with_protocol_handler(Protocol::Rip, RipHandler::default())
-                .build()
-                .await,
-        );
-        let node_runner = node.clone();
-        tokio::spawn(async move {
-            node_runner.run().await;
-        });
-        // Give nodes time to converge on routes
-        tokio::time::sleep(Duration::from_millis(300)).await;
-        node
+        start_node_with(&cfg, drop_policy, |b| b).await
+    }
+
+    /// Starts neighbors A and B, and connects A to B on `port`. Returns the
+    /// nodes, then A's and B's ends of the connection.
+    async fn connected_pair(
+        port: Port,
+    ) -> (Arc<Node<NeverDrop>>, Arc<Node<NeverDrop>>, TcpConn, TcpConn) {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+        let (conn, remote_conn) = connect_nodes(&n1, &n2, port).await;
+        (n1, n2, conn, remote_conn)
+    }
+
+    /// Connects `n1` to `n2` on `port`, over the first link of `n2`. Returns
+    /// `n1`'s and `n2`'s ends of the connection.
+    async fn connect_nodes<DP1: DropPolicy, DP2: DropPolicy>(
+        n1: &Node<DP1>,
+        n2: &Node<DP2>,
+        port: Port,
+    ) -> (TcpConn, TcpConn) {
+        let mut listener = n2.listen(port).await.unwrap();
+        let (_, dest_ip, _) = n2.interface_ips().await[0];
+        let conn = n1.connect(dest_ip, port).await.unwrap();
+        let remote_conn = listener.accept().await.unwrap();
+        (conn, remote_conn)
+    }
+
+    /// Acks every segment at once, so that only `cc` decides how fast
+    /// connections send.
+    fn with_cc(
+        builder: NodeBuilderStep<'_, NeverDrop>,
+        cc: CongestionAlgorithm,
+    ) -> NodeBuilderStep<'_, NeverDrop> {
+        builder
+            .with_delayed_ack_interval(Duration::ZERO)
+            .with_congestion_control(cc)
+    }
+
+    /// Lets connections follow a route change, with RIP timers slow enough
+    /// to switch interfaces in between.
+    fn migrating(builder: NodeBuilderStep<'_, NeverDrop>) -> NodeBuilderStep<'_, NeverDrop> {
+        builder
+            .with_rip_interval(Duration::from_millis(10))
+            .with_entry_max_age(Duration::from_millis(100))
+            .with_prune_interval(Duration::from_millis(10))
+            .with_connection_migration()
+    }
+
+    /// Starts neighbors A and B. Returns the nodes and their addresses on
+    /// the link between them.
+    async fn start_md5_pair() -> (
+        Arc<Node<NeverDrop>>,
+        Arc<Node<NeverDrop>>,
+        Ipv4Addr,
+        Ipv4Addr,
+    ) {
+        let abc_net = crate::fixture::netlinks::abc::gen_unique();
+        let a_to_b = abc_net.a.links[0];
+        let n1 = create_and_start_node(abc_net.a.clone(), NeverDrop).await;
+        let n2 = create_and_start_node(abc_net.b.clone(), NeverDrop).await;
+        (n1, n2, a_to_b.interface_ip, a_to_b.dest_ip)
+    }
+
+    /// Connects A to B, sends a payload from A and closes A's end. Returns
+    /// the nodes and B's end of the connection.
+    async fn send_then_close(payload: &[u8]) -> (Vec<Arc<Node<NeverDrop>>>, TcpConn) {
+        let (n1, n2, conn, remote_conn) = connected_pair(Port(5656)).await;
+
+        conn.send_all(payload).await.unwrap();
+        n1.close_socket(conn.socket_id()).await.unwrap();
+        (vec![n1, n2], remote_conn)
     }
 
-    async fn test_timeout<F: Future>(dur: Duration, f: F) {
+    async fn test_timeout<F: Future>(dur: Duration, f: F) -> F::Output {
         tokio::time::timeout(dur, f)
             .await
-            .expect("Test should finish within time limit");
+            .expect("Test should finish within time limit")
     }
 }
@@ -0,0 +1,92 @@
+//! TCP header options exchanged during the handshake.
+
+pub const TCP_OPTION_END: u8 = 0;
+pub const TCP_OPTION_NOOP: u8 = 1;
+/// The option kind of the maximum segment size option.
+pub const TCP_OPTION_MSS: u8 = 2;
+/// The option kind of the window scale option (RFC 7323).
+pub const TCP_OPTION_WINDOW_SCALE: u8 = 3;
+/// The largest window shift a peer may use (RFC 7323, section 2.3).
+pub const TCP_MAX_WINDOW_SHIFT: u8 = 14;
+
+const MSS_OPTION_LEN: usize = 4;
+const WINDOW_SCALE_OPTION_LEN: usize = 3;
+
+/// Lays out a maximum segment size option.
+pub fn mss_option(mss: u16) -> Vec<u8> {
+    let mut option = vec![TCP_OPTION_MSS, MSS_OPTION_LEN as u8];
+    option.extend_from_slice(&mss.to_be_bytes());
+    option
+}
+
+/// Finds the maximum segment size a peer advertised.
+pub fn find_mss(options: &[u8]) -> Option<u16> {
+    let mss = find_option(options, TCP_OPTION_MSS)?;
+    Some(u16::from_be_bytes(mss.try_into().ok()?))
+}
+
+/// Lays out a window scale option, padded to 4 bytes.
+pub fn window_scale_option(shift: u8) -> Vec<u8> {
+    vec![
+        TCP_OPTION_NOOP,
+        TCP_OPTION_WINDOW_SCALE,
+        WINDOW_SCALE_OPTION_LEN as u8,
+        shift,
+    ]
+}
+
+/// Finds the window shift a peer advertised, capped at
+/// `TCP_MAX_WINDOW_SHIFT`.
+pub fn find_window_scale(options: &[u8]) -> Option<u8> {
+    match find_option(options, TCP_OPTION_WINDOW_SCALE)? {
+        &[shift] => Some(shift.min(TCP_MAX_WINDOW_SHIFT)),
+        _ => None,
+    }
+}
+
+/// Finds the data of the first option of kind `kind`.
+pub fn find_option(mut options: &[u8], kind: u8) -> Option<&[u8]> {
+    while let Some(&curr_kind) = options.first() {
+        match curr_kind {
+            TCP_OPTION_END => return None,
+            TCP_OPTION_NOOP => options = &options[1..],
+            _ => {
+                let len = *options.get(1)? as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if curr_kind == kind {
+                    return Some(&options[2..len]);
+                }
+                options = &options[len..];
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_mss_among_other_options() {
+        let mut options = vec![TCP_OPTION_NOOP, 34, 2];
+        options.extend(mss_option(256));
+        assert_eq!(find_mss(&options), Some(256));
+
+        assert_eq!(find_mss(&[TCP_OPTION_NOOP, TCP_OPTION_END]), None);
+        // A truncated option ends the search.
+        assert_eq!(find_mss(&[TCP_OPTION_MSS, 4, 1]), None);
+    }
+
+    #[test]
+    fn finds_window_scale() {
+        let mut options = mss_option(1024);
+        options.extend(window_scale_option(4));
+        assert_eq!(find_window_scale(&options), Some(4));
+
+        assert_eq!(find_window_scale(&window_scale_option(20)), Some(14));
+        assert_eq!(find_window_scale(&mss_option(1024)), None);
+    }
+}
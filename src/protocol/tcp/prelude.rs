@@ -49,6 +49,9 @@ impl From<u16> for Port {
 #[derive(Hash, PartialEq, Eq, Debug, Copy, Clone)]
 pub struct SocketId {
     remote: (Ipv4Addr, Port),
+    // Only set on listen sockets bound to one interface. Unspecified
+    // otherwise.
+    local_ip: Ipv4Addr,
     local_port: Port,
 }
 
@@ -57,9 +60,12 @@ impl SocketId {
         SocketIdBuilder::default()
     }
 
-    pub fn for_listen_socket(local_port: Port) -> Self {
+    /// The id of a socket listening on `local_port` at interface IP
+    /// `local_ip`, or at every interface if `local_ip` is unspecified.
+    pub fn for_listen_socket(local_ip: Ipv4Addr, local_port: Port) -> Self {
         Self {
             remote: (Ipv4Addr::new(0, 0, 0, 0), Port(0)),
+            local_ip,
             local_port,
         }
     }
@@ -76,6 +82,10 @@ impl SocketId {
         self.remote.1
     }
 
+    pub fn local_ip(&self) -> Ipv4Addr {
+        self.local_ip
+    }
+
     pub fn local_port(&self) -> Port {
         self.local_port
     }
@@ -117,6 +127,7 @@ impl SocketIdBuilder {
                 self.remote_ip.ok_or(BuildSocketIdError::NoRemoteIp)?,
                 self.remote_port.ok_or(BuildSocketIdError::NoRemotePort)?,
             ),
+            local_ip: Ipv4Addr::UNSPECIFIED,
             local_port: self.local_port.ok_or(BuildSocketIdError::NoLocalPort)?,
         })
     }
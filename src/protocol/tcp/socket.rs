@@ -1,50 +1,82 @@
 use crate::net::Net;
-use crate::protocol::tcp::buf::{FillError, SetTailError, WriteRangeError};
+use crate::node_log;
+use crate::protocol::tcp::buf::{FillError, SendBufClosed, SetTailError, WriteRangeError};
 use crate::protocol::tcp::prelude::SocketIdBuilder;
-use crate::protocol::tcp::transport::RtxConfig;
+use crate::protocol::tcp::transport::{RtxConfig, TCP_MAX_SYN_RETRIES};
 use crate::protocol::tcp::{TcpAcceptError, TcpReadError, TcpSendError};
-use crate::protocol::Protocol;
+use crate::utils::logging::NodeLogger;
 use crate::utils::sync::RaceOneShotSender;
 use etherparse::{Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use log::Level;
 use rand::{thread_rng, Rng};
 use std::cmp::min;
+use std::collections::VecDeque;
+use std::future::Future;
 use std::net::Ipv4Addr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
 
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::sync::mpsc::{self, channel};
-use tokio::sync::{broadcast, oneshot, Mutex};
+use tokio::sync::{broadcast, oneshot, watch, Mutex, Notify, OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
+use tokio_stream::Stream;
 
 use super::ack_policy::{self, AckPolicy};
-use super::buf::{RecvBuf, SendBuf};
-use super::transport::{transport_single_message, AckHandle, TcpTransport};
+use super::buf::{RecvBuf, SendBuf, SendReservation};
+use super::fastopen;
+use super::md5sig::Md5Keys;
+use super::options;
+use super::transport::{
+    send_segment, transport_single_message, transport_single_message_from, AckHandle,
+    KeepaliveConfig, SocketStats, TcpTransport,
+};
 use super::{
-    Port, Remote, SocketDescriptor, SocketId, TcpCloseError, TcpConnError, TCP_DEFAULT_WINDOW_SZ,
+    make_reset_reply, Port, Remote, SocketConfig, SocketDescriptor, SocketId, TcpCloseError,
+    TcpConnError, MAX_PENDING_TCP_CONNECTIONS, MAX_SEGMENT_SZ, TCP_BANDWIDTH_WINDOW,
+    TCP_CHALLENGE_ACK_INTERVAL, TCP_CHALLENGE_ACK_LIMIT, TCP_DEFAULT_READ_TO_END_LIMIT,
+    TCP_DEFAULT_WINDOW_SZ, TCP_DELIVERY_RATE_INTERVAL, TCP_THROUGHPUT_WINDOW, TCP_WINDOW_SHIFT,
 };
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct TcpConn {
-    inner: Arc<InnerTcpConn<TCP_DEFAULT_WINDOW_SZ, ack_policy::AlwaysAck>>,
+    inner: Arc<InnerTcpConn<TCP_DEFAULT_WINDOW_SZ, ack_policy::ConfigurableAck>>,
     socket_id: SocketId,
+    /// Operations started by `AsyncRead` and `AsyncWrite` polls, which are
+    /// not shared with clones.
+    io: std::sync::Mutex<PendingIo>,
 }
 
 impl TcpConn {
+    #[allow(clippy::too_many_arguments)]
     fn new<N: Net + Send + Sync>(
         socket_id: SocketId,
         remote: Remote,
+        local_ip: Ipv4Addr,
         local_port: Port,
         start_seq_no: usize,
         start_ack_no: usize,
+        negotiated: Negotiated,
         net: Arc<N>,
+        config: Arc<SocketConfig>,
+        md5_key: Option<Vec<u8>>,
     ) -> Self {
         Self {
             socket_id,
+            io: Default::default(),
             inner: Arc::new(InnerTcpConn::new(
                 remote,
+                local_ip,
                 local_port,
                 start_seq_no,
                 start_ack_no,
+                negotiated,
                 net,
+                config,
+                md5_key,
             )),
         }
     }
@@ -61,6 +93,27 @@ impl TcpConn {
         self.inner.read_all(out_buffer).await
     }
 
+    /// Reads at least one byte into `out_buffer`, returning the number of
+    /// bytes read. Errs with `TcpReadError::Closed(0)` once the remote has
+    /// closed and every byte it sent has been read.
+    pub async fn read_some(&self, out_buffer: &mut [u8]) -> Result<usize, TcpReadError> {
+        self.inner.read_some(out_buffer).await
+    }
+
+    /// Reads whatever bytes are buffered into `out_buffer` without waiting,
+    /// returning the number of bytes read. Errs with
+    /// `TcpReadError::WouldBlock` if none are buffered yet.
+    pub async fn try_read(&self, out_buffer: &mut [u8]) -> Result<usize, TcpReadError> {
+        self.inner.try_read(out_buffer).await
+    }
+
+    /// Reads up to and including the next `delim` byte, returning the bytes
+    /// read. Stops early after `max` bytes, or once the remote has closed;
+    /// errs with `TcpReadError::Closed(0)` only if nothing was left to read.
+    pub async fn read_until(&self, delim: u8, max: usize) -> Result<Vec<u8>, TcpReadError> {
+        self.inner.read_until(delim, max).await
+    }
+
     /// Read all bytes from the connection until it is closed.
     pub async fn read_till_closed(&self) -> Vec<u8> {
         let mut read_buf = [0; 1024];
@@ -84,6 +137,35 @@ impl TcpConn {
         out_buf
     }
 
+    /// Reads all bytes from the connection until the remote closes, up to
+    /// `TCP_DEFAULT_READ_TO_END_LIMIT` bytes.
+    pub async fn read_to_end(&self) -> Result<Vec<u8>, TcpReadError> {
+        self.read_to_end_with_limit(TCP_DEFAULT_READ_TO_END_LIMIT)
+            .await
+    }
+
+    /// Reads all bytes from the connection until the remote closes, erring
+    /// once more than `limit` bytes arrive.
+    pub async fn read_to_end_with_limit(&self, limit: usize) -> Result<Vec<u8>, TcpReadError> {
+        let mut read_buf = [0; 1024];
+        let mut out_buf = Vec::new();
+
+        loop {
+            let (read_bytes, closed) = match self.read_all(&mut read_buf).await {
+                Ok(_) => (read_buf.len(), false),
+                Err(TcpReadError::Closed(read_bytes)) => (read_bytes, true),
+                Err(e) => return Err(e),
+            };
+            if out_buf.len() + read_bytes > limit {
+                return Err(TcpReadError::ExceedsLimit(limit));
+            }
+            out_buf.extend_from_slice(&read_buf[..read_bytes]);
+            if closed {
+                return Ok(out_buf);
+            }
+        }
+    }
+
     pub fn socket_id(&self) -> SocketId {
         self.socket_id
     }
@@ -92,19 +174,256 @@ impl TcpConn {
         &self.inner.remote
     }
 
+    /// The interface IP this connection sends from. It is kept even if the
+    /// route to the remote changes.
+    pub fn local_ip(&self) -> Ipv4Addr {
+        self.inner.local_ip
+    }
+
     pub fn local_port(&self) -> Port {
         self.inner.local_port
     }
 
+    /// The key signing this connection's segments (RFC 2385), if any.
+    pub fn md5_key(&self) -> Option<&[u8]> {
+        self.inner.md5_key.as_deref()
+    }
+
+    /// The largest segment sent on this connection: the smaller of the
+    /// maximum segment sizes advertised by either end.
+    pub fn mss(&self) -> usize {
+        self.inner.negotiated.mss
+    }
+
+    /// The status of the socket owning this connection.
+    pub fn state(&self) -> SocketStatus {
+        self.inner.status()
+    }
+
+    fn set_state(&self, status: SocketStatus) {
+        self.inner.status.send_replace(status);
+    }
+
+    /// Set the policy deciding which incoming segments are ACKed right away.
+    /// In-order data is then acked as the policy says, rather than delayed;
+    /// the delayed ACK timer only acks what the policy leaves unacked.
+    pub fn set_ack_policy<A: AckPolicy + Send + Sync + 'static>(&self, policy: A) {
+        self.inner.ack_policy.set(policy);
+        self.inner.delayed_ack.send_replace(false);
+    }
+
+    /// Enable, tune or (with `None`) disable keepalive probes on this
+    /// connection.
+    pub fn set_keepalive(&self, keepalive: Option<KeepaliveConfig>) {
+        self.inner.keepalive.send_replace(keepalive);
+    }
+
+    pub fn keepalive(&self) -> Option<KeepaliveConfig> {
+        *self.inner.keepalive.borrow()
+    }
+
+    /// Disable (`true`) or re-enable (`false`) Nagle's algorithm. With it
+    /// enabled, small writes are coalesced while sent data is unacked.
+    pub fn set_nodelay(&self, nodelay: bool) {
+        self.inner.nodelay.send_replace(nodelay);
+    }
+
+    pub fn nodelay(&self) -> bool {
+        *self.inner.nodelay.borrow()
+    }
+
+    /// Ack (`true`) every data segment as soon as it arrives, regardless of
+    /// the ACK policy and of delayed ACKs, or go back to them (`false`).
+    pub fn set_quickack(&self, quickack: bool) {
+        self.inner.quickack.store(quickack, Ordering::Relaxed);
+    }
+
+    pub fn quickack(&self) -> bool {
+        self.inner.quickack.load(Ordering::Relaxed)
+    }
+
+    /// Set what closing this connection does with data not yet acked.
+    pub fn set_linger(&self, linger: LingerConfig) {
+        *self.inner.linger.lock().unwrap() = linger;
+    }
+
+    pub fn linger(&self) -> LingerConfig {
+        *self.inner.linger.lock().unwrap()
+    }
+
+    /// Tune the connection for request/response traffic: small writes go
+    /// out at once, and received data is acked at once.
+    pub fn set_low_latency(&self) {
+        self.set_nodelay(true);
+        self.set_quickack(true);
+    }
+
+    /// Retransmit the oldest unacked segment now, regardless of the
+    /// retransmission timer. Does nothing if all sent data is acked.
+    #[cfg(any(test, feature = "debug-commands"))]
+    pub fn force_retransmit(&self) {
+        self.inner.retransmit.notify_one();
+    }
+
+    /// Bits per second acknowledged by the remote over the last
+    /// `TCP_THROUGHPUT_WINDOW`.
+    pub fn throughput_bps(&self) -> f64 {
+        self.inner.throughput.bits_per_sec()
+    }
+
+    /// The bottleneck bandwidth of the path to the remote in bits per second,
+    /// estimated as the highest rate data was acknowledged at over the last
+    /// `TCP_BANDWIDTH_WINDOW`. `None` until enough acks have arrived.
+    pub fn estimated_bandwidth(&self) -> Option<f64> {
+        self.inner.bandwidth.bits_per_sec()
+    }
+
+    /// The smoothed round-trip time to the remote (RFC 6298), or `None`
+    /// until an ack has been timed.
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        *self.inner.srtt.borrow()
+    }
+
+    /// Counts of the segments and bytes this connection has sent and
+    /// received so far.
+    pub fn stats(&self) -> SocketStats {
+        *self.inner.stats.lock().unwrap()
+    }
+
+    /// Restart this connection's throughput and bandwidth measurements.
+    pub fn reset_stats(&self) {
+        self.inner.throughput.reset();
+        self.inner.bandwidth.reset();
+    }
+
+    /// Write a CSV row of this connection's statistics to `path` every
+    /// `interval`, until `stop_sampling` is called or the connection closes.
+    /// Replaces any sampling already in progress.
+    pub async fn start_sampling(&self, interval: Duration, path: &str) -> std::io::Result<()> {
+        self.stop_sampling().await;
+
+        let mut f = tokio::fs::File::create(path).await?;
+        f.write_all(b"elapsed_ms,throughput_bps,unacked_bytes,send_window,recv_window\n")
+            .await?;
+
+        let (stop_tx, mut stop_rx) = oneshot::channel();
+        let inner = Arc::downgrade(&self.inner);
+        let worker = tokio::spawn(async move {
+            let start = Instant::now();
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = &mut stop_rx => break,
+                    _ = ticker.tick() => (),
+                }
+                let row = match inner.upgrade() {
+                    Some(inner) if inner.status() != SocketStatus::Closed => {
+                        inner.stats_row(start.elapsed()).await
+                    }
+                    _ => break,
+                };
+                if f.write_all(row.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            f.flush().await.ok();
+        });
+
+        *self.inner.sampler.lock().unwrap() = Some(Sampler {
+            stop: stop_tx,
+            worker,
+        });
+        Ok(())
+    }
+
+    /// Stop sampling started by `start_sampling`. Returns once all rows
+    /// sampled so far are written.
+    pub async fn stop_sampling(&self) {
+        let sampler = self.inner.sampler.lock().unwrap().take();
+        if let Some(sampler) = sampler {
+            sampler.stop.send(()).ok();
+            sampler.worker.await.ok();
+        }
+    }
+
+    /// Copy every byte the application reads from this connection to
+    /// `path`, until `stop_tee` is called. Replaces any tee of received bytes
+    /// already in progress.
+    pub async fn tee(&self, path: &str) -> std::io::Result<()> {
+        let tee = Tee::create(path).await?;
+        let prev = self.inner.recv_tee.lock().unwrap().replace(tee);
+        if let Some(prev) = prev {
+            prev.close().await;
+        }
+        Ok(())
+    }
+
+    /// Like `tee`, but copies the bytes the application sends.
+    pub async fn tee_sent(&self, path: &str) -> std::io::Result<()> {
+        let tee = Tee::create(path).await?;
+        let prev = self.inner.send_tee.lock().unwrap().replace(tee);
+        if let Some(prev) = prev {
+            prev.close().await;
+        }
+        Ok(())
+    }
+
+    /// Stop teeing started by `tee` and `tee_sent`. Returns once all bytes
+    /// copied so far are written.
+    pub async fn stop_tee(&self) {
+        let recv_tee = self.inner.recv_tee.lock().unwrap().take();
+        let send_tee = self.inner.send_tee.lock().unwrap().take();
+        for tee in [recv_tee, send_tee].into_iter().flatten() {
+            tee.close().await;
+        }
+    }
+
+    /// The [start, end) sequence number ranges received ahead of the next
+    /// expected byte, in ascending order.
+    pub async fn out_of_order_ranges(&self) -> Vec<(usize, usize)> {
+        self.inner.recv_buf.early_arrival_ranges().await
+    }
+
+    /// Discard all out-of-order segments, returning how many were discarded.
+    pub async fn clear_out_of_order(&self) -> usize {
+        self.inner.recv_buf.clear_early_arrivals().await
+    }
+
+    /// The number of bytes written to the connection that the remote has not
+    /// acked yet.
+    pub async fn send_buffered(&self) -> usize {
+        self.inner.send_buf.head().await - self.inner.send_buf.tail().await
+    }
+
+    /// The number of bytes received in order that have not been read yet.
+    pub async fn recv_buffered(&self) -> usize {
+        self.inner.recv_buf.head().await - self.inner.recv_buf.tail().await
+    }
+
     /// Close the write-end of the socket.
     async fn close(&self) {
         self.inner.close().await.ok();
     }
 
+    /// Notified once the application asks to close the write-end through
+    /// `AsyncWrite::poll_shutdown`, or once the connection is dropped.
+    fn shutdown_requests(&self) -> Arc<Notify> {
+        self.inner.shutdown_requested.clone()
+    }
+
+    fn aborts(&self) -> Arc<Notify> {
+        self.inner.aborted.clone()
+    }
+
     async fn close_read(&self) {
         self.inner.close_read().await.ok();
     }
 
+    /// Close the read-end of the socket on the application's request.
+    async fn shutdown_read(&self) {
+        self.inner.shutdown_read().await.ok();
+    }
+
     pub fn is_read_closed(&self) -> bool {
         self.inner.is_read_closed()
     }
@@ -124,6 +443,37 @@ impl TcpConn {
         self.inner.drain_content_on_close().await
     }
 
+    /// Has the transport send a RST, then close both ends of the connection.
+    fn reset(&self) {
+        self.inner.reset.notify_one();
+    }
+
+    /// Close both ends of the connection without waiting for unsent data.
+    async fn abort(&self) {
+        self.inner.close().await.ok();
+        self.inner.close_read().await.ok();
+    }
+
+    fn send_ack(&self) {
+        self.inner.should_ack.send(()).ok();
+    }
+
+    /// Writes data that arrived before the connection was established into
+    /// the receive buffer, and acks it.
+    async fn deliver_early_data(&self, seq_no: u32, payload: &[u8]) {
+        self.inner.write_received_bytes(seq_no, payload).await;
+        self.inner.should_ack.send(()).ok();
+    }
+
+    /// Queues bytes to be sent, without waiting for them to be acked.
+    async fn queue_data(&self, bytes: &[u8]) {
+        self.inner.send_buf.write_all(bytes).await.ok();
+    }
+
+    async fn next_expected_seq_no(&self) -> usize {
+        self.inner.recv_buf.head().await
+    }
+
     async fn local_window_sz(&self) -> usize {
         self.inner.local_window_sz().await
     }
@@ -133,49 +483,352 @@ impl TcpConn {
     }
 }
 
+/// What closing a connection does with data not yet acked, akin to
+/// `SO_LINGER`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LingerConfig {
+    /// Queued data is sent, and the FIN follows once the remote acked it.
+    #[default]
+    Graceful,
+    /// A RST is sent at once, and queued data is discarded. The socket skips
+    /// TIME_WAIT.
+    Abort,
+}
+
+type BoxedIo<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// The state `AsyncRead` and `AsyncWrite` keep between polls.
+#[derive(Default)]
+struct PendingIo {
+    read: Option<BoxedIo<Result<Vec<u8>, TcpReadError>>>,
+    /// Bytes read that did not fit in the caller's buffer.
+    unread: VecDeque<u8>,
+    /// Room in the send buffer being waited for.
+    reserve: Option<BoxedIo<Result<SendReservation<TCP_DEFAULT_WINDOW_SZ>, SendBufClosed>>>,
+    shutdown_requested: bool,
+}
+
+impl Clone for TcpConn {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            socket_id: self.socket_id,
+            io: Default::default(),
+        }
+    }
+}
+
+impl std::fmt::Debug for PendingIo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PendingIo")
+            .field("read", &self.read.is_some())
+            .field("unread", &self.unread.len())
+            .field("reserve", &self.reserve.is_some())
+            .finish()
+    }
+}
+
+fn closed_for_writes() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "connection closed")
+}
+
+impl AsyncRead for TcpConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let inner = self.inner.clone();
+        let io = self.get_mut().io.get_mut().unwrap();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if !io.unread.is_empty() {
+            let n = min(buf.remaining(), io.unread.len());
+            let (front, back) = io.unread.as_slices();
+            let from_front = min(n, front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..n - from_front]);
+            io.unread.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+
+        let len = buf.remaining();
+        let read = io.read.get_or_insert_with(|| {
+            Box::pin(async move {
+                let mut bytes = vec![0; len];
+                let n = inner.read_some(&mut bytes).await?;
+                bytes.truncate(n);
+                Ok(bytes)
+            })
+        });
+        let result = ready!(read.as_mut().poll(cx));
+        io.read = None;
+        match result {
+            Ok(bytes) => {
+                let n = min(buf.remaining(), bytes.len());
+                buf.put_slice(&bytes[..n]);
+                io.unread.extend(&bytes[n..]);
+                Poll::Ready(Ok(()))
+            }
+            // The remote closed: end of stream.
+            Err(TcpReadError::Closed(_)) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(std::io::Error::other(format!("{e:?}")))),
+        }
+    }
+}
+
+impl AsyncWrite for TcpConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let inner = self.inner.clone();
+        let io = self.get_mut().io.get_mut().unwrap();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Only the wait for room outlives the poll: the bytes are copied
+        // from whichever buffer the poll that gets the room is given.
+        let send_buf = inner.send_buf.clone();
+        let reserve = io
+            .reserve
+            .get_or_insert_with(|| Box::pin(async move { send_buf.reserve().await }));
+        let reserved = ready!(reserve.as_mut().poll(cx));
+        io.reserve = None;
+        let n = reserved.map_err(|_| closed_for_writes())?.write(buf);
+        Tee::write(&inner.send_tee, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    /// Written bytes are already in the send buffer, from which the transport
+    /// sends them on its own: flushing does not wait for them to be acked.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends a FIN once every byte written so far is acked.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !self.io.get_mut().unwrap().shutdown_requested {
+            ready!(self.as_mut().poll_flush(cx))?;
+            self.io.get_mut().unwrap().shutdown_requested = true;
+            self.inner.shutdown_requested.notify_one();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
 #[derive(Debug)]
 struct InnerTcpConn<const BUF_SZ: usize, A: AckPolicy> {
     send_buf: SendBuf<BUF_SZ>,
     recv_buf: RecvBuf<BUF_SZ>,
     remote: Remote,
+    local_ip: Ipv4Addr,
     local_port: Port,
+    negotiated: Negotiated,
     transport_worker: JoinHandle<()>,
     should_ack: broadcast::Sender<()>,
+    dup_ack: broadcast::Sender<usize>,
     ack_policy: A,
+    throughput: ThroughputMeter,
+    bandwidth: BandwidthEstimator,
+    keepalive: watch::Sender<Option<KeepaliveConfig>>,
+    nodelay: watch::Sender<bool>,
+    last_heard: watch::Sender<Instant>,
+    srtt: watch::Receiver<Option<Duration>>,
+    stats: Arc<std::sync::Mutex<SocketStats>>,
+    status: watch::Sender<SocketStatus>,
+    send_stall_timeout: Option<Duration>,
+    /// Whether in-order data may be acked late, by the transport's ACK timer.
+    /// Cleared once an ACK policy is set, which then decides.
+    delayed_ack: watch::Sender<bool>,
+    /// Whether every data segment is acked at once.
+    quickack: AtomicBool,
+    linger: std::sync::Mutex<LingerConfig>,
+    shutdown_requested: Arc<Notify>,
+    /// Whether the application shut the read side down: received data is
+    /// still acked, then dropped.
+    read_shutdown: AtomicBool,
+    reset: Arc<Notify>,
+    #[cfg(any(test, feature = "debug-commands"))]
+    retransmit: Arc<Notify>,
+    /// Notified once the transport gives up on the connection: the remote
+    /// stopped answering keepalives, or the connection was reset.
+    aborted: Arc<Notify>,
+    md5_key: Option<Vec<u8>>,
+    logger: NodeLogger,
+    sampler: std::sync::Mutex<Option<Sampler>>,
+    /// Copies of the bytes the application reads.
+    recv_tee: std::sync::Mutex<Option<Tee>>,
+    /// Copies of the bytes the application sends.
+    send_tee: std::sync::Mutex<Option<Tee>>,
+}
+
+/// A task writing a connection's statistics to a CSV file.
+#[derive(Debug)]
+struct Sampler {
+    stop: oneshot::Sender<()>,
+    worker: JoinHandle<()>,
+}
+
+/// A task copying one direction of a connection's byte stream to a file.
+#[derive(Debug)]
+struct Tee {
+    bytes: mpsc::UnboundedSender<Vec<u8>>,
+    worker: JoinHandle<()>,
+}
+
+impl Tee {
+    async fn create(path: &str) -> std::io::Result<Self> {
+        let mut f = tokio::fs::File::create(path).await?;
+        let (bytes, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let worker = tokio::spawn(async move {
+            while let Some(bytes) = rx.recv().await {
+                if f.write_all(&bytes).await.is_err() {
+                    break;
+                }
+            }
+            f.flush().await.ok();
+        });
+        Ok(Self { bytes, worker })
+    }
+
+    fn write(tee: &std::sync::Mutex<Option<Tee>>, bytes: &[u8]) {
+        if let Some(tee) = tee.lock().unwrap().as_ref() {
+            tee.bytes.send(bytes.to_vec()).ok();
+        }
+    }
+
+    /// Returns once every byte copied so far is written.
+    async fn close(self) {
+        drop(self.bytes);
+        self.worker.await.ok();
+    }
 }
 
 impl<const BUF_SZ: usize, A: AckPolicy + Default> InnerTcpConn<BUF_SZ, A> {
+    #[allow(clippy::too_many_arguments)]
     fn new<N: Net + Send + Sync>(
         remote: Remote,
+        local_ip: Ipv4Addr,
         local_port: Port,
         start_seq_no: usize,
         start_ack_no: usize,
+        negotiated: Negotiated,
         net: Arc<N>,
+        config: Arc<SocketConfig>,
+        md5_key: Option<Vec<u8>>,
     ) -> Self {
         let send_buf = SendBuf::new(start_seq_no);
-        let recv_buf = RecvBuf::new(start_ack_no);
+        let recv_buf =
+            RecvBuf::with_max_early_ranges(start_ack_no, config.tcp.max_out_of_order_ranges);
         let (should_ack_tx, should_ack_rx) = broadcast::channel(10);
+        let (dup_ack_tx, dup_ack_rx) = broadcast::channel(10);
+        let (keepalive_tx, keepalive_rx) = watch::channel(config.tcp.keepalive);
+        let (nodelay_tx, nodelay_rx) = watch::channel(false);
+        let (last_heard_tx, last_heard_rx) = watch::channel(Instant::now());
+        let (srtt_tx, srtt_rx) = watch::channel(None);
+        let stats = Arc::new(std::sync::Mutex::new(SocketStats::default()));
+        let send_stall_timeout = config.tcp.send_stall_timeout;
+        let (delayed_ack_tx, delayed_ack_rx) =
+            watch::channel(config.tcp.delayed_ack_interval.is_some());
+        let reset = Arc::new(Notify::new());
+        let retransmit = Arc::new(Notify::new());
+        let aborted = Arc::new(Notify::new());
+        let logger = net.logger();
 
         let sb = send_buf.clone();
         let rb = recv_buf.clone();
+        let transport_reset = reset.clone();
+        let transport_retransmit = retransmit.clone();
+        let transport_aborted = aborted.clone();
+        let transport_stats = stats.clone();
+        let transport_md5_key = md5_key.clone();
         let transport_worker = tokio::spawn(async move {
-            TcpTransport::init(sb, rb, remote, local_port, net, should_ack_rx)
-                .await
-                .run()
-                .await;
+            TcpTransport::init(
+                sb,
+                rb,
+                remote,
+                local_port,
+                net,
+                &config.tcp,
+                should_ack_rx,
+                dup_ack_rx,
+                keepalive_rx,
+                nodelay_rx,
+                delayed_ack_rx,
+                last_heard_rx,
+                srtt_tx,
+                transport_stats,
+                transport_reset,
+                transport_retransmit,
+                transport_aborted,
+                transport_md5_key,
+                local_ip,
+                negotiated.mss,
+                negotiated.recv_window_shift,
+            )
+            .await
+            .run()
+            .await;
         });
 
         Self {
             send_buf,
             recv_buf,
             remote,
+            local_ip,
             local_port,
+            negotiated,
             transport_worker,
             should_ack: should_ack_tx,
+            dup_ack: dup_ack_tx,
             ack_policy: A::default(),
+            throughput: ThroughputMeter::default(),
+            bandwidth: BandwidthEstimator::default(),
+            keepalive: keepalive_tx,
+            nodelay: nodelay_tx,
+            last_heard: last_heard_tx,
+            srtt: srtt_rx,
+            stats,
+            status: watch::channel(SocketStatus::Established).0,
+            send_stall_timeout,
+            delayed_ack: delayed_ack_tx,
+            quickack: AtomicBool::new(false),
+            linger: std::sync::Mutex::new(LingerConfig::default()),
+            shutdown_requested: Arc::new(Notify::new()),
+            read_shutdown: AtomicBool::new(false),
+            reset,
+            #[cfg(any(test, feature = "debug-commands"))]
+            retransmit,
+            aborted,
+            md5_key,
+            logger,
+            sampler: std::sync::Mutex::new(None),
+            recv_tee: std::sync::Mutex::new(None),
+            send_tee: std::sync::Mutex::new(None),
         }
     }
 
+    fn status(&self) -> SocketStatus {
+        *self.status.borrow()
+    }
+
+    /// A CSV row of this connection's statistics, `elapsed` into sampling.
+    async fn stats_row(&self, elapsed: Duration) -> String {
+        let unacked = self.send_buf.head().await - self.send_buf.tail().await;
+        format!(
+            "{},{:.0},{},{},{}\n",
+            elapsed.as_millis(),
+            self.throughput.bits_per_sec(),
+            unacked,
+            self.send_buf.window_size(),
+            self.recv_buf.window_size().await,
+        )
+    }
+
     async fn close(&self) -> Result<(), TcpCloseError> {
         self.send_buf
             .close()
@@ -190,25 +843,87 @@ impl<const BUF_SZ: usize, A: AckPolicy + Default> InnerTcpConn<BUF_SZ, A> {
             .map_err(|_| TcpCloseError::AlreadyClosed)
     }
 
+    /// Stop reading: pending reads end, and data received from now on is
+    /// acked but dropped, so the remote is never stalled on a full window.
+    async fn shutdown_read(&self) -> Result<(), TcpCloseError> {
+        self.read_shutdown.store(true, Ordering::Release);
+        let closed = self.close_read().await;
+        self.recv_buf.discard().await;
+        closed
+    }
+
     async fn send_all(&self, bytes: &[u8]) -> Result<(), TcpSendError> {
-        self.send_buf
-            .write_all(bytes)
-            .await
-            .map_err(|_| TcpSendError::ConnClosed)
+        let stall_timeout = match self.send_stall_timeout {
+            Some(timeout) => timeout,
+            None => {
+                return self
+                    .write_all_acked(bytes)
+                    .await
+                    .map_err(|_| TcpSendError::ConnClosed)
+            }
+        };
+
+        // The write is making progress as long as the remote acks new bytes.
+        let mut acked = self.send_buf.tail_update();
+        let mut acked_up_to = self.send_buf.tail().await;
+        let write = self.write_all_acked(bytes);
+        let stalled = tokio::time::sleep(stall_timeout);
+        tokio::pin!(write, stalled);
+        loop {
+            tokio::select! {
+                written = &mut write => {
+                    return written.map_err(|_| TcpSendError::ConnClosed);
+                }
+                Ok(ack) = acked.recv() => {
+                    if ack > acked_up_to {
+                        acked_up_to = ack;
+                        stalled
+                            .as_mut()
+                            .reset(tokio::time::Instant::now() + stall_timeout);
+                    }
+                }
+                _ = &mut stalled => {
+                    node_log!(self.logger, Level::Warn,
+                        "No progress sending to {}:{} for {:?}, resetting connection",
+                        self.remote.ip(),
+                        self.remote.port().0,
+                        stall_timeout
+                    );
+                    self.reset.notify_one();
+                    self.status.send_replace(SocketStatus::Closed);
+                    return Err(TcpSendError::StallTimeout);
+                }
+            }
+        }
+    }
+
+    /// Writes all bytes into the send buffer, then waits for the remote to ack
+    /// them.
+    async fn write_all_acked(&self, bytes: &[u8]) -> Result<(), SendBufClosed> {
+        self.send_buf.write_all(bytes).await?;
+        Tee::write(&self.send_tee, bytes);
+        let end = self.send_buf.head().await;
+        self.send_buf.wait_for_acked(end).await
     }
 
     async fn read_all(&self, out_buffer: &mut [u8]) -> Result<(), TcpReadError> {
         const MAX_READ_SZ: usize = 1024;
 
+        if out_buffer.is_empty() {
+            return Ok(());
+        }
+
         let mut curr = 0;
         while curr < out_buffer.len() {
             let end = min(out_buffer.len(), curr + MAX_READ_SZ);
             match self.recv_buf.fill(&mut out_buffer[curr..end]).await {
                 Ok(_) => {
+                    Tee::write(&self.recv_tee, &out_buffer[curr..end]);
                     curr = end;
                 }
                 Err(e) => match e {
                     FillError::Closed(filled_bytes) => {
+                        Tee::write(&self.recv_tee, &out_buffer[curr..curr + filled_bytes]);
                         curr += filled_bytes;
                         return Err(TcpReadError::Closed(curr));
                     }
@@ -218,6 +933,47 @@ impl<const BUF_SZ: usize, A: AckPolicy + Default> InnerTcpConn<BUF_SZ, A> {
         Ok(())
     }
 
+    async fn read_some(&self, out_buffer: &mut [u8]) -> Result<usize, TcpReadError> {
+        let n = self
+            .recv_buf
+            .try_fill_some(out_buffer)
+            .await
+            .map_err(|_| TcpReadError::Closed(0))?;
+        Tee::write(&self.recv_tee, &out_buffer[..n]);
+        Ok(n)
+    }
+
+    async fn read_until(&self, delim: u8, max: usize) -> Result<Vec<u8>, TcpReadError> {
+        let mut out_buf = vec![0; max];
+        let mut curr = 0;
+        while curr < max {
+            match self.recv_buf.fill_until(delim, &mut out_buf[curr..]).await {
+                Ok(n) => {
+                    Tee::write(&self.recv_tee, &out_buf[curr..curr + n]);
+                    curr += n;
+                    if out_buf[curr - 1] == delim {
+                        break;
+                    }
+                }
+                Err(_) if curr == 0 => return Err(TcpReadError::Closed(0)),
+                Err(_) => break,
+            }
+        }
+        out_buf.truncate(curr);
+        Ok(out_buf)
+    }
+
+    async fn try_read(&self, out_buffer: &mut [u8]) -> Result<usize, TcpReadError> {
+        match self.recv_buf.fill_available(out_buffer).await {
+            Ok(0) if !out_buffer.is_empty() => Err(TcpReadError::WouldBlock),
+            Ok(n) => {
+                Tee::write(&self.recv_tee, &out_buffer[..n]);
+                Ok(n)
+            }
+            Err(_) => Err(TcpReadError::Closed(0)),
+        }
+    }
+
     fn is_read_closed(&self) -> bool {
         self.recv_buf.closed()
     }
@@ -229,50 +985,145 @@ impl<const BUF_SZ: usize, A: AckPolicy + Default> InnerTcpConn<BUF_SZ, A> {
         payload: &[u8],
     ) {
         assert!(tcp_header.ack());
-        self.send_buf.set_window_size(tcp_header.window_size());
+        self.last_heard.send_replace(Instant::now());
+        self.stats.lock().unwrap().segments_received += 1;
+
+        let window_sz = usize::from(tcp_header.window_size()) << self.negotiated.send_window_shift;
+
+        // A bare ACK that neither acks new data nor updates the window hints
+        // that a segment was lost (RFC 5681, section 2).
+        let ack: usize = tcp_header.acknowledgment_number().try_into().unwrap();
+        if payload.is_empty()
+            && window_sz == self.send_buf.window_size()
+            && ack == self.send_buf.tail().await
+        {
+            self.stats.lock().unwrap().dup_acks += 1;
+            self.dup_ack.send(ack).ok();
+        }
+
+        self.send_buf.set_window_size(window_sz);
         self.update_last_acked_byte(tcp_header.acknowledgment_number())
             .await;
 
+        let head = self.recv_buf.head().await;
+        let mut in_order = true;
         if !payload.is_empty() {
-            self.write_received_bytes(tcp_header.sequence_number(), payload)
-                .await;
+            let seq_no = tcp_header.sequence_number();
+            self.write_received_bytes(seq_no, payload).await;
+            // Out-of-order segments, and those filling a gap, are acked at
+            // once so the remote can recover quickly (RFC 5681, section 4.2).
+            let seq_no: usize = seq_no.try_into().unwrap();
+            in_order = seq_no == head && self.recv_buf.head().await == head + payload.len();
+        }
+
+        // A bare ACK is not acked, or both ends would ack each other's ACKs
+        // forever. One below the expected sequence number, like a keepalive
+        // probe, still is.
+        let seq_no: usize = tcp_header.sequence_number().try_into().unwrap();
+        if payload.is_empty() && seq_no == head {
+            return;
         }
 
-        if self.ack_policy.should_ack(tcp_header) {
-            self.should_ack.send(()).unwrap();
+        // Delayed in-order data is acked by the transport's ACK timer.
+        let delay = *self.delayed_ack.borrow() && in_order && !payload.is_empty();
+        let quickack = self.quickack.load(Ordering::Relaxed) && !payload.is_empty();
+        if quickack || (self.ack_policy.should_ack(tcp_header) && !delay) {
+            self.should_ack.send(()).ok();
         }
     }
 }
 
 impl<const N: usize, A: AckPolicy> InnerTcpConn<N, A> {
     async fn update_last_acked_byte(&self, ack: u32) {
-        if let Err(e) = self.send_buf.set_tail(ack.try_into().unwrap()).await {
-            match e {
-                SetTailError::LowerThanCurrent => log::error!("Remote responded with a lower ack"),
-                SetTailError::TooBig => log::error!(
+        let ack: usize = ack.try_into().unwrap();
+        let prev_tail = self.send_buf.tail().await;
+        match self.send_buf.set_tail(ack).await {
+            Ok(_) => {
+                if ack > prev_tail {
+                    self.stats.lock().unwrap().bytes_acked += ack - prev_tail;
+                    self.throughput.record(ack - prev_tail);
+                    let srtt = *self.srtt.borrow();
+                    self.bandwidth.record(ack - prev_tail, srtt);
+                }
+                if ack == self.send_buf.head().await {
+                    self.bandwidth.end_flight();
+                }
+            }
+            Err(e) => match e {
+                SetTailError::LowerThanCurrent => node_log!(
+                    self.logger,
+                    Level::Error,
+                    "Remote responded with a lower ack"
+                ),
+                SetTailError::TooBig => node_log!(
+                    self.logger,
+                    Level::Error,
                     "Remote responded with an ack that's higher than the greatest sent seq no."
                 ),
-            }
+            },
         }
     }
 
+    /// Writes a segment's payload into the receive buffer. Bytes received
+    /// before are trimmed off by the buffer; bytes past the receive window are
+    /// trimmed off here, keeping the part of the segment that fits.
     async fn write_received_bytes(&self, seq_no: u32, payload: &[u8]) {
+        let start: usize = seq_no.try_into().unwrap();
+        let window_end = self.recv_buf.head().await + self.recv_buf.window_size().await;
+        let payload = if start < window_end && start + payload.len() > window_end {
+            node_log!(
+                self.logger,
+                Level::Debug,
+                "Trimmed {} bytes past the receive window, seq no {}",
+                start + payload.len() - window_end,
+                seq_no
+            );
+            self.should_ack.send(()).ok();
+            &payload[..window_end - start]
+        } else {
+            payload
+        };
+
         if let Err(e) = self
             .recv_buf
-            .write(seq_no.try_into().unwrap(), payload)
+            .try_write(seq_no.try_into().unwrap(), payload)
             .await
         {
             match e {
-                WriteRangeError::SeqNoTooSmall(min_seq_no) => log::info!(
+                WriteRangeError::SeqNoTooSmall(min_seq_no) => node_log!(
+                    self.logger,
+                    Level::Info,
                     "Received delayed packet, min seq no: {}, got seq no {}",
                     min_seq_no,
                     seq_no
                 ),
+                WriteRangeError::TooManyEarlyArrivals => node_log!(
+                    self.logger,
+                    Level::Debug,
+                    "Dropping out-of-order segment, too many gaps, seq no {}",
+                    seq_no
+                ),
                 WriteRangeError::ExceedBuffer(_) => {
-                    log::error!("Remote did not honor window size")
+                    if self.recv_buf.window_size().await == 0 {
+                        node_log!(
+                            self.logger,
+                            Level::Debug,
+                            "Received zero window probe, seq no {}",
+                            seq_no
+                        )
+                    } else {
+                        node_log!(
+                            self.logger,
+                            Level::Error,
+                            "Remote did not honor window size"
+                        )
+                    }
                 }
             };
-            self.should_ack.send(()).unwrap();
+            self.should_ack.send(()).ok();
+        }
+        if self.read_shutdown.load(Ordering::Acquire) {
+            self.recv_buf.discard().await;
         }
     }
 
@@ -285,26 +1136,49 @@ impl<const N: usize, A: AckPolicy> InnerTcpConn<N, A> {
     }
 
     async fn remote_window_sz(&self) -> usize {
-        self.send_buf.window_size().into()
+        self.send_buf.window_size()
     }
 }
 
 impl<const N: usize, A: AckPolicy> Drop for InnerTcpConn<N, A> {
     fn drop(&mut self) {
         self.transport_worker.abort();
+        self.shutdown_requested.notify_one();
     }
 }
 
 pub struct TcpListener {
     receiver: mpsc::Receiver<TcpConn>,
+    /// Keys signing connections with given peers, shared with the listen
+    /// socket.
+    md5_keys: Arc<Md5Keys>,
+    /// Bounds the connections handed out by `accept_with_limit`.
+    in_flight: Option<Arc<Semaphore>>,
+}
+
+/// Counts an accepted connection against its listener's concurrency limit
+/// until dropped.
+#[derive(Debug)]
+pub struct AcceptPermit {
+    _permit: OwnedSemaphorePermit,
 }
 
 impl TcpListener {
     /// Creates a new TcpListener.
     ///
     /// The listener can be used to accept incoming connections
-    pub fn new(receiver: mpsc::Receiver<TcpConn>) -> Self {
-        Self { receiver }
+    pub fn new(receiver: mpsc::Receiver<TcpConn>, md5_keys: Arc<Md5Keys>) -> Self {
+        Self {
+            receiver,
+            md5_keys,
+            in_flight: None,
+        }
+    }
+
+    /// Sets or (with `None`) removes the key signing connections with `peer`
+    /// (RFC 2385). Only connections accepted after the change are affected.
+    pub fn set_md5_key(&self, peer: Ipv4Addr, key: Option<Vec<u8>>) {
+        self.md5_keys.set(peer, key);
     }
 
     /// Yields new client connections.
@@ -322,22 +1196,101 @@ impl TcpListener {
             .await
             .ok_or(TcpAcceptError::ListenSocketClosed)
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-pub enum TransportError {
-    DestUnreachable(Ipv4Addr),
-}
+    /// Like `accept`, but also yields the peer's IP and port.
+    pub async fn accept_from(&mut self) -> Result<(TcpConn, Remote), TcpAcceptError> {
+        let conn = self.accept().await?;
+        let peer = conn.socket_id().remote();
+        Ok((conn, peer))
+    }
 
-/// Possible socket state types.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum SocketStatus {
-    Closed,
-    SynSent,
-    SynReceived,
-    Established,
-    Listen,
-    FinWait1,
+    /// Like `accept`, but holds off while `max_concurrent` connections it
+    /// handed out are still being handled, leaving new ones in the backlog.
+    /// A connection is handled until its `AcceptPermit` is dropped.
+    ///
+    /// The limit is set by the first call.
+    pub async fn accept_with_limit(
+        &mut self,
+        max_concurrent: usize,
+    ) -> Result<(TcpConn, AcceptPermit), TcpAcceptError> {
+        let in_flight = self
+            .in_flight
+            .get_or_insert_with(|| Arc::new(Semaphore::new(max_concurrent)))
+            .clone();
+        let permit = in_flight
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let conn = self.accept().await?;
+        Ok((conn, AcceptPermit { _permit: permit }))
+    }
+
+    /// A stream of the connections `accept` would yield, in order. The stream
+    /// ends once the listen socket is closed.
+    ///
+    /// ```ignore
+    /// let mut incoming = listener.incoming();
+    /// while let Some(conn) = incoming.next().await {
+    ///     // handle new conn...
+    /// }
+    /// ```
+    pub fn incoming(&mut self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+}
+
+/// Connections accepted by a `TcpListener`. See `TcpListener::incoming`.
+pub struct Incoming<'a> {
+    listener: &'a mut TcpListener,
+}
+
+impl Stream for Incoming<'_> {
+    type Item = Result<TcpConn, TcpAcceptError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.listener
+            .receiver
+            .poll_recv(cx)
+            .map(|conn| conn.map(Ok))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum TransportError {
+    DestUnreachable(Ipv4Addr),
+}
+
+/// Why a handshake segment was dropped, leaving the socket as it was.
+#[derive(Debug, Copy, Clone)]
+pub enum HandshakeError {
+    /// The segment's flags don't fit the handshake step.
+    UnexpectedFlags,
+    /// The segment acks something other than our SYN.
+    UnexpectedAck(u32),
+    DestUnreachable(Ipv4Addr),
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::UnexpectedFlags => write!(f, "unexpected flags for handshake"),
+            HandshakeError::UnexpectedAck(ack_no) => {
+                write!(f, "ack number {} does not ack our SYN", ack_no)
+            }
+            HandshakeError::DestUnreachable(ip) => write!(f, "{} is unreachable", ip),
+        }
+    }
+}
+
+/// Possible socket state types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketStatus {
+    Closed,
+    SynSent,
+    SynReceived,
+    Established,
+    Listen,
+    FinWait1,
     FinWait2,
     Closing,
     TimeWait,
@@ -371,8 +1324,8 @@ enum TcpState<N> {
     Listen(Listen<N>),
     FinWait1(FinWait1<N>),
     FinWait2(FinWait2<N>),
-    Closing(Closing),
-    TimeWait(TimeWait),
+    Closing(Closing<N>),
+    TimeWait(TimeWait<N>),
     CloseWait(CloseWait<N>),
     LastAck(LastAck<N>),
 }
@@ -425,14 +1378,14 @@ impl<N: Net> From<FinWait2<N>> for TcpState<N> {
     }
 }
 
-impl<N: Net> From<Closing> for TcpState<N> {
-    fn from(s: Closing) -> Self {
+impl<N: Net> From<Closing<N>> for TcpState<N> {
+    fn from(s: Closing<N>) -> Self {
         Self::Closing(s)
     }
 }
 
-impl<N: Net> From<TimeWait> for TcpState<N> {
-    fn from(s: TimeWait) -> Self {
+impl<N: Net> From<TimeWait<N>> for TcpState<N> {
+    fn from(s: TimeWait<N>) -> Self {
         Self::TimeWait(s)
     }
 }
@@ -482,6 +1435,44 @@ impl<N: Net> TcpState<N> {
         }
     }
 
+    /// The state after the connection is given up on. Sockets without a
+    /// connection are left as they are.
+    fn into_aborted(self) -> Self {
+        match self {
+            TcpState::Established(s) => Closed::new(s.net).into(),
+            TcpState::FinWait1(s) => Closed::new(s.net).into(),
+            TcpState::FinWait2(s) => Closed::new(s.net).into(),
+            TcpState::CloseWait(s) => Closed::new(s.net).into(),
+            state => state,
+        }
+    }
+
+    fn logger(&self) -> NodeLogger {
+        match self {
+            TcpState::Closed(s) => s.net.logger(),
+            TcpState::SynSent(s) => s.net.logger(),
+            TcpState::SynReceived(s) => s.net.logger(),
+            TcpState::Established(s) => s.net.logger(),
+            TcpState::Listen(s) => s.net.logger(),
+            TcpState::FinWait1(s) => s.net.logger(),
+            TcpState::FinWait2(s) => s.net.logger(),
+            TcpState::Closing(s) => s.net.logger(),
+            TcpState::TimeWait(s) => s.net.logger(),
+            TcpState::CloseWait(s) => s.net.logger(),
+            TcpState::LastAck(s) => s.net.logger(),
+        }
+    }
+
+    fn conn(&self) -> Option<&TcpConn> {
+        match self {
+            TcpState::Established(s) => Some(&s.conn),
+            TcpState::FinWait1(s) => Some(&s.conn),
+            TcpState::FinWait2(s) => Some(&s.conn),
+            TcpState::CloseWait(s) => Some(&s.conn),
+            _ => None,
+        }
+    }
+
     fn is_read_closed(&self) -> Option<bool> {
         match self {
             TcpState::Closed(_) => None,
@@ -512,16 +1503,45 @@ impl<N: Net> Closed<N> {
         }
     }
 
+    /// Sends a SYN to the destination, transitioning into SynSent. `data` is
+    /// sent first once the connection is established, or in the SYN itself if
+    /// Fast Open is enabled and a cookie for the destination is at hand.
+    ///
+    /// The SYN, keeping its sequence number, is retransmitted with backoff
+    /// until the connection is established. Connecting fails with
+    /// `TcpConnError::Timeout` after `timeout`, or once `TCP_MAX_SYN_RETRIES`
+    /// retransmissions go unanswered.
+    /// If the SYN cannot be sent, errs and hands this state back.
     pub async fn connect(
         self,
         src_port: Port,
         dest: (Ipv4Addr, Port),
-    ) -> Result<(oneshot::Receiver<Result<TcpConn, TcpConnError>>, SynSent<N>), TransportError>
-    {
+        data: &[u8],
+        timeout: Duration,
+        config: Arc<SocketConfig>,
+        md5_key: Option<Vec<u8>>,
+    ) -> Result<
+        (oneshot::Receiver<Result<TcpConn, TcpConnError>>, SynSent<N>),
+        (TransportError, Self),
+    > {
         let (established_tx, established_rx) = oneshot::channel();
         let (dest_ip, dest_port) = dest;
 
-        let syn_pkt = self.make_syn_packet(src_port, dest_port, dest_ip).await;
+        // Without a cookie, the SYN asks for one, for later connections.
+        let (cookie, syn_data) = match &config.fast_open {
+            Some(fast_open) => match fast_open.cached_cookie(dest_ip) {
+                Some(cookie) => (Some(cookie), &data[..min(data.len(), MAX_SEGMENT_SZ)]),
+                None => (Some(Vec::new()), &[][..]),
+            },
+            None => (None, &[][..]),
+        };
+        let syn_pkt = match self
+            .make_syn_packet(src_port, dest_port, dest_ip, cookie.as_deref(), syn_data)
+            .await
+        {
+            Ok(syn_pkt) => syn_pkt,
+            Err(e) => return Err((e, self)),
+        };
 
         let established_tx = RaceOneShotSender::from(established_tx);
         let established = established_tx.clone();
@@ -530,12 +1550,14 @@ impl<N: Net> Closed<N> {
             syn_pkt,
             Remote::new(dest_ip, dest_port),
             self.net.clone(),
-            RtxConfig::default(),
+            md5_key.clone(),
+            RtxConfig::with_backoff(timeout).with_max_retries(TCP_MAX_SYN_RETRIES),
             move |_| {
                 established.send(Err(TcpConnError::Timeout)).ok();
             },
         );
 
+        let early_data = EarlyData::new(self.net.logger());
         let syn_sent = SynSent {
             src_port,
             dest_port,
@@ -543,21 +1565,41 @@ impl<N: Net> Closed<N> {
             syn_packet_rtx_handle: ack_handle,
             established_tx,
             net: self.net,
+            config,
             seq_no: self.seq_no + 1,
+            data: data.to_vec(),
+            syn_data_len: syn_data.len(),
+            early_data,
+            md5_key,
         };
         Ok((established_rx, syn_sent))
     }
 
-    pub fn listen(self, port: Port, tx: mpsc::Sender<TcpConn>) -> Listen<N> {
+    pub fn listen(
+        self,
+        port: Port,
+        tx: mpsc::Sender<TcpConn>,
+        config: Arc<SocketConfig>,
+        md5_keys: Arc<Md5Keys>,
+    ) -> Listen<N> {
         Listen {
             port,
             seq_no: self.seq_no,
             net: self.net,
+            config,
             new_conn_tx: tx,
+            md5_keys,
         }
     }
 
-    async fn make_syn_packet(&self, src_port: Port, dst_port: Port, dst_ip: Ipv4Addr) -> Vec<u8> {
+    async fn make_syn_packet(
+        &self,
+        src_port: Port,
+        dst_port: Port,
+        dst_ip: Ipv4Addr,
+        fast_open_cookie: Option<&[u8]>,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, TransportError> {
         let mut bytes = Vec::new();
 
         let mut header = TcpHeader::new(
@@ -567,14 +1609,21 @@ impl<N: Net> Closed<N> {
             TCP_DEFAULT_WINDOW_SZ.try_into().unwrap(),
         );
         header.syn = true;
-        let payload: &[u8] = &[];
-        let src_ip = self.net.get_outbound_ip(dst_ip).await.unwrap();
+        header
+            .set_options_raw(&handshake_options(true, fast_open_cookie))
+            .unwrap();
+        let src_ip = self
+            .net
+            .get_outbound_ip(dst_ip)
+            .await
+            .ok_or(TransportError::DestUnreachable(dst_ip))?;
         let checksum = header
             .calc_checksum_ipv4_raw(src_ip, dst_ip.octets(), payload)
             .unwrap();
         header.checksum = checksum;
         header.write(&mut bytes).unwrap();
-        bytes
+        bytes.extend_from_slice(payload);
+        Ok(bytes)
     }
 
     fn gen_rand_seq_no() -> u32 {
@@ -586,9 +1635,12 @@ struct Listen<N> {
     port: Port,
     seq_no: u32,
     net: Arc<N>,
+    config: Arc<SocketConfig>,
     // Notifies when new connections are established with a new TcpConn.
     // The TcpListener has the receiving end of this channel.
     new_conn_tx: mpsc::Sender<TcpConn>,
+    // Keys by peer, set through the TcpListener.
+    md5_keys: Arc<Md5Keys>,
 }
 
 impl<N: Net> Listen<N> {
@@ -596,17 +1648,51 @@ impl<N: Net> Listen<N> {
         &self,
         ip_header: &Ipv4HeaderSlice<'a>,
         syn_packet: &TcpHeaderSlice<'a>,
-    ) -> Result<SynReceived<N>, TransportError> {
-        assert!(syn_packet.syn());
+        payload: &[u8],
+    ) -> Result<SynReceived<N>, HandshakeError> {
+        if !syn_packet.syn() || syn_packet.ack() || syn_packet.rst() {
+            return Err(HandshakeError::UnexpectedFlags);
+        }
 
         let src_ip = ip_header.destination_addr();
         let dst_ip = ip_header.source_addr();
-        let syn_ack_pkt = self.make_syn_ack_packet(syn_packet, src_ip, dst_ip);
+        let ack_no = syn_packet.sequence_number().wrapping_add(1);
+
+        // With Fast Open, a SYN carrying a valid cookie has its data taken
+        // right away. Any other cookie option gets the client a fresh cookie.
+        let mut early_data = EarlyData::new(self.net.logger());
+        let mut syn_data_len = 0;
+        let mut cookie = None;
+        if let (Some(fast_open), Some(presented)) = (
+            &self.config.fast_open,
+            fastopen::find_cookie(syn_packet.options()),
+        ) {
+            if fast_open.is_valid_cookie(dst_ip, presented) {
+                early_data.push(ack_no, payload);
+                syn_data_len = payload.len();
+            } else {
+                cookie = Some(fast_open.cookie_for(dst_ip));
+            }
+        }
 
-        let ack_handle = transport_single_message(
-            syn_ack_pkt,
+        let syn_ack_pkt = make_syn_ack_packet(
+            self.port,
+            self.seq_no,
+            syn_packet,
+            ack_no.wrapping_add(syn_data_len as u32),
+            cookie.as_deref(),
+            (src_ip, dst_ip),
+        );
+
+        // Answer from the address the remote connected to. The SYN-ACK is
+        // resent on a timer until the handshake ACK arrives.
+        let md5_key = self.md5_keys.get(dst_ip);
+        let ack_handle = transport_single_message_from(
+            src_ip,
+            syn_ack_pkt.clone(),
             Remote::new(ip_header.source_addr(), syn_packet.source_port().into()),
             self.net.clone(),
+            md5_key.clone(),
             RtxConfig::default(),
             move |_| {
                 // TODO: delete SynReceived socketx
@@ -615,45 +1701,60 @@ impl<N: Net> Listen<N> {
 
         let syn_recvd = SynReceived {
             seq_no: self.seq_no + 1,
+            ack_no,
+            local_ip: src_ip,
             local_port: self.port,
+            negotiated: Negotiated::from_handshake(syn_packet),
+            syn_ack_pkt,
             synack_ack_handle: ack_handle,
             remote_ip: ip_header.source_addr(),
             remote_port: Port(syn_packet.source_port()),
             net: self.net.clone(),
-            new_conn_tx: self.new_conn_tx.clone(),
+            config: self.config.clone(),
+            waiter: ConnWaiter::Listener(self.new_conn_tx.clone()),
+            early_data,
+            md5_key,
         };
 
         Ok(syn_recvd)
     }
+}
 
-    fn make_syn_ack_packet(
-        &self,
-        syn_packet: &TcpHeaderSlice<'_>,
-        src_ip: Ipv4Addr,
-        dst_ip: Ipv4Addr,
-    ) -> Vec<u8> {
-        let mut bytes = Vec::new();
-
-        let src_port = self.port.0;
-        let dst_port = syn_packet.source_port();
-
-        let mut header = TcpHeader::new(
-            src_port,
-            dst_port,
-            self.seq_no,
-            TCP_DEFAULT_WINDOW_SZ.try_into().unwrap(),
-        );
-        header.syn = true;
-        header.ack = true;
-        header.acknowledgment_number = syn_packet.sequence_number() + 1;
-        let payload: &[u8] = &[];
-        let checksum = header
-            .calc_checksum_ipv4_raw(src_ip.octets(), dst_ip.octets(), payload)
-            .unwrap();
-        header.checksum = checksum;
-        header.write(&mut bytes).unwrap();
-        bytes
-    }
+/// Makes a SYN-ACK from `src_port` answering `syn_packet`, with `seq_no` as
+/// its own sequence number. `ips` holds the (source, destination) addresses.
+fn make_syn_ack_packet(
+    src_port: Port,
+    seq_no: u32,
+    syn_packet: &TcpHeaderSlice<'_>,
+    ack_no: u32,
+    fast_open_cookie: Option<&[u8]>,
+    ips: (Ipv4Addr, Ipv4Addr),
+) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let (src_ip, dst_ip) = ips;
+
+    let mut header = TcpHeader::new(
+        src_port.0,
+        syn_packet.source_port(),
+        seq_no,
+        TCP_DEFAULT_WINDOW_SZ.try_into().unwrap(),
+    );
+    header.syn = true;
+    header.ack = true;
+    header.acknowledgment_number = ack_no;
+    header
+        .set_options_raw(&handshake_options(
+            options::find_window_scale(syn_packet.options()).is_some(),
+            fast_open_cookie,
+        ))
+        .unwrap();
+    let payload: &[u8] = &[];
+    let checksum = header
+        .calc_checksum_ipv4_raw(src_ip.octets(), dst_ip.octets(), payload)
+        .unwrap();
+    header.checksum = checksum;
+    header.write(&mut bytes).unwrap();
+    bytes
 }
 
 struct SynSent<N> {
@@ -663,29 +1764,70 @@ struct SynSent<N> {
     dest_port: Port,
     syn_packet_rtx_handle: AckHandle,
     net: Arc<N>,
+    config: Arc<SocketConfig>,
     established_tx: RaceOneShotSender<Result<TcpConn, TcpConnError>>,
+    // The connection's first bytes to send.
+    data: Vec<u8>,
+    // How many bytes of `data` the SYN carried, with Fast Open.
+    syn_data_len: usize,
+    // Data that arrived ahead of the SYN-ACK.
+    early_data: EarlyData,
+    md5_key: Option<Vec<u8>>,
 }
 
 impl<N: Net> SynSent<N> {
     pub async fn establish<'a>(
         mut self,
         syn_ack_packet: &TcpHeaderSlice<'a>,
-    ) -> Result<Established<N>, TransportError> {
-        assert!(syn_ack_packet.syn());
-        assert!(syn_ack_packet.ack());
-        assert_eq!(syn_ack_packet.acknowledgment_number(), self.seq_no);
-
-        self.syn_packet_rtx_handle.acked();
+        payload: &[u8],
+    ) -> Result<Established<N>, (HandshakeError, Self)> {
+        if !syn_ack_packet.syn() {
+            return Err((HandshakeError::UnexpectedFlags, self));
+        }
+        if !self.acks_syn(syn_ack_packet) {
+            let ack_no = syn_ack_packet.acknowledgment_number();
+            return Err((HandshakeError::UnexpectedAck(ack_no), self));
+        }
+        let data_acked = syn_ack_packet
+            .acknowledgment_number()
+            .wrapping_sub(self.seq_no) as usize;
 
-        let ack_pkt = self.make_ack_packet(syn_ack_packet, self.dest_ip).await;
+        // The connection keeps this address even if its route changes.
+        let Some(local_ip) = self.net.get_outbound_ip(self.dest_ip).await else {
+            return Err((HandshakeError::DestUnreachable(self.dest_ip), self));
+        };
+        let local_ip = Ipv4Addr::from(local_ip);
+        let ack_pkt = self.make_ack_packet(syn_ack_packet, local_ip);
+        if send_segment(
+            &*self.net,
+            Some(local_ip),
+            &ack_pkt,
+            self.dest_ip,
+            self.md5_key.as_deref(),
+        )
+        .await
+        .is_err()
+        {
+            return Err((HandshakeError::DestUnreachable(self.dest_ip), self));
+        }
 
-        self.net
-            .send(&ack_pkt, Protocol::Tcp, self.dest_ip)
-            .await
-            .map_err(|_| TransportError::DestUnreachable(self.dest_ip))?;
+        self.syn_packet_rtx_handle.acked();
+        self.seq_no = syn_ack_packet.acknowledgment_number();
+        if let (Some(fast_open), Some(cookie)) = (
+            &self.config.fast_open,
+            fastopen::find_cookie(syn_ack_packet.options()),
+        ) {
+            if !cookie.is_empty() {
+                fast_open.cache_cookie(self.dest_ip, cookie);
+            }
+        }
 
         let send_buf_start = self.seq_no.try_into().unwrap();
-        let recv_buf_start = (syn_ack_packet.sequence_number() + 1).try_into().unwrap();
+        let recv_buf_start = syn_ack_packet
+            .sequence_number()
+            .wrapping_add(1)
+            .try_into()
+            .unwrap();
 
         let sock_id = SocketIdBuilder::default()
             .with_remote_ip(self.dest_ip)
@@ -697,14 +1839,25 @@ impl<N: Net> SynSent<N> {
         let conn = TcpConn::new(
             sock_id,
             Remote::new(self.dest_ip, self.dest_port),
+            local_ip,
             self.src_port,
             send_buf_start,
             recv_buf_start,
+            Negotiated::from_handshake(syn_ack_packet),
             self.net.clone(),
+            self.config.clone(),
+            self.md5_key,
         );
-        self.established_tx
-            .send(Ok(conn.clone()))
-            .expect("Failed to notify new connection established");
+        // Data carried by the SYN-ACK starts right after its SYN.
+        self.early_data
+            .push(syn_ack_packet.sequence_number().wrapping_add(1), payload);
+        self.early_data.deliver(&conn).await;
+        conn.queue_data(&self.data[data_acked..]).await;
+        if self.established_tx.send(Ok(conn.clone())).is_err() {
+            // The connect gave up before the SYN-ACK arrived, and nobody takes
+            // the connection. It is reset, which closes the socket.
+            conn.reset();
+        }
 
         Ok(Established {
             local_port: self.src_port,
@@ -712,29 +1865,150 @@ impl<N: Net> SynSent<N> {
             remote_port: self.dest_port,
             conn,
             net: self.net,
-            last_seq: self.seq_no + 1,
+            challenge_acks: ChallengeAckLimiter::default(),
         })
     }
 
-    async fn make_ack_packet<'a>(
-        &mut self,
-        syn_ack_packet: &TcpHeaderSlice<'a>,
-        dst_ip: Ipv4Addr,
-    ) -> Vec<u8> {
+    /// A bare SYN means the remote is connecting to us at the same time
+    /// (simultaneous open, RFC 793, section 3.4). It is answered as a listener
+    /// would, with a SYN-ACK that repeats our SYN, and the handshake completes
+    /// once the remote acks it. Our SYN's data, if any, is sent again after.
+    async fn simultaneous_open<'a>(
+        mut self,
+        ip_header: &Ipv4HeaderSlice<'a>,
+        syn_packet: &TcpHeaderSlice<'a>,
+    ) -> SynReceived<N> {
+        let local_ip = ip_header.destination_addr();
+        let iss = self.seq_no.wrapping_sub(1);
+        let ack_no = syn_packet.sequence_number().wrapping_add(1);
+        let syn_ack_pkt = make_syn_ack_packet(
+            self.src_port,
+            iss,
+            syn_packet,
+            ack_no,
+            None,
+            (local_ip, self.dest_ip),
+        );
+
+        // Our SYN is now resent as part of the SYN-ACK.
+        self.syn_packet_rtx_handle.acked();
+        let established = self.established_tx.clone();
+        let ack_handle = transport_single_message_from(
+            local_ip,
+            syn_ack_pkt.clone(),
+            Remote::new(self.dest_ip, self.dest_port),
+            self.net.clone(),
+            self.md5_key.clone(),
+            RtxConfig::default(),
+            move |_| {
+                established.send(Err(TcpConnError::Timeout)).ok();
+            },
+        );
+
+        let early_data = EarlyData::new(self.net.logger());
+        SynReceived {
+            seq_no: self.seq_no,
+            ack_no,
+            local_ip,
+            local_port: self.src_port,
+            negotiated: Negotiated::from_handshake(syn_packet),
+            syn_ack_pkt,
+            synack_ack_handle: ack_handle,
+            remote_ip: self.dest_ip,
+            remote_port: self.dest_port,
+            net: self.net,
+            config: self.config,
+            waiter: ConnWaiter::Connector {
+                established_tx: self.established_tx,
+                data: self.data,
+            },
+            early_data,
+            md5_key: self.md5_key,
+        }
+    }
+
+    /// Whether the segment acks our SYN. The server acks the SYN's data only
+    /// if it took it.
+    fn acks_syn(&self, tcp_header: &TcpHeaderSlice<'_>) -> bool {
+        let acked = tcp_header.acknowledgment_number().wrapping_sub(self.seq_no) as usize;
+        tcp_header.ack() && (acked == 0 || acked == self.syn_data_len)
+    }
+
+    /// A segment acking something we never sent belongs to a stale or
+    /// spoofed connection: it is answered with a RST (RFC 793, section 3.4),
+    /// and the handshake goes on.
+    async fn reject_bad_ack(
+        self,
+        ip_header: &Ipv4HeaderSlice<'_>,
+        tcp_header: &TcpHeaderSlice<'_>,
+        payload_len: usize,
+    ) -> Self {
+        node_log!(
+            self.net.logger(),
+            Level::Info,
+            "Resetting segment with unexpected ack no {}",
+            tcp_header.acknowledgment_number()
+        );
+        let rst = make_reset_reply(ip_header, tcp_header, payload_len);
+        send_segment(
+            &*self.net,
+            Some(ip_header.destination_addr()),
+            &rst,
+            ip_header.source_addr(),
+            self.md5_key.as_deref(),
+        )
+        .await
+        .ok();
+        self
+    }
+
+    /// Keeps a segment that arrived ahead of the SYN-ACK. Whether it is in
+    /// the window can only be told once the SYN-ACK arrives.
+    fn buffer_early_data(mut self, tcp_header: &TcpHeaderSlice<'_>, payload: &[u8]) -> Self {
+        self.early_data.push(tcp_header.sequence_number(), payload);
+        self
+    }
+
+    /// A RST is taken as a refusal only if it acks the SYN, so a stale RST
+    /// cannot fail the connection attempt.
+    fn handle_rst(mut self, tcp_header: &TcpHeaderSlice<'_>) -> TcpState<N> {
+        let acked = tcp_header.acknowledgment_number().wrapping_sub(self.seq_no) as usize;
+        if !tcp_header.ack() || acked > self.syn_data_len {
+            node_log!(
+                self.net.logger(),
+                Level::Info,
+                "Dropped RST that doesn't ack the SYN"
+            );
+            return self.into();
+        }
+
+        node_log!(
+            self.net.logger(),
+            Level::Info,
+            "Connection refused by {}:{}",
+            self.dest_ip,
+            self.dest_port.0
+        );
+        self.syn_packet_rtx_handle.acked();
+        self.established_tx.send(Err(TcpConnError::Refused)).ok();
+        Closed::new(self.net).into()
+    }
+
+    fn make_ack_packet(&self, syn_ack_packet: &TcpHeaderSlice<'_>, src_ip: Ipv4Addr) -> Vec<u8> {
         let mut bytes = Vec::new();
 
         let mut header = TcpHeader::new(
             self.src_port.0,
             self.dest_port.0,
-            self.seq_no,
+            syn_ack_packet.acknowledgment_number(),
             TCP_DEFAULT_WINDOW_SZ.try_into().unwrap(),
         );
+        // The last segment of the handshake only acks: its SYN stays clear.
         header.ack = true;
-        header.acknowledgment_number = syn_ack_packet.sequence_number() + 1;
+        header.acknowledgment_number = syn_ack_packet.sequence_number().wrapping_add(1);
         let payload: &[u8] = &[];
-        let src_ip = self.net.get_outbound_ip(dst_ip).await.unwrap();
         let checksum = header
-            .calc_checksum_ipv4_raw(src_ip, dst_ip.octets(), payload)
+            .calc_checksum_ipv4_raw(src_ip.octets(), self.dest_ip.octets(), payload)
             .unwrap();
         header.checksum = checksum;
         header.write(&mut bytes).unwrap();
@@ -745,21 +2019,49 @@ impl<N: Net> SynSent<N> {
 
 pub struct SynReceived<N> {
     seq_no: u32,
+    // The sequence number of the remote's first data byte.
+    ack_no: u32,
+    local_ip: Ipv4Addr,
     local_port: Port,
+    negotiated: Negotiated,
     remote_ip: Ipv4Addr,
     remote_port: Port,
     net: Arc<N>,
+    config: Arc<SocketConfig>,
+    // Resent right away if the remote's SYN shows up again.
+    syn_ack_pkt: Vec<u8>,
     synack_ack_handle: AckHandle,
-    new_conn_tx: mpsc::Sender<TcpConn>,
+    waiter: ConnWaiter,
+    // In-window data that arrived ahead of the handshake ACK.
+    early_data: EarlyData,
+    md5_key: Option<Vec<u8>>,
+}
+
+/// Who is handed the connection once the handshake completes.
+enum ConnWaiter {
+    /// A listener, whose `accept` takes it.
+    Listener(mpsc::Sender<TcpConn>),
+    /// A `connect` that met the remote's own SYN, with the bytes it sends
+    /// first.
+    Connector {
+        established_tx: RaceOneShotSender<Result<TcpConn, TcpConnError>>,
+        data: Vec<u8>,
+    },
 }
 
 impl<N: Net> SynReceived<N> {
-    async fn establish<'a>(mut self, ack_packet: &TcpHeaderSlice<'a>) -> Established<N> {
-        assert!(ack_packet.ack());
+    async fn establish<'a>(
+        mut self,
+        ack_packet: &TcpHeaderSlice<'a>,
+        payload: &[u8],
+    ) -> Result<Established<N>, (HandshakeError, Self)> {
+        if !self.is_handshake_ack(ack_packet) {
+            return Err((HandshakeError::UnexpectedFlags, self));
+        }
         self.synack_ack_handle.acked();
 
         let send_buf_start = self.seq_no.try_into().unwrap();
-        let recv_buf_start = ack_packet.sequence_number().try_into().unwrap();
+        let recv_buf_start = self.ack_no.try_into().unwrap();
 
         let sock_id = SocketIdBuilder::default()
             .with_remote_ip(self.remote_ip)
@@ -771,72 +2073,524 @@ impl<N: Net> SynReceived<N> {
         let conn = TcpConn::new(
             sock_id,
             Remote::new(self.remote_ip, self.remote_port),
+            self.local_ip,
             self.local_port,
             send_buf_start,
             recv_buf_start,
+            self.negotiated,
             self.net.clone(),
+            self.config.clone(),
+            self.md5_key,
         );
+        if ack_packet.syn() {
+            // The remote's SYN-ACK in a simultaneous open, acked right away.
+            self.early_data
+                .push(ack_packet.sequence_number().wrapping_add(1), payload);
+            conn.send_ack();
+        } else {
+            self.early_data.push(ack_packet.sequence_number(), payload);
+        }
+        self.early_data.deliver(&conn).await;
 
-        self.new_conn_tx.send(conn.clone()).await.ok();
+        match self.waiter {
+            ConnWaiter::Listener(new_conn_tx) => {
+                new_conn_tx.send(conn.clone()).await.ok();
+            }
+            ConnWaiter::Connector {
+                established_tx,
+                data,
+            } => {
+                conn.queue_data(&data).await;
+                established_tx.send(Ok(conn.clone())).ok();
+            }
+        }
 
-        Established {
+        Ok(Established {
             local_port: self.local_port,
             remote_ip: self.remote_ip,
             remote_port: self.remote_port,
             conn,
             net: self.net,
-            last_seq: self.seq_no,
+            challenge_acks: ChallengeAckLimiter::default(),
+        })
+    }
+
+    /// Only a pure ACK of our SYN-ACK completes the handshake (RFC 793). In
+    /// a simultaneous open, so does the remote's SYN-ACK.
+    fn is_handshake_ack(&self, tcp_header: &TcpHeaderSlice<'_>) -> bool {
+        let acks_syn_ack = tcp_header.ack() && tcp_header.acknowledgment_number() == self.seq_no;
+        match self.waiter {
+            ConnWaiter::Listener(_) => acks_syn_ack && !tcp_header.syn(),
+            ConnWaiter::Connector { .. } => {
+                acks_syn_ack
+                    && (!tcp_header.syn()
+                        || tcp_header.sequence_number().wrapping_add(1) == self.ack_no)
+            }
+        }
+    }
+
+    /// A repeated SYN means our SYN-ACK was likely lost, so it is resent
+    /// without waiting for the retransmission timer.
+    async fn handle_dup_syn(self, tcp_header: &TcpHeaderSlice<'_>) -> Self {
+        if tcp_header.sequence_number().wrapping_add(1) != self.ack_no {
+            node_log!(
+                self.net.logger(),
+                Level::Info,
+                "Dropped SYN with an unexpected seq no"
+            );
+            return self;
+        }
+
+        node_log!(
+            self.net.logger(),
+            Level::Debug,
+            "Resending SYN-ACK to {}:{}",
+            self.remote_ip,
+            self.remote_port.0
+        );
+        send_segment(
+            &*self.net,
+            Some(self.local_ip),
+            &self.syn_ack_pkt,
+            self.remote_ip,
+            self.md5_key.as_deref(),
+        )
+        .await
+        .ok();
+        self
+    }
+
+    /// Keeps an in-window segment that arrived ahead of the handshake ACK.
+    fn buffer_early_data(mut self, tcp_header: &TcpHeaderSlice<'_>, payload: &[u8]) -> Self {
+        let seq_no = tcp_header.sequence_number();
+        let offset: usize = seq_no.wrapping_sub(self.ack_no).try_into().unwrap();
+        if offset < TCP_DEFAULT_WINDOW_SZ {
+            self.early_data.push(seq_no, payload);
+        } else if !payload.is_empty() {
+            node_log!(
+                self.net.logger(),
+                Level::Info,
+                "Dropped early segment outside of the receive window, seq no {seq_no}"
+            );
+        }
+        self
+    }
+
+    fn handle_rst(mut self, tcp_header: &TcpHeaderSlice<'_>) -> TcpState<N> {
+        if tcp_header.sequence_number() != self.ack_no {
+            node_log!(
+                self.net.logger(),
+                Level::Info,
+                "Dropped RST with an unexpected seq no"
+            );
+            return self.into();
         }
+
+        node_log!(
+            self.net.logger(),
+            Level::Info,
+            "Handshake reset by {}:{}",
+            self.remote_ip,
+            self.remote_port.0
+        );
+        self.synack_ack_handle.acked();
+        Closed::new(self.net).into()
+    }
+
+    pub fn socket_id(&self) -> SocketId {
+        SocketIdBuilder::default()
+            .with_remote_ip(self.remote_ip)
+            .with_remote_port(self.remote_port)
+            .with_local_port(self.local_port)
+            .build()
+            .unwrap()
     }
 
-    pub fn into_socket(self, socket_id: SocketId, descriptor: SocketDescriptor) -> Socket<N> {
-        Socket::with_state(socket_id, descriptor, self.into())
+    pub fn into_socket(
+        self,
+        descriptor: SocketDescriptor,
+        updates: mpsc::UnboundedSender<UpdateAction<N>>,
+    ) -> Socket<N> {
+        let socket_id = self.socket_id();
+        let config = self.config.clone();
+        let md5_key = self.md5_key.clone();
+        let socket = Socket::with_state(socket_id, descriptor, self.into(), config, updates);
+        socket.set_md5_key(socket_id.remote_ip(), md5_key);
+        socket
     }
 }
 
-struct Established<N> {
-    local_port: Port,
-    remote_ip: Ipv4Addr,
-    remote_port: Port,
-    conn: TcpConn,
-    last_seq: u32,
-    net: Arc<N>,
+/// Lays out the options of a SYN or SYN-ACK: our maximum segment size, our
+/// window shift if scaling is offered, then the Fast Open cookie, if any.
+fn handshake_options(window_scale: bool, fast_open_cookie: Option<&[u8]>) -> Vec<u8> {
+    let mut handshake_options = options::mss_option(MAX_SEGMENT_SZ.try_into().unwrap());
+    if window_scale {
+        handshake_options.extend(options::window_scale_option(TCP_WINDOW_SHIFT));
+    }
+    if let Some(cookie) = fast_open_cookie {
+        handshake_options.extend(fastopen::cookie_option(cookie));
+    }
+    handshake_options
 }
 
-impl<N: Net> Established<N> {
-    async fn handle_packet<'a>(
-        self,
-        ip_header: &Ipv4HeaderSlice<'a>,
-        tcp_header: &TcpHeaderSlice<'a>,
-        payload: &[u8],
-    ) -> TcpState<N> {
-        if tcp_header.fin() {
-            return self.passive_close(tcp_header).await.into();
+/// Connection parameters agreed on in the handshake.
+#[derive(Debug, Clone, Copy)]
+struct Negotiated {
+    /// The largest segment to send.
+    mss: usize,
+    /// How far the windows the remote advertises are shifted.
+    send_window_shift: u8,
+    /// How far the windows we advertise are shifted.
+    recv_window_shift: u8,
+}
+
+impl Default for Negotiated {
+    fn default() -> Self {
+        Self {
+            mss: MAX_SEGMENT_SZ,
+            send_window_shift: 0,
+            recv_window_shift: 0,
         }
-        self.conn
-            .handle_packet(ip_header, tcp_header, payload)
-            .await;
-        self.into()
     }
+}
 
-    /// Perform transition from Established to CloseWait upon receiving a FIN
-    /// packet.
-    async fn passive_close<'a>(self, tcp_header: &TcpHeaderSlice<'a>) -> CloseWait<N> {
-        let ack_packet = self
-            .make_handshake_ack_packet(tcp_header, self.remote_ip)
-            .await;
-        self.net
-            .send(&ack_packet, Protocol::Tcp, self.remote_ip)
-            .await
-            .map_err(|_| TransportError::DestUnreachable(self.remote_ip))
-            .unwrap();
+impl Negotiated {
+    /// Reads the parameters off the remote's SYN or SYN-ACK.
+    ///
+    /// The segment size is the smaller of ours and the remote's; a remote that
+    /// advertises none is taken to use ours. Windows are scaled only if the
+    /// remote's handshake carries the window scale option: a SYN-ACK carries
+    /// it only in answer to a SYN that did (RFC 7323, section 2.2).
+    fn from_handshake(handshake_packet: &TcpHeaderSlice<'_>) -> Self {
+        let options = handshake_packet.options();
+        let mss = options::find_mss(options).map_or(MAX_SEGMENT_SZ, |mss| {
+            usize::from(mss).clamp(1, MAX_SEGMENT_SZ)
+        });
+        match options::find_window_scale(options) {
+            Some(shift) => Self {
+                mss,
+                send_window_shift: shift,
+                recv_window_shift: TCP_WINDOW_SHIFT,
+            },
+            None => Self {
+                mss,
+                ..Self::default()
+            },
+        }
+    }
+}
 
-        self.conn.close_read().await;
+/// Limits the rate of challenge ACKs sent on a connection, so that an attacker
+/// cannot use them to amplify traffic (RFC 5961, section 7).
+struct ChallengeAckLimiter {
+    interval_start: Instant,
+    sent_in_interval: usize,
+}
 
-        CloseWait {
-            conn: self.conn,
-            last_seq: self.last_seq,
-            net: self.net,
+impl Default for ChallengeAckLimiter {
+    fn default() -> Self {
+        Self {
+            interval_start: Instant::now(),
+            sent_in_interval: 0,
+        }
+    }
+}
+
+impl ChallengeAckLimiter {
+    /// Returns whether another challenge ACK may be sent, counting it if so.
+    fn try_acquire(&mut self) -> bool {
+        if self.interval_start.elapsed() >= TCP_CHALLENGE_ACK_INTERVAL {
+            self.interval_start = Instant::now();
+            self.sent_in_interval = 0;
+        }
+
+        if self.sent_in_interval < TCP_CHALLENGE_ACK_LIMIT {
+            self.sent_in_interval += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Data received before a connection is established, delivered to the
+/// connection once it is.
+struct EarlyData {
+    segments: Vec<(u32, Vec<u8>)>,
+    buffered: usize,
+    logger: NodeLogger,
+}
+
+impl EarlyData {
+    fn new(logger: NodeLogger) -> Self {
+        Self {
+            segments: Vec::new(),
+            buffered: 0,
+            logger,
+        }
+    }
+
+    /// Keeps a segment's payload, dropping it if the buffered data would
+    /// outgrow the receive window.
+    fn push(&mut self, seq_no: u32, payload: &[u8]) {
+        if payload.is_empty() {
+            return;
+        }
+        if self.buffered + payload.len() > TCP_DEFAULT_WINDOW_SZ {
+            node_log!(
+                self.logger,
+                Level::Info,
+                "Dropped early segment exceeding the receive window, seq no {seq_no}"
+            );
+            return;
+        }
+        self.buffered += payload.len();
+        self.segments.push((seq_no, payload.to_vec()));
+    }
+
+    async fn deliver(self, conn: &TcpConn) {
+        for (seq_no, payload) in self.segments {
+            conn.deliver_early_data(seq_no, &payload).await;
+        }
+    }
+}
+
+/// Counts the bytes acknowledged on a connection within a sliding window of
+/// `TCP_THROUGHPUT_WINDOW`.
+#[derive(Debug)]
+struct ThroughputMeter {
+    created_at: std::sync::Mutex<Instant>,
+    samples: std::sync::Mutex<VecDeque<(Instant, usize)>>,
+}
+
+impl Default for ThroughputMeter {
+    fn default() -> Self {
+        Self {
+            created_at: std::sync::Mutex::new(Instant::now()),
+            samples: std::sync::Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl ThroughputMeter {
+    fn record(&self, n_bytes: usize) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back((now, n_bytes));
+        Self::evict_expired(&mut samples, now);
+    }
+
+    fn bits_per_sec(&self) -> f64 {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        Self::evict_expired(&mut samples, now);
+
+        // A connection younger than the window has only been sending for its
+        // lifetime so far.
+        let span = min(
+            now - *self.created_at.lock().unwrap(),
+            TCP_THROUGHPUT_WINDOW,
+        );
+        if span.is_zero() {
+            return 0.0;
+        }
+        let n_bytes: usize = samples.iter().map(|(_, n)| n).sum();
+        (n_bytes * 8) as f64 / span.as_secs_f64()
+    }
+
+    /// Discard all samples, measuring afresh from now.
+    fn reset(&self) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.clear();
+        *self.created_at.lock().unwrap() = Instant::now();
+    }
+
+    fn evict_expired(samples: &mut VecDeque<(Instant, usize)>, now: Instant) {
+        while let Some((t, _)) = samples.front() {
+            if now - *t <= TCP_THROUGHPUT_WINDOW {
+                break;
+            }
+            samples.pop_front();
+        }
+    }
+}
+
+/// Samples the rate at which a connection's data is acknowledged, each sample
+/// spanning at least `TCP_DELIVERY_RATE_INTERVAL` and a round trip of acks.
+/// The highest recent sample estimates the bottleneck bandwidth, as lower ones
+/// are taken while the acks were delayed. No sample spans a time the sender
+/// had nothing in flight.
+///
+/// Acks that arrive bunched up, say after queueing behind other traffic, make
+/// a span shorter than a round trip overstate the rate data got through at.
+/// Linux's delivery rate sampling discards such samples for the same reason.
+#[derive(Debug, Default)]
+struct BandwidthEstimator {
+    state: std::sync::Mutex<DeliveryRateSamples>,
+}
+
+#[derive(Debug, Default)]
+struct DeliveryRateSamples {
+    /// When the sample being taken started, and the bytes acked since.
+    current: Option<(Instant, usize)>,
+    /// Completed samples in bytes per second, with when they were taken.
+    samples: VecDeque<(Instant, f64)>,
+}
+
+impl BandwidthEstimator {
+    fn record(&self, n_bytes: usize, srtt: Option<Duration>) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let Some((started_at, acked)) = state.current.as_mut() else {
+            // The time these bytes took to deliver is unknown, so sampling
+            // starts with the next ack.
+            state.current = Some((now, 0));
+            return;
+        };
+
+        *acked += n_bytes;
+        let elapsed = now - *started_at;
+        let min_interval = srtt.map_or(TCP_DELIVERY_RATE_INTERVAL, |rtt| {
+            rtt.max(TCP_DELIVERY_RATE_INTERVAL)
+        });
+        if elapsed >= min_interval {
+            let rate = *acked as f64 / elapsed.as_secs_f64();
+            state.current = Some((now, 0));
+            state.samples.push_back((now, rate));
+        }
+        Self::evict_expired(&mut state.samples, now);
+    }
+
+    /// Drops the sample being taken once everything sent has been acked, as
+    /// the time until the next send says nothing about the path.
+    fn end_flight(&self) {
+        self.state.lock().unwrap().current = None;
+    }
+
+    fn bits_per_sec(&self) -> Option<f64> {
+        let mut state = self.state.lock().unwrap();
+        Self::evict_expired(&mut state.samples, Instant::now());
+        state
+            .samples
+            .iter()
+            .map(|(_, rate)| rate * 8.0)
+            .reduce(f64::max)
+    }
+
+    fn reset(&self) {
+        *self.state.lock().unwrap() = DeliveryRateSamples::default();
+    }
+
+    fn evict_expired(samples: &mut VecDeque<(Instant, f64)>, now: Instant) {
+        while let Some((t, _)) = samples.front() {
+            if now - *t <= TCP_BANDWIDTH_WINDOW {
+                break;
+            }
+            samples.pop_front();
+        }
+    }
+}
+
+struct Established<N> {
+    local_port: Port,
+    remote_ip: Ipv4Addr,
+    remote_port: Port,
+    conn: TcpConn,
+    net: Arc<N>,
+    challenge_acks: ChallengeAckLimiter,
+}
+
+impl<N: Net> Established<N> {
+    async fn handle_packet<'a>(
+        mut self,
+        ip_header: &Ipv4HeaderSlice<'a>,
+        tcp_header: &TcpHeaderSlice<'a>,
+        payload: &[u8],
+    ) -> TcpState<N> {
+        if tcp_header.rst() {
+            return self.handle_rst(tcp_header).await;
+        }
+        if tcp_header.syn() {
+            // An established connection never expects a SYN. Rather than
+            // trusting it, ask the remote to prove it is in sync with us.
+            self.challenge_ack();
+            return self.into();
+        }
+        if tcp_header.fin() {
+            return self.passive_close(tcp_header).await.into();
+        }
+        self.conn
+            .handle_packet(ip_header, tcp_header, payload)
+            .await;
+        self.into()
+    }
+
+    /// Handles an incoming RST according to RFC 5961, section 3.2.
+    ///
+    /// Only a RST whose sequence number exactly matches the next expected one
+    /// resets the connection. A RST elsewhere in the receive window triggers a
+    /// challenge ACK, and a RST outside of the window is dropped.
+    async fn handle_rst<'a>(mut self, tcp_header: &TcpHeaderSlice<'a>) -> TcpState<N> {
+        let seq_no: usize = tcp_header.sequence_number().try_into().unwrap();
+        let next_expected = self.conn.next_expected_seq_no().await;
+        let window_sz = self.conn.local_window_sz().await;
+
+        if seq_no == next_expected {
+            node_log!(
+                self.net.logger(),
+                Level::Info,
+                "Connection reset by {}:{}",
+                self.remote_ip,
+                self.remote_port.0
+            );
+            self.conn.abort().await;
+            return Closed::new(self.net).into();
+        }
+
+        if seq_no > next_expected && seq_no < next_expected + window_sz {
+            self.challenge_ack();
+        } else {
+            node_log!(
+                self.net.logger(),
+                Level::Info,
+                "Dropped RST outside of the receive window, seq no {seq_no}"
+            );
+        }
+        self.into()
+    }
+
+    fn challenge_ack(&mut self) {
+        if self.challenge_acks.try_acquire() {
+            self.conn.send_ack();
+        } else {
+            node_log!(
+                self.net.logger(),
+                Level::Debug,
+                "Challenge ACK rate limit reached, suppressing challenge ACK"
+            );
+        }
+    }
+
+    /// Perform transition from Established to CloseWait upon receiving a FIN
+    /// packet.
+    async fn passive_close<'a>(self, tcp_header: &TcpHeaderSlice<'a>) -> CloseWait<N> {
+        let ack_packet = self.make_handshake_ack_packet(tcp_header, self.remote_ip);
+        send_segment(
+            &*self.net,
+            Some(self.conn.local_ip()),
+            &ack_packet,
+            self.remote_ip,
+            self.conn.md5_key(),
+        )
+        .await
+        .map_err(|_| TransportError::DestUnreachable(self.remote_ip))
+        .unwrap();
+
+        self.conn.close_read().await;
+
+        CloseWait {
+            conn: self.conn,
+            net: self.net,
         }
     }
 
@@ -866,39 +2620,13 @@ impl<N: Net> Established<N> {
         // 7. Upon transitioning into FinWait2, a oneshot message is sent to the
         // FIN packet transporter. This stops the FIN packet's retransmissions.
 
-        let conn = self.conn.clone();
-        conn.close().await;
-
-        let (fin_acked_tx, fin_acked_rx) = oneshot::channel();
-        let fin_seq_no: Arc<Mutex<Option<usize>>> = Arc::new(Mutex::new(None));
-
-        let net = self.net.clone();
-        let src_ip = self.net.get_outbound_ip(self.remote_ip).await.unwrap();
-        let local_port = self.local_port;
-        let remote = Remote::new(self.remote_ip, self.remote_port);
-        let fin_seq_no_clone = fin_seq_no.clone();
-        tokio::spawn(async move {
-            let fin_seq_no = conn.drain_content_on_close().await;
-            let fin_packet = Self::make_shutdown_fin_packet(
-                fin_seq_no,
-                local_port,
-                remote.port(),
-                src_ip,
-                remote.ip().octets(),
-            );
-
-            let mut ack_handle =
-                transport_single_message(fin_packet, remote, net, RtxConfig::default(), |_| {});
-
-            {
-                let mut write_guard = fin_seq_no_clone.lock().await;
-                *write_guard = Some(fin_seq_no);
-            }
-
-            // TODO: optimize
-            fin_acked_rx.await.unwrap();
-            ack_handle.acked();
-        });
+        self.conn.close().await;
+        let (fin_seq_no, fin_acked_tx) = send_fin_when_drained(
+            self.conn.clone(),
+            self.net.clone(),
+            self.local_port,
+            Remote::new(self.remote_ip, self.remote_port),
+        );
 
         FinWait1 {
             local_port: self.local_port,
@@ -912,36 +2640,10 @@ impl<N: Net> Established<N> {
     }
 
     async fn close_read(&self) {
-        self.conn.close_read().await;
-    }
-
-    fn make_shutdown_fin_packet(
-        fin_seq_no: usize,
-        local_port: Port,
-        remote_port: Port,
-        src_ip: [u8; 4],
-        dst_ip: [u8; 4],
-    ) -> Vec<u8> {
-        let mut bytes = Vec::new();
-
-        let mut header = TcpHeader::new(
-            local_port.0,
-            remote_port.0,
-            fin_seq_no.try_into().unwrap(),
-            TCP_DEFAULT_WINDOW_SZ.try_into().unwrap(),
-        );
-        header.fin = true;
-        let payload: &[u8] = &[];
-        let checksum = header
-            .calc_checksum_ipv4_raw(src_ip, dst_ip, payload)
-            .unwrap();
-        header.checksum = checksum;
-        header.write(&mut bytes).unwrap();
-
-        bytes
+        self.conn.shutdown_read().await;
     }
 
-    async fn make_handshake_ack_packet<'a>(
+    fn make_handshake_ack_packet<'a>(
         &self,
         fin_ack_header: &TcpHeaderSlice<'a>,
         dst_ip: Ipv4Addr,
@@ -957,9 +2659,9 @@ impl<N: Net> Established<N> {
         header.ack = true;
         header.acknowledgment_number = fin_ack_header.sequence_number() + 1;
         let payload: &[u8] = &[];
-        let src_ip = self.net.get_outbound_ip(dst_ip).await.unwrap();
+        let src_ip = self.conn.local_ip();
         let checksum = header
-            .calc_checksum_ipv4_raw(src_ip, dst_ip.octets(), payload)
+            .calc_checksum_ipv4_raw(src_ip.octets(), dst_ip.octets(), payload)
             .unwrap();
         header.checksum = checksum;
         header.write(&mut bytes).unwrap();
@@ -984,69 +2686,64 @@ impl<N: Net> FinWait1<N> {
         tcp_header: &TcpHeaderSlice<'a>,
         payload: &[u8],
     ) -> TcpState<N> {
-        let ack = tcp_header.ack();
-        let fin = tcp_header.fin();
-
-        let fin_seq_no = *self.fin_seq_no.lock().await;
+        let fin_acked = is_fin_acked(&self.fin_seq_no, tcp_header).await;
 
-        if fin && ack {
-            // Remote closed too
+        if tcp_header.fin() {
             let ack_packet = self.make_ack_packet(
                 tcp_header,
                 ip_header.destination_addr(),
-                ip_header.destination_addr(),
+                ip_header.source_addr(),
             );
-            self.net
-                .send(&ack_packet, Protocol::Tcp, self.remote_ip)
-                .await
-                .map_err(|_| TransportError::DestUnreachable(self.remote_ip))
-                .unwrap();
-
-            if let Some(fin_seq_no) = fin_seq_no {
-                if tcp_header.acknowledgment_number() > fin_seq_no as u32 {
-                    self.fin_acked_tx.send(()).unwrap();
-                }
+            send_segment(
+                &*self.net,
+                Some(ip_header.destination_addr()),
+                &ack_packet,
+                self.remote_ip,
+                self.conn.md5_key(),
+            )
+            .await
+            .map_err(|_| TransportError::DestUnreachable(self.remote_ip))
+            .unwrap();
+            // The remote sends no more, so pending reads end.
+            self.conn.close_read().await;
+
+            if fin_acked {
+                // Remote closed too
+                self.fin_acked_tx.send(()).ok();
+                let state = TimeWait {
+                    local_port: self.local_port,
+                    remote_ip: self.remote_ip,
+                    remote_port: self.remote_port,
+                    net: self.net,
+                    md5_key: self.conn.md5_key().map(<[u8]>::to_vec),
+                };
+                return state.into();
             }
 
-            let state = TimeWait {};
-            return state.into();
-        }
-
-        if fin {
             // Simultaneous close
-
-            let ack_packet = self.make_ack_packet(
-                tcp_header,
-                ip_header.destination_addr(),
-                ip_header.destination_addr(),
-            );
-            self.net
-                .send(ack_packet.as_slice(), Protocol::Tcp, self.remote_ip)
-                .await
-                .map_err(|_| TransportError::DestUnreachable(self.remote_ip))
-                .unwrap();
-
             let state = Closing {
+                local_port: self.local_port,
+                remote_ip: self.remote_ip,
+                remote_port: self.remote_port,
+                net: self.net,
+                fin_seq_no: self.fin_seq_no,
                 fin_acked_tx: self.fin_acked_tx,
+                md5_key: self.conn.md5_key().map(<[u8]>::to_vec),
             };
             return state.into();
         }
 
-        if ack {
-            if let Some(fin_seq_no) = fin_seq_no {
-                if tcp_header.acknowledgment_number() > fin_seq_no as u32 {
-                    self.fin_acked_tx.send(()).unwrap();
+        if fin_acked {
+            self.fin_acked_tx.send(()).ok();
 
-                    let state = FinWait2 {
-                        conn: self.conn,
-                        local_port: self.local_port,
-                        remote_ip: self.remote_ip,
-                        remote_port: self.remote_port,
-                        net: self.net,
-                    };
-                    return state.into();
-                }
-            }
+            let state = FinWait2 {
+                conn: self.conn,
+                local_port: self.local_port,
+                remote_ip: self.remote_ip,
+                remote_port: self.remote_port,
+                net: self.net,
+            };
+            return state.into();
         }
 
         // Handle normal traffic
@@ -1108,20 +2805,32 @@ impl<N: Net> FinWait2<N> {
     }
 
     async fn handle_fin<'a>(
-        &self,
+        self,
         ip_header: &Ipv4HeaderSlice<'a>,
         tcp_header: &TcpHeaderSlice<'a>,
-    ) -> TimeWait {
+    ) -> TimeWait<N> {
         let ack_packet = self.make_ack_packet(
             tcp_header,
             ip_header.destination_addr(),
             ip_header.source_addr(),
         );
-        self.net
-            .send(&ack_packet, Protocol::Tcp, self.remote_ip)
-            .await
-            .unwrap();
-        TimeWait {}
+        send_segment(
+            &*self.net,
+            Some(ip_header.destination_addr()),
+            &ack_packet,
+            self.remote_ip,
+            self.conn.md5_key(),
+        )
+        .await
+        .unwrap();
+        self.conn.close_read().await;
+        TimeWait {
+            local_port: self.local_port,
+            remote_ip: self.remote_ip,
+            remote_port: self.remote_port,
+            net: self.net,
+            md5_key: self.conn.md5_key().map(<[u8]>::to_vec),
+        }
     }
 
     fn make_ack_packet(
@@ -1149,70 +2858,88 @@ impl<N: Net> FinWait2<N> {
     }
 }
 
-struct Closing {
+/// Both sides sent a FIN before either was acked.
+struct Closing<N> {
+    local_port: Port,
+    remote_ip: Ipv4Addr,
+    remote_port: Port,
+    net: Arc<N>,
+    fin_seq_no: Arc<Mutex<Option<usize>>>,
     fin_acked_tx: oneshot::Sender<()>,
+    md5_key: Option<Vec<u8>>,
 }
 
-impl Closing {
-    fn handle_ack(self) -> TimeWait {
-        self.fin_acked_tx.send(()).unwrap();
-        TimeWait {}
+impl<N: Net> Closing<N> {
+    /// Moves to TimeWait once the remote acks our FIN.
+    async fn handle_packet<'a>(self, tcp_header: &TcpHeaderSlice<'a>) -> TcpState<N> {
+        if !is_fin_acked(&self.fin_seq_no, tcp_header).await {
+            return self.into();
+        }
+        self.fin_acked_tx.send(()).ok();
+        TimeWait {
+            local_port: self.local_port,
+            remote_ip: self.remote_ip,
+            remote_port: self.remote_port,
+            net: self.net,
+            md5_key: self.md5_key,
+        }
+        .into()
     }
 }
 
-struct TimeWait {}
-
-impl TimeWait {}
-
-struct CloseWait<N> {
-    conn: TcpConn,
-    last_seq: u32,
+struct TimeWait<N> {
+    local_port: Port,
+    remote_ip: Ipv4Addr,
+    remote_port: Port,
     net: Arc<N>,
+    md5_key: Option<Vec<u8>>,
 }
 
-impl<N: Net> CloseWait<N> {
-    async fn close(&self, id: SocketId, local_port: Port) -> LastAck<N> {
-        // FIXME: send this packet?
-        let _fin_packet = self
-            .make_fin_packet(local_port, id.remote_port(), id.remote_ip())
-            .await;
-        self.conn.close().await;
-        LastAck {
-            net: self.net.clone(),
-        }
-    }
-
+impl<N: Net> TimeWait<N> {
+    /// A FIN arriving in TimeWait is a retransmission: the remote did not get
+    /// our ACK, so send it again.
     async fn handle_packet<'a>(
         self,
         ip_header: &Ipv4HeaderSlice<'a>,
         tcp_header: &TcpHeaderSlice<'a>,
-        payload: &[u8],
     ) -> TcpState<N> {
-        if !payload.is_empty() {
-            log::warn!(
-                "Got payload when the remote has already closed their end of the connection"
+        if tcp_header.fin() {
+            let ack_packet = self.make_ack_packet(
+                tcp_header,
+                ip_header.destination_addr(),
+                ip_header.source_addr(),
             );
+            send_segment(
+                &*self.net,
+                Some(ip_header.destination_addr()),
+                &ack_packet,
+                self.remote_ip,
+                self.md5_key.as_deref(),
+            )
+            .await
+            .ok();
         }
-        self.conn.handle_packet(ip_header, tcp_header, &[]).await;
         self.into()
     }
 
-    async fn make_fin_packet(&self, src_port: Port, dst_port: Port, dst_ip: Ipv4Addr) -> Vec<u8> {
+    fn make_ack_packet(
+        &self,
+        tcp_header: &TcpHeaderSlice<'_>,
+        src_ip: Ipv4Addr,
+        dst_ip: Ipv4Addr,
+    ) -> Vec<u8> {
         let mut bytes = Vec::new();
-
         let mut header = TcpHeader::new(
-            src_port.0,
-            dst_port.0,
-            self.last_seq + 1,
+            self.local_port.0,
+            self.remote_port.0,
+            tcp_header.acknowledgment_number(),
             TCP_DEFAULT_WINDOW_SZ.try_into().unwrap(),
         );
-        header.ack = false;
-        header.fin = true;
-
+        header.ack = true;
+        header.acknowledgment_number = tcp_header.sequence_number() + 1;
         let payload: &[u8] = &[];
-        let src_ip = self.net.get_outbound_ip(dst_ip).await.unwrap();
         let checksum = header
-            .calc_checksum_ipv4_raw(src_ip, dst_ip.octets(), payload)
+            .calc_checksum_ipv4_raw(src_ip.octets(), dst_ip.octets(), payload)
             .unwrap();
         header.checksum = checksum;
         header.write(&mut bytes).unwrap();
@@ -1220,18 +2947,204 @@ impl<N: Net> CloseWait<N> {
     }
 }
 
-struct LastAck<N> {
+struct CloseWait<N> {
+    conn: TcpConn,
     net: Arc<N>,
 }
 
-impl<N: Net> LastAck<N> {
-    fn handle_ack(self) -> Closed<N> {
-        Closed::new(self.net)
+impl<N: Net> CloseWait<N> {
+    /// Perform transition from CloseWait to LastAck in a close() syscall. As
+    /// in an active close, the FIN is sent once the remote has acked all
+    /// buffered data.
+    async fn close(self, id: SocketId, local_port: Port) -> LastAck<N> {
+        self.conn.close().await;
+        let (fin_seq_no, fin_acked_tx) =
+            send_fin_when_drained(self.conn, self.net.clone(), local_port, id.remote());
+
+        LastAck {
+            net: self.net,
+            fin_seq_no,
+            fin_acked_tx,
+        }
     }
-}
 
-#[derive(Debug)]
-pub enum ListenTransitionError {
+    async fn handle_packet<'a>(
+        self,
+        ip_header: &Ipv4HeaderSlice<'a>,
+        tcp_header: &TcpHeaderSlice<'a>,
+        payload: &[u8],
+    ) -> TcpState<N> {
+        if !payload.is_empty() {
+            node_log!(
+                self.net.logger(),
+                Level::Warn,
+                "Got payload when the remote has already closed their end of the connection"
+            );
+        }
+        self.conn.handle_packet(ip_header, tcp_header, &[]).await;
+        self.into()
+    }
+}
+
+struct LastAck<N> {
+    net: Arc<N>,
+    fin_seq_no: Arc<Mutex<Option<usize>>>,
+    fin_acked_tx: oneshot::Sender<()>,
+}
+
+impl<N: Net> LastAck<N> {
+    /// Closes the connection once the remote acks our FIN.
+    async fn handle_packet<'a>(self, tcp_header: &TcpHeaderSlice<'a>) -> TcpState<N> {
+        if !is_fin_acked(&self.fin_seq_no, tcp_header).await {
+            return self.into();
+        }
+        self.fin_acked_tx.send(()).ok();
+        Closed::new(self.net).into()
+    }
+}
+
+/// Sends a FIN on `conn`'s behalf once the remote has acked all of its
+/// buffered data, retransmitting it until the returned sender is signalled.
+///
+/// The FIN's sequence number is published through the returned Mutex once the
+/// FIN is sent.
+fn send_fin_when_drained<N: Net>(
+    conn: TcpConn,
+    net: Arc<N>,
+    local_port: Port,
+    remote: Remote,
+) -> (Arc<Mutex<Option<usize>>>, oneshot::Sender<()>) {
+    let (fin_acked_tx, fin_acked_rx) = oneshot::channel();
+    let fin_seq_no: Arc<Mutex<Option<usize>>> = Arc::new(Mutex::new(None));
+
+    let src_ip = conn.local_ip();
+    let md5_key = conn.md5_key().map(<[u8]>::to_vec);
+    let fin_seq_no_clone = fin_seq_no.clone();
+    tokio::spawn(async move {
+        let fin_seq_no = conn.drain_content_on_close().await;
+        // Past the remote's FIN too, if it came first.
+        let ack_no = conn.next_expected_seq_no().await + conn.is_read_closed() as usize;
+        let fin_packet = make_fin_packet(
+            fin_seq_no,
+            ack_no,
+            local_port,
+            remote.port(),
+            src_ip.octets(),
+            remote.ip().octets(),
+        );
+
+        let mut ack_handle = transport_single_message_from(
+            src_ip,
+            fin_packet,
+            remote,
+            net,
+            md5_key,
+            RtxConfig::default(),
+            |_| {},
+        );
+
+        {
+            let mut write_guard = fin_seq_no_clone.lock().await;
+            *write_guard = Some(fin_seq_no);
+        }
+
+        // TODO: optimize
+        fin_acked_rx.await.ok();
+        ack_handle.acked();
+    });
+
+    (fin_seq_no, fin_acked_tx)
+}
+
+fn make_fin_packet(
+    fin_seq_no: usize,
+    ack_no: usize,
+    local_port: Port,
+    remote_port: Port,
+    src_ip: [u8; 4],
+    dst_ip: [u8; 4],
+) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    let mut header = TcpHeader::new(
+        local_port.0,
+        remote_port.0,
+        fin_seq_no.try_into().unwrap(),
+        TCP_DEFAULT_WINDOW_SZ.try_into().unwrap(),
+    );
+    header.fin = true;
+    header.ack = true;
+    header.acknowledgment_number = ack_no.try_into().unwrap();
+    let payload: &[u8] = &[];
+    let checksum = header
+        .calc_checksum_ipv4_raw(src_ip, dst_ip, payload)
+        .unwrap();
+    header.checksum = checksum;
+    header.write(&mut bytes).unwrap();
+
+    bytes
+}
+
+/// Whether a segment acks the FIN whose sequence number is in `fin_seq_no`.
+async fn is_fin_acked(fin_seq_no: &Mutex<Option<usize>>, tcp_header: &TcpHeaderSlice<'_>) -> bool {
+    match *fin_seq_no.lock().await {
+        Some(fin_seq_no) => {
+            tcp_header.ack() && tcp_header.acknowledgment_number() > fin_seq_no as u32
+        }
+        None => false,
+    }
+}
+
+/// Aborts `conn` if a RST's sequence number is exactly the next one expected.
+/// While closing, a RST anywhere else is dropped.
+async fn reset_conn(conn: &TcpConn, tcp_header: &TcpHeaderSlice<'_>) -> bool {
+    let seq_no: usize = tcp_header.sequence_number().try_into().unwrap();
+    if seq_no != conn.next_expected_seq_no().await {
+        node_log!(
+            conn.inner.logger,
+            Level::Info,
+            "Dropped RST with an unexpected seq no {seq_no}"
+        );
+        return false;
+    }
+
+    let remote = conn.remote();
+    node_log!(
+        conn.inner.logger,
+        Level::Info,
+        "Connection reset by {}:{}",
+        remote.ip(),
+        remote.port().0
+    );
+    conn.abort().await;
+    true
+}
+
+/// Sends a FIN on the connection in `state`. Returns whether the connection
+/// was open for writing.
+async fn close_write<N: Net>(state: &mut Option<TcpState<N>>, id: SocketId) -> bool {
+    let (next_state, closed) = match state.take().expect("State should exist") {
+        TcpState::Established(s) => (s.active_close().await.into(), true),
+        TcpState::CloseWait(s) => (s.close(id, id.local_port()).await.into(), true),
+        state => (state, false),
+    };
+    *state = Some(next_state);
+    closed
+}
+
+/// Schedules the socket's removal if handling a RST closed it.
+fn closed_on_reset<N: Net>(
+    state: TcpState<N>,
+    id: SocketId,
+) -> (TcpState<N>, Option<UpdateAction<N>>) {
+    match state {
+        TcpState::Closed(_) => (state, Some(UpdateAction::CloseSocket(id))),
+        _ => (state, None),
+    }
+}
+
+#[derive(Debug)]
+pub enum ListenTransitionError {
     // Errs when attempting to transition into Listen state from a state that's
     // not Closed.
     NotFromClosed,
@@ -1240,28 +3153,59 @@ pub enum ListenTransitionError {
 pub enum UpdateAction<N: Net> {
     NewSynReceivedSocket(SynReceived<N>),
     CloseSocket(SocketId),
+    /// The socket entered TIME_WAIT, and should be removed once it expires.
+    CloseSocketAfterTimeWait(SocketId),
 }
 
 pub struct Socket<N: Net> {
     id: SocketId,
     descriptor: SocketDescriptor,
-    state: Mutex<Option<TcpState<N>>>,
+    state: Arc<Mutex<Option<TcpState<N>>>>,
+    config: Arc<SocketConfig>,
+    // Where the socket asks the stack to act on changes it makes on its own,
+    // rather than in answer to a segment.
+    updates: mpsc::UnboundedSender<UpdateAction<N>>,
+    // The socket's connection, kept past the states that hold it so that its
+    // status stays current until the socket is dropped.
+    conn: std::sync::Mutex<Option<TcpConn>>,
+    // Keys signing the socket's segments, by peer (RFC 2385).
+    md5_keys: Arc<Md5Keys>,
 }
 
-impl<N: Net> Socket<N> {
-    pub fn new(id: SocketId, descriptor: SocketDescriptor, net: Arc<N>) -> Self {
-        Self {
-            id,
-            descriptor,
-            state: Mutex::new(Some(TcpState::new(net))),
+impl<N: Net> Drop for Socket<N> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.get_mut().unwrap() {
+            conn.set_state(SocketStatus::Closed);
         }
     }
+}
 
-    fn with_state(id: SocketId, descriptor: SocketDescriptor, state: TcpState<N>) -> Self {
+impl<N: Net> Socket<N> {
+    pub(crate) fn new(
+        id: SocketId,
+        descriptor: SocketDescriptor,
+        net: Arc<N>,
+        config: Arc<SocketConfig>,
+        updates: mpsc::UnboundedSender<UpdateAction<N>>,
+    ) -> Self {
+        Self::with_state(id, descriptor, TcpState::new(net), config, updates)
+    }
+
+    fn with_state(
+        id: SocketId,
+        descriptor: SocketDescriptor,
+        state: TcpState<N>,
+        config: Arc<SocketConfig>,
+        updates: mpsc::UnboundedSender<UpdateAction<N>>,
+    ) -> Self {
         Self {
             id,
             descriptor,
-            state: Mutex::new(Some(state)),
+            state: Arc::new(Mutex::new(Some(state))),
+            config,
+            updates,
+            conn: std::sync::Mutex::new(None),
+            md5_keys: Arc::default(),
         }
     }
 
@@ -1271,9 +3215,16 @@ impl<N: Net> Socket<N> {
 
         match state {
             TcpState::Closed(s) => {
-                let (new_conn_tx, new_conn_rx) = channel(1024);
-                let listener = TcpListener::new(new_conn_rx);
-                let new_state: TcpState<N> = s.listen(port, new_conn_tx).into();
+                let (new_conn_tx, new_conn_rx) = channel(MAX_PENDING_TCP_CONNECTIONS);
+                let listener = TcpListener::new(new_conn_rx, self.md5_keys.clone());
+                let new_state: TcpState<N> = s
+                    .listen(
+                        port,
+                        new_conn_tx,
+                        self.config.clone(),
+                        self.md5_keys.clone(),
+                    )
+                    .into();
                 *state_guard = Some(new_state);
                 Ok(listener)
             }
@@ -1308,6 +3259,17 @@ impl<N: Net> Socket<N> {
         (self.remote_ip(), self.remote_port())
     }
 
+    /// Sets or (with `None`) removes the key signing segments exchanged with
+    /// `peer` (RFC 2385).
+    pub fn set_md5_key(&self, peer: Ipv4Addr, key: Option<Vec<u8>>) {
+        self.md5_keys.set(peer, key);
+    }
+
+    /// The key segments from `peer` must be signed with, if any.
+    pub fn md5_key(&self, peer: Ipv4Addr) -> Option<Vec<u8>> {
+        self.md5_keys.get(peer)
+    }
+
     pub async fn status(&self) -> SocketStatus {
         let state_guard = self.state.lock().await;
         SocketStatus::from((*state_guard).as_ref().unwrap())
@@ -1318,7 +3280,8 @@ impl<N: Net> Socket<N> {
             let state_guard = self.state.lock().await;
             match (*state_guard).as_ref().unwrap() {
                 TcpState::Established(s) => s.conn.clone(),
-                TcpState::Listen(_) | TcpState::SynSent(_) | TcpState::SynReceived(_) => {
+                TcpState::Listen(_) => return Err(TcpSendError::NotAConnection),
+                TcpState::SynSent(_) | TcpState::SynReceived(_) => {
                     return Err(TcpSendError::ConnNotEstablished)
                 }
                 _ => return Err(TcpSendError::ConnClosed),
@@ -1329,32 +3292,57 @@ impl<N: Net> Socket<N> {
 
     /// Reads N bytes from the connection, where N is `out_buffer`'s size.
     pub async fn read_all(&self, out_buffer: &mut [u8]) -> Result<(), TcpReadError> {
-        let conn = {
-            let state_guard = self.state.lock().await;
-            match (*state_guard).as_ref().unwrap() {
-                TcpState::Established(s) => s.conn.clone(),
-                TcpState::Listen(_) | TcpState::SynSent(_) | TcpState::SynReceived(_) => {
-                    return Err(TcpReadError::ConnNotEstablished)
-                }
-                _ => return Err(TcpReadError::Closed(0)),
+        self.readable_conn().await?.read_all(out_buffer).await
+    }
+
+    /// Reads whatever bytes are buffered, without waiting for more.
+    pub async fn try_read(&self, out_buffer: &mut [u8]) -> Result<usize, TcpReadError> {
+        self.readable_conn().await?.try_read(out_buffer).await
+    }
+
+    async fn readable_conn(&self) -> Result<TcpConn, TcpReadError> {
+        let state_guard = self.state.lock().await;
+        match (*state_guard).as_ref().unwrap() {
+            TcpState::Established(s) => Ok(s.conn.clone()),
+            TcpState::Listen(_) | TcpState::SynSent(_) | TcpState::SynReceived(_) => {
+                Err(TcpReadError::ConnNotEstablished)
             }
-        };
-        conn.read_all(out_buffer).await
+            _ => Err(TcpReadError::Closed(0)),
+        }
     }
 
+    /// Connects to the remote, sending `data` as the connection's first
+    /// bytes. Fails with `TcpConnError::Timeout` if the connection is not
+    /// established within `timeout`.
     pub async fn initiate_connection(
         &self,
+        data: &[u8],
+        timeout: Duration,
     ) -> Result<oneshot::Receiver<Result<TcpConn, TcpConnError>>, TcpConnError> {
         let mut state_guard = self.state.lock().await;
         let state = state_guard.take().expect("State should exist");
         match state {
             TcpState::Closed(s) => {
-                let (established_rx, syn_sent) = s
-                    .connect(self.local_port(), self.remote_ip_port())
+                match s
+                    .connect(
+                        self.local_port(),
+                        self.remote_ip_port(),
+                        data,
+                        timeout,
+                        self.config.clone(),
+                        self.md5_key(self.remote_ip()),
+                    )
                     .await
-                    .map_err(TcpConnError::Transport)?;
-                *state_guard = Some(syn_sent.into());
-                Ok(established_rx)
+                {
+                    Ok((established_rx, syn_sent)) => {
+                        *state_guard = Some(syn_sent.into());
+                        Ok(established_rx)
+                    }
+                    Err((e, closed)) => {
+                        *state_guard = Some(closed.into());
+                        Err(TcpConnError::Transport(e))
+                    }
+                }
             }
             _ => {
                 *state_guard = Some(state);
@@ -1371,76 +3359,266 @@ impl<N: Net> Socket<N> {
     ) -> Option<UpdateAction<N>> {
         let mut state_guard = self.state.lock().await;
         let state = state_guard.take().expect("State should exist");
+        let logger = state.logger();
 
         let (next_state, action) = match state {
-            TcpState::Closed(_) => {
-                panic!("Should not receive packet under closed state");
+            // Closed on its own, and not yet removed from the table.
+            state @ TcpState::Closed(_) => {
+                node_log!(
+                    logger,
+                    Level::Info,
+                    "Dropped segment for closed socket {:?}",
+                    self.id
+                );
+                (state, Some(UpdateAction::CloseSocket(self.id)))
             }
             TcpState::Listen(s) => {
                 if tcp_header.syn() {
-                    let syn_recvd_state = s.syn_received(ip_header, tcp_header).await.unwrap();
+                    match s.syn_received(ip_header, tcp_header, payload).await {
+                        Ok(syn_recvd_state) => (
+                            s.into(),
+                            Some(UpdateAction::NewSynReceivedSocket(syn_recvd_state)),
+                        ),
+                        Err(e) => {
+                            node_log!(logger, Level::Warn, "Dropping SYN on {:?}: {}", self.id, e);
+                            (TcpState::Listen(s), None)
+                        }
+                    }
+                } else {
+                    (TcpState::Listen(s), None)
+                }
+            }
+            TcpState::SynSent(s) => {
+                if tcp_header.rst() {
+                    closed_on_reset(s.handle_rst(tcp_header), self.id)
+                } else if tcp_header.ack() && !s.acks_syn(tcp_header) {
+                    let s = s.reject_bad_ack(ip_header, tcp_header, payload.len());
+                    (s.await.into(), None)
+                } else if tcp_header.syn() && !tcp_header.ack() {
                     (
-                        s.into(),
-                        Some(UpdateAction::NewSynReceivedSocket(syn_recvd_state)),
+                        s.simultaneous_open(ip_header, tcp_header).await.into(),
+                        None,
                     )
+                } else if tcp_header.syn() && tcp_header.ack() {
+                    match s.establish(tcp_header, payload).await {
+                        Ok(s) => (s.into(), None),
+                        Err((e, s)) => {
+                            node_log!(
+                                logger,
+                                Level::Warn,
+                                "Dropping SYN-ACK on {:?}: {}",
+                                self.id,
+                                e
+                            );
+                            (s.into(), None)
+                        }
+                    }
                 } else {
-                    (TcpState::Listen(s), None)
+                    (s.buffer_early_data(tcp_header, payload).into(), None)
                 }
             }
-            TcpState::SynSent(s) => (s.establish(tcp_header).await.unwrap().into(), None),
             TcpState::SynReceived(s) => {
-                if tcp_header.acknowledgment_number() == s.seq_no {
-                    (s.establish(tcp_header).await.into(), None)
+                if tcp_header.rst() {
+                    closed_on_reset(s.handle_rst(tcp_header), self.id)
+                } else if s.is_handshake_ack(tcp_header) {
+                    match s.establish(tcp_header, payload).await {
+                        Ok(s) => (s.into(), None),
+                        Err((e, s)) => {
+                            node_log!(
+                                logger,
+                                Level::Warn,
+                                "Dropping handshake ACK on {:?}: {}",
+                                self.id,
+                                e
+                            );
+                            (s.into(), None)
+                        }
+                    }
+                } else if tcp_header.syn() && !tcp_header.ack() {
+                    (s.handle_dup_syn(tcp_header).await.into(), None)
                 } else {
-                    (s.into(), None)
+                    (s.buffer_early_data(tcp_header, payload).into(), None)
                 }
             }
             TcpState::Established(s) => {
-                (s.handle_packet(ip_header, tcp_header, payload).await, None)
+                let new_state = s.handle_packet(ip_header, tcp_header, payload).await;
+                match new_state {
+                    // The connection was reset by the remote.
+                    TcpState::Closed(_) => (new_state, Some(UpdateAction::CloseSocket(self.id))),
+                    _ => (new_state, None),
+                }
+            }
+            TcpState::FinWait1(s) if tcp_header.rst() => {
+                let new_state = match reset_conn(&s.conn, tcp_header).await {
+                    true => Closed::new(s.net).into(),
+                    false => s.into(),
+                };
+                closed_on_reset(new_state, self.id)
+            }
+            TcpState::FinWait1(s) => {
+                let new_state = s.handle_packet(ip_header, tcp_header, payload).await;
+                match new_state {
+                    TcpState::TimeWait(state) => (
+                        state.into(),
+                        Some(UpdateAction::CloseSocketAfterTimeWait(self.id)),
+                    ),
+                    _ => (new_state, None),
+                }
+            }
+            TcpState::FinWait2(s) if tcp_header.rst() => {
+                let new_state = match reset_conn(&s.conn, tcp_header).await {
+                    true => Closed::new(s.net).into(),
+                    false => s.into(),
+                };
+                closed_on_reset(new_state, self.id)
             }
-            TcpState::FinWait1(s) => (s.handle_packet(ip_header, tcp_header, payload).await, None),
             TcpState::FinWait2(s) => {
                 let new_state = s.handle_packet(ip_header, tcp_header, payload).await;
                 match new_state {
-                    TcpState::TimeWait(state) => {
-                        (state.into(), Some(UpdateAction::CloseSocket(self.id)))
-                    }
+                    TcpState::TimeWait(state) => (
+                        state.into(),
+                        Some(UpdateAction::CloseSocketAfterTimeWait(self.id)),
+                    ),
                     TcpState::FinWait2(state) => (state.into(), None),
                     _ => (new_state, None),
                 }
             }
             TcpState::Closing(s) => {
-                if tcp_header.ack() {
-                    (s.handle_ack().into(), None)
-                } else {
-                    (s.into(), None)
+                let new_state = s.handle_packet(tcp_header).await;
+                match new_state {
+                    TcpState::TimeWait(_) => (
+                        new_state,
+                        Some(UpdateAction::CloseSocketAfterTimeWait(self.id)),
+                    ),
+                    _ => (new_state, None),
                 }
             }
-            TcpState::TimeWait(s) => (s.into(), None),
+            TcpState::TimeWait(s) => (s.handle_packet(ip_header, tcp_header).await, None),
+            TcpState::CloseWait(s) if tcp_header.rst() => {
+                let new_state = match reset_conn(&s.conn, tcp_header).await {
+                    true => Closed::new(s.net).into(),
+                    false => s.into(),
+                };
+                closed_on_reset(new_state, self.id)
+            }
             TcpState::CloseWait(s) => (s.handle_packet(ip_header, tcp_header, payload).await, None),
-            TcpState::LastAck(s) => (s.handle_ack().into(), None), //TODO return action that triggers socket table pruning
+            TcpState::LastAck(s) => {
+                let new_state = s.handle_packet(tcp_header).await;
+                match new_state {
+                    TcpState::Closed(_) => (new_state, Some(UpdateAction::CloseSocket(self.id))),
+                    _ => (new_state, None),
+                }
+            }
         };
 
+        self.publish_status(&next_state);
         *state_guard = Some(next_state);
         action
     }
 
-    pub async fn close(&self) {
+    /// Reports a socket's new state to its connection.
+    fn publish_status(&self, state: &TcpState<N>) {
+        let mut conn = self.conn.lock().unwrap();
+        if let Some(state_conn) = state.conn() {
+            if conn.is_none() {
+                self.close_on_shutdown_request(state_conn);
+                self.close_on_abort(state_conn);
+            }
+            conn.get_or_insert_with(|| state_conn.clone());
+        }
+        if let Some(conn) = conn.as_ref() {
+            conn.set_state(state.into());
+        }
+    }
+
+    /// Closes the write-end once `conn` asks for it through `AsyncWrite`.
+    fn close_on_shutdown_request(&self, conn: &TcpConn) {
+        let shutdown_requests = conn.shutdown_requests();
+        let state = Arc::downgrade(&self.state);
+        let id = self.id;
+        tokio::spawn(async move {
+            shutdown_requests.notified().await;
+            // The connection is dropped along with its socket.
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let mut state_guard = state.lock().await;
+            if close_write(&mut state_guard, id).await {
+                let state = state_guard.as_ref().expect("State should exist");
+                if let Some(conn) = state.conn() {
+                    conn.set_state(state.into());
+                }
+            }
+        });
+    }
+
+    /// Closes the socket once the transport gives up on `conn`, the same way
+    /// a RST from the remote does.
+    fn close_on_abort(&self, conn: &TcpConn) {
+        let aborts = conn.aborts();
+        let state = Arc::downgrade(&self.state);
+        let updates = self.updates.clone();
+        let id = self.id;
+        tokio::spawn(async move {
+            aborts.notified().await;
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let mut state_guard = state.lock().await;
+            let state = state_guard.take().expect("State should exist");
+            let state = state.into_aborted();
+            let closed = matches!(state, TcpState::Closed(_));
+            *state_guard = Some(state);
+            if closed {
+                updates.send(UpdateAction::CloseSocket(id)).ok();
+            }
+        });
+    }
+
+    /// Set the linger setting of the socket's connection, if it has one.
+    pub fn set_linger(&self, linger: LingerConfig) {
+        if let Some(conn) = self.conn.lock().unwrap().as_ref() {
+            conn.set_linger(linger);
+        }
+    }
+
+    /// The linger setting of the socket's connection, if it has one.
+    pub fn linger(&self) -> LingerConfig {
+        self.conn
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(TcpConn::linger)
+            .unwrap_or_default()
+    }
+
+    /// Resets the connection, discarding data not yet acked. Returns whether
+    /// there was an open connection to reset; a closing one is left to finish.
+    pub async fn abort(&self) -> bool {
         let mut state_guard = self.state.lock().await;
         let state = state_guard.take().expect("State should exist");
-        match state {
+        let (state, aborted) = match state {
             TcpState::Established(s) => {
-                let state = s.active_close().await;
-                *state_guard = Some(state.into());
+                s.conn.reset();
+                (Closed::new(s.net).into(), true)
             }
             TcpState::CloseWait(s) => {
-                let state = s.close(self.id, self.local_port()).await;
-                *state_guard = Some(state.into());
-            }
-            _ => {
-                *state_guard = Some(state);
-                eprintln!("Should not be able to close a connection that's not established");
+                s.conn.reset();
+                (Closed::new(s.net).into(), true)
             }
+            state => (state, false),
+        };
+        self.publish_status(&state);
+        *state_guard = Some(state);
+        aborted
+    }
+
+    pub async fn close(&self) {
+        let mut state_guard = self.state.lock().await;
+        if close_write(&mut state_guard, self.id).await {
+            self.publish_status(state_guard.as_ref().expect("State should exist"));
+        } else {
+            eprintln!("Should not be able to close a connection that's not established");
         }
     }
 
@@ -1459,20 +3637,34 @@ impl<N: Net> Socket<N> {
         }
     }
 
-    pub async fn close_rw(&self) {
+    /// Shut both ends of the connection down: a FIN is sent, and pending
+    /// and later reads fail. Errs if both ends are already shut down.
+    pub async fn close_rw(&self) -> Result<(), TcpCloseError> {
         let mut state_guard = self.state.lock().await;
         let state = state_guard.take().expect("State should exist");
-        match state {
+        let (state, closed) = match state {
             TcpState::Established(s) => {
                 s.close_read().await;
-                let state = s.active_close().await;
-                *state_guard = Some(state.into());
+                (s.active_close().await.into(), Ok(()))
             }
-            _ => {
-                *state_guard = Some(state);
-                eprintln!("Should not be able to close a connection that's not established");
+            TcpState::CloseWait(s) => {
+                s.conn.shutdown_read().await;
+                (s.close(self.id, self.local_port()).await.into(), Ok(()))
             }
-        }
+            // Only the write end is shut down.
+            TcpState::FinWait1(s) if !s.conn.is_read_closed() => {
+                s.conn.shutdown_read().await;
+                (s.into(), Ok(()))
+            }
+            TcpState::FinWait2(s) if !s.conn.is_read_closed() => {
+                s.conn.shutdown_read().await;
+                (s.into(), Ok(()))
+            }
+            state => (state, Err(TcpCloseError::AlreadyClosed)),
+        };
+        self.publish_status(&state);
+        *state_guard = Some(state);
+        closed
     }
 
     pub async fn is_read_closed(&self) -> Option<bool> {
@@ -1484,6 +3676,59 @@ impl<N: Net> Socket<N> {
             .is_read_closed()
     }
 
+    /// Bits per second acknowledged by the remote, if the socket has a
+    /// connection.
+    pub async fn throughput_bps(&self) -> Option<f64> {
+        self.conn().await.map(|conn| conn.throughput_bps())
+    }
+
+    /// The statistics of the socket's connection, if it has one.
+    pub async fn stats(&self) -> Option<SocketStats> {
+        self.conn().await.map(|conn| conn.stats())
+    }
+
+    /// Disable or re-enable Nagle's algorithm on the socket's connection.
+    /// Returns `None` if the socket has no connection.
+    pub async fn set_nodelay(&self, nodelay: bool) -> Option<()> {
+        self.conn().await?.set_nodelay(nodelay);
+        Some(())
+    }
+
+    /// Retransmit the oldest unacked segment on the socket's connection.
+    /// Returns `None` if the socket has no connection.
+    #[cfg(any(test, feature = "debug-commands"))]
+    pub async fn force_retransmit(&self) -> Option<()> {
+        self.conn().await?.force_retransmit();
+        Some(())
+    }
+
+    /// The out-of-order byte ranges buffered by the connection, if any.
+    pub async fn out_of_order_ranges(&self) -> Option<Vec<(usize, usize)>> {
+        Some(self.conn().await?.out_of_order_ranges().await)
+    }
+
+    /// Discard the connection's out-of-order segments, returning how many
+    /// were discarded.
+    pub async fn clear_out_of_order(&self) -> Option<usize> {
+        Some(self.conn().await?.clear_out_of_order().await)
+    }
+
+    /// The (send, receive) bytes buffered by the connection, if any.
+    pub async fn buffered_bytes(&self) -> Option<(usize, usize)> {
+        let conn = self.conn().await?;
+        Some((conn.send_buffered().await, conn.recv_buffered().await))
+    }
+
+    async fn conn(&self) -> Option<TcpConn> {
+        self.state
+            .lock()
+            .await
+            .as_ref()
+            .expect("State should exist")
+            .conn()
+            .cloned()
+    }
+
     pub async fn as_table_entry_string(&self) -> String {
         let id = self.descriptor.0;
         let state = self.status().await;
@@ -1493,6 +3738,20 @@ impl<N: Net> Socket<N> {
 
         format!("{id}\t{state:?}\t\t{local_window_sz}\t\t\t{remote_window_sz}")
     }
+
+    /// A one-line summary of the connection's statistics, if it has one.
+    pub async fn stats_summary(&self) -> Option<String> {
+        let stats = self.stats().await?;
+        Some(format!(
+            "sent {} segs ({} retransmitted), received {} segs, {}/{} bytes acked, {} dup acks",
+            stats.segments_sent,
+            stats.retransmissions,
+            stats.segments_received,
+            stats.bytes_acked,
+            stats.bytes_sent,
+            stats.dup_acks
+        ))
+    }
 }
 
 impl<N: Net> Socket<N> {
@@ -1516,3 +3775,1168 @@ impl<N: Net> Socket<N> {
             .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use etherparse::{Ipv4Header, TcpOptionElement};
+
+    use crate::net::SendError;
+    use crate::protocol::tcp::congestion::CongestionAlgorithm;
+    use crate::protocol::tcp::{TcpConfig, MAX_SEGMENT_SZ};
+    use crate::protocol::Protocol;
+
+    const LOCAL_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const LOCAL_PORT: u16 = 5656;
+    const LOCAL_SEQ_NO: usize = 1000;
+    const REMOTE_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const REMOTE_PORT: u16 = 1024;
+    const REMOTE_SEQ_NO: usize = 5000;
+
+    /// A `Net` that records outgoing TCP segments instead of sending them.
+    #[derive(Default)]
+    struct RecordingNet {
+        sent: std::sync::Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl RecordingNet {
+        fn take_sent_headers(&self) -> Vec<TcpHeader> {
+            self.sent
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|bytes| TcpHeaderSlice::from_slice(&bytes).unwrap().to_header())
+                .collect()
+        }
+
+        /// The sequence numbers of the segments sent that carry data.
+        fn take_data_seq_nos(&self) -> Vec<usize> {
+            self.sent
+                .lock()
+                .unwrap()
+                .drain(..)
+                .filter_map(|bytes| {
+                    let header = TcpHeaderSlice::from_slice(&bytes).unwrap();
+                    (bytes.len() > header.slice().len()).then(|| header.sequence_number() as usize)
+                })
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl Net for RecordingNet {
+        async fn get_outbound_ip(&self, _dest: Ipv4Addr) -> Option<[u8; 4]> {
+            Some(LOCAL_IP.octets())
+        }
+
+        async fn send<P: Into<u8> + Send>(
+            &self,
+            payload: &[u8],
+            _protocol: P,
+            _dest: Ipv4Addr,
+        ) -> Result<(), SendError> {
+            self.sent.lock().unwrap().push(payload.to_vec());
+            Ok(())
+        }
+
+        async fn send_from<P: Into<u8> + Send>(
+            &self,
+            _src: Ipv4Addr,
+            payload: &[u8],
+            protocol: P,
+            dest: Ipv4Addr,
+        ) -> Result<(), SendError> {
+            self.send(payload, protocol, dest).await
+        }
+
+        async fn send_via<P: Into<u8> + Send>(
+            &self,
+            _link_no: u16,
+            payload: &[u8],
+            protocol: P,
+            dest: Ipv4Addr,
+        ) -> Result<(), SendError> {
+            self.send(payload, protocol, dest).await
+        }
+    }
+
+    #[tokio::test]
+    async fn challenge_ack_on_in_window_rst() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32 + 100, |h| h.rst = true);
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        let action = socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+
+        assert!(action.is_none());
+        assert_eq!(socket.status().await, SocketStatus::Established);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let sent = net.take_sent_headers();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].ack && !sent[0].rst);
+        assert_eq!(sent[0].acknowledgment_number, REMOTE_SEQ_NO as u32);
+    }
+
+    #[tokio::test]
+    async fn reset_on_exact_rst() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32, |h| h.rst = true);
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        let action = socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+
+        assert!(matches!(action, Some(UpdateAction::CloseSocket(_))));
+        assert_eq!(socket.status().await, SocketStatus::Closed);
+    }
+
+    #[tokio::test]
+    async fn out_of_order_ranges() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+        let start = REMOTE_SEQ_NO;
+
+        deliver_data(&socket, start + 100, &[1; 50]).await;
+        deliver_data(&socket, start + 150, &[1; 50]).await;
+        deliver_data(&socket, start + 300, &[1; 50]).await;
+        assert_eq!(
+            socket.out_of_order_ranges().await.unwrap(),
+            vec![(start + 100, start + 200), (start + 300, start + 350)]
+        );
+
+        deliver_data(&socket, start, &[1; 100]).await;
+        assert_eq!(
+            socket.out_of_order_ranges().await.unwrap(),
+            vec![(start + 300, start + 350)]
+        );
+
+        assert_eq!(socket.clear_out_of_order().await, Some(1));
+        assert!(socket.out_of_order_ranges().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reassembles_out_of_order_segments() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+        let conn = socket.conn().await.unwrap();
+        let start = REMOTE_SEQ_NO;
+        let window = socket.local_window_sz().await;
+
+        deliver_data(&socket, start + 200, &[3; 100]).await;
+        deliver_data(&socket, start, &[1; 100]).await;
+        assert_eq!(socket.local_window_sz().await, window - 100);
+
+        deliver_data(&socket, start + 100, &[2; 100]).await;
+        assert_eq!(socket.local_window_sz().await, window - 300);
+
+        let mut out = [0; 300];
+        conn.read_all(&mut out).await.unwrap();
+        assert_eq!(out[..100], [1; 100]);
+        assert_eq!(out[100..200], [2; 100]);
+        assert_eq!(out[200..], [3; 100]);
+        assert_eq!(socket.local_window_sz().await, window);
+    }
+
+    #[tokio::test]
+    async fn delivers_novel_bytes_of_overlapping_segments_once() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+        let conn = socket.conn().await.unwrap();
+        let start = REMOTE_SEQ_NO;
+        let window = socket.local_window_sz().await;
+
+        // A retransmission straddling the received bytes keeps only its tail.
+        deliver_data(&socket, start, &[1; 100]).await;
+        deliver_data(&socket, start + 50, &[2; 100]).await;
+        assert_eq!(socket.local_window_sz().await, window - 150);
+
+        // A segment running past the window keeps only the part that fits.
+        let rest = socket.local_window_sz().await;
+        deliver_data(&socket, start + 150, &vec![3; rest + 100]).await;
+        assert_eq!(socket.local_window_sz().await, 0);
+
+        let mut out = vec![0; window];
+        conn.read_all(&mut out).await.unwrap();
+        assert_eq!(out[..100], [1; 100]);
+        assert_eq!(out[100..150], [2; 50]);
+        assert!(out[150..].iter().all(|&b| b == 3));
+        assert_eq!(socket.local_window_sz().await, window);
+    }
+
+    #[tokio::test]
+    async fn try_read_returns_buffered_bytes_without_waiting() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+        let conn = socket.conn().await.unwrap();
+        let mut out = [0; 100];
+
+        assert!(matches!(
+            conn.try_read(&mut out).await,
+            Err(TcpReadError::WouldBlock)
+        ));
+
+        deliver_data(&socket, REMOTE_SEQ_NO, &[1; 60]).await;
+        assert_eq!(conn.try_read(&mut out).await.unwrap(), 60);
+        assert_eq!(out[..60], [1; 60]);
+        assert!(matches!(
+            conn.try_read(&mut out).await,
+            Err(TcpReadError::WouldBlock)
+        ));
+    }
+
+    #[tokio::test]
+    async fn keeps_data_arriving_around_handshake_ack() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_syn_received_socket(net.clone()).await;
+        let start = REMOTE_SEQ_NO;
+
+        // The first data segment overtakes the handshake ACK.
+        let (ip_bytes, tcp_bytes) = make_segment(start as u32, |_| {});
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        socket
+            .handle_packet(&ip_header, &tcp_header, &[1; 100])
+            .await;
+        assert_eq!(socket.status().await, SocketStatus::SynReceived);
+
+        // The next segments carry the ACK, and arrive right behind it.
+        deliver_data(&socket, start + 100, &[2; 100]).await;
+        assert_eq!(socket.status().await, SocketStatus::Established);
+        deliver_data(&socket, start + 200, &[3; 100]).await;
+
+        let conn = socket.conn().await.unwrap();
+        let mut out = [0; 300];
+        conn.read_all(&mut out).await.unwrap();
+        assert_eq!(out[..100], [1; 100]);
+        assert_eq!(out[100..200], [2; 100]);
+        assert_eq!(out[200..], [3; 100]);
+    }
+
+    #[tokio::test]
+    async fn resends_syn_ack_on_duplicate_syn() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_syn_received_socket(net.clone()).await;
+        // Let the first SYN-ACK go out, well before it is due again.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let first = net.take_sent_headers();
+        assert_eq!(first.len(), 1);
+        assert!(first[0].syn && first[0].ack);
+
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32 - 1, |h| h.syn = true);
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+
+        assert_eq!(socket.status().await, SocketStatus::SynReceived);
+        assert_eq!(net.take_sent_headers(), first);
+
+        // A SYN for some other connection attempt gets no answer.
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32 + 1000, |h| h.syn = true);
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+        assert!(net.take_sent_headers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn third_handshake_segment_is_ack_only() {
+        let net = Arc::new(RecordingNet::default());
+        let (socket, syn, _established_rx) = make_syn_sent_socket(net.clone()).await;
+        assert!(syn.syn && !syn.ack);
+
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32 - 1, |h| {
+            h.syn = true;
+            h.ack = true;
+            h.acknowledgment_number = syn.sequence_number + 1;
+        });
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+        assert_eq!(socket.status().await, SocketStatus::Established);
+
+        let sent = net.sent.lock().unwrap();
+        let ack = TcpHeaderSlice::from_slice(&sent[0]).unwrap();
+        assert!(ack.ack() && !ack.syn());
+        assert_eq!(ack.sequence_number(), syn.sequence_number + 1);
+        assert_eq!(ack.acknowledgment_number(), REMOTE_SEQ_NO as u32);
+    }
+
+    #[tokio::test]
+    async fn syn_ack_after_connect_gave_up_is_reset() {
+        let net = Arc::new(RecordingNet::default());
+        let (socket, syn, established_rx) = make_syn_sent_socket(net.clone()).await;
+        drop(established_rx);
+
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32 - 1, |h| {
+            h.syn = true;
+            h.ack = true;
+            h.acknowledgment_number = syn.sequence_number + 1;
+        });
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(net.take_sent_headers().iter().any(|h| h.rst));
+        assert_eq!(socket.status().await, SocketStatus::Closed);
+    }
+
+    #[tokio::test]
+    async fn syn_ack_with_wrong_ack_no_is_reset() {
+        let net = Arc::new(RecordingNet::default());
+        let (socket, syn, _established_rx) = make_syn_sent_socket(net.clone()).await;
+
+        let bad_ack_no = syn.sequence_number + 1000;
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32 - 1, |h| {
+            h.syn = true;
+            h.ack = true;
+            h.acknowledgment_number = bad_ack_no;
+        });
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+
+        assert_eq!(socket.status().await, SocketStatus::SynSent);
+        let sent = net.take_sent_headers();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].rst);
+        assert_eq!(sent[0].sequence_number, bad_ack_no);
+    }
+
+    #[tokio::test]
+    async fn syn_ack_does_not_complete_handshake() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_syn_received_socket(net.clone()).await;
+
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32, |h| {
+            h.syn = true;
+            h.ack = true;
+            h.acknowledgment_number = LOCAL_SEQ_NO as u32;
+        });
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+        assert_eq!(socket.status().await, SocketStatus::SynReceived);
+
+        deliver_data(&socket, REMOTE_SEQ_NO, &[]).await;
+        assert_eq!(socket.status().await, SocketStatus::Established);
+    }
+
+    #[tokio::test]
+    async fn fast_retransmit_after_three_dup_acks() {
+        // An RTO longer than the test, so that only a fast retransmit resends.
+        let net = Arc::new(RecordingNet::default());
+        let config = TcpConfig {
+            initial_rto: Some(Duration::from_secs(1)),
+            ..test_config()
+        };
+        let socket = make_established_socket_with(net.clone(), config).await;
+        let conn = socket.conn().await.unwrap();
+        tokio::spawn(async move { conn.send_all(&[1; 4 * MAX_SEGMENT_SZ]).await });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let first_segments = net
+            .take_sent_headers()
+            .iter()
+            .filter(|h| h.sequence_number == LOCAL_SEQ_NO as u32)
+            .count();
+        assert_eq!(first_segments, 1);
+
+        // The first segment is lost, so the remote acks each later one with
+        // the sequence number it still expects.
+        for _ in 0..3 {
+            deliver_data(&socket, REMOTE_SEQ_NO, &[]).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let retransmitted = net
+            .take_sent_headers()
+            .iter()
+            .filter(|h| h.sequence_number == LOCAL_SEQ_NO as u32)
+            .count();
+        assert_eq!(retransmitted, 1);
+    }
+
+    #[tokio::test]
+    async fn frto_detects_spurious_timeout() {
+        // An RTO that fires once before the delayed ACKs arrive.
+        let net = Arc::new(RecordingNet::default());
+        let config = TcpConfig {
+            initial_rto: Some(Duration::from_millis(50)),
+            ..test_config()
+        };
+        let socket = make_established_socket_with(net.clone(), config).await;
+        let conn = socket.conn().await.unwrap();
+        tokio::spawn(async move { conn.send_all(&[1; 4 * MAX_SEGMENT_SZ]).await });
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        let seq_nos = net.take_data_seq_nos();
+        assert!(seq_nos.len() > 2);
+        assert_eq!(seq_nos.first(), seq_nos.last());
+
+        // ACKs of the original first and second segments arrive: neither
+        // acks only the retransmission, so the timeout was spurious and the
+        // rest of the segments in flight are not resent.
+        deliver_ack(&socket, seq_nos[1]).await;
+        deliver_ack(&socket, seq_nos[2]).await;
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(net.take_data_seq_nos().is_empty());
+    }
+
+    #[tokio::test]
+    async fn frto_restores_reno_window() {
+        let net = Arc::new(RecordingNet::default());
+        let config = TcpConfig {
+            initial_rto: Some(Duration::from_millis(50)),
+            congestion_control: CongestionAlgorithm::Reno,
+            ..test_config()
+        };
+        let socket = make_established_socket_with(net.clone(), config).await;
+        let conn = socket.conn().await.unwrap();
+        tokio::spawn(async move { conn.send_all(&[1; 8 * MAX_SEGMENT_SZ]).await });
+
+        // The initial window goes out, then the timeout shrinks the window to
+        // the one segment it resends.
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        let seq_nos = net.take_data_seq_nos();
+        assert!(seq_nos.len() > 4);
+        assert_eq!(seq_nos.first(), seq_nos.last());
+
+        // The timeout is found spurious, and the window it shrank is restored,
+        // still in slow start: the second ACK grows it to five segments, three
+        // more than are left in flight.
+        deliver_ack(&socket, seq_nos[1]).await;
+        deliver_ack(&socket, seq_nos[2]).await;
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let sent = net.take_data_seq_nos();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|seq_no| *seq_no > seq_nos[3]));
+    }
+
+    #[tokio::test]
+    async fn segments_fit_peer_mss() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_syn_received_socket_from(net.clone(), |h| {
+            h.syn = true;
+            h.set_options(&[TcpOptionElement::MaximumSegmentSize(256)])
+                .unwrap();
+        })
+        .await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let syn_ack = net.take_sent_headers();
+        assert_eq!(
+            options::find_mss(syn_ack[0].options()),
+            Some(MAX_SEGMENT_SZ as u16)
+        );
+
+        deliver_data(&socket, REMOTE_SEQ_NO, &[]).await;
+        let conn = socket.conn().await.unwrap();
+        assert_eq!(conn.mss(), 256);
+        tokio::spawn(async move { conn.send_all(&[1; 2 * MAX_SEGMENT_SZ]).await });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let segment_szs: Vec<_> = net
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|bytes| bytes.len() - TcpHeaderSlice::from_slice(bytes).unwrap().slice().len())
+            .filter(|&sz| sz > 0)
+            .collect();
+        assert!(!segment_szs.is_empty());
+        assert!(segment_szs.iter().all(|&sz| sz <= 256));
+    }
+
+    #[tokio::test]
+    async fn sender_respects_scaled_window() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_syn_received_socket_from(net.clone(), |h| {
+            h.syn = true;
+            h.set_options(&[TcpOptionElement::WindowScale(4)]).unwrap();
+        })
+        .await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let syn_ack = net.take_sent_headers();
+        assert_eq!(
+            options::find_window_scale(syn_ack[0].options()),
+            Some(TCP_WINDOW_SHIFT)
+        );
+
+        // The first ACK completes the handshake, the second updates the
+        // window: 8 KiB, scaled up to 128 KiB.
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32, |h| {
+            h.ack = true;
+            h.acknowledgment_number = LOCAL_SEQ_NO as u32;
+            h.window_size = 0x2000;
+        });
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        for _ in 0..2 {
+            socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+        }
+        let conn = socket.conn().await.unwrap();
+        assert_eq!(conn.remote_window_sz().await, 0x2000 << 4);
+
+        // More than the unscaled window goes out without any further ACK.
+        let payload_sz = 4 * 0x2000;
+        tokio::spawn(async move { conn.send_all(&vec![1; payload_sz]).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let sent_up_to = net
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|bytes| {
+                let tcp_header = TcpHeaderSlice::from_slice(bytes).unwrap();
+                let payload_sz = bytes.len() - tcp_header.slice().len();
+                tcp_header.sequence_number() as usize + payload_sz
+            })
+            .max()
+            .unwrap();
+        assert_eq!(sent_up_to, LOCAL_SEQ_NO + payload_sz);
+    }
+
+    #[tokio::test]
+    async fn nagle_coalesces_small_writes() {
+        async fn count_data_segments(nodelay: bool) -> usize {
+            // An RTO longer than the test, so that nothing is retransmitted.
+            let net = Arc::new(RecordingNet::default());
+            let config = TcpConfig {
+                initial_rto: Some(Duration::from_secs(1)),
+                ..test_config()
+            };
+            let socket = make_established_socket_with(net.clone(), config).await;
+            let conn = socket.conn().await.unwrap();
+            conn.set_nodelay(nodelay);
+
+            for _ in 0..20 {
+                let conn = conn.clone();
+                tokio::spawn(async move { conn.send_all(&[1]).await });
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let sent = net.sent.lock().unwrap();
+            sent.iter()
+                .filter(|bytes| {
+                    bytes.len() > TcpHeaderSlice::from_slice(bytes).unwrap().slice().len()
+                })
+                .count()
+        }
+
+        let with_nagle = count_data_segments(false).await;
+        let without_nagle = count_data_segments(true).await;
+        // Nothing is acked, so Nagle holds back everything after the first
+        // byte.
+        assert_eq!(with_nagle, 1);
+        assert!(without_nagle > with_nagle);
+    }
+
+    #[tokio::test]
+    async fn delayed_ack_waits_for_timer() {
+        let net = Arc::new(RecordingNet::default());
+        let config = TcpConfig {
+            delayed_ack_interval: Some(Duration::from_millis(40)),
+            ..test_config()
+        };
+        let socket = make_established_socket_with(net.clone(), config).await;
+        let mss = socket.conn().await.unwrap().mss();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        net.take_sent_headers();
+
+        deliver_data(&socket, REMOTE_SEQ_NO, &vec![1; mss]).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(net.take_sent_headers().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let sent = net.take_sent_headers();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].acknowledgment_number, (REMOTE_SEQ_NO + mss) as u32);
+    }
+
+    #[tokio::test]
+    async fn delayed_ack_acks_second_full_segment_at_once() {
+        let net = Arc::new(RecordingNet::default());
+        let config = TcpConfig {
+            delayed_ack_interval: Some(Duration::from_millis(40)),
+            ..test_config()
+        };
+        let socket = make_established_socket_with(net.clone(), config).await;
+        let mss = socket.conn().await.unwrap().mss();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        net.take_sent_headers();
+
+        deliver_data(&socket, REMOTE_SEQ_NO, &vec![1; mss]).await;
+        deliver_data(&socket, REMOTE_SEQ_NO + mss, &vec![2; mss]).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let sent = net.take_sent_headers();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].acknowledgment_number,
+            (REMOTE_SEQ_NO + 2 * mss) as u32
+        );
+    }
+
+    #[tokio::test]
+    async fn every_n_ack_policy_decides_acks() {
+        async fn count_acks(n: usize, num_segments: usize) -> usize {
+            // Delayed ACK is on, as by default, but the policy overrides it.
+            let net = Arc::new(RecordingNet::default());
+            let config = TcpConfig {
+                delayed_ack_interval: Some(Duration::from_millis(40)),
+                ..test_config()
+            };
+            let socket = make_established_socket_with(net.clone(), config).await;
+            let conn = socket.conn().await.unwrap();
+            conn.set_ack_policy(ack_policy::EveryNAck::new(n));
+            let mss = conn.mss();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            net.take_sent_headers();
+
+            for i in 0..num_segments {
+                deliver_data(&socket, REMOTE_SEQ_NO + i * mss, &vec![1; mss]).await;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            net.take_sent_headers().len()
+        }
+
+        let num_segments = 10;
+        for n in [2, 5] {
+            let num_acks = count_acks(n, num_segments).await;
+            assert!(
+                num_acks.abs_diff(num_segments / n) <= 1,
+                "{num_acks} ACKs for {num_segments} segments, acking every {n}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalive_probes_after_idle() {
+        let keepalive = KeepaliveConfig {
+            idle: Duration::from_millis(100),
+            interval: Duration::from_millis(20),
+            probes: 3,
+        };
+
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+        socket.conn().await.unwrap().set_keepalive(Some(keepalive));
+
+        let quiet_net = Arc::new(RecordingNet::default());
+        let _quiet_socket = make_established_socket(quiet_net.clone()).await;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(net.take_sent_headers().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let probes = net.take_sent_headers();
+        assert!(!probes.is_empty());
+        assert!(probes
+            .iter()
+            .all(|h| h.sequence_number == LOCAL_SEQ_NO as u32 - 1 && h.ack));
+
+        assert!(quiet_net.take_sent_headers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn async_write_copies_bytes_of_the_poll_that_finds_room() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+        let mut conn = socket.conn().await.unwrap();
+        let send_buf = conn.inner.send_buf.clone();
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+
+        send_buf.write(&[0; TCP_DEFAULT_WINDOW_SZ]).await.unwrap();
+        assert!(Pin::new(&mut conn)
+            .poll_write(&mut cx, b"stale")
+            .is_pending());
+
+        // The caller may retry with other bytes once room frees up.
+        send_buf.set_tail(LOCAL_SEQ_NO + 5).await.unwrap();
+        let written = Pin::new(&mut conn).poll_write(&mut cx, b"fresh");
+        assert!(matches!(written, Poll::Ready(Ok(5))));
+        let mut tail = [0; 5];
+        send_buf
+            .try_slice(LOCAL_SEQ_NO + TCP_DEFAULT_WINDOW_SZ, &mut tail)
+            .await
+            .unwrap();
+        assert_eq!(&tail, b"fresh");
+
+        // Nothing is acked, yet flushing does not wait.
+        assert!(matches!(
+            Pin::new(&mut conn).poll_flush(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+    }
+
+    #[tokio::test]
+    async fn forced_retransmission_is_deduplicated() {
+        // An RTO longer than the test, so that only the forced
+        // retransmission happens.
+        let active_net = Arc::new(RecordingNet::default());
+        let config = TcpConfig {
+            initial_rto: Some(Duration::from_secs(1)),
+            ..test_config()
+        };
+        let active = make_established_socket_with(active_net.clone(), config).await;
+        let passive_net = Arc::new(RecordingNet::default());
+        let passive = make_established_socket_between(
+            passive_net.clone(),
+            test_config(),
+            (REMOTE_IP, REMOTE_PORT, REMOTE_SEQ_NO),
+            (LOCAL_IP, LOCAL_PORT, LOCAL_SEQ_NO),
+        )
+        .await;
+
+        let conn = active.conn().await.unwrap();
+        tokio::spawn(async move { conn.send_all(b"hello").await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        active.force_retransmit().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let data_seq_nos: Vec<_> = active_net
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|bytes| (TcpHeaderSlice::from_slice(bytes).unwrap(), bytes.len()))
+            .filter(|(h, len)| *len > h.slice().len())
+            .map(|(h, _)| h.sequence_number())
+            .collect();
+        assert_eq!(data_seq_nos, vec![LOCAL_SEQ_NO as u32; 2]);
+
+        relay(&active_net, LOCAL_IP, REMOTE_IP, &passive).await;
+        let passive_conn = passive.conn().await.unwrap();
+        let mut buf = [0; 64];
+        let n = passive_conn.read_some(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), passive_conn.read_some(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn zero_window_probe_resumes_sending() {
+        // An RTO longer than the test, so that only probes are resent.
+        let active_net = Arc::new(RecordingNet::default());
+        let config = TcpConfig {
+            initial_rto: Some(Duration::from_secs(10)),
+            ..test_config()
+        };
+        let active = make_established_socket_with(active_net.clone(), config).await;
+        let passive_net = Arc::new(RecordingNet::default());
+        let passive = make_established_socket_between(
+            passive_net.clone(),
+            test_config(),
+            (REMOTE_IP, REMOTE_PORT, REMOTE_SEQ_NO),
+            (LOCAL_IP, LOCAL_PORT, LOCAL_SEQ_NO),
+        )
+        .await;
+
+        let conn = active.conn().await.unwrap();
+        let sent =
+            tokio::spawn(async move { conn.send_all(&vec![7; TCP_DEFAULT_WINDOW_SZ + 100]).await });
+
+        let passive_conn = passive.conn().await.unwrap();
+        while passive_conn.local_window_sz().await > 0 {
+            relay(&active_net, LOCAL_IP, REMOTE_IP, &passive).await;
+            relay(&passive_net, REMOTE_IP, LOCAL_IP, &active).await;
+        }
+
+        // Probes into the closed window back off, and are acked but not
+        // accepted.
+        active_net.take_sent_headers();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let probes = active_net
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|bytes| {
+                bytes.len() == TcpHeaderSlice::from_slice(bytes).unwrap().slice().len() + 1
+            })
+            .count();
+        assert!((1..=3).contains(&probes));
+        relay(&active_net, LOCAL_IP, REMOTE_IP, &passive).await;
+        relay(&passive_net, REMOTE_IP, LOCAL_IP, &active).await;
+        assert_eq!(passive_conn.local_window_sz().await, 0);
+
+        // Drain the receiver, losing its window update.
+        let mut buf = vec![0; TCP_DEFAULT_WINDOW_SZ];
+        passive_conn.read_all(&mut buf).await.unwrap();
+        passive_net.take_sent_headers();
+
+        // The next probe finds the window open, and sending resumes.
+        let mut rest = [0; 100];
+        let read = tokio::time::timeout(Duration::from_secs(3), async {
+            loop {
+                relay(&active_net, LOCAL_IP, REMOTE_IP, &passive).await;
+                relay(&passive_net, REMOTE_IP, LOCAL_IP, &active).await;
+                if passive_conn.local_window_sz().await == TCP_DEFAULT_WINDOW_SZ - 100 {
+                    break passive_conn.read_all(&mut rest).await;
+                }
+            }
+        });
+        read.await.unwrap().unwrap();
+        assert_eq!(rest, [7; 100]);
+
+        relay(&passive_net, REMOTE_IP, LOCAL_IP, &active).await;
+        sent.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tiny_reads_do_not_open_silly_windows() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+        let conn = socket.conn().await.unwrap();
+        let mss = conn.mss();
+
+        let mut seq_no = REMOTE_SEQ_NO;
+        while seq_no < REMOTE_SEQ_NO + TCP_DEFAULT_WINDOW_SZ {
+            let len = min(mss, REMOTE_SEQ_NO + TCP_DEFAULT_WINDOW_SZ - seq_no);
+            deliver_data(&socket, seq_no, &vec![1; len]).await;
+            seq_no += len;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(net.take_sent_headers().last().unwrap().window_size, 0);
+
+        let mut byte = [0; 1];
+        for i in 0..mss + mss / 2 {
+            conn.read_some(&mut byte).await.unwrap();
+            // Give the transport's timers a chance to advertise a window.
+            if i % 64 == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let windows: Vec<_> = net
+            .take_sent_headers()
+            .iter()
+            .map(|h| usize::from(h.window_size))
+            .collect();
+        assert!(!windows.is_empty());
+        assert!(windows.iter().all(|&w| w == 0 || w >= mss));
+        assert!(windows.iter().any(|&w| w >= mss));
+    }
+
+    #[tokio::test]
+    async fn four_way_close() {
+        let active_net = Arc::new(RecordingNet::default());
+        let active = make_established_socket(active_net.clone()).await;
+        let passive_net = Arc::new(RecordingNet::default());
+        let passive = make_established_socket_between(
+            passive_net.clone(),
+            test_config(),
+            (REMOTE_IP, REMOTE_PORT, REMOTE_SEQ_NO),
+            (LOCAL_IP, LOCAL_PORT, LOCAL_SEQ_NO),
+        )
+        .await;
+
+        active.close().await;
+        assert_eq!(active.status().await, SocketStatus::FinWait1);
+
+        relay(&active_net, LOCAL_IP, REMOTE_IP, &passive).await;
+        assert_eq!(passive.status().await, SocketStatus::CloseWait);
+
+        relay(&passive_net, REMOTE_IP, LOCAL_IP, &active).await;
+        assert_eq!(active.status().await, SocketStatus::FinWait2);
+
+        passive.close().await;
+        assert_eq!(passive.status().await, SocketStatus::LastAck);
+
+        let actions = relay(&passive_net, REMOTE_IP, LOCAL_IP, &active).await;
+        assert_eq!(active.status().await, SocketStatus::TimeWait);
+        assert!(actions
+            .iter()
+            .any(|a| matches!(a, UpdateAction::CloseSocketAfterTimeWait(_))));
+
+        let actions = relay(&active_net, LOCAL_IP, REMOTE_IP, &passive).await;
+        assert_eq!(passive.status().await, SocketStatus::Closed);
+        assert!(actions
+            .iter()
+            .any(|a| matches!(a, UpdateAction::CloseSocket(_))));
+    }
+
+    #[tokio::test]
+    async fn time_wait_reacks_duplicate_fin() {
+        let net = Arc::new(RecordingNet::default());
+        let socket = make_established_socket(net.clone()).await;
+        socket.close().await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        net.take_sent_headers();
+
+        // The remote acks our FIN and sends its own.
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32, |h| {
+            h.fin = true;
+            h.ack = true;
+            h.acknowledgment_number = LOCAL_SEQ_NO as u32 + 1;
+        });
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        let action = socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+        assert!(matches!(
+            action,
+            Some(UpdateAction::CloseSocketAfterTimeWait(_))
+        ));
+        assert_eq!(socket.status().await, SocketStatus::TimeWait);
+        assert_eq!(net.take_sent_headers().len(), 1);
+
+        // Our ACK was lost, so the remote retransmits its FIN.
+        let action = socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+        assert!(action.is_none());
+        assert_eq!(socket.status().await, SocketStatus::TimeWait);
+
+        let sent = net.take_sent_headers();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].ack && !sent[0].fin);
+        assert_eq!(sent[0].acknowledgment_number, REMOTE_SEQ_NO as u32 + 1);
+    }
+
+    #[test]
+    fn challenge_ack_rate_limit() {
+        let mut limiter = ChallengeAckLimiter::default();
+        for _ in 0..TCP_CHALLENGE_ACK_LIMIT {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+    }
+
+    /// The configuration of the sockets under test, which ack every segment
+    /// as it arrives.
+    fn test_config() -> TcpConfig {
+        TcpConfig {
+            delayed_ack_interval: None,
+            ..Default::default()
+        }
+    }
+
+    async fn make_established_socket(net: Arc<RecordingNet>) -> Socket<RecordingNet> {
+        make_established_socket_with(net, test_config()).await
+    }
+
+    async fn make_established_socket_with(
+        net: Arc<RecordingNet>,
+        config: TcpConfig,
+    ) -> Socket<RecordingNet> {
+        make_established_socket_between(
+            net,
+            config,
+            (LOCAL_IP, LOCAL_PORT, LOCAL_SEQ_NO),
+            (REMOTE_IP, REMOTE_PORT, REMOTE_SEQ_NO),
+        )
+        .await
+    }
+
+    /// Makes a socket that sent a SYN to the remote, returning it along with
+    /// the SYN and where the connection is handed over once established.
+    async fn make_syn_sent_socket(
+        net: Arc<RecordingNet>,
+    ) -> (
+        Socket<RecordingNet>,
+        TcpHeader,
+        oneshot::Receiver<Result<TcpConn, TcpConnError>>,
+    ) {
+        let config = Arc::new(SocketConfig::new(test_config()));
+        let (established_rx, syn_sent) = Closed::new(net.clone())
+            .connect(
+                Port(LOCAL_PORT),
+                (REMOTE_IP, Port(REMOTE_PORT)),
+                &[],
+                Duration::from_secs(1),
+                config.clone(),
+                None,
+            )
+            .await
+            .ok()
+            .unwrap();
+        let socket_id = SocketId::build()
+            .with_remote_ip(REMOTE_IP)
+            .with_remote_port(Port(REMOTE_PORT))
+            .with_local_port(Port(LOCAL_PORT))
+            .build()
+            .unwrap();
+        let socket = Socket::with_state(
+            socket_id,
+            SocketDescriptor(0),
+            syn_sent.into(),
+            config,
+            mpsc::unbounded_channel().0,
+        );
+        loop {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            if let Some(syn) = net.take_sent_headers().pop() {
+                return (socket, syn, established_rx);
+            }
+        }
+    }
+
+    /// Makes a socket that received the remote's SYN and is waiting for the
+    /// ACK of its SYN-ACK.
+    async fn make_syn_received_socket(net: Arc<RecordingNet>) -> Socket<RecordingNet> {
+        make_syn_received_socket_from(net, |h| h.syn = true).await
+    }
+
+    /// Makes a socket that received the given SYN from the remote.
+    async fn make_syn_received_socket_from(
+        net: Arc<RecordingNet>,
+        set_flags: impl FnOnce(&mut TcpHeader),
+    ) -> Socket<RecordingNet> {
+        let listen = Listen {
+            port: Port(LOCAL_PORT),
+            seq_no: LOCAL_SEQ_NO as u32 - 1,
+            net: net.clone(),
+            config: Arc::new(SocketConfig::new(test_config())),
+            new_conn_tx: channel(1).0,
+            md5_keys: Arc::default(),
+        };
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32 - 1, set_flags);
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        let syn_recvd = listen
+            .syn_received(&ip_header, &tcp_header, &[])
+            .await
+            .unwrap();
+
+        syn_recvd.into_socket(SocketDescriptor(0), mpsc::unbounded_channel().0)
+    }
+
+    /// Makes an established socket between the given (ip, port, initial seq)
+    /// endpoints.
+    async fn make_established_socket_between(
+        net: Arc<RecordingNet>,
+        config: TcpConfig,
+        (local_ip, local_port, local_seq_no): (Ipv4Addr, u16, usize),
+        (remote_ip, remote_port, remote_seq_no): (Ipv4Addr, u16, usize),
+    ) -> Socket<RecordingNet> {
+        let config = Arc::new(SocketConfig::new(config));
+        let socket_id = SocketId::build()
+            .with_remote_ip(remote_ip)
+            .with_remote_port(Port(remote_port))
+            .with_local_port(Port(local_port))
+            .build()
+            .unwrap();
+        let conn = TcpConn::new(
+            socket_id,
+            Remote::new(remote_ip, Port(remote_port)),
+            local_ip,
+            Port(local_port),
+            local_seq_no,
+            remote_seq_no,
+            Negotiated::default(),
+            net.clone(),
+            config.clone(),
+            None,
+        );
+        let state = Established {
+            local_port: Port(local_port),
+            remote_ip,
+            remote_port: Port(remote_port),
+            conn,
+            net: net.clone(),
+            challenge_acks: ChallengeAckLimiter::default(),
+        };
+
+        // Let the transport send its initial ACK before observing traffic.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        net.take_sent_headers();
+
+        Socket::with_state(
+            socket_id,
+            SocketDescriptor(0),
+            state.into(),
+            config,
+            mpsc::unbounded_channel().0,
+        )
+    }
+
+    /// Delivers every segment sent on `from` so far to `to`, returning the
+    /// resulting socket actions.
+    async fn relay(
+        from: &RecordingNet,
+        src_ip: Ipv4Addr,
+        dst_ip: Ipv4Addr,
+        to: &Socket<RecordingNet>,
+    ) -> Vec<UpdateAction<RecordingNet>> {
+        // Let spawned FIN senders run first.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let sent: Vec<_> = from.sent.lock().unwrap().drain(..).collect();
+        let mut actions = Vec::new();
+        for tcp_bytes in sent {
+            let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+            let payload = &tcp_bytes[tcp_header.slice().len()..];
+            let ip_header = Ipv4Header::new(
+                tcp_bytes.len().try_into().unwrap(),
+                15,
+                Protocol::Tcp.into(),
+                src_ip.octets(),
+                dst_ip.octets(),
+            );
+            let mut ip_bytes = Vec::new();
+            ip_header.write(&mut ip_bytes).unwrap();
+            let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+
+            actions.extend(to.handle_packet(&ip_header, &tcp_header, payload).await);
+        }
+        actions
+    }
+
+    async fn deliver_data(socket: &Socket<RecordingNet>, seq_no: usize, payload: &[u8]) {
+        let (ip_bytes, tcp_bytes) = make_segment(seq_no as u32, |h| {
+            h.ack = true;
+            h.acknowledgment_number = LOCAL_SEQ_NO as u32;
+        });
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        socket.handle_packet(&ip_header, &tcp_header, payload).await;
+    }
+
+    async fn deliver_ack(socket: &Socket<RecordingNet>, ack_no: usize) {
+        let (ip_bytes, tcp_bytes) = make_segment(REMOTE_SEQ_NO as u32, |h| {
+            h.ack = true;
+            h.acknowledgment_number = ack_no as u32;
+        });
+        let ip_header = Ipv4HeaderSlice::from_slice(&ip_bytes).unwrap();
+        let tcp_header = TcpHeaderSlice::from_slice(&tcp_bytes).unwrap();
+        socket.handle_packet(&ip_header, &tcp_header, &[]).await;
+    }
+
+    /// Makes a segment from the remote, returning its IP and TCP header bytes.
+    fn make_segment(seq_no: u32, set_flags: impl FnOnce(&mut TcpHeader)) -> (Vec<u8>, Vec<u8>) {
+        let mut tcp_header = TcpHeader::new(
+            REMOTE_PORT,
+            LOCAL_PORT,
+            seq_no,
+            TCP_DEFAULT_WINDOW_SZ.try_into().unwrap(),
+        );
+        set_flags(&mut tcp_header);
+        let mut tcp_bytes = Vec::new();
+        tcp_header.write(&mut tcp_bytes).unwrap();
+
+        let ip_header = Ipv4Header::new(
+            tcp_bytes.len().try_into().unwrap(),
+            15,
+            Protocol::Tcp.into(),
+            REMOTE_IP.octets(),
+            LOCAL_IP.octets(),
+        );
+        let mut ip_bytes = Vec::new();
+        ip_header.write(&mut ip_bytes).unwrap();
+
+        (ip_bytes, tcp_bytes)
+    }
+}
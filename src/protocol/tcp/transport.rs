@@ -1,5 +1,7 @@
 use std::{
     cmp::min,
+    collections::VecDeque,
+    net::Ipv4Addr,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -8,103 +10,148 @@ use etherparse::TcpHeader;
 use tokio::sync::{
     broadcast,
     broadcast::error::RecvError::{Closed, Lagged},
-    oneshot,
+    oneshot, watch, Notify,
 };
 
 use crate::{
     net::{Net, SendError},
+    node_log,
     protocol::Protocol,
 };
+use log::Level;
 
 use super::{
     buf::{RecvBuf, SendBuf, SliceError},
-    Port, Remote, MAX_SEGMENT_SZ, TCP_DEFAULT_WINDOW_SZ,
+    congestion::{AckSample, CongestionControl},
+    md5sig, Port, Remote, TcpConfig, MAX_SEGMENT_SZ, TCP_DEFAULT_WINDOW_SZ,
 };
 
 const TCP_DEFAULT_RTX_TICK_INTERVAL: Duration = Duration::from_millis(10);
 
-#[allow(unused)]
+const TCP_KEEPALIVE_TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+const TCP_PERSIST_TICK_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The wait before the first probe into a zero window. It doubles with every
+/// unanswered probe, up to `TCP_MAX_PERSIST_INTERVAL`.
+const TCP_MIN_PERSIST_INTERVAL: Duration = Duration::from_millis(10);
+
+const TCP_MAX_PERSIST_INTERVAL: Duration = Duration::from_secs(2);
+
 const TCP_DEFAULT_INITIAL_RTO: Duration = Duration::from_millis(10);
 
-#[allow(unused)]
-struct RtxRequest {
-    seq_no: usize,
-    tx_time: Instant,
-}
+const TCP_MIN_RTO: Duration = Duration::from_millis(10);
 
-#[allow(unused)]
-impl RtxRequest {
-    fn new(seq_no: usize, tx_time: Instant) -> Self {
-        Self { seq_no, tx_time }
-    }
+const TCP_MAX_RTO: Duration = Duration::from_secs(2);
 
-    async fn tx_time(&self) -> Instant {
-        self.tx_time
-    }
-}
+/// How far behind schedule paced segments may fall before the schedule
+/// restarts from now. Timers fire late, so segments owed in the meantime may
+/// go out back to back to catch up, up to this much of a burst.
+const TCP_PACING_QUANTUM: Duration = Duration::from_millis(1);
 
-impl PartialEq for RtxRequest {
-    fn eq(&self, other: &Self) -> bool {
-        self.seq_no == other.seq_no
-    }
-}
+/// Retransmissions of a SYN after which connecting fails (as Linux's
+/// `tcp_syn_retries`).
+pub const TCP_MAX_SYN_RETRIES: usize = 6;
 
-impl Eq for RtxRequest {}
+/// The longest wait between retransmissions of a SYN.
+const TCP_MAX_SYN_RTX_INTERVAL: Duration = Duration::from_secs(1);
 
-impl PartialOrd for RtxRequest {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.seq_no.partial_cmp(&other.seq_no)
-    }
+/// Duplicate ACKs after which the oldest unacked segment is retransmitted
+/// without waiting for the RTO (RFC 5681, section 3.2).
+const TCP_DUP_ACK_THRESHOLD: usize = 3;
+
+/// A sent segment awaiting acknowledgement.
+struct RtxSegment {
+    seq_no: usize,
+    len: usize,
+    tx_time: Instant,
+    /// Whether the segment was sent more than once. Per Karn's algorithm,
+    /// its ack then cannot be used as an RTT sample.
+    retransmitted: bool,
+    /// Bytes delivered when the segment was last sent, and when the last of
+    /// them was acked. Its ack then tells the delivery rate in between.
+    delivered: usize,
+    delivered_time: Instant,
 }
 
-impl Ord for RtxRequest {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.seq_no.cmp(&other.seq_no)
+impl RtxSegment {
+    fn new(
+        seq_no: usize,
+        len: usize,
+        tx_time: Instant,
+        delivered: usize,
+        delivered_time: Instant,
+    ) -> Self {
+        Self {
+            seq_no,
+            len,
+            tx_time,
+            retransmitted: false,
+            delivered,
+            delivered_time,
+        }
+    }
+
+    /// The sequence number right after this segment.
+    fn end(&self) -> usize {
+        self.seq_no + self.len
     }
 }
 
-/// Dynamically updated retransmission timeout, based on RFC6298:
+/// Round-trip time estimation and the resulting retransmission timeout,
+/// based on RFC6298:
 ///
 /// https://www.rfc-editor.org/rfc/rfc6298.html.
-#[allow(unused)]
-struct DynamicRto {
+///
+/// Per Karn's algorithm, callers must only sample segments that were never
+/// retransmitted.
+#[derive(Debug)]
+pub struct Rtt {
     /// smoothed round-trip time
     srtt: Option<Duration>,
     /// round-trip time variation
-    rtt_var: Option<Duration>,
+    rtt_var: Duration,
     /// retransmission timeout
     rto: Duration,
-    /// timer tick interval
+    /// clock granularity
     tick: Duration,
+    min_rto: Duration,
+    max_rto: Duration,
 
     // parameters for smoothening
     alpha: f64,
     beta: f64,
 }
 
-#[allow(unused)]
-impl DynamicRto {
-    fn new(tick_interval: Duration, initial_rto: Duration) -> Self {
+impl Rtt {
+    pub fn new(
+        tick: Duration,
+        initial_rto: Duration,
+        min_rto: Duration,
+        max_rto: Duration,
+    ) -> Self {
         Self {
             srtt: None,
-            rtt_var: None,
+            rtt_var: Duration::ZERO,
             rto: initial_rto,
-            tick: tick_interval,
+            tick,
+            min_rto,
+            max_rto,
             alpha: 0.125,
-            beta: 0.24,
+            beta: 0.25,
         }
     }
 
-    fn update(&mut self, rtt: Duration) {
+    /// Feeds a round-trip time measurement into the estimate.
+    pub fn sample(&mut self, rtt: Duration) {
         const K: u32 = 4;
 
-        match (self.srtt, self.rtt_var) {
-            (None, None) => {
-                self.srtt = Some(rtt);
-                self.rtt_var = Some(rtt / 2);
-                self.rto = std::cmp::max(self.tick, K * self.rtt_var.unwrap());
+        let srtt = match self.srtt {
+            None => {
+                self.rtt_var = rtt / 2;
+                rtt
             }
-            (Some(srtt), Some(rtt_var)) => {
+            Some(srtt) => {
                 let (alpha, beta) = (self.alpha, self.beta);
 
                 // RTTVAR <- (1 - beta) * RTTVAR + beta * |SRTT - R'|
@@ -118,38 +165,168 @@ impl DynamicRto {
                     }
                 };
 
-                let rtt_var = rtt_var.mul_f64(1f64 - beta) + srtt_rtt_diff.mul_f64(beta);
-                let srtt = srtt.mul_f64(1f64 - alpha) + rtt.mul_f64(alpha);
+                self.rtt_var = self.rtt_var.mul_f64(1f64 - beta) + srtt_rtt_diff.mul_f64(beta);
+                srtt.mul_f64(1f64 - alpha) + rtt.mul_f64(alpha)
+            }
+        };
+        self.srtt = Some(srtt);
 
-                self.srtt = Some(srtt);
-                self.rtt_var = Some(rtt_var);
+        // RTO <- SRTT + max (G, K*RTTVAR)
+        self.rto = self.clamp(srtt + std::cmp::max(self.tick, K * self.rtt_var));
+    }
 
-                // RTO <- SRTT + max (G, K*RTTVAR)
-                self.rto = srtt + std::cmp::max(self.tick, K * rtt_var);
-            }
-            _ => unreachable!(),
+    /// Doubles the RTO after a retransmission timeout fired.
+    pub fn back_off(&mut self) {
+        self.rto = self.clamp(self.rto * 2);
+    }
+
+    /// Restores the RTO from before a timeout that turned out spurious.
+    pub fn restore(&mut self, rto: Duration) {
+        self.rto = rto;
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    fn clamp(&self, rto: Duration) -> Duration {
+        rto.clamp(self.min_rto, self.max_rto)
+    }
+}
+
+/// Forward RTO-Recovery (RFC 5682): the two ACKs after a retransmission
+/// timeout tell whether the segments in flight were lost, or only delayed.
+#[derive(Debug, Clone, Copy)]
+enum Frto {
+    /// The oldest segment was resent on timeout; awaiting the first ACK.
+    FirstAck { recover: usize, rto: Duration },
+    /// The first ACK acked new data; awaiting the second.
+    SecondAck { recover: usize, rto: Duration },
+}
+
+impl Frto {
+    /// The sequence number sent up to when the timeout fired.
+    fn recover(&self) -> usize {
+        match *self {
+            Frto::FirstAck { recover, .. } | Frto::SecondAck { recover, .. } => recover,
         }
     }
+}
+
+/// When to probe a connection the remote has gone quiet on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// How long the remote may stay quiet before the first probe.
+    pub idle: Duration,
+    /// Time between unanswered probes.
+    pub interval: Duration,
+    /// Unanswered probes after which the connection is aborted.
+    pub probes: usize,
+}
 
-    fn rto(&self) -> Instant {
-        Instant::now() + self.rto
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+            probes: 5,
+        }
     }
 }
 
+/// Counts of what a connection has sent and received, to tell why a
+/// transfer is slow.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SocketStats {
+    /// Segments sent, bare ACKs and retransmissions included.
+    pub segments_sent: usize,
+    pub segments_received: usize,
+    /// Segments resent on a timeout or a fast retransmit.
+    pub retransmissions: usize,
+    /// Payload bytes sent, retransmitted bytes included.
+    pub bytes_sent: usize,
+    pub bytes_acked: usize,
+    /// ACKs received that acked nothing new.
+    pub dup_acks: usize,
+}
+
 pub struct TcpTransport<const BUF_SZ: usize, N: Net> {
     send_buf: SendBuf<BUF_SZ>,
     recv_buf: RecvBuf<BUF_SZ>,
     remote: Remote,
+    local_ip: Ipv4Addr,
     local_port: Port,
     net: Arc<N>,
     seq_no: usize,
+    /// The largest segment to send, as negotiated in the handshake.
+    mss: usize,
+    /// How far the windows we advertise are shifted (RFC 7323).
+    window_shift: u8,
     last_transmitted: Instant,
     ack_batch_timeout: Duration,
     last_acked: usize,
-    zero_window_probe_interval: Duration,
+    rtt: Rtt,
+    /// When to retransmit the oldest unacked segment, if any is in flight.
+    rtx_deadline: Option<Instant>,
+    /// Sent segments not yet fully acked, oldest first.
+    rtx_queue: VecDeque<RtxSegment>,
+    /// Consecutive duplicate ACKs of `last_acked`.
+    dup_acks: usize,
+    /// The sequence number sent up to when fast retransmit began, while
+    /// recovering the segments lost before it (RFC 6582).
+    recover: Option<usize>,
+    /// Set while checking whether a retransmission timeout was spurious.
+    frto: Option<Frto>,
+    congestion: Box<dyn CongestionControl>,
+    /// Bytes the remote has acked, and when it last acked any.
+    delivered: usize,
+    delivered_time: Instant,
+    /// When pacing lets the next segment go.
+    next_send_at: Instant,
+    /// Whether to pace at the window per RTT when the congestion controller
+    /// sets no pacing rate of its own.
+    pacing: bool,
+    dup_ack: broadcast::Receiver<usize>,
+    /// When to next probe the remote's zero window, if it is closed.
+    persist_deadline: Option<Instant>,
+    /// The wait between zero window probes, backing off exponentially.
+    persist_interval: Duration,
     send_ack_request: broadcast::Receiver<()>,
     last_ack_transmitted: usize,
+    /// How long received data may go unacked. `None` acks on the next tick.
+    delayed_ack_interval: Option<Duration>,
+    /// When the delayed ACK for data received so far must go out.
+    ack_deadline: Option<Instant>,
+    /// The right edge of the receive window last advertised. To avoid silly
+    /// window syndrome, it only moves by `sws_threshold()` or more.
+    advertised_edge: Option<usize>,
+    /// How much of the remote's window is left, past the data in flight.
     remaining_window_sz: usize,
+    keepalive: watch::Receiver<Option<KeepaliveConfig>>,
+    /// Whether Nagle's algorithm is disabled.
+    nodelay: watch::Receiver<bool>,
+    /// Whether delayed ACK decides when in-order data is acked. Once the
+    /// connection's ACK policy decides instead, the delay only bounds how
+    /// long data may go unacked.
+    delayed_ack: watch::Receiver<bool>,
+    last_heard: watch::Receiver<Instant>,
+    /// Where the smoothed RTT is published after every sample.
+    srtt: watch::Sender<Option<Duration>>,
+    keepalive_probes_sent: usize,
+    /// Shared with the connection, which counts what it receives.
+    stats: Arc<std::sync::Mutex<SocketStats>>,
+    reset_request: Arc<Notify>,
+    /// Asks for the oldest unacked segment to be retransmitted right away.
+    retransmit_request: Arc<Notify>,
+    /// Notified once the connection is given up on, so that its socket is
+    /// closed.
+    aborted: Arc<Notify>,
+    /// The key signing segments to the remote (RFC 2385), if any.
+    md5_key: Option<Vec<u8>>,
 }
 
 enum NextSendDecision {
@@ -157,50 +334,126 @@ enum NextSendDecision {
     SendBufClosed,
 }
 
+enum KeepaliveDecision {
+    Alive,
+    Dead,
+}
+
 impl<const BUF_SZ: usize, N: Net> TcpTransport<BUF_SZ, N> {
+    #[allow(clippy::too_many_arguments)]
     pub async fn init(
         send_buf: SendBuf<BUF_SZ>,
         recv_buf: RecvBuf<BUF_SZ>,
         remote: Remote,
         local_port: Port,
         net: Arc<N>,
+        config: &TcpConfig,
         should_ack: broadcast::Receiver<()>,
+        dup_ack: broadcast::Receiver<usize>,
+        keepalive: watch::Receiver<Option<KeepaliveConfig>>,
+        nodelay: watch::Receiver<bool>,
+        delayed_ack: watch::Receiver<bool>,
+        last_heard: watch::Receiver<Instant>,
+        srtt: watch::Sender<Option<Duration>>,
+        stats: Arc<std::sync::Mutex<SocketStats>>,
+        reset_request: Arc<Notify>,
+        retransmit_request: Arc<Notify>,
+        aborted: Arc<Notify>,
+        md5_key: Option<Vec<u8>>,
+        local_ip: Ipv4Addr,
+        mss: usize,
+        window_shift: u8,
     ) -> Self {
         let seq_no = send_buf.tail().await;
+        // The handshake already acked everything up to here.
+        let last_ack_transmitted = recv_buf.head().await;
+        let delayed_ack_interval = config.delayed_ack_interval;
+        // A lone segment is acked only once the remote's delayed ACK timer
+        // runs out. Taking it to run as long as ours, the RTO outlasts it.
+        let min_rto = TCP_MIN_RTO + delayed_ack_interval.unwrap_or_default();
+        let initial_rto = config
+            .initial_rto
+            .unwrap_or(TCP_DEFAULT_INITIAL_RTO)
+            .max(min_rto);
+        let congestion = config.congestion_control.controller(mss);
+        let pacing = config.pacing;
         Self {
             send_buf,
             recv_buf,
             remote,
+            local_ip,
             local_port,
             net,
             seq_no,
+            mss,
+            window_shift,
             last_transmitted: Instant::now(),
             ack_batch_timeout: Duration::from_millis(1),
-            last_acked: 0,
-            zero_window_probe_interval: Duration::from_millis(1),
+            last_acked: seq_no,
+            rtt: Rtt::new(
+                TCP_DEFAULT_RTX_TICK_INTERVAL,
+                initial_rto,
+                min_rto,
+                TCP_MAX_RTO,
+            ),
+            rtx_deadline: None,
+            rtx_queue: VecDeque::new(),
+            dup_acks: 0,
+            recover: None,
+            frto: None,
+            congestion,
+            delivered: 0,
+            delivered_time: Instant::now(),
+            next_send_at: Instant::now(),
+            pacing,
+            dup_ack,
+            persist_deadline: None,
+            persist_interval: TCP_MIN_PERSIST_INTERVAL,
             send_ack_request: should_ack,
-            last_ack_transmitted: 0,
+            last_ack_transmitted,
+            delayed_ack_interval,
+            ack_deadline: None,
+            advertised_edge: None,
             remaining_window_sz: TCP_DEFAULT_WINDOW_SZ,
+            keepalive,
+            nodelay,
+            delayed_ack,
+            last_heard,
+            srtt,
+            keepalive_probes_sent: 0,
+            stats,
+            reset_request,
+            retransmit_request,
+            aborted,
+            md5_key,
         }
     }
 
     pub async fn run(mut self) {
         let mut segment = [0; MAX_SEGMENT_SZ];
-        let mut segment_sz = MAX_SEGMENT_SZ;
+        let mut segment_sz = self.mss;
 
         // Set upper bound on how long before acks are sent back to sender.
         let mut transmit_ack_interval = tokio::time::interval(self.ack_batch_timeout);
-        let mut zero_window_probe_interval = tokio::time::interval(self.zero_window_probe_interval);
+        let mut persist_tick = tokio::time::interval(TCP_PERSIST_TICK_INTERVAL);
         let mut rtx_tick = tokio::time::interval(TCP_DEFAULT_RTX_TICK_INTERVAL);
+        let mut keepalive_tick = tokio::time::interval(TCP_KEEPALIVE_TICK_INTERVAL);
         let mut window_sz_update = self.send_buf.window_size_update();
         let mut last_acked_update = self.send_buf.tail_update();
 
         loop {
+            let may_send = self.congestion_allows_send();
+            let pacing_holds = Instant::now() < self.next_send_at;
             tokio::select! {
-                _ = self.send_buf.wait_for_new_data(self.seq_no) => {
+                _ = self.send_buf.wait_for_new_data(self.next_send_threshold()),
+                    if may_send && !pacing_holds =>
+                {
+                    if self.nagle_holds_small_segments() {
+                        segment_sz = self.mss;
+                    }
                     segment_sz = min(segment_sz, self.remaining_window_sz);
                     if segment_sz == 0  {
-                        segment_sz = min(self.remaining_window_sz, MAX_SEGMENT_SZ);
+                        segment_sz = min(self.remaining_window_sz, self.mss);
                     }
                     if segment_sz > 0 {
                         match self.try_consume_and_send(&mut segment[..segment_sz]).await {
@@ -209,13 +462,17 @@ impl<const BUF_SZ: usize, N: Net> TcpTransport<BUF_SZ, N> {
                         }
                     }
                 }
-                Ok(window_sz) = window_sz_update.recv() => {
-                    self.remaining_window_sz = window_sz.into();
+                _ = tokio::time::sleep_until(self.next_send_at.into()), if pacing_holds => {}
+                Ok(_) = window_sz_update.recv() => {
+                    self.update_remaining_window();
                 }
                 Ok(next_expected_seq_no) = last_acked_update.recv() => {
-                    self.on_last_byte_acked_updated(next_expected_seq_no).await;
+                    self.on_last_byte_acked_updated(next_expected_seq_no, &mut segment).await;
                 }
-                _ = zero_window_probe_interval.tick() => {
+                Ok(ack) = self.dup_ack.recv() => {
+                    self.on_dup_ack(ack, &mut segment).await;
+                }
+                _ = persist_tick.tick() => {
                     self.check_and_zero_window_probe().await;
                 }
                 _ = transmit_ack_interval.tick() => {
@@ -234,6 +491,20 @@ impl<const BUF_SZ: usize, N: Net> TcpTransport<BUF_SZ, N> {
                 _ = rtx_tick.tick() => {
                     self.check_retransmission(&mut segment).await;
                 }
+                _ = keepalive_tick.tick() => {
+                    if let KeepaliveDecision::Dead = self.check_keepalive().await {
+                        self.aborted.notify_one();
+                        break;
+                    }
+                }
+                _ = self.retransmit_request.notified() => {
+                    self.retransmit_oldest(&mut segment).await;
+                }
+                _ = self.reset_request.notified() => {
+                    self.reset().await;
+                    self.aborted.notify_one();
+                    break;
+                }
             }
         }
     }
@@ -243,13 +514,12 @@ impl<const BUF_SZ: usize, N: Net> TcpTransport<BUF_SZ, N> {
             Ok(bytes_readable) => {
                 // TODO: handle send failure
                 if self.send(self.seq_no, buf).await.is_ok() {
+                    self.on_data_sent(buf.len());
+                    self.pace(buf.len());
                     self.seq_no += buf.len();
                     self.remaining_window_sz -= buf.len();
 
-                    let next_seg_sz = min(
-                        MAX_SEGMENT_SZ,
-                        min(self.remaining_window_sz, bytes_readable),
-                    );
+                    let next_seg_sz = min(self.mss, min(self.remaining_window_sz, bytes_readable));
                     NextSendDecision::NextSegmentSize(next_seg_sz)
                 } else {
                     NextSendDecision::NextSegmentSize(buf.len())
@@ -265,44 +535,363 @@ impl<const BUF_SZ: usize, N: Net> TcpTransport<BUF_SZ, N> {
                 SliceError::StartSeqTooLow(next_seq_no) => {
                     // SendBuf's tail has been advanced due to zero probing.
                     self.seq_no = next_seq_no;
+                    self.update_remaining_window();
 
-                    NextSendDecision::NextSegmentSize(min(self.remaining_window_sz, MAX_SEGMENT_SZ))
+                    NextSendDecision::NextSegmentSize(min(self.remaining_window_sz, self.mss))
                 }
             },
         }
     }
 
+    /// Bytes sent that the remote has not acked yet.
+    fn in_flight(&self) -> usize {
+        // A probed byte can be acked before `seq_no` catches up to it.
+        self.seq_no.saturating_sub(self.last_acked)
+    }
+
+    /// Whether the congestion window has room for another full segment.
+    fn congestion_allows_send(&self) -> bool {
+        let in_flight = self.in_flight();
+        in_flight == 0 || in_flight + self.mss <= self.congestion.window()
+    }
+
+    /// Holds the next segment back until `len` bytes have gone out at the
+    /// pacing rate.
+    fn pace(&mut self, len: usize) {
+        let rate = self
+            .congestion
+            .pacing_rate()
+            .or_else(|| self.window_pacing_rate());
+        if let Some(rate) = rate.filter(|rate| *rate > 0.0) {
+            let now = Instant::now();
+            let behind = now.checked_sub(TCP_PACING_QUANTUM).unwrap_or(now);
+            self.next_send_at =
+                self.next_send_at.max(behind) + Duration::from_secs_f64(len as f64 / rate);
+        }
+    }
+
+    /// Bytes per second that spread a full window over a round trip, if
+    /// pacing is enabled and the RTT is known.
+    fn window_pacing_rate(&self) -> Option<f64> {
+        if !self.pacing {
+            return None;
+        }
+        let window = min(self.congestion.window(), self.send_buf.window_size());
+        Some(window as f64 / self.rtt.srtt()?.as_secs_f64())
+    }
+
+    /// Whether only full segments may be sent right now: under Nagle's
+    /// algorithm, small writes are held back while sent data is unacked
+    /// (RFC 896).
+    fn nagle_holds_small_segments(&self) -> bool {
+        !*self.nodelay.borrow() && self.seq_no > self.last_acked
+    }
+
+    /// The sequence number the send buffer must fill past before the next
+    /// segment goes out.
+    fn next_send_threshold(&self) -> usize {
+        if self.nagle_holds_small_segments() {
+            self.seq_no + self.mss - 1
+        } else {
+            self.seq_no
+        }
+    }
+
+    /// The remote accepts data up to its window past the last ACK
+    /// (RFC 793, section 3.7).
+    ///
+    /// The window is read from the send buffer rather than from its update,
+    /// since window and ACK updates may be handled out of order.
+    fn update_remaining_window(&mut self) {
+        self.remaining_window_sz =
+            (self.last_acked + self.send_buf.window_size()).saturating_sub(self.seq_no);
+    }
+
+    /// Runs the persist timer: while the remote advertises a zero window,
+    /// probe it with backoff so that a lost window update cannot stall the
+    /// connection. A window merely filled by data in flight is left to the
+    /// retransmission timer.
     async fn check_and_zero_window_probe(&mut self) {
-        if self.remaining_window_sz == 0 {
-            self.zero_window_probe().await;
+        if self.send_buf.window_size() > 0 {
+            self.persist_deadline = None;
+            self.persist_interval = TCP_MIN_PERSIST_INTERVAL;
+            return;
         }
+
+        let now = Instant::now();
+        let deadline = *self
+            .persist_deadline
+            .get_or_insert(now + self.persist_interval);
+        if now < deadline {
+            return;
+        }
+
+        self.zero_window_probe().await;
+        self.persist_interval = min(self.persist_interval * 2, TCP_MAX_PERSIST_INTERVAL);
+        self.persist_deadline = Some(now + self.persist_interval);
     }
 
     async fn check_and_retransmit_ack(&mut self) {
-        if self.last_transmitted.elapsed() > self.ack_batch_timeout {
-            let curr_ack = self.recv_buf.head().await;
-            if curr_ack != self.last_ack_transmitted {
+        if self.window_update_due().await {
+            self.send_ack().await.ok();
+            return;
+        }
+
+        let curr_ack = self.recv_buf.head().await;
+        if curr_ack == self.last_ack_transmitted {
+            self.ack_deadline = None;
+            return;
+        }
+
+        let Some(interval) = self.delayed_ack_interval else {
+            if self.last_transmitted.elapsed() > self.ack_batch_timeout {
                 self.send_ack().await.ok();
             }
+            return;
+        };
+
+        // Ack at least every second full-sized segment, and otherwise once
+        // the delay runs out (RFC 1122, section 4.2.3.2).
+        let deadline = *self.ack_deadline.get_or_insert(Instant::now() + interval);
+        let unacked = curr_ack.wrapping_sub(self.last_ack_transmitted);
+        let every_other = *self.delayed_ack.borrow() && unacked >= 2 * self.mss;
+        if every_other || Instant::now() >= deadline {
+            self.send_ack().await.ok();
         }
     }
 
+    /// The least the advertised window's right edge may move by, per the
+    /// receiver side silly window avoidance of RFC 1122, section 4.2.3.3.
+    fn sws_threshold(&self) -> usize {
+        min(self.mss, BUF_SZ / 2)
+    }
+
+    /// The window to advertise along with `ack`. Until enough of the receive
+    /// buffer frees up, the previous right edge is kept, rather than offering
+    /// the remote a sliver of a window on every read.
+    async fn window_to_advertise(&mut self, ack: usize) -> usize {
+        let edge = ack + self.recv_buf.window_size().await;
+        match self.advertised_edge {
+            Some(prev_edge) if edge < prev_edge + self.sws_threshold() => {
+                prev_edge.saturating_sub(ack)
+            }
+            _ => {
+                self.advertised_edge = Some(edge);
+                edge - ack
+            }
+        }
+    }
+
+    /// Whether the remote was offered less than a useful window, and the
+    /// application has since read enough to offer a useful one.
+    async fn window_update_due(&self) -> bool {
+        let Some(prev_edge) = self.advertised_edge else {
+            return false;
+        };
+        let ack = self.recv_buf.head().await;
+        let edge = ack + self.recv_buf.window_size().await;
+        let threshold = self.sws_threshold();
+        prev_edge.saturating_sub(ack) < threshold && edge >= prev_edge + threshold
+    }
+
     async fn check_retransmission(&mut self, segment_buf: &mut [u8]) {
+        match self.rtx_deadline {
+            Some(deadline) if Instant::now() >= deadline => {}
+            _ => return,
+        }
+
+        if self.rtx_queue.is_empty() {
+            self.rtx_deadline = None;
+            return;
+        }
+
+        let rto = self.rtt.rto();
+        self.congestion.on_timeout(self.in_flight());
+        self.retransmit_oldest(segment_buf).await;
+        self.rtt.back_off();
+        self.rtx_deadline = Some(Instant::now() + self.rtt.rto());
+
+        // A timeout while already recovering is taken at face value.
+        self.frto = match (self.frto, self.recover) {
+            (None, None) => Some(Frto::FirstAck {
+                recover: self.seq_no,
+                rto,
+            }),
+            (Some(frto), _) => {
+                self.recover = Some(frto.recover());
+                None
+            }
+            (None, Some(_)) => None,
+        };
+    }
+
+    /// Resends the unacked part of the oldest segment in flight.
+    async fn retransmit_oldest(&mut self, segment_buf: &mut [u8]) {
+        let end = match self.rtx_queue.front_mut() {
+            Some(oldest) => {
+                oldest.retransmitted = true;
+                oldest.delivered = self.delivered;
+                oldest.delivered_time = self.delivered_time;
+                oldest.end()
+            }
+            None => return,
+        };
+
+        let unacked = end.saturating_sub(self.last_acked);
+        let segment = &mut segment_buf[..min(unacked, self.mss)];
         if self
             .send_buf
-            .try_slice(self.last_acked, segment_buf)
+            .try_slice(self.last_acked, segment)
             .await
             .is_ok()
         {
             // TODO: handle failure
-            self.send(self.last_acked, segment_buf).await.unwrap();
+            if self.send(self.last_acked, segment).await.is_ok() {
+                self.stats.lock().unwrap().retransmissions += 1;
+            }
+        }
+    }
+
+    /// Queues the segment of `len` bytes just sent at `self.seq_no`, and arms
+    /// the retransmission timer if it is not running.
+    fn on_data_sent(&mut self, len: usize) {
+        let now = Instant::now();
+        self.rtx_queue.push_back(RtxSegment::new(
+            self.seq_no,
+            len,
+            now,
+            self.delivered,
+            self.delivered_time,
+        ));
+        if self.rtx_deadline.is_none() {
+            self.rtx_deadline = Some(now + self.rtt.rto());
+        }
+    }
+
+    /// Counts an ACK that acked nothing new, fast retransmitting the oldest
+    /// segment on the third in a row.
+    async fn on_dup_ack(&mut self, ack: usize, segment_buf: &mut [u8]) {
+        if ack != self.last_acked || self.rtx_queue.is_empty() {
+            return;
+        }
+
+        // The timeout was genuine: the rest of the segments sent before it
+        // are resent as their predecessors get acked.
+        if let Some(frto) = self.frto.take() {
+            self.recover = Some(frto.recover());
+            self.retransmit_oldest(segment_buf).await;
+            return;
+        }
+
+        self.dup_acks += 1;
+        if self.dup_acks == TCP_DUP_ACK_THRESHOLD {
+            node_log!(
+                self.net.logger(),
+                Level::Debug,
+                "Fast retransmitting seq no {}",
+                self.last_acked
+            );
+            self.recover = Some(self.seq_no);
+            self.congestion.on_fast_retransmit(self.in_flight());
+            self.retransmit_oldest(segment_buf).await;
+            self.rtx_deadline = Some(Instant::now() + self.rtt.rto());
         }
     }
 
-    async fn on_last_byte_acked_updated(&mut self, next_expected_seq_no: usize) {
+    async fn on_last_byte_acked_updated(
+        &mut self,
+        next_expected_seq_no: usize,
+        segment_buf: &mut [u8],
+    ) {
+        if next_expected_seq_no <= self.last_acked {
+            return;
+        }
+        let acked = next_expected_seq_no - self.last_acked;
         self.last_acked = next_expected_seq_no;
+        self.dup_acks = 0;
+        self.update_remaining_window();
+        self.frto_on_new_ack(next_expected_seq_no);
+
+        let mut newest_acked = None;
+        while let Some(oldest) = self.rtx_queue.front() {
+            if oldest.end() > next_expected_seq_no {
+                break;
+            }
+            newest_acked = self.rtx_queue.pop_front();
+        }
+        let now = Instant::now();
+        self.delivered += acked;
+        let mut sample = AckSample {
+            acked,
+            in_flight: self.in_flight(),
+            rtt: None,
+            retransmitted: false,
+            delivery_rate: None,
+            prior_delivered: 0,
+            delivered: self.delivered,
+        };
+        if let Some(newest) = newest_acked {
+            let rtt = newest.tx_time.elapsed();
+            if !newest.retransmitted {
+                self.rtt.sample(rtt);
+                self.srtt.send_replace(self.rtt.srtt());
+            }
+            sample.rtt = Some(rtt);
+            sample.retransmitted = newest.retransmitted;
+            let interval = now - newest.delivered_time;
+            if !interval.is_zero() {
+                sample.delivery_rate =
+                    Some((self.delivered - newest.delivered) as f64 / interval.as_secs_f64());
+            }
+            sample.prior_delivered = newest.delivered;
+        }
+        self.delivered_time = now;
+        self.congestion.on_ack(&sample, now);
+
+        // An ACK short of the recovery point means the segment after it was
+        // lost too, so it goes out at once rather than on a timeout.
+        match self.recover {
+            Some(recover) if next_expected_seq_no < recover => {
+                self.retransmit_oldest(segment_buf).await;
+            }
+            _ => self.recover = None,
+        }
+
+        // Restart the timer for the remaining unacked data, if any.
+        self.rtx_deadline = if next_expected_seq_no >= self.seq_no {
+            None
+        } else {
+            Some(Instant::now() + self.rtt.rto())
+        };
     }
 
+    /// Advances F-RTO on an ACK of new data. Two such ACKs in a row mean the
+    /// segments sent before the timeout were delayed rather than lost, so
+    /// the RTO backoff and window reduction are undone and no more of them
+    /// are resent.
+    fn frto_on_new_ack(&mut self, ack: usize) {
+        self.frto = match self.frto.take() {
+            Some(Frto::FirstAck { recover, rto }) if ack < recover => {
+                Some(Frto::SecondAck { recover, rto })
+            }
+            Some(Frto::SecondAck { rto, .. }) => {
+                node_log!(
+                    self.net.logger(),
+                    Level::Debug,
+                    "Spurious retransmission timeout, restoring RTO {:?}",
+                    rto
+                );
+                self.rtt.restore(rto);
+                self.congestion.on_spurious_timeout();
+                None
+            }
+            // Everything sent before the timeout is acked.
+            Some(Frto::FirstAck { .. }) | None => None,
+        };
+    }
+
+    /// Sends the next unsent byte into the closed window. The remote acks it
+    /// with its current window, accepting the byte only if the window opened.
     async fn zero_window_probe(&mut self) {
         let mut buf = [0; 1];
         if self.send_buf.try_slice(self.seq_no, &mut buf).await.is_ok() {
@@ -310,6 +899,69 @@ impl<const BUF_SZ: usize, N: Net> TcpTransport<BUF_SZ, N> {
         }
     }
 
+    async fn check_keepalive(&mut self) -> KeepaliveDecision {
+        let cfg = match *self.keepalive.borrow() {
+            Some(cfg) => cfg,
+            None => return KeepaliveDecision::Alive,
+        };
+
+        let quiet_for = self.last_heard.borrow().elapsed();
+        if quiet_for < cfg.idle {
+            self.keepalive_probes_sent = 0;
+            return KeepaliveDecision::Alive;
+        }
+
+        let next_probe_at = cfg.idle + cfg.interval * self.keepalive_probes_sent as u32;
+        if quiet_for < next_probe_at {
+            return KeepaliveDecision::Alive;
+        }
+
+        if self.keepalive_probes_sent == cfg.probes {
+            node_log!(
+                self.net.logger(),
+                Level::Warn,
+                "{}:{} did not answer {} keepalive probes, aborting connection",
+                self.remote.ip(),
+                self.remote.port().0,
+                cfg.probes
+            );
+            self.send_buf.close().await.ok();
+            self.recv_buf.close().await.ok();
+            return KeepaliveDecision::Dead;
+        }
+
+        // A segment the remote has already seen, which it must ACK.
+        self.send(self.seq_no - 1, &[]).await.ok();
+        self.keepalive_probes_sent += 1;
+        KeepaliveDecision::Alive
+    }
+
+    /// Aborts the connection, telling the remote with a RST. It carries the
+    /// next sequence number to send, which is what the remote expects once it
+    /// received everything in flight.
+    async fn reset(&mut self) {
+        let mut tcp_header = self.prepare_tcp_packet(self.seq_no).await;
+        tcp_header.rst = true;
+        tcp_header.checksum = tcp_header
+            .calc_checksum_ipv4_raw(self.local_ip.octets(), self.remote.ip().octets(), &[])
+            .unwrap();
+
+        let mut bytes = Vec::new();
+        tcp_header.write(&mut bytes).unwrap();
+        send_segment(
+            &*self.net,
+            Some(self.local_ip),
+            &bytes,
+            self.remote.ip(),
+            self.md5_key.as_deref(),
+        )
+        .await
+        .ok();
+
+        self.send_buf.close().await.ok();
+        self.recv_buf.close().await.ok();
+    }
+
     async fn send_ack(&mut self) -> Result<(), SendError> {
         // The empty-payload packet's main purpose is to update the remote
         // about our latest ACK sequence number.
@@ -320,36 +972,44 @@ impl<const BUF_SZ: usize, N: Net> TcpTransport<BUF_SZ, N> {
         let mut bytes = Vec::new();
         let mut tcp_header = self.prepare_tcp_packet(seq_no).await;
 
-        let src_ip = self
-            .net
-            .get_outbound_ip(self.remote.ip())
-            .await
-            .expect("Cannot find remote in the IP layer");
         let checksum = tcp_header
-            .calc_checksum_ipv4_raw(src_ip, self.remote.ip().octets(), payload)
+            .calc_checksum_ipv4_raw(self.local_ip.octets(), self.remote.ip().octets(), payload)
             .unwrap();
         tcp_header.checksum = checksum;
         let ack = tcp_header.acknowledgment_number;
         tcp_header.write(&mut bytes).unwrap();
         bytes.extend_from_slice(payload);
-        self.net
-            .send(&bytes, Protocol::Tcp, self.remote.ip())
-            .await
-            .map(|_| {
-                self.last_transmitted = Instant::now();
-                self.last_ack_transmitted = ack.try_into().unwrap();
-            })
+        send_segment(
+            &*self.net,
+            Some(self.local_ip),
+            &bytes,
+            self.remote.ip(),
+            self.md5_key.as_deref(),
+        )
+        .await
+        .map(|_| {
+            self.last_transmitted = Instant::now();
+            self.last_ack_transmitted = ack.try_into().unwrap();
+            self.ack_deadline = None;
+
+            let mut stats = self.stats.lock().unwrap();
+            stats.segments_sent += 1;
+            stats.bytes_sent += payload.len();
+        })
     }
 
-    async fn prepare_tcp_packet(&self, seq_no: usize) -> TcpHeader {
+    async fn prepare_tcp_packet(&mut self, seq_no: usize) -> TcpHeader {
         let src_port = self.local_port.0;
         let dst_port = self.remote.port().0;
         let seq_no = seq_no.try_into().expect("seq no overflow");
-        let window_sz = self.recv_buf.window_size().await.try_into().unwrap();
+        let ack = self.recv_buf.head().await;
+        let window_sz = (self.window_to_advertise(ack).await >> self.window_shift)
+            .try_into()
+            .unwrap();
 
         let mut header = TcpHeader::new(src_port, dst_port, seq_no, window_sz);
         header.ack = true;
-        header.acknowledgment_number = self.recv_buf.head().await.try_into().unwrap();
+        header.acknowledgment_number = ack.try_into().unwrap();
         header
     }
 }
@@ -357,6 +1017,10 @@ impl<const BUF_SZ: usize, N: Net> TcpTransport<BUF_SZ, N> {
 pub struct RtxConfig {
     max_err_retries: usize,
     rtx_interval: Duration,
+    /// The wait between retransmissions doubles after each, up to this.
+    max_rtx_interval: Duration,
+    /// Retransmissions after which the message is given up on, if limited.
+    max_retries: Option<usize>,
     timeout: Duration,
 }
 
@@ -365,15 +1029,117 @@ impl Default for RtxConfig {
         Self {
             max_err_retries: 80,
             rtx_interval: Duration::from_millis(50),
+            max_rtx_interval: Duration::from_millis(50),
+            max_retries: None,
             timeout: Duration::from_secs(3),
         }
     }
 }
 
+impl RtxConfig {
+    /// Retransmits with exponential backoff, up to `TCP_MAX_SYN_RTX_INTERVAL`
+    /// between tries, giving up after `timeout`.
+    pub fn with_backoff(timeout: Duration) -> Self {
+        Self {
+            max_rtx_interval: TCP_MAX_SYN_RTX_INTERVAL,
+            timeout,
+            ..Self::default()
+        }
+    }
+
+    /// Gives up once `max_retries` retransmissions have gone unacked for an
+    /// interval, even before the timeout.
+    pub fn with_max_retries(self, max_retries: usize) -> Self {
+        Self {
+            max_retries: Some(max_retries),
+            ..self
+        }
+    }
+}
+
+/// Sends a TCP segment to `dest`, from `src` or else the outbound IP towards
+/// it. With a key shared with `dest`, the segment is signed first (RFC 2385);
+/// one that cannot be signed is dropped, as if lost on the way.
+pub async fn send_segment<N: Net>(
+    net: &N,
+    src: Option<Ipv4Addr>,
+    segment: &[u8],
+    dest: Ipv4Addr,
+    md5_key: Option<&[u8]>,
+) -> Result<(), SendError> {
+    let Some(key) = md5_key else {
+        return match src {
+            Some(src) => net.send_from(src, segment, Protocol::Tcp, dest).await,
+            None => net.send(segment, Protocol::Tcp, dest).await,
+        };
+    };
+
+    // The signature covers the source address, so it is fixed up front.
+    let src = match src {
+        Some(src) => src,
+        None => net
+            .get_outbound_ip(dest)
+            .await
+            .ok_or(SendError::NoRoute)?
+            .into(),
+    };
+    match md5sig::sign_segment(segment, src, dest, key) {
+        Ok(signed) => net.send_from(src, &signed, Protocol::Tcp, dest).await,
+        Err(e) => {
+            node_log!(
+                net.logger(),
+                Level::Warn,
+                "Dropping TCP segment to {} that could not be signed, {:?}",
+                dest,
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
 pub fn transport_single_message<F: FnOnce(TransmissionError) + Send + Sync + 'static, N: Net>(
     payload: Vec<u8>,
     remote: Remote,
     router: Arc<N>,
+    md5_key: Option<Vec<u8>>,
+    cfg: RtxConfig,
+    on_err: F,
+) -> AckHandle {
+    spawn_single_message_transport(None, payload, remote, router, md5_key, cfg, on_err)
+}
+
+/// Like `transport_single_message`, but sends from `local_ip` rather than the
+/// outbound IP towards the remote.
+pub fn transport_single_message_from<
+    F: FnOnce(TransmissionError) + Send + Sync + 'static,
+    N: Net,
+>(
+    local_ip: Ipv4Addr,
+    payload: Vec<u8>,
+    remote: Remote,
+    router: Arc<N>,
+    md5_key: Option<Vec<u8>>,
+    cfg: RtxConfig,
+    on_err: F,
+) -> AckHandle {
+    spawn_single_message_transport(
+        Some(local_ip),
+        payload,
+        remote,
+        router,
+        md5_key,
+        cfg,
+        on_err,
+    )
+}
+
+fn spawn_single_message_transport<F: FnOnce(TransmissionError) + Send + Sync + 'static, N: Net>(
+    local_ip: Option<Ipv4Addr>,
+    payload: Vec<u8>,
+    remote: Remote,
+    router: Arc<N>,
+    md5_key: Option<Vec<u8>>,
     cfg: RtxConfig,
     on_err: F,
 ) -> AckHandle {
@@ -382,8 +1148,10 @@ pub fn transport_single_message<F: FnOnce(TransmissionError) + Send + Sync + 'st
     tokio::spawn(async move {
         let transporter = SingleMessageTransport {
             payload,
+            local_ip,
             remote,
             net: router,
+            md5_key,
             rtx_cfg: cfg,
             acked_rx,
             on_err,
@@ -429,8 +1197,10 @@ pub enum TransmissionError {
 /// TCP Transporter that ensures delivery of only one packet.
 struct SingleMessageTransport<F: FnOnce(TransmissionError) + Send, N: Net> {
     payload: Vec<u8>,
+    local_ip: Option<Ipv4Addr>,
     remote: Remote,
     net: Arc<N>,
+    md5_key: Option<Vec<u8>>,
     rtx_cfg: RtxConfig,
     acked_rx: oneshot::Receiver<()>,
     on_err: F,
@@ -454,11 +1224,22 @@ impl<F: FnOnce(TransmissionError) + Send, N: Net> SingleMessageTransport<F, N> {
     /// Transmit repeatedly, returning true if acked.
     async fn transmission_loop(&mut self) -> bool {
         let mut retried = 0;
+        let mut sent = 0;
 
-        let mut retransmit = tokio::time::interval(self.rtx_cfg.rtx_interval);
+        let mut rtx_interval = self.rtx_cfg.rtx_interval;
+        let retransmit = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(retransmit);
         loop {
             tokio::select! {
-                _ = retransmit.tick() => {
+                _ = &mut retransmit => {
+                    if self.rtx_cfg.max_retries.is_some_and(|max| sent > max) {
+                        return false;
+                    }
+                    sent += 1;
+                    retransmit
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + rtx_interval);
+                    rtx_interval = min(rtx_interval * 2, self.rtx_cfg.max_rtx_interval);
                     if self.send().await.is_err() {
                         retried += 1;
                         if retried >= self.rtx_cfg.max_err_retries {
@@ -478,8 +1259,57 @@ impl<F: FnOnce(TransmissionError) + Send, N: Net> SingleMessageTransport<F, N> {
     /// Errs when failed over the max retry limit. Otherwise, forward the send
     /// result to the caller.
     async fn send(&self) -> Result<(), SendError> {
-        self.net
-            .send(&self.payload, Protocol::Tcp, self.remote.ip())
-            .await
+        send_segment(
+            &*self.net,
+            self.local_ip,
+            &self.payload,
+            self.remote.ip(),
+            self.md5_key.as_deref(),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rto_converges() {
+        let tick = Duration::from_millis(10);
+        let mut rtt = Rtt::new(
+            tick,
+            Duration::from_secs(1),
+            Duration::from_millis(20),
+            Duration::from_secs(2),
+        );
+
+        // First sample: RTO = R + 4 * R/2.
+        rtt.sample(Duration::from_millis(100));
+        assert_eq!(rtt.rto(), Duration::from_millis(300));
+
+        // Jittery samples around 100ms settle the RTO near SRTT + G.
+        for i in 0..200 {
+            rtt.sample(Duration::from_millis(if i % 2 == 0 { 98 } else { 102 }));
+        }
+        let srtt = rtt.srtt.unwrap();
+        assert!(srtt.abs_diff(Duration::from_millis(100)) < Duration::from_millis(2));
+        assert!(rtt.rto() >= srtt + tick);
+        assert!(rtt.rto() < Duration::from_millis(120));
+
+        // Backoff doubles up to the maximum.
+        let rto = rtt.rto();
+        rtt.back_off();
+        assert_eq!(rtt.rto(), rto * 2);
+        for _ in 0..10 {
+            rtt.back_off();
+        }
+        assert_eq!(rtt.rto(), Duration::from_secs(2));
+
+        // A fresh sample recomputes the RTO, clamped to the minimum.
+        for _ in 0..200 {
+            rtt.sample(Duration::from_millis(1));
+        }
+        assert_eq!(rtt.rto(), Duration::from_millis(20));
     }
 }
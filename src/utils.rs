@@ -8,6 +8,13 @@ pub async fn loop_with_interval<Fut: Future<Output = ()>>(interval: Duration, f:
     }
 }
 
+/// Whether two byte strings are equal, compared without branching on their
+/// contents, so that timing does not reveal how much of a guessed secret was
+/// right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 /// Time any arbitrary expression's execution time.
 #[macro_export]
 macro_rules! timed {
@@ -18,6 +25,53 @@ macro_rules! timed {
     }};
 }
 
+/// Log through a [`logging::NodeLogger`], honoring its level override.
+#[macro_export]
+macro_rules! node_log {
+    ($logger: expr, $lvl: expr, $($arg: tt)+) => {
+        $logger.log($lvl, module_path!(), format_args!($($arg)+))
+    };
+}
+
+pub mod logging {
+    use std::fmt;
+
+    use log::{Level, LevelFilter, Record};
+
+    /// Filters a node's log lines.
+    ///
+    /// Without an override, lines go through the global `log` filter as
+    /// usual. With one, the override replaces the global max level, so a node
+    /// can be made more or less verbose without touching env vars.
+    #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+    pub struct NodeLogger {
+        level: Option<LevelFilter>,
+    }
+
+    impl NodeLogger {
+        pub const fn new(level: Option<LevelFilter>) -> Self {
+            Self { level }
+        }
+
+        pub fn enabled(&self, level: Level) -> bool {
+            level <= log::STATIC_MAX_LEVEL && level <= self.level.unwrap_or_else(log::max_level)
+        }
+
+        pub fn log(&self, level: Level, target: &str, args: fmt::Arguments<'_>) {
+            if !self.enabled(level) {
+                return;
+            }
+            log::logger().log(
+                &Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(args)
+                    .build(),
+            );
+        }
+    }
+}
+
 pub mod net {
     use std::net::{Ipv4Addr, SocketAddr};
 
@@ -31,6 +85,7 @@ pub mod net {
     pub struct Ipv4PacketBuilder<'a> {
         payload: Option<&'a [u8]>,
         ttl: Option<u8>,
+        identification: Option<u16>,
         protocol: Option<u8>,
         src: Option<Ipv4Addr>,
         dst: Option<Ipv4Addr>,
@@ -56,6 +111,12 @@ pub mod net {
             self
         }
 
+        /// Identifies the fragments of the packet, should it be fragmented.
+        pub fn with_identification(&mut self, identification: u16) -> &mut Self {
+            self.identification = Some(identification);
+            self
+        }
+
         pub fn with_protocol<P: Into<u8>>(&mut self, protocol: P) -> &mut Self {
             self.protocol = Some(protocol.into());
             self
@@ -83,7 +144,11 @@ pub mod net {
             let dst = self.dst.ok_or(BuildError::NoDestinationAddress)?;
             let ttl = self.ttl.unwrap_or_else(Ipv4PacketBuilder::default_ttl);
 
-            let ip_header = Ipv4Header::new(payload_len, ttl, protocol, src.octets(), dst.octets());
+            let mut ip_header =
+                Ipv4Header::new(payload_len, ttl, protocol, src.octets(), dst.octets());
+            ip_header.identification = self.identification.unwrap_or_default();
+            // Let links split packets larger than their MTU.
+            ip_header.dont_fragment = false;
 
             ip_header
                 .write(&mut buf)
@@ -238,6 +303,16 @@ pub mod sync {
 
 #[cfg(test)]
 mod tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn constant_time_eq_compares_bytes() {
+        assert!(constant_time_eq(b"cookie", b"cookie"));
+        assert!(!constant_time_eq(b"cookie", b"cookiE"));
+        assert!(!constant_time_eq(b"cookie", b"cook"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
     mod net {
 
         use std::net::Ipv4Addr;
@@ -264,15 +339,15 @@ mod tests {
                 .unwrap();
 
             let mut expected = Vec::new();
-            Ipv4Header::new(
+            let mut header = Ipv4Header::new(
                 payload.len().try_into().unwrap(),
                 ttl,
                 protocol,
                 src.octets(),
                 dst.octets(),
-            )
-            .write(&mut expected)
-            .unwrap();
+            );
+            header.dont_fragment = false;
+            header.write(&mut expected).unwrap();
             expected.extend_from_slice(&payload);
 
             assert_eq!(expected, packet_bytes);